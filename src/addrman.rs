@@ -0,0 +1,381 @@
+// Address manager, modeled on Bitcoin Core's addrman. Addresses we've only heard
+// about live in the "new" table; once we've connected to one successfully it
+// moves to the "tried" table. Both tables are split into buckets, and which
+// bucket (and slot within it) an address lands in is decided by hashing it with
+// a secret key. Since the bucket also depends on the address's network group
+// (and, for new addresses, on the group of the peer that told us about it), an
+// attacker controlling a few address ranges can only ever occupy a small
+// fraction of either table, which makes eclipsing the node expensive.
+
+use std::{
+	collections::HashMap,
+	io::{Read, Write},
+};
+
+use rand::{Rng, seq::SliceRandom};
+
+use crate::{
+	err::*,
+	common::*,
+	crypto::sha256::compute_double_sha256,
+	network::message::{NetAddr, NetHost, MAX_ADDR_TO_SEND},
+};
+
+const NEW_BUCKET_COUNT: usize = 1024;
+const TRIED_BUCKET_COUNT: usize = 256;
+const BUCKET_SIZE: usize = 64;
+// how many tried buckets a single group can spread over
+const TRIED_BUCKETS_PER_GROUP: u64 = 8;
+// how many new buckets the addresses learned from a single source group can spread over
+const NEW_BUCKETS_PER_SOURCE_GROUP: u64 = 64;
+
+const DAY: u64 = 24 * 60 * 60;
+// addresses not seen for this long are forgotten
+const HORIZON: u64 = 30 * DAY;
+// give up on never-successful addresses after this many tries
+const RETRIES: u32 = 3;
+// ...and on previously good ones after this many failures over MIN_FAIL_TIME
+const MAX_FAILURES: u32 = 10;
+const MIN_FAIL_TIME: u64 = 7 * DAY;
+
+// share at most this percentage of the table in response to getaddr
+const GETADDR_MAX_PCT: usize = 23;
+
+const FILE_VERSION: u8 = 1;
+
+// addresses are identified by host and port
+pub type AddrKey = (NetHost, u16);
+
+fn key_bytes(key: &AddrKey) -> Vec<u8> {
+	let mut bytes = vec![key.0.network_id()];
+	bytes.extend_from_slice(&key.0.as_bytes());
+	bytes.extend_from_slice(&key.1.to_be_bytes());
+	bytes
+}
+
+struct AddrInfo {
+	addr: NetAddr,
+	source: NetHost,
+	last_try: u64,
+	last_success: u64,
+	attempts: u32,
+	in_tried: bool,
+}
+
+impl AddrInfo {
+	fn key(&self) -> AddrKey {
+		(self.addr.host, self.addr.port)
+	}
+
+	// terrible addresses are the first to go when their slot is needed
+	fn is_terrible(&self, now: u64) -> bool {
+		let timestamp = self.addr.timestamp as u64;
+		if self.last_try > 0 && self.last_try + 60 >= now {
+			// tried in the last minute; give it a chance
+			false
+		} else if timestamp > now + 10 * 60 || timestamp + HORIZON < now {
+			// came from the future, or not seen for too long
+			true
+		} else if self.last_success == 0 && self.attempts >= RETRIES {
+			true
+		} else {
+			self.last_success + MIN_FAIL_TIME < now && self.attempts >= MAX_FAILURES
+		}
+	}
+
+	// relative likelihood of picking this address for an outbound connection
+	fn chance(&self, now: u64) -> f64 {
+		let mut chance = 1.0;
+		if self.last_try + 10 * 60 > now {
+			chance *= 0.01;
+		}
+		chance * 0.66f64.powi(std::cmp::min(self.attempts, 8) as i32)
+	}
+}
+
+pub struct AddrMan {
+	secret: [u8; 32],
+	entries: HashMap<AddrKey, AddrInfo>,
+	new_table: Vec<Option<AddrKey>>,
+	tried_table: Vec<Option<AddrKey>>,
+}
+
+impl AddrMan {
+	pub fn new() -> Self {
+		AddrMan {
+			secret: rand::random(),
+			entries: HashMap::new(),
+			new_table: vec![None; NEW_BUCKET_COUNT * BUCKET_SIZE],
+			tried_table: vec![None; TRIED_BUCKET_COUNT * BUCKET_SIZE],
+		}
+	}
+
+	pub fn len(&self) -> usize {
+		self.entries.len()
+	}
+
+	pub fn tried_count(&self) -> usize {
+		self.entries.values().filter(|info| info.in_tried).count()
+	}
+
+	fn hash(&self, parts: &[&[u8]]) -> u64 {
+		let mut buf = self.secret.to_vec();
+		for part in parts {
+			buf.extend_from_slice(part);
+		}
+		let hash = compute_double_sha256(&*buf);
+		u64::from_le_bytes(hash.as_bytes()[0..8].try_into().unwrap())
+	}
+
+	fn tried_slot(&self, key: &AddrKey) -> usize {
+		let h = self.hash(&[&key_bytes(key)]) % TRIED_BUCKETS_PER_GROUP;
+		let bucket = self.hash(&[&key.0.group(), &h.to_le_bytes()]) as usize % TRIED_BUCKET_COUNT;
+		let position = self.hash(&[b"K", &bucket.to_le_bytes(), &key_bytes(key)]) as usize % BUCKET_SIZE;
+		bucket * BUCKET_SIZE + position
+	}
+
+	fn new_slot(&self, key: &AddrKey, source: &NetHost) -> usize {
+		let source_group = source.group();
+		let h = self.hash(&[&key.0.group(), &source_group]) % NEW_BUCKETS_PER_SOURCE_GROUP;
+		let bucket = self.hash(&[&source_group, &h.to_le_bytes()]) as usize % NEW_BUCKET_COUNT;
+		let position = self.hash(&[b"N", &bucket.to_le_bytes(), &key_bytes(key)]) as usize % BUCKET_SIZE;
+		bucket * BUCKET_SIZE + position
+	}
+
+	fn remove(&mut self, key: &AddrKey) {
+		if let Some(info) = self.entries.remove(key) {
+			let slot = if info.in_tried {
+				self.tried_slot(key)
+			} else {
+				self.new_slot(key, &info.source)
+			};
+			let table = if info.in_tried { &mut self.tried_table } else { &mut self.new_table };
+			if table[slot] == Some(*key) {
+				table[slot] = None;
+			}
+		}
+	}
+
+	// places an entry in the new table, evicting a terrible occupant if need be
+	fn place_new(&mut self, info: AddrInfo, now: u64) -> bool {
+		let key = info.key();
+		let slot = self.new_slot(&key, &info.source);
+		if let Some(occupant) = self.new_table[slot] {
+			if !self.entries[&occupant].is_terrible(now) {
+				return false;
+			}
+			self.remove(&occupant);
+		}
+		self.new_table[slot] = Some(key);
+		self.entries.insert(key, AddrInfo { in_tried: false, ..info });
+		true
+	}
+
+	// places an entry in the tried table; whatever was there goes back to the new table
+	fn place_tried(&mut self, info: AddrInfo, now: u64) {
+		let key = info.key();
+		let slot = self.tried_slot(&key);
+		if let Some(occupant) = self.tried_table[slot] {
+			self.tried_table[slot] = None;
+			if let Some(evicted) = self.entries.remove(&occupant) {
+				self.place_new(evicted, now);
+			}
+		}
+		self.tried_table[slot] = Some(key);
+		self.entries.insert(key, AddrInfo { in_tried: true, ..info });
+	}
+
+	// learned about `addr` from a peer at `source`. returns true if it's new to us.
+	pub fn add(&mut self, mut addr: NetAddr, source: NetHost, time_penalty: u64) -> bool {
+		if !addr.host.is_routable() {
+			return false;
+		}
+		let now = now();
+		addr.timestamp = (addr.timestamp as u64).saturating_sub(time_penalty) as u32;
+
+		let key = (addr.host, addr.port);
+		if let Some(info) = self.entries.get_mut(&key) {
+			if addr.timestamp > info.addr.timestamp {
+				info.addr.timestamp = addr.timestamp;
+			}
+			info.addr.services |= addr.services;
+			return false;
+		}
+
+		self.place_new(AddrInfo {
+			addr,
+			source,
+			last_try: 0,
+			last_success: 0,
+			attempts: 0,
+			in_tried: false,
+		}, now)
+	}
+
+	// we're about to try connecting to `key`
+	pub fn attempt(&mut self, key: &AddrKey) {
+		if let Some(info) = self.entries.get_mut(key) {
+			info.last_try = now();
+			info.attempts += 1;
+		}
+	}
+
+	// completed a handshake with `key`; it graduates to the tried table
+	pub fn good(&mut self, key: &AddrKey) {
+		let now = now();
+		if let Some(info) = self.entries.get_mut(key) {
+			info.last_success = now;
+			info.last_try = now;
+			info.attempts = 0;
+			info.addr.timestamp = now as u32;
+			if info.in_tried {
+				return;
+			}
+		} else {
+			return;
+		}
+		let source = self.entries[key].source;
+		let slot = self.new_slot(key, &source);
+		if self.new_table[slot] == Some(*key) {
+			self.new_table[slot] = None;
+		}
+		let info = self.entries.remove(key).unwrap();
+		self.place_tried(info, now);
+	}
+
+	// picks an address to connect to, favoring ones that haven't failed recently
+	pub fn select(&self) -> Option<NetAddr> {
+		if self.entries.is_empty() {
+			return None;
+		}
+		let tried_count = self.tried_count();
+		let new_count = self.entries.len() - tried_count;
+		let mut rng = rand::thread_rng();
+		let now = now();
+		let mut factor = 1.0;
+
+		for _ in 0..10_000 {
+			let use_tried = new_count == 0 || (tried_count > 0 && rng.gen_bool(0.5));
+			let (table, bucket_count) = if use_tried {
+				(&self.tried_table, TRIED_BUCKET_COUNT)
+			} else {
+				(&self.new_table, NEW_BUCKET_COUNT)
+			};
+			// a random bucket, scanned from a random position
+			let bucket = rng.gen_range(0..bucket_count);
+			let start = rng.gen_range(0..BUCKET_SIZE);
+			let key = (0..BUCKET_SIZE)
+				.filter_map(|i| table[bucket * BUCKET_SIZE + (start + i) % BUCKET_SIZE])
+				.next();
+			if let Some(key) = key {
+				let info = &self.entries[&key];
+				if rng.gen::<f64>() < factor * info.chance(now) {
+					return Some(info.addr.clone());
+				}
+				factor *= 1.2;
+			}
+		}
+		None
+	}
+
+	// a random sample of the addresses we know, for answering getaddr
+	pub fn get_addrs(&self) -> Vec<NetAddr> {
+		let now = now();
+		let mut addrs = self.entries.values()
+			.filter(|info| !info.is_terrible(now))
+			.map(|info| info.addr.clone())
+			.collect::<Vec<_>>();
+		addrs.shuffle(&mut rand::thread_rng());
+		let count = std::cmp::min(MAX_ADDR_TO_SEND, self.entries.len() * GETADDR_MAX_PCT / 100);
+		addrs.truncate(count);
+		addrs
+	}
+
+	pub fn load(path: &str) -> Self {
+		let mut addrman = AddrMan::new();
+		if !std::path::Path::new(path).is_file() {
+			return addrman;
+		}
+		let mut file = match std::fs::File::open(path) {
+			Ok(file) => file,
+			Err(err) => {
+				log_error!("warning: failed to load {}: {}", path, err.to_string());
+				return addrman;
+			}
+		};
+		let result: Result<()> = try {
+			addrman.deserialize(&mut std::io::BufReader::new(&mut file))?;
+		};
+		match result {
+			Ok(_) => log_info!("loaded {} addresses ({} tried)", addrman.len(), addrman.tried_count()),
+			Err(err) => {
+				log_error!("warning: {} is corrupt: {}", path, err.to_string());
+				addrman = AddrMan::new();
+			},
+		}
+		addrman
+	}
+
+	pub fn save(&self, path: &str) {
+		let result: Result<()> = try {
+			let file = std::fs::File::create(path)
+				.map_err(|err| Err::IOError(err.to_string()))?;
+			let mut stream = std::io::BufWriter::new(file);
+			self.serialize(&mut stream)?;
+			stream.flush().map_err(|err| Err::IOError(err.to_string()))?;
+		};
+		if let Err(err) = result {
+			log_error!("warning: failed to save addresses: {}", err.to_string());
+		}
+	}
+
+	// table positions aren't stored; they're recomputed from the secret on load
+	fn serialize(&self, stream: &mut dyn Write) -> Result<()> {
+		write_u8(stream, FILE_VERSION)?;
+		write_buf_exact(stream, &self.secret)?;
+		write_u64(stream, self.entries.len() as u64)?;
+		for info in self.entries.values() {
+			write_u32(stream, info.addr.timestamp)?;
+			write_u64(stream, info.addr.services)?;
+			info.addr.host.serialize_v2(stream)?;
+			write_u16(stream, info.addr.port)?;
+			info.source.serialize_v2(stream)?;
+			write_u64(stream, info.last_try)?;
+			write_u64(stream, info.last_success)?;
+			write_u32(stream, info.attempts)?;
+			write_bool(stream, info.in_tried)?;
+		}
+		Ok(())
+	}
+
+	fn deserialize(&mut self, stream: &mut dyn Read) -> Result<()> {
+		let version = read_u8(stream)?;
+		if version != FILE_VERSION {
+			return Err(Err::ValueError(format!("unsupported version: {}", version)));
+		}
+		read_buf_exact(stream, &mut self.secret)?;
+		let now = now();
+		let count = read_u64(stream)?;
+		for _ in 0..count {
+			let timestamp = read_u32(stream)?;
+			let services = read_u64(stream)?;
+			let host = NetHost::deserialize_v2(stream)?;
+			let port = read_u16(stream)?;
+			let source = NetHost::deserialize_v2(stream)?;
+			let info = AddrInfo {
+				addr: NetAddr::new(host.ok_or_else(|| Err::ValueError("unknown network".to_owned()))?, port, services, timestamp),
+				source: source.ok_or_else(|| Err::ValueError("unknown network".to_owned()))?,
+				last_try: read_u64(stream)?,
+				last_success: read_u64(stream)?,
+				attempts: read_u32(stream)?,
+				in_tried: read_bool(stream)?,
+			};
+			if info.in_tried {
+				self.place_tried(info, now);
+			} else {
+				self.place_new(info, now);
+			}
+		}
+		Ok(())
+	}
+}
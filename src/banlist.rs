@@ -0,0 +1,138 @@
+// Banned hosts, with the time each ban expires. Peers end up here by scoring too
+// much misbehavior, or by hand via `setban`. Bans are per host rather than per
+// address, so a banned peer can't come straight back on another port.
+
+use std::{
+	collections::HashMap,
+	io::{Read, Write},
+};
+
+use crate::{
+	err::*,
+	common::*,
+	network::message::NetHost,
+};
+
+pub const DEFAULT_BAN_TIME: u64 = 24 * 60 * 60;
+
+const FILE_VERSION: u8 = 1;
+
+#[derive(Clone, Copy)]
+pub struct BanEntry {
+	pub created: u64,
+	pub until: u64,
+}
+
+pub struct BanList {
+	bans: HashMap<NetHost, BanEntry>,
+}
+
+impl BanList {
+	pub fn new() -> Self {
+		BanList {
+			bans: HashMap::new(),
+		}
+	}
+
+	pub fn ban(&mut self, host: NetHost, duration: u64) {
+		let now = now();
+		let until = now.saturating_add(duration);
+		let entry = self.bans.entry(host).or_insert(BanEntry { created: now, until });
+		// an existing ban is only ever extended
+		entry.until = std::cmp::max(entry.until, until);
+	}
+
+	pub fn unban(&mut self, host: &NetHost) -> bool {
+		self.bans.remove(host).is_some()
+	}
+
+	pub fn clear(&mut self) {
+		self.bans.clear();
+	}
+
+	pub fn is_banned(&self, host: &NetHost) -> bool {
+		self.bans.get(host).is_some_and(|entry| entry.until > now())
+	}
+
+	// drops expired bans
+	pub fn sweep(&mut self) {
+		let now = now();
+		self.bans.retain(|_, entry| entry.until > now);
+	}
+
+	// sorted by expiry
+	pub fn entries(&self) -> Vec<(NetHost, BanEntry)> {
+		let mut entries = self.bans.iter().map(|(&host, &entry)| (host, entry)).collect::<Vec<_>>();
+		entries.sort_by_key(|(_, entry)| entry.until);
+		entries
+	}
+
+	pub fn load(path: &str) -> Self {
+		let mut banlist = BanList::new();
+		if !std::path::Path::new(path).is_file() {
+			return banlist;
+		}
+		let mut file = match std::fs::File::open(path) {
+			Ok(file) => file,
+			Err(err) => {
+				log_error!("warning: failed to load {}: {}", path, err.to_string());
+				return banlist;
+			}
+		};
+		let result: Result<()> = try {
+			banlist.deserialize(&mut std::io::BufReader::new(&mut file))?;
+		};
+		match result {
+			Ok(_) => {
+				banlist.sweep();
+				log_info!("loaded {} banned hosts", banlist.bans.len());
+			},
+			Err(err) => {
+				log_error!("warning: {} is corrupt: {}", path, err.to_string());
+				banlist = BanList::new();
+			},
+		}
+		banlist
+	}
+
+	pub fn save(&self, path: &str) {
+		let result: Result<()> = try {
+			let file = std::fs::File::create(path)
+				.map_err(|err| Err::IOError(err.to_string()))?;
+			let mut stream = std::io::BufWriter::new(file);
+			self.serialize(&mut stream)?;
+			stream.flush().map_err(|err| Err::IOError(err.to_string()))?;
+		};
+		if let Err(err) = result {
+			log_error!("warning: failed to save ban list: {}", err.to_string());
+		}
+	}
+
+	fn serialize(&self, stream: &mut dyn Write) -> Result<()> {
+		write_u8(stream, FILE_VERSION)?;
+		write_u64(stream, self.bans.len() as u64)?;
+		for (host, entry) in self.bans.iter() {
+			host.serialize_v2(stream)?;
+			write_u64(stream, entry.created)?;
+			write_u64(stream, entry.until)?;
+		}
+		Ok(())
+	}
+
+	fn deserialize(&mut self, stream: &mut dyn Read) -> Result<()> {
+		let version = read_u8(stream)?;
+		if version != FILE_VERSION {
+			return Err(Err::ValueError(format!("unsupported version: {}", version)));
+		}
+		let count = read_u64(stream)?;
+		for _ in 0..count {
+			let host = NetHost::deserialize_v2(stream)?;
+			let created = read_u64(stream)?;
+			let until = read_u64(stream)?;
+			if let Some(host) = host {
+				self.bans.insert(host, BanEntry { created, until });
+			}
+		}
+		Ok(())
+	}
+}
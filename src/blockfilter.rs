@@ -0,0 +1,138 @@
+// BIP 158 basic block filters: a Golomb-coded set of every output script a
+// block creates and every script its inputs spend. Light clients (BIP 157)
+// fetch these instead of blocks, and only download the blocks that match.
+
+use std::collections::BTreeSet;
+
+use crate::{
+	common::write_var_int,
+	crypto::sha256::{Sha256, compute_double_sha256},
+	crypto::siphash::compute_siphash,
+	network::message::Block,
+	script::Script,
+};
+
+pub const BASIC_FILTER_TYPE: u8 = 0;
+
+// golomb-rice parameters of the basic filter type
+const P: u8 = 19;
+const M: u64 = 784931;
+
+const OP_RETURN: u8 = 0x6a;
+
+pub struct BlockFilter(Vec<u8>);
+
+impl BlockFilter {
+	// `spent` is the scripts of the outputs the block's inputs spend
+	pub fn basic<'a>(block: &Block, spent: impl Iterator<Item = &'a Script>) -> Self {
+		let elements = block.txs.iter()
+			.flat_map(|tx| tx.outputs.iter().map(|output| output.lock.as_bytes()))
+			.filter(|script| script.first().is_some_and(|&op| op != OP_RETURN))
+			.chain(spent.map(|script| script.as_bytes()).filter(|script| !script.is_empty()))
+			.collect::<BTreeSet<_>>();
+		BlockFilter::new(&block.header.compute_hash(), elements.into_iter())
+	}
+
+	fn new<'a>(block_hash: &Sha256, elements: impl ExactSizeIterator<Item = &'a [u8]>) -> Self {
+		let bytes = block_hash.as_bytes();
+		let k0 = u64::from_le_bytes(bytes[0..8].try_into().unwrap());
+		let k1 = u64::from_le_bytes(bytes[8..16].try_into().unwrap());
+		let n = elements.len() as u64;
+
+		// each element hashes to [0, n * M), which spaces them M apart on average
+		let range = n * M;
+		let mut values = elements
+			.map(|e| ((compute_siphash(k0, k1, e) as u128 * range as u128) >> 64) as u64)
+			.collect::<Vec<_>>();
+		values.sort_unstable();
+
+		let mut data = Vec::new();
+		write_var_int(&mut data, n).unwrap();
+		let mut bits = BitWriter::new(data);
+		let mut last = 0;
+		for value in values {
+			let delta = value - last;
+			last = value;
+			// the quotient in unary, then the remainder in P bits
+			for _ in 0..(delta >> P) {
+				bits.write(1, 1);
+			}
+			bits.write(0, 1);
+			bits.write(delta, P);
+		}
+		BlockFilter(bits.finish())
+	}
+
+	pub fn from_bytes(bytes: Vec<u8>) -> Self {
+		BlockFilter(bytes)
+	}
+
+	pub fn as_bytes(&self) -> &[u8] {
+		&self.0
+	}
+
+	pub fn compute_hash(&self) -> Sha256 {
+		compute_double_sha256(self.as_bytes())
+	}
+}
+
+// filter headers chain the filters together the way block headers chain blocks
+pub fn compute_filter_header(filter_hash: &Sha256, prev_header: &Sha256) -> Sha256 {
+	let mut bytes = Vec::with_capacity(64);
+	bytes.extend_from_slice(filter_hash.as_bytes());
+	bytes.extend_from_slice(prev_header.as_bytes());
+	compute_double_sha256(&*bytes)
+}
+
+// bits are packed most significant first
+struct BitWriter {
+	bytes: Vec<u8>,
+	used: u8,
+}
+
+impl BitWriter {
+	fn new(bytes: Vec<u8>) -> Self {
+		BitWriter {
+			bytes,
+			used: 8,
+		}
+	}
+
+	// the low `count` bits of `value`
+	fn write(&mut self, value: u64, count: u8) {
+		for i in (0..count).rev() {
+			if self.used == 8 {
+				self.bytes.push(0);
+				self.used = 0;
+			}
+			if value >> i & 1 != 0 {
+				*self.bytes.last_mut().unwrap() |= 0x80 >> self.used;
+			}
+			self.used += 1;
+		}
+	}
+
+	fn finish(self) -> Vec<u8> {
+		self.bytes
+	}
+}
+
+#[test]
+fn basic_filter() {
+	use crate::common::bytes_to_hex;
+
+	// the genesis block vector of BIP 158's testnet test data
+	let mut block = Block::genesis();
+	block.header.timestamp = 1296688602;
+	block.header.set_nonce(414098458);
+	assert!(format!("{}", block.header.compute_hash()) == "000000000933ea01ad0ee984209779baaec3ced90fa3f408719526f8d77f4943");
+
+	let filter = BlockFilter::basic(&block, std::iter::empty());
+	assert!(bytes_to_hex(filter.as_bytes()) == "019dfca8");
+	let header = compute_filter_header(&filter.compute_hash(), &Sha256::default());
+	assert!(format!("{}", header) == "21584579b7eb08997773e5aeff3a7f932700042d0ed2a6129012b7d7ae81b750");
+
+	// no elements is just the count
+	let filter = BlockFilter::new(&Sha256::default(), std::iter::empty());
+	assert!(filter.as_bytes() == [0]);
+}
@@ -0,0 +1,147 @@
+// BIP 37 bloom filters. A light client loads one describing its own scripts,
+// keys and outpoints; after that it's only told about txs that match, and gets
+// blocks as merkleblocks proving which txs in them did.
+
+use crate::{
+	err::*,
+	crypto::murmur3::compute_murmur3,
+	network::message::{FilterLoad, Tx, TxOutput},
+	script::{Op, Script},
+};
+
+pub const MAX_BLOOM_FILTER_SIZE: usize = 36_000;
+pub const MAX_HASH_FUNCS: u32 = 50;
+// the largest element a filteradd can carry: the largest script push
+pub const MAX_FILTER_ADD_SIZE: usize = 520;
+
+// what to add to the filter when a tx output matches it, so spends of that
+// output match too. 0 adds nothing
+const BLOOM_UPDATE_ALL: u8 = 1;
+const BLOOM_UPDATE_P2PUBKEY_ONLY: u8 = 2;
+const BLOOM_UPDATE_MASK: u8 = 3;
+
+pub struct BloomFilter {
+	data: Vec<u8>,
+	hash_funcs: u32,
+	tweak: u32,
+	flags: u8,
+}
+
+impl BloomFilter {
+	pub fn new(load: FilterLoad) -> Result<Self> {
+		if load.data.len() > MAX_BLOOM_FILTER_SIZE || load.hash_funcs > MAX_HASH_FUNCS {
+			return Err(Err::ValueError(format!("bloom filter too large: {} bytes, {} hash functions", load.data.len(), load.hash_funcs)));
+		}
+		Ok(BloomFilter {
+			data: load.data,
+			hash_funcs: load.hash_funcs,
+			tweak: load.tweak,
+			flags: load.flags,
+		})
+	}
+
+	fn bit_index(&self, n: u32, key: &[u8]) -> usize {
+		let seed = n.wrapping_mul(0xfba4c795).wrapping_add(self.tweak);
+		compute_murmur3(seed, key) as usize % (self.data.len() * 8)
+	}
+
+	pub fn insert(&mut self, key: &[u8]) {
+		if self.data.is_empty() {
+			return;
+		}
+		for n in 0..self.hash_funcs {
+			let i = self.bit_index(n, key);
+			self.data[i / 8] |= 1 << (i % 8);
+		}
+	}
+
+	// an empty filter matches everything
+	pub fn contains(&self, key: &[u8]) -> bool {
+		if self.data.is_empty() {
+			return true;
+		}
+		(0..self.hash_funcs).all(|n| {
+			let i = self.bit_index(n, key);
+			self.data[i / 8] & (1 << (i % 8)) != 0
+		})
+	}
+
+	// whether the tx touches anything in the filter. outputs that match get
+	// their outpoint added according to the update flags
+	pub fn is_relevant_and_update(&mut self, tx: &Tx) -> bool {
+		let txid = tx.compute_hash();
+		let mut found = self.contains(txid.as_bytes());
+
+		for (index, output) in tx.outputs.iter().enumerate() {
+			let matched = pushes(&output.lock).any(|data| !data.is_empty() && self.contains(data));
+			if !matched {
+				continue;
+			}
+			found = true;
+			let update = match self.flags & BLOOM_UPDATE_MASK {
+				BLOOM_UPDATE_ALL => true,
+				BLOOM_UPDATE_P2PUBKEY_ONLY => is_pubkey_or_multisig(output),
+				_ => false,
+			};
+			if update {
+				self.insert(&outpoint(txid.as_bytes(), index as u32));
+			}
+		}
+		if found {
+			return true;
+		}
+
+		tx.inputs.iter().any(|input| {
+			self.contains(&outpoint(input.tx_hash.as_bytes(), input.index)) ||
+				pushes(&input.unlock).any(|data| !data.is_empty() && self.contains(data))
+		})
+	}
+}
+
+fn outpoint(txid: &[u8], index: u32) -> Vec<u8> {
+	let mut bytes = txid.to_vec();
+	bytes.extend_from_slice(&index.to_le_bytes());
+	bytes
+}
+
+fn pushes(script: &Script) -> impl Iterator<Item = &[u8]> {
+	script.ops().filter_map(|op| match op {
+		Op::DATA(data) | Op::PUSHDATA1(data) | Op::PUSHDATA2(data) | Op::PUSHDATA4(data) => Some(data),
+		_ => None,
+	})
+}
+
+// <pubkey> CHECKSIG, or <m> <pubkey>... <n> CHECKMULTISIG
+fn is_pubkey_or_multisig(output: &TxOutput) -> bool {
+	let ops = output.lock.ops().collect::<Vec<_>>();
+	match ops.as_slice() {
+		[key, Op::CHECKSIG] => key.push_data().is_some_and(|key| key.len() == 33 || key.len() == 65),
+		[m, keys @ .., n, Op::CHECKMULTISIG] => {
+			m.small_int().is_some() &&
+				n.small_int() == Some(keys.len()) &&
+				keys.iter().all(|key| key.push_data().is_some_and(|key| key.len() == 33 || key.len() == 65))
+		},
+		_ => false,
+	}
+}
+
+#[test]
+fn bloom_filter() {
+	use crate::common::hex_to_bytes;
+
+	// bitcoind's bloom_create_insert_serialize: 3 elements at a 1% false positive rate
+	let load = FilterLoad { data: vec![0; 3], hash_funcs: 5, tweak: 0, flags: BLOOM_UPDATE_ALL };
+	let mut filter = BloomFilter::new(load).unwrap();
+	let key = |hex: &str| hex_to_bytes(hex).unwrap();
+	filter.insert(&key("99108ad8ed9bb6274d3980bab5a85c048f0950c8"));
+	assert!(filter.contains(&key("99108ad8ed9bb6274d3980bab5a85c048f0950c8")));
+	assert!(!filter.contains(&key("19108ad8ed9bb6274d3980bab5a85c048f0950c8")));
+	filter.insert(&key("b5a2c786d9ef4658287ced5914b37a1b4aa32eee"));
+	filter.insert(&key("b9300670b4c5366e95b2699e8b18bc75e5f729c5"));
+	assert!(filter.contains(&key("b5a2c786d9ef4658287ced5914b37a1b4aa32eee")));
+	assert!(filter.contains(&key("b9300670b4c5366e95b2699e8b18bc75e5f729c5")));
+	assert!(filter.data == [0x61, 0x4e, 0x9b]);
+
+	let too_big = FilterLoad { data: vec![0; MAX_BLOOM_FILTER_SIZE + 1], hash_funcs: 5, tweak: 0, flags: 0 };
+	assert!(BloomFilter::new(too_big).is_err());
+}
@@ -142,14 +142,69 @@ fn hex_to_bytes_impl<T: Iterator<Item=(u8, u8)>>(hex: T, mut bytes: Vec<u8>) ->
 	Ok(bytes)
 }
 
+const BASE32_ALPHABET: &[u8; 32] = b"abcdefghijklmnopqrstuvwxyz234567";
+
+// RFC 4648 base32, lowercase and unpadded (as used in .onion and .b32.i2p names)
+pub fn bytes_to_base32(bytes: &[u8]) -> String {
+	let mut s = String::new();
+	let mut buffer = 0u32;
+	let mut bits = 0;
+	for &b in bytes {
+		buffer = (buffer << 8) | b as u32;
+		bits += 8;
+		while bits >= 5 {
+			bits -= 5;
+			s.push(BASE32_ALPHABET[((buffer >> bits) & 0x1f) as usize] as char);
+		}
+	}
+	if bits > 0 {
+		s.push(BASE32_ALPHABET[((buffer << (5 - bits)) & 0x1f) as usize] as char);
+	}
+	s
+}
+
+pub fn base32_to_bytes(s: &str) -> Result<Vec<u8>> {
+	let mut bytes = Vec::new();
+	let mut buffer = 0u32;
+	let mut bits = 0;
+	for c in s.bytes() {
+		let value = BASE32_ALPHABET.iter()
+			.position(|&e| e == c.to_ascii_lowercase())
+			.ok_or_else(|| Err::ValueError(format!("the input `{}` is not valid base32", s)))?;
+		buffer = (buffer << 5) | value as u32;
+		bits += 5;
+		if bits >= 8 {
+			bits -= 8;
+			bytes.push((buffer >> bits) as u8);
+		}
+	}
+	Ok(bytes)
+}
+
 // pub fn hash<T: Serialize>(item: &T) -> Result<Sha256> {
 // 	Ok(compute_double_sha256(&*serialize(item)?))
 // }
 
 pub fn now() -> u64 {
+	#[cfg(test)]
+	if let Some(time) = MOCK_TIME.get() {
+		return time;
+	}
 	std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs()
 }
 
+// what now() returns, for tests that need to move the clock along. per thread,
+// so tests running alongside aren't affected
+#[cfg(test)]
+thread_local! {
+	static MOCK_TIME: std::cell::Cell<Option<u64>> = const { std::cell::Cell::new(None) };
+}
+
+#[cfg(test)]
+pub fn set_mock_time(time: Option<u64>) {
+	MOCK_TIME.set(time);
+}
+
 pub fn read_u8(stream: &mut dyn Read) -> Result<u8> {
 	let mut buf = [0; 1];
 	stream.read_exact(&mut buf).map_err(|err| Err::NetworkError(err.to_string()))?;
@@ -230,11 +285,23 @@ pub fn read_var_int(stream: &mut dyn Read) -> Result<u64> {
 	}
 }
 
+// largest length prefix we accept; anything bigger can't fit in a message anyway,
+// and allocating for it up front would let a peer exhaust our memory
+pub const MAX_SIZE: u64 = 0x0200_0000;
+
+pub fn read_var_size(stream: &mut dyn Read) -> Result<usize> {
+	let size = read_var_int(stream)?;
+	if size > MAX_SIZE {
+		return Err(Err::ValueError(format!("size too large: {}", size)));
+	}
+	Ok(size as usize)
+}
+
 pub fn read_var_str(stream: &mut dyn Read) -> Result<String> {
-	let len = read_var_int(stream)? as usize;
+	let len = read_var_size(stream)?;
 	let mut buf = vec![0; len];
 	read_buf_exact(stream, &mut buf)?;
-	Ok(String::from_utf8(buf).unwrap())
+	Ok(String::from_utf8_lossy(&buf).into_owned())
 }
 
 pub fn read_str_exact(stream: &mut dyn Read, size: usize) -> Result<String> {
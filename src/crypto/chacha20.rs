@@ -0,0 +1,112 @@
+// ChaCha20 (RFC 8439), and the forward-secure variant BIP 324 encrypts packet
+// lengths with.
+
+const BLOCK_SIZE: usize = 64;
+
+fn quarter_round(s: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+	s[a] = s[a].wrapping_add(s[b]); s[d] = (s[d] ^ s[a]).rotate_left(16);
+	s[c] = s[c].wrapping_add(s[d]); s[b] = (s[b] ^ s[c]).rotate_left(12);
+	s[a] = s[a].wrapping_add(s[b]); s[d] = (s[d] ^ s[a]).rotate_left(8);
+	s[c] = s[c].wrapping_add(s[d]); s[b] = (s[b] ^ s[c]).rotate_left(7);
+}
+
+pub fn chacha20_block(key: &[u8; 32], nonce: &[u8; 12], counter: u32) -> [u8; BLOCK_SIZE] {
+	let word = |bytes: &[u8], i: usize| u32::from_le_bytes(bytes[i * 4..i * 4 + 4].try_into().unwrap());
+	let mut state = [
+		0x61707865, 0x3320646e, 0x79622d32, 0x6b206574,
+		word(key, 0), word(key, 1), word(key, 2), word(key, 3),
+		word(key, 4), word(key, 5), word(key, 6), word(key, 7),
+		counter, word(nonce, 0), word(nonce, 1), word(nonce, 2),
+	];
+	let initial = state;
+	for _ in 0..10 {
+		quarter_round(&mut state, 0, 4, 8, 12);
+		quarter_round(&mut state, 1, 5, 9, 13);
+		quarter_round(&mut state, 2, 6, 10, 14);
+		quarter_round(&mut state, 3, 7, 11, 15);
+		quarter_round(&mut state, 0, 5, 10, 15);
+		quarter_round(&mut state, 1, 6, 11, 12);
+		quarter_round(&mut state, 2, 7, 8, 13);
+		quarter_round(&mut state, 3, 4, 9, 14);
+	}
+
+	let mut block = [0; BLOCK_SIZE];
+	for (i, chunk) in block.chunks_exact_mut(4).enumerate() {
+		chunk.copy_from_slice(&state[i].wrapping_add(initial[i]).to_le_bytes());
+	}
+	block
+}
+
+// the keystream as a stream: bytes left over from one call are used by the next
+pub struct ChaCha20 {
+	key: [u8; 32],
+	nonce: [u8; 12],
+	counter: u32,
+	keystream: Vec<u8>,
+}
+
+impl ChaCha20 {
+	pub fn new(key: [u8; 32], nonce: [u8; 12], counter: u32) -> Self {
+		ChaCha20 {
+			key,
+			nonce,
+			counter,
+			keystream: Vec::new(),
+		}
+	}
+
+	pub fn keystream(&mut self, size: usize) -> Vec<u8> {
+		while self.keystream.len() < size {
+			self.keystream.extend_from_slice(&chacha20_block(&self.key, &self.nonce, self.counter));
+			self.counter = self.counter.wrapping_add(1);
+		}
+		self.keystream.drain(..size).collect()
+	}
+
+	pub fn crypt(&mut self, data: &mut [u8]) {
+		let keystream = self.keystream(data.len());
+		for (byte, k) in data.iter_mut().zip(keystream) {
+			*byte ^= k;
+		}
+	}
+}
+
+// chunks are encrypted as one stream, and every REKEY_INTERVAL chunks the key
+// is replaced with the next 32 bytes of keystream, so an attacker who learns
+// the current key can't read what came before (BIP 324)
+pub const REKEY_INTERVAL: u64 = 224;
+
+pub struct FSChaCha20 {
+	cipher: ChaCha20,
+	chunk_counter: u64,
+	rekey_counter: u64,
+}
+
+impl FSChaCha20 {
+	pub fn new(key: [u8; 32]) -> Self {
+		FSChaCha20 {
+			cipher: ChaCha20::new(key, rekey_nonce(0, 0), 0),
+			chunk_counter: 0,
+			rekey_counter: 0,
+		}
+	}
+
+	pub fn crypt(&mut self, chunk: &mut [u8]) {
+		self.cipher.crypt(chunk);
+		self.chunk_counter += 1;
+		if self.chunk_counter == REKEY_INTERVAL {
+			let key = self.cipher.keystream(32).try_into().unwrap();
+			self.rekey_counter += 1;
+			self.cipher = ChaCha20::new(key, rekey_nonce(0, self.rekey_counter), 0);
+			self.chunk_counter = 0;
+		}
+	}
+}
+
+// a 96-bit nonce made of a 32-bit and a 64-bit part, both little endian
+pub fn rekey_nonce(first: u32, second: u64) -> [u8; 12] {
+	let mut nonce = [0; 12];
+	nonce[..4].copy_from_slice(&first.to_le_bytes());
+	nonce[4..].copy_from_slice(&second.to_le_bytes());
+	nonce
+}
@@ -0,0 +1,107 @@
+// the ChaCha20-Poly1305 AEAD (RFC 8439), and the forward-secure variant BIP 324
+// encrypts packet contents with.
+
+use crate::crypto::chacha20::{ChaCha20, chacha20_block, rekey_nonce, REKEY_INTERVAL};
+use crate::crypto::poly1305::compute_poly1305;
+
+pub const TAG_SIZE: usize = 16;
+
+fn compute_tag(key: &[u8; 32], nonce: &[u8; 12], aad: &[u8], ciphertext: &[u8]) -> [u8; TAG_SIZE] {
+	let poly_key = chacha20_block(key, nonce, 0)[..32].try_into().unwrap();
+	let pad = |message: &mut Vec<u8>| message.resize(message.len().div_ceil(16) * 16, 0);
+
+	let mut message = aad.to_vec();
+	pad(&mut message);
+	message.extend_from_slice(ciphertext);
+	pad(&mut message);
+	message.extend_from_slice(&(aad.len() as u64).to_le_bytes());
+	message.extend_from_slice(&(ciphertext.len() as u64).to_le_bytes());
+	compute_poly1305(&poly_key, &message)
+}
+
+// returns the ciphertext with the tag appended
+pub fn encrypt(key: &[u8; 32], nonce: &[u8; 12], aad: &[u8], plaintext: &[u8]) -> Vec<u8> {
+	let mut data = plaintext.to_vec();
+	ChaCha20::new(*key, *nonce, 1).crypt(&mut data);
+	let tag = compute_tag(key, nonce, aad, &data);
+	data.extend_from_slice(&tag);
+	data
+}
+
+// None if the tag doesn't match
+pub fn decrypt(key: &[u8; 32], nonce: &[u8; 12], aad: &[u8], data: &[u8]) -> Option<Vec<u8>> {
+	if data.len() < TAG_SIZE {
+		return None;
+	}
+	let (ciphertext, tag) = data.split_at(data.len() - TAG_SIZE);
+	if compute_tag(key, nonce, aad, ciphertext) != tag {
+		return None;
+	}
+	let mut plaintext = ciphertext.to_vec();
+	ChaCha20::new(*key, *nonce, 1).crypt(&mut plaintext);
+	Some(plaintext)
+}
+
+// every packet gets its own nonce, and every REKEY_INTERVAL packets the key is
+// replaced (BIP 324)
+pub struct FSChaCha20Poly1305 {
+	key: [u8; 32],
+	packet_counter: u64,
+	rekey_counter: u64,
+}
+
+impl FSChaCha20Poly1305 {
+	pub fn new(key: [u8; 32]) -> Self {
+		FSChaCha20Poly1305 {
+			key,
+			packet_counter: 0,
+			rekey_counter: 0,
+		}
+	}
+
+	fn nonce(&self) -> [u8; 12] {
+		rekey_nonce(self.packet_counter as u32, self.rekey_counter)
+	}
+
+	fn next_packet(&mut self) {
+		self.packet_counter += 1;
+		if self.packet_counter == REKEY_INTERVAL {
+			let nonce = rekey_nonce(0xffffffff, self.rekey_counter);
+			self.key = ChaCha20::new(self.key, nonce, 1).keystream(32).try_into().unwrap();
+			self.packet_counter = 0;
+			self.rekey_counter += 1;
+		}
+	}
+
+	pub fn encrypt(&mut self, aad: &[u8], plaintext: &[u8]) -> Vec<u8> {
+		let data = encrypt(&self.key, &self.nonce(), aad, plaintext);
+		self.next_packet();
+		data
+	}
+
+	// the packet counter only advances on success; a failure ends the connection
+	pub fn decrypt(&mut self, aad: &[u8], data: &[u8]) -> Option<Vec<u8>> {
+		let plaintext = decrypt(&self.key, &self.nonce(), aad, data)?;
+		self.next_packet();
+		Some(plaintext)
+	}
+}
+
+#[test]
+fn chacha20poly1305() {
+	use crate::common::{bytes_to_hex, hex_to_bytes};
+
+	// RFC 8439 section 2.8.2
+	let key = hex_to_bytes("808182838485868788898a8b8c8d8e8f909192939495969798999a9b9c9d9e9f").unwrap().try_into().unwrap();
+	let nonce = hex_to_bytes("070000004041424344454647").unwrap().try_into().unwrap();
+	let aad = hex_to_bytes("50515253c0c1c2c3c4c5c6c7").unwrap();
+	let plaintext = b"Ladies and Gentlemen of the class of '99: If I could offer you only one tip for the future, sunscreen would be it.";
+	let data = encrypt(&key, &nonce, &aad, plaintext);
+	assert_eq!(bytes_to_hex(&data[..16]), "d31a8d34648e60db7b86afbc53ef7ec2");
+	assert_eq!(bytes_to_hex(&data[data.len() - TAG_SIZE..]), "1ae10b594f09e26a7e902ecbd0600691");
+	assert_eq!(decrypt(&key, &nonce, &aad, &data).unwrap(), plaintext);
+
+	let mut tampered = data.clone();
+	tampered[0] ^= 1;
+	assert!(decrypt(&key, &nonce, &aad, &tampered).is_none());
+}
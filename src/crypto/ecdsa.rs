@@ -1,6 +1,6 @@
-use std::io::Read;
+use std::io::{Read, Write};
 use crate::{
-	network::Deserialize,
+	network::{Serialize, Deserialize},
 	err::*,
 	common::*,
 	json::*,
@@ -29,12 +29,12 @@ pub enum ECDSAPoint {
 
 #[cfg(feature = "use-libsecp256k1")]
 pub fn verify(pubkey: &[u8], sig: &[u8], hash: &Sha256) -> Result<()> {
-	let mut s = secp256k1::ecdsa::Signature::from_der_lax(sig).unwrap();
+	let invalid = |_| Err::ScriptError("invalid signature".to_owned());
+	let mut s = secp256k1::ecdsa::Signature::from_der_lax(sig).map_err(invalid)?;
 	s.normalize_s();
-	let result = secp256k1::SECP256K1.verify_ecdsa(
-		&secp256k1::Message::from_slice(hash.as_bytes()).unwrap(),
-		&s, &secp256k1::PublicKey::from_slice(pubkey).unwrap());
-	result.map_err(|_| Err::ScriptError("invalid signature".to_owned()))
+	let message = secp256k1::Message::from_slice(hash.as_bytes()).map_err(invalid)?;
+	let pubkey = secp256k1::PublicKey::from_slice(pubkey).map_err(invalid)?;
+	secp256k1::SECP256K1.verify_ecdsa(&message, &s, &pubkey).map_err(invalid)
 }
 
 #[cfg(not(feature = "use-libsecp256k1"))]
@@ -115,20 +115,91 @@ impl ECDSAPoint {
 	fn mul(&self, scalar: u256, a: u256, p: u256) -> ECDSAPoint {
 		match self {
 			&ECDSAPoint::Infinity => ECDSAPoint::Infinity,
-			&ECDSAPoint::Coord {..} => {
-				let mut q = ECDSAPoint::Infinity;
+			&ECDSAPoint::Coord { x, y } => {
+				// double-and-add in jacobian coordinates (x/z², y/z³), so that only the
+				// final conversion back needs a modular inverse
+				let mut q = Jacobian::infinity();
 				for i in (0..256).rev() {
 					q = q.double(a, p);
 					if scalar.bit(i) {
-						q = q.add(self, a, p);
+						q = q.add_affine(x, y, a, p);
 					}
 				}
-				q
+				q.to_affine(p)
 			}
 		}
 	}
 }
 
+#[derive(Clone, Copy)]
+struct Jacobian {
+	x: u256,
+	y: u256,
+	z: u256,
+}
+
+impl Jacobian {
+	fn infinity() -> Self {
+		Jacobian { x: 1.into(), y: 1.into(), z: 0.into() }
+	}
+
+	fn is_infinity(&self) -> bool {
+		self.z == 0.into()
+	}
+
+	fn double(&self, a: u256, p: u256) -> Jacobian {
+		if self.is_infinity() || self.y == 0.into() {
+			return Jacobian::infinity();
+		}
+		let Jacobian { x, y, z } = *self;
+		let yy = y.mul_mod(y, p);
+		// s = 4xy², m = 3x² + az⁴
+		let s = x.mul_mod(yy, p).mul_mod(4.into(), p);
+		let zz = z.mul_mod(z, p);
+		let m = x.mul_mod(x, p).mul_mod(3.into(), p).add_mod(a.mul_mod(zz.mul_mod(zz, p), p), p);
+		let new_x = m.mul_mod(m, p).sub_mod(s, p).sub_mod(s, p);
+		let new_y = m.mul_mod(s.sub_mod(new_x, p), p).sub_mod(yy.mul_mod(yy, p).mul_mod(8.into(), p), p);
+		let new_z = y.mul_mod(z, p).mul_mod(2.into(), p);
+		Jacobian { x: new_x, y: new_y, z: new_z }
+	}
+
+	fn add_affine(&self, x2: u256, y2: u256, a: u256, p: u256) -> Jacobian {
+		if self.is_infinity() {
+			return Jacobian { x: x2, y: y2, z: 1.into() };
+		}
+		let Jacobian { x, y, z } = *self;
+		let zz = z.mul_mod(z, p);
+		let h = x2.mul_mod(zz, p).sub_mod(x, p);
+		let r = y2.mul_mod(zz.mul_mod(z, p), p).sub_mod(y, p);
+		if h == 0.into() {
+			return if r == 0.into() {
+				self.double(a, p)
+			} else {
+				Jacobian::infinity()
+			};
+		}
+		let hh = h.mul_mod(h, p);
+		let hhh = hh.mul_mod(h, p);
+		let xhh = x.mul_mod(hh, p);
+		let new_x = r.mul_mod(r, p).sub_mod(hhh, p).sub_mod(xhh, p).sub_mod(xhh, p);
+		let new_y = r.mul_mod(xhh.sub_mod(new_x, p), p).sub_mod(y.mul_mod(hhh, p), p);
+		let new_z = z.mul_mod(h, p);
+		Jacobian { x: new_x, y: new_y, z: new_z }
+	}
+
+	fn to_affine(self, p: u256) -> ECDSAPoint {
+		if self.is_infinity() {
+			return ECDSAPoint::Infinity;
+		}
+		let z_inv = self.z.mod_inv(p);
+		let zz_inv = z_inv.mul_mod(z_inv, p);
+		ECDSAPoint::Coord {
+			x: self.x.mul_mod(zz_inv, p),
+			y: self.y.mul_mod(zz_inv.mul_mod(z_inv, p), p),
+		}
+	}
+}
+
 impl std::ops::Mul<u256> for ECDSAPoint {
 	type Output = ECDSAPoint;
 	fn mul(self, n: u256) -> ECDSAPoint {
@@ -155,7 +226,7 @@ impl ToJson for ECDSAPoint {
 	}
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct ECDSAPubKey {
 	x: u256,
 	y: u256,
@@ -257,6 +328,85 @@ impl Deserialize for ECDSAPubKey {
 	}
 }
 
+impl Serialize for ECDSAPubKey {
+	fn serialize(&self, stream: &mut dyn Write) -> Result<()> {
+		// always use the compressed encoding
+		write_u8(stream, if self.y.is_odd() { 0x03 } else { 0x02 })?;
+		let x = self.x.as_bytes().iter().copied().rev().collect::<Vec<_>>();
+		write_buf_exact(stream, &x)
+	}
+}
+
+#[derive(Clone)]
+pub struct ECDSAPrivKey {
+	d: u256,
+}
+
+impl ECDSAPrivKey {
+	pub fn new(d: u256) -> Result<Self> {
+		if d < 1.into() || d > ECDSA_ORDER - 1.into() {
+			return Err(Err::ValueError("private key out of range".to_owned()));
+		}
+		Ok(ECDSAPrivKey { d })
+	}
+
+	pub fn generate() -> Self {
+		loop {
+			let d = u256::from(rand::random::<[u8; 32]>());
+			if let Ok(key) = ECDSAPrivKey::new(d) {
+				return key;
+			}
+		}
+	}
+
+	pub(super) fn secret(&self) -> u256 {
+		self.d
+	}
+
+	pub fn pubkey(&self) -> ECDSAPubKey {
+		match ECDSA_BASE * self.d {
+			ECDSAPoint::Coord { x, y } => ECDSAPubKey { x, y },
+			ECDSAPoint::Infinity => unreachable!(),
+		}
+	}
+
+	pub fn sign(&self, hash: &Sha256) -> ECDSASig {
+		let n = ECDSA_ORDER;
+		let z = hash.to_u256_be() % n;
+		loop {
+			let k = ECDSAPrivKey::generate().d;
+			let r = match ECDSA_BASE * k {
+				ECDSAPoint::Coord { x, .. } => x % n,
+				ECDSAPoint::Infinity => continue,
+			};
+			if r == 0.into() {
+				continue;
+			}
+			let mut s = k.mod_inv(n).mul_mod(z.add_mod(r.mul_mod(self.d, n), n), n);
+			if s == 0.into() {
+				continue;
+			}
+			// low-S form, as required by standardness rules (BIP 62)
+			if s > n >> 1 {
+				s = n - s;
+			}
+			return ECDSASig { r, s };
+		}
+	}
+}
+
+impl std::convert::TryFrom<&str> for ECDSAPrivKey {
+	type Error = Err;
+	fn try_from(s: &str) -> Result<ECDSAPrivKey> {
+		let bytes = hex_to_bytes(s)?;
+		if bytes.len() != 32 {
+			return Err(Err::ValueError("private key must be 32 bytes".to_owned()));
+		}
+		let le: [u8; 32] = bytes.iter().copied().rev().collect::<Vec<_>>().try_into().unwrap();
+		ECDSAPrivKey::new(u256::from(le))
+	}
+}
+
 #[derive(Debug)]
 pub struct ECDSASig {
 	r: u256,
@@ -304,6 +454,27 @@ fn read_der_32_byte_int(stream: &mut dyn Read) -> Result<[u8; 32]> {
 	Ok(value)
 }
 
+fn write_der_32_byte_int(stream: &mut dyn Write, n: &u256) -> Result<()> {
+	let mut value = n.as_bytes().iter().copied().rev().skip_while(|&b| b == 0).collect::<Vec<_>>();
+	if value.first().map(|&b| b >= 0x80).unwrap_or(true) {
+		value.insert(0, 0);
+	}
+	write_u8(stream, 0x02)?;
+	write_u8(stream, value.len() as u8)?;
+	write_buf_exact(stream, &value)
+}
+
+impl Serialize for ECDSASig {
+	fn serialize(&self, stream: &mut dyn Write) -> Result<()> {
+		let mut body = Vec::new();
+		write_der_32_byte_int(&mut body, &self.r)?;
+		write_der_32_byte_int(&mut body, &self.s)?;
+		write_u8(stream, 0x30)?;
+		write_u8(stream, body.len() as u8)?;
+		write_buf_exact(stream, &body)
+	}
+}
+
 impl Deserialize for ECDSASig {
 	fn deserialize(stream: &mut dyn Read) -> Result<Self> {
 		// "compound structure" indicator
@@ -0,0 +1,184 @@
+// ElligatorSwift (BIP 324): public keys encoded as 64 bytes that are
+// indistinguishable from random, and the x-only ECDH done on them.
+
+use crate::crypto::{
+	big_int::u256,
+	ecdsa::{ECDSAPoint, ECDSAPrivKey, ECDSA_PRIME, ECDSA_BASE},
+	sha256::compute_sha256,
+};
+
+pub const ELLSWIFT_SIZE: usize = 64;
+
+const P: u256 = ECDSA_PRIME;
+// (p+1)/4, since p = 3 mod 4 a square root is a single exponentiation
+const SQRT_EXP: u256 = u256::from_raw_le([0xffffffffbfffff0c, 0xffffffffffffffff, 0xffffffffffffffff, 0x3fffffffffffffff]);
+// the square root of -3 that the exponentiation gives
+const MINUS_3_SQRT: u256 = u256::from_raw_le([0x7d8d27ae1cd5f852, 0xc61f6d15da14ecd4, 0x233770c2a797962c, 0x0a2d2ba93507f1df]);
+
+fn add(a: u256, b: u256) -> u256 { a.add_mod(b, P) }
+fn sub(a: u256, b: u256) -> u256 { a.sub_mod(b, P) }
+fn mul(a: u256, b: u256) -> u256 { a.mul_mod(b, P) }
+fn div(a: u256, b: u256) -> u256 { a.mul_mod(b.mod_inv(P), P) }
+fn neg(a: u256) -> u256 { sub(0.into(), a) }
+
+fn sqrt(a: u256) -> Option<u256> {
+	let root = a.pow_mod(SQRT_EXP, P);
+	(mul(root, root) == a).then_some(root)
+}
+
+// x³ + 7
+fn curve(x: u256) -> u256 {
+	add(mul(mul(x, x), x), 7.into())
+}
+
+fn is_valid_x(x: u256) -> bool {
+	sqrt(curve(x)).is_some()
+}
+
+fn from_be_bytes(bytes: &[u8]) -> u256 {
+	let le: [u8; 32] = bytes.iter().copied().rev().collect::<Vec<_>>().try_into().unwrap();
+	u256::from(le) % P
+}
+
+fn to_be_bytes(n: u256) -> Vec<u8> {
+	n.as_bytes().iter().copied().rev().collect()
+}
+
+// maps any pair of field elements to the x coordinate of a point on the curve
+fn xswiftec(mut u: u256, mut t: u256) -> u256 {
+	if u == 0.into() {
+		u = 1.into();
+	}
+	if t == 0.into() {
+		t = 1.into();
+	}
+	if add(curve(u), mul(t, t)) == 0.into() {
+		t = add(t, t);
+	}
+	let x = div(sub(curve(u), mul(t, t)), add(t, t));
+	let y = div(add(x, t), mul(MINUS_3_SQRT, u));
+	let half = |n| div(n, 2.into());
+	let candidates = [
+		add(u, mul(4.into(), mul(y, y))),
+		half(sub(neg(div(x, y)), u)),
+		half(sub(div(x, y), u)),
+	];
+	candidates.into_iter().find(|&x| is_valid_x(x)).unwrap()
+}
+
+// the inverse of xswiftec for a given u: one of eight possible t, if that one exists
+fn xswiftec_inv(x: u256, u: u256, case: u8) -> Option<u256> {
+	let (v, s) = if case & 2 == 0 {
+		if is_valid_x(sub(neg(x), u)) {
+			return None;
+		}
+		let s = neg(div(curve(u), add(add(mul(u, u), mul(u, x)), mul(x, x))));
+		(x, s)
+	} else {
+		let s = sub(x, u);
+		if s == 0.into() {
+			return None;
+		}
+		let uu = mul(u, u);
+		let r = sqrt(neg(mul(s, add(mul(4.into(), curve(u)), mul(3.into(), mul(s, uu))))))?;
+		if case & 1 != 0 && r == 0.into() {
+			return None;
+		}
+		(div(sub(div(r, s), u), 2.into()), s)
+	};
+	let w = sqrt(s)?;
+	let w = if case & 4 == 0 { w } else { neg(w) };
+	let t = if case & 1 == 0 {
+		mul(w, sub(div(mul(u, sub(MINUS_3_SQRT, 1.into())), 2.into()), v))
+	} else {
+		mul(w, add(div(mul(u, add(MINUS_3_SQRT, 1.into())), 2.into()), v))
+	};
+	Some(t)
+}
+
+pub fn ellswift_create(key: &ECDSAPrivKey) -> [u8; ELLSWIFT_SIZE] {
+	let x = (ECDSA_BASE * key.secret()).x().unwrap();
+	loop {
+		let u = from_be_bytes(&rand::random::<[u8; 32]>());
+		if u == 0.into() {
+			continue;
+		}
+		if let Some(t) = xswiftec_inv(x, u, rand::random::<u8>() % 8) {
+			let mut encoding = [0; ELLSWIFT_SIZE];
+			encoding[..32].copy_from_slice(&to_be_bytes(u));
+			encoding[32..].copy_from_slice(&to_be_bytes(t));
+			return encoding;
+		}
+	}
+}
+
+pub fn ellswift_decode(encoding: &[u8; ELLSWIFT_SIZE]) -> u256 {
+	xswiftec(from_be_bytes(&encoding[..32]), from_be_bytes(&encoding[32..]))
+}
+
+// the x coordinate of our key times theirs. which y the point is lifted
+// with doesn't matter, negating it only negates the result's y.
+pub fn ellswift_ecdh_xonly(theirs: &[u8; ELLSWIFT_SIZE], key: &ECDSAPrivKey) -> [u8; 32] {
+	let x = ellswift_decode(theirs);
+	let y = sqrt(curve(x)).unwrap();
+	let shared = (ECDSAPoint::Coord { x, y } * key.secret()).x().unwrap();
+	to_be_bytes(shared).try_into().unwrap()
+}
+
+// the secret both sides of a BIP 324 connection agree on. the initiator's
+// encoding goes first.
+pub fn v2_ecdh(key: &ECDSAPrivKey, theirs: &[u8; ELLSWIFT_SIZE], ours: &[u8; ELLSWIFT_SIZE], initiating: bool) -> [u8; 32] {
+	let x = ellswift_ecdh_xonly(theirs, key);
+	let (first, second) = if initiating { (ours, theirs) } else { (theirs, ours) };
+	let tag = compute_sha256(&b"bip324_ellswift_xonly_ecdh"[..]);
+	let mut message = Vec::new();
+	message.extend_from_slice(tag.as_bytes());
+	message.extend_from_slice(tag.as_bytes());
+	message.extend_from_slice(first);
+	message.extend_from_slice(second);
+	message.extend_from_slice(&x);
+	compute_sha256(&message[..]).as_bytes().try_into().unwrap()
+}
+
+#[test]
+fn ellswift() {
+	use std::convert::TryFrom;
+	use crate::common::{bytes_to_hex, hex_to_bytes};
+
+	let a = ECDSAPrivKey::generate();
+	let b = ECDSAPrivKey::generate();
+	let ours = ellswift_create(&a);
+	let theirs = ellswift_create(&b);
+	assert_eq!(ellswift_decode(&ours), (ECDSA_BASE * a.secret()).x().unwrap());
+	assert_eq!(v2_ecdh(&a, &theirs, &ours, true), v2_ecdh(&b, &ours, &theirs, false));
+
+	// from the BIP 324 decoding vectors
+	let x = ellswift_decode(&[0; ELLSWIFT_SIZE]);
+	assert_eq!(x, u256::hex("edd1fd3e327ce90cc7a3542614289aee9682003e9cf7dcc9cf2ca9743be5aa0c"));
+
+	// the keys and encodings of the BIP 324 packet encoding vectors: each encoding
+	// decodes to its key, comes back out of the inverse map for one of the
+	// eight cases, and the first gives the vector's shared secret
+	let vectors = [
+		("61062ea5071d800bbfd59e2e8b53d47d194b095ae5a4df04936b49772ef0d4d7",
+			"ec0adff257bbfe500c188c80b4fdd640f6b45a482bbc15fc7cef5931deff0aa186f6eb9bba7b85dc4dcc28b28722de1e3d9108b985e2967045668f66098e475b"),
+		("1f9c581b35231838f0f17cf0c979835baccb7f3abbbb96ffcc318ab71e6e126f",
+			"a1855e10e94e00baa23041d916e259f7044e491da6171269694763f018c7e63693d29575dcb464ac816baa1be353ba12e3876cba7628bd0bd8e755e721eb0140"),
+	];
+	for (key, encoding) in vectors {
+		let key = ECDSAPrivKey::try_from(key).unwrap();
+		let encoding: [u8; ELLSWIFT_SIZE] = hex_to_bytes(encoding).unwrap().try_into().unwrap();
+		let x = ellswift_decode(&encoding);
+		assert_eq!(x, (ECDSA_BASE * key.secret()).x().unwrap());
+		let (u, t) = (from_be_bytes(&encoding[..32]), from_be_bytes(&encoding[32..]));
+		assert!((0..8).any(|case| xswiftec_inv(x, u, case) == Some(t)));
+	}
+	let key = ECDSAPrivKey::try_from(vectors[0].0).unwrap();
+	let ours = hex_to_bytes(vectors[0].1).unwrap().try_into().unwrap();
+	let theirs = hex_to_bytes("a4a94dfce69b4a2a0a099313d10f9f7e7d649d60501c9e1d274c300e0d89aafaffffffffffffffffffffffffffffffffffffffffffffffffffffffff8faf88d5").unwrap().try_into().unwrap();
+	assert_eq!(bytes_to_hex(&v2_ecdh(&key, &theirs, &ours, true)), "c6992a117f5edbea70c3f511d32d26b9798be4b81a62eaee1a5acaa8459a3592");
+
+	// every input decodes to some point, including ones outside the field
+	let x = ellswift_decode(&[0xff; ELLSWIFT_SIZE]);
+	assert!(is_valid_x(x));
+}
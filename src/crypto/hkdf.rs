@@ -0,0 +1,45 @@
+// HMAC-SHA256 (RFC 2104) and HKDF-SHA256 (RFC 5869), used to derive the BIP 324
+// session keys.
+
+use crate::crypto::sha256::compute_sha256;
+
+const BLOCK_SIZE: usize = 64;
+
+pub fn compute_hmac_sha256(key: &[u8], message: &[u8]) -> [u8; 32] {
+	let mut padded = [0; BLOCK_SIZE];
+	if key.len() > BLOCK_SIZE {
+		padded[..32].copy_from_slice(compute_sha256(key).as_bytes());
+	} else {
+		padded[..key.len()].copy_from_slice(key);
+	}
+
+	let mut inner: Vec<u8> = padded.iter().map(|b| b ^ 0x36).collect();
+	inner.extend_from_slice(message);
+	let mut outer: Vec<u8> = padded.iter().map(|b| b ^ 0x5c).collect();
+	outer.extend_from_slice(compute_sha256(&inner[..]).as_bytes());
+	compute_sha256(&outer[..]).as_bytes().try_into().unwrap()
+}
+
+pub fn hkdf_extract(salt: &[u8], ikm: &[u8]) -> [u8; 32] {
+	compute_hmac_sha256(salt, ikm)
+}
+
+// only a single block of output is ever needed
+pub fn hkdf_expand32(prk: &[u8; 32], info: &[u8]) -> [u8; 32] {
+	let mut message = info.to_vec();
+	message.push(1);
+	compute_hmac_sha256(prk, &message)
+}
+
+#[test]
+fn hkdf() {
+	use crate::common::{bytes_to_hex, hex_to_bytes};
+
+	// RFC 5869 test case 1
+	let ikm = hex_to_bytes("0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b").unwrap();
+	let salt = hex_to_bytes("000102030405060708090a0b0c").unwrap();
+	let info = hex_to_bytes("f0f1f2f3f4f5f6f7f8f9").unwrap();
+	let prk = hkdf_extract(&salt, &ikm);
+	assert_eq!(bytes_to_hex(&prk), "077709362c2e32df0ddc3f0dc47bba6390b6c73bb50f9c3122ec844ad7c2b3e5");
+	assert_eq!(bytes_to_hex(&hkdf_expand32(&prk, &info)), "3cb25f25faacd57a90434f64d0362f2a2d2d0a90cf1a5a4c5db02d56ecc4c5bf");
+}
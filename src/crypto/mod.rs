@@ -1,4 +1,13 @@
 pub mod sha256;
 pub mod ripemd160;
+pub mod sha1;
+pub mod sha3;
+pub mod siphash;
+pub mod murmur3;
+pub mod chacha20;
+pub mod poly1305;
+pub mod chacha20poly1305;
+pub mod hkdf;
+pub mod ellswift;
 pub mod ecdsa;
 pub mod big_int;
\ No newline at end of file
@@ -0,0 +1,56 @@
+// MurmurHash3 (x86, 32-bit). not cryptographic at all; BIP 37 bloom filters use it.
+
+pub fn compute_murmur3(seed: u32, message: &[u8]) -> u32 {
+	const C1: u32 = 0xcc9e2d51;
+	const C2: u32 = 0x1b873593;
+
+	let mut h = seed;
+	let mut chunks = message.chunks_exact(4);
+	for chunk in chunks.by_ref() {
+		let k = u32::from_le_bytes(chunk.try_into().unwrap())
+			.wrapping_mul(C1)
+			.rotate_left(15)
+			.wrapping_mul(C2);
+		h = (h ^ k).rotate_left(13).wrapping_mul(5).wrapping_add(0xe6546b64);
+	}
+
+	let tail = chunks.remainder();
+	if !tail.is_empty() {
+		let mut last = [0; 4];
+		last[..tail.len()].copy_from_slice(tail);
+		let k = u32::from_le_bytes(last)
+			.wrapping_mul(C1)
+			.rotate_left(15)
+			.wrapping_mul(C2);
+		h ^= k;
+	}
+
+	h ^= message.len() as u32;
+	h ^= h >> 16;
+	h = h.wrapping_mul(0x85ebca6b);
+	h ^= h >> 13;
+	h = h.wrapping_mul(0xc2b2ae35);
+	h ^ (h >> 16)
+}
+
+#[test]
+fn murmur3() {
+	use crate::common::hex_to_bytes;
+
+	// the vectors from bitcoind's hash tests
+	let hash = |seed, hex: &str| compute_murmur3(seed, &hex_to_bytes(hex).unwrap());
+	assert!(hash(0x00000000, "") == 0x00000000);
+	assert!(hash(0xfba4c795, "") == 0x6a396f08);
+	assert!(hash(0xffffffff, "") == 0x81f16f39);
+	assert!(hash(0x00000000, "00") == 0x514e28b7);
+	assert!(hash(0xfba4c795, "00") == 0xea3f0b17);
+	assert!(hash(0x00000000, "ff") == 0xfd6cf10d);
+	assert!(hash(0x00000000, "0011") == 0x16c6b7ab);
+	assert!(hash(0x00000000, "001122") == 0x8eb51c3d);
+	assert!(hash(0x00000000, "00112233") == 0xb4471bf8);
+	assert!(hash(0x00000000, "0011223344") == 0xe2301fa8);
+	assert!(hash(0x00000000, "001122334455") == 0xfc2e4a15);
+	assert!(hash(0x00000000, "00112233445566") == 0xb074502c);
+	assert!(hash(0x00000000, "0011223344556677") == 0x8034d2a0);
+	assert!(hash(0x00000000, "001122334455667788") == 0xb4698def);
+}
@@ -0,0 +1,80 @@
+// Poly1305 (RFC 8439), a one-time authenticator. 26-bit limbs, so that the
+// products fit in a u64.
+
+pub fn compute_poly1305(key: &[u8; 32], message: &[u8]) -> [u8; 16] {
+	let word = |bytes: &[u8], i: usize| u32::from_le_bytes(bytes[i..i + 4].try_into().unwrap());
+
+	// r, clamped
+	let r = [
+		word(key, 0) & 0x3ffffff,
+		(word(key, 3) >> 2) & 0x3ffff03,
+		(word(key, 6) >> 4) & 0x3ffc0ff,
+		(word(key, 9) >> 6) & 0x3f03fff,
+		(word(key, 12) >> 8) & 0x00fffff,
+	].map(|e| e as u64);
+	let s = [1, 2, 3, 4].map(|i| r[i] * 5);
+
+	let mut h = [0u64; 5];
+	for chunk in message.chunks(16) {
+		// each chunk gets a one bit appended
+		let mut block = [0u8; 17];
+		block[..chunk.len()].copy_from_slice(chunk);
+		block[chunk.len()] = 1;
+		h[0] += (word(&block, 0) & 0x3ffffff) as u64;
+		h[1] += ((word(&block, 3) >> 2) & 0x3ffffff) as u64;
+		h[2] += ((word(&block, 6) >> 4) & 0x3ffffff) as u64;
+		h[3] += ((word(&block, 9) >> 6) & 0x3ffffff) as u64;
+		h[4] += (word(&block, 12) >> 8) as u64 | (block[16] as u64) << 24;
+
+		// h *= r, mod 2^130 - 5
+		let d = [
+			h[0] * r[0] + h[1] * s[3] + h[2] * s[2] + h[3] * s[1] + h[4] * s[0],
+			h[0] * r[1] + h[1] * r[0] + h[2] * s[3] + h[3] * s[2] + h[4] * s[1],
+			h[0] * r[2] + h[1] * r[1] + h[2] * r[0] + h[3] * s[3] + h[4] * s[2],
+			h[0] * r[3] + h[1] * r[2] + h[2] * r[1] + h[3] * r[0] + h[4] * s[3],
+			h[0] * r[4] + h[1] * r[3] + h[2] * r[2] + h[3] * r[1] + h[4] * r[0],
+		];
+		let mut carry = 0;
+		for i in 0..5 {
+			let n = d[i] + carry;
+			h[i] = n & 0x3ffffff;
+			carry = n >> 26;
+		}
+		h[0] += carry * 5;
+		h[1] += h[0] >> 26;
+		h[0] &= 0x3ffffff;
+	}
+
+	// fully reduce: h - p if that doesn't go negative
+	let mut carry = 0;
+	for limb in h.iter_mut() {
+		*limb += carry;
+		carry = *limb >> 26;
+		*limb &= 0x3ffffff;
+	}
+	h[0] += carry * 5;
+	h[1] += h[0] >> 26;
+	h[0] &= 0x3ffffff;
+	let mut g = [0u64; 5];
+	let mut carry = 5;
+	for i in 0..5 {
+		g[i] = h[i] + carry;
+		carry = g[i] >> 26;
+		g[i] &= 0x3ffffff;
+	}
+	if carry != 0 {
+		h = g;
+	}
+
+	// h + s, mod 2^128
+	let h = (0..5).fold(0u128, |acc, i| acc | (h[i] as u128) << (26 * i));
+	let s = u128::from_le_bytes(key[16..].try_into().unwrap());
+	h.wrapping_add(s).to_le_bytes()
+}
+
+#[test]
+fn poly1305() {
+	let key = crate::common::hex_to_bytes("85d6be7857556d337f4452fe42d506a80103808afb0db2fd4abff6af4149f51b").unwrap();
+	let tag = compute_poly1305(&key.try_into().unwrap(), b"Cryptographic Forum Research Group");
+	assert_eq!(crate::common::bytes_to_hex(&tag), "a8061dc1305136c6c22b8baf0c0127a9");
+}
@@ -0,0 +1,63 @@
+// SHA-1 (FIPS 180-4). broken as a hash function, but OP_SHA1 is still part of
+// the script language.
+
+fn compress(state: &mut [u32; 5], block: &[u8]) {
+	let mut w = [0u32; 80];
+	for (i, word) in block.chunks_exact(4).enumerate() {
+		w[i] = u32::from_be_bytes(word.try_into().unwrap());
+	}
+	for i in 16..80 {
+		w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+	}
+
+	let [mut a, mut b, mut c, mut d, mut e] = *state;
+	for (i, w) in w.iter().enumerate() {
+		let (f, k) = match i {
+			0..=19  => ((b & c) | (!b & d), 0x5a827999),
+			20..=39 => (b ^ c ^ d, 0x6ed9eba1),
+			40..=59 => ((b & c) | (b & d) | (c & d), 0x8f1bbcdc),
+			_       => (b ^ c ^ d, 0xca62c1d6),
+		};
+		let t = a.rotate_left(5).wrapping_add(f).wrapping_add(e).wrapping_add(k).wrapping_add(*w);
+		e = d;
+		d = c;
+		c = b.rotate_left(30);
+		b = a;
+		a = t;
+	}
+
+	for (s, x) in state.iter_mut().zip([a, b, c, d, e]) {
+		*s = s.wrapping_add(x);
+	}
+}
+
+pub fn compute_sha1(message: &[u8]) -> [u8; 20] {
+	let mut state = [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476, 0xc3d2e1f0];
+
+	let mut padded = message.to_vec();
+	padded.push(0x80);
+	while padded.len() % 64 != 56 {
+		padded.push(0);
+	}
+	padded.extend_from_slice(&(message.len() as u64 * 8).to_be_bytes());
+
+	for block in padded.chunks_exact(64) {
+		compress(&mut state, block);
+	}
+
+	let mut digest = [0; 20];
+	for (i, word) in state.iter().enumerate() {
+		digest[i * 4..i * 4 + 4].copy_from_slice(&word.to_be_bytes());
+	}
+	digest
+}
+
+#[test]
+fn sha1() {
+	use crate::common::bytes_to_hex;
+
+	assert!(bytes_to_hex(&compute_sha1(b"")) == "da39a3ee5e6b4b0d3255bfef95601890afd80709");
+	assert!(bytes_to_hex(&compute_sha1(b"abc")) == "a9993e364706816aba3e25717850c26c9cd0d89d");
+	assert!(bytes_to_hex(&compute_sha1(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"))
+		== "84983e441c3bd26ebaae4aa1f95129e5e54670f1");
+}
@@ -44,9 +44,11 @@ impl Default for Sha256 {
 
 impl std::convert::From<[u8; 32]> for Sha256 {
 	fn from(bytes: [u8; 32]) -> Self {
-		let words: &[u32] = unsafe { std::slice::from_raw_parts(std::mem::transmute(&bytes), 8)};
+		// byte arrays aren't aligned like words, so copy rather than reinterpret
 		let mut digest = [0; 8];
-		digest.copy_from_slice(words);
+		for (word, chunk) in digest.iter_mut().zip(bytes.chunks_exact(4)) {
+			*word = u32::from_ne_bytes(chunk.try_into().unwrap());
+		}
 		Sha256 { digest }
 	}
 }
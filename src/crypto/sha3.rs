@@ -0,0 +1,97 @@
+// SHA3-256 (FIPS 202). only needed for the checksum in Tor v3 onion addresses.
+
+const ROUND_CONSTANTS: [u64; 24] = [
+	0x0000000000000001, 0x0000000000008082, 0x800000000000808a, 0x8000000080008000,
+	0x000000000000808b, 0x0000000080000001, 0x8000000080008081, 0x8000000000008009,
+	0x000000000000008a, 0x0000000000000088, 0x0000000080008009, 0x000000008000000a,
+	0x000000008000808b, 0x800000000000008b, 0x8000000000008089, 0x8000000000008003,
+	0x8000000000008002, 0x8000000000000080, 0x000000000000800a, 0x800000008000000a,
+	0x8000000080008081, 0x8000000000008080, 0x0000000080000001, 0x8000000080008008,
+];
+
+const ROTATIONS: [u32; 25] = [
+	 0,  1, 62, 28, 27,
+	36, 44,  6, 55, 20,
+	 3, 10, 43, 25, 39,
+	41, 45, 15, 21,  8,
+	18,  2, 61, 56, 14,
+];
+
+// 1600 - 2 * 256 bits
+const RATE: usize = 136;
+
+fn keccak_f(state: &mut [u64; 25]) {
+	for rc in ROUND_CONSTANTS {
+		// theta
+		let mut c = [0; 5];
+		for x in 0..5 {
+			c[x] = state[x] ^ state[x + 5] ^ state[x + 10] ^ state[x + 15] ^ state[x + 20];
+		}
+		for x in 0..5 {
+			let d = c[(x + 4) % 5] ^ c[(x + 1) % 5].rotate_left(1);
+			for y in 0..5 {
+				state[x + 5 * y] ^= d;
+			}
+		}
+
+		// rho and pi
+		let mut b = [0; 25];
+		for x in 0..5 {
+			for y in 0..5 {
+				b[y + 5 * ((2 * x + 3 * y) % 5)] = state[x + 5 * y].rotate_left(ROTATIONS[x + 5 * y]);
+			}
+		}
+
+		// chi
+		for x in 0..5 {
+			for y in 0..5 {
+				state[x + 5 * y] = b[x + 5 * y] ^ (!b[(x + 1) % 5 + 5 * y] & b[(x + 2) % 5 + 5 * y]);
+			}
+		}
+
+		// iota
+		state[0] ^= rc;
+	}
+}
+
+fn absorb(state: &mut [u64; 25], block: &[u8]) {
+	for (i, word) in block.chunks_exact(8).enumerate() {
+		state[i] ^= u64::from_le_bytes(word.try_into().unwrap());
+	}
+	keccak_f(state);
+}
+
+pub fn compute_sha3_256(message: &[u8]) -> [u8; 32] {
+	let mut state = [0u64; 25];
+
+	let mut chunks = message.chunks_exact(RATE);
+	for chunk in &mut chunks {
+		absorb(&mut state, chunk);
+	}
+
+	// SHA3 domain separation bits, then pad10*1
+	let remainder = chunks.remainder();
+	let mut last = [0u8; RATE];
+	last[..remainder.len()].copy_from_slice(remainder);
+	last[remainder.len()] ^= 0x06;
+	last[RATE - 1] ^= 0x80;
+	absorb(&mut state, &last);
+
+	let mut digest = [0; 32];
+	for (i, word) in state.iter().take(4).enumerate() {
+		digest[i * 8..i * 8 + 8].copy_from_slice(&word.to_le_bytes());
+	}
+	digest
+}
+
+#[test]
+fn sha3_256() {
+	use crate::common::bytes_to_hex;
+
+	assert!(bytes_to_hex(&compute_sha3_256(b"")) == "a7ffc6f8bf1ed76651c14756a061d662f580ff4de43b49fa82d80a4b80f8434a");
+	assert!(bytes_to_hex(&compute_sha3_256(b"abc")) == "3a985da74fe225b2045c172d6bd390bd855f086e3e9d525b46bfe24511431532");
+	assert!(bytes_to_hex(&compute_sha3_256(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"))
+		== "41c0dba2a9d6240849100376a8235e2c82e1b9998a999e21db32dd97496d3376");
+	assert!(bytes_to_hex(&compute_sha3_256(&[b'a'; 1_000_000]))
+		== "5c8875ae474a3634ba4fd55ec85bffd661f32aca75c6d699d0cdcb6c115891c1");
+}
@@ -0,0 +1,58 @@
+// SipHash-2-4. a keyed hash, used for the short transaction ids in compact blocks (BIP 152).
+
+fn round(v: &mut [u64; 4]) {
+	v[0] = v[0].wrapping_add(v[1]);
+	v[1] = v[1].rotate_left(13) ^ v[0];
+	v[0] = v[0].rotate_left(32);
+	v[2] = v[2].wrapping_add(v[3]);
+	v[3] = v[3].rotate_left(16) ^ v[2];
+	v[0] = v[0].wrapping_add(v[3]);
+	v[3] = v[3].rotate_left(21) ^ v[0];
+	v[2] = v[2].wrapping_add(v[1]);
+	v[1] = v[1].rotate_left(17) ^ v[2];
+	v[2] = v[2].rotate_left(32);
+}
+
+pub fn compute_siphash(k0: u64, k1: u64, message: &[u8]) -> u64 {
+	let mut v = [
+		k0 ^ 0x736f6d6570736575,
+		k1 ^ 0x646f72616e646f6d,
+		k0 ^ 0x6c7967656e657261,
+		k1 ^ 0x7465646279746573,
+	];
+
+	let mut compress = |m: u64| {
+		v[3] ^= m;
+		round(&mut v);
+		round(&mut v);
+		v[0] ^= m;
+	};
+
+	let mut chunks = message.chunks_exact(8);
+	for chunk in chunks.by_ref() {
+		compress(u64::from_le_bytes(chunk.try_into().unwrap()));
+	}
+	// the last word holds the leftover bytes, and the message length in its top byte
+	let mut last = [0; 8];
+	last[..chunks.remainder().len()].copy_from_slice(chunks.remainder());
+	compress(u64::from_le_bytes(last) | (message.len() as u64) << 56);
+
+	v[2] ^= 0xff;
+	for _ in 0..4 {
+		round(&mut v);
+	}
+	v[0] ^ v[1] ^ v[2] ^ v[3]
+}
+
+#[test]
+fn siphash() {
+	// from the reference implementation's test vectors
+	let k0 = u64::from_le_bytes([0, 1, 2, 3, 4, 5, 6, 7]);
+	let k1 = u64::from_le_bytes([8, 9, 10, 11, 12, 13, 14, 15]);
+	let message = (0..64).collect::<Vec<u8>>();
+	assert!(compute_siphash(k0, k1, &[]) == 0x726fdb47dd0e0e31);
+	assert!(compute_siphash(k0, k1, &message[..1]) == 0x74f839c593dc67fd);
+	assert!(compute_siphash(k0, k1, &message[..8]) == 0x93f5f5799a932462);
+	assert!(compute_siphash(k0, k1, &message[..15]) == 0xa129ca6149be45e5);
+	assert!(compute_siphash(k0, k1, &message[..63]) == 0x958a324ceb064572);
+}
@@ -5,6 +5,7 @@ pub enum Err {
     ValueError(String),
     ScriptError(String),
     ConsensusError(String),
+    FeeError(String),
     ChannelError,
 }
 
@@ -24,6 +25,7 @@ impl std::fmt::Display for Err {
             Err::ValueError(message) => write!(f, "{}", message),
             Err::ScriptError(message) => write!(f, "{}", message),
             Err::ConsensusError(message) => write!(f, "{}", message),
+            Err::FeeError(message) => write!(f, "{}", message),
             Err::ChannelError => write!(f, "channel closed unexpectedly"),
         }
     }
@@ -0,0 +1,91 @@
+// Choosing an inbound peer to drop when every inbound slot is taken. Peers that
+// are useful to us, or that an attacker would find hard to imitate, are set
+// aside first: a few from distinct network groups, the fastest to answer pings,
+// the last to bring us new txs or blocks and the longest connected. Of the rest,
+// the newest peer from the network group with the most connections goes, since
+// an attacker with many connections most likely has them from few groups.
+
+use std::{
+	collections::{HashMap, hash_map::DefaultHasher},
+	hash::{Hash, Hasher},
+	time::{Duration, Instant},
+};
+
+const PROTECT_BY_GROUP: usize = 4;
+const PROTECT_BY_PING: usize = 8;
+const PROTECT_BY_TX: usize = 4;
+const PROTECT_BY_BLOCK: usize = 4;
+
+pub struct EvictionCandidate {
+	pub id: usize,
+	pub connected: Instant,
+	pub min_ping_time: Option<Duration>,
+	// when the peer last gave us a tx or block we didn't have
+	pub last_tx: Option<Instant>,
+	pub last_block: Option<Instant>,
+	pub group: Vec<u8>,
+}
+
+// drops the first `count` candidates by `key` order, the longest connected first on ties
+fn protect<K: Ord>(candidates: &mut Vec<EvictionCandidate>, count: usize, key: impl Fn(&EvictionCandidate) -> K) {
+	candidates.sort_by_key(|candidate| (key(candidate), candidate.connected));
+	candidates.drain(..count.min(candidates.len()));
+}
+
+// `key` is a secret of ours, so which groups get protected can't be predicted
+pub fn select_peer_to_evict(mut candidates: Vec<EvictionCandidate>, key: u64) -> Option<usize> {
+	protect(&mut candidates, PROTECT_BY_GROUP, |candidate| {
+		let mut hasher = DefaultHasher::new();
+		(key, &candidate.group).hash(&mut hasher);
+		hasher.finish()
+	});
+	// peers that never answered a ping count as slowest
+	protect(&mut candidates, PROTECT_BY_PING, |candidate| candidate.min_ping_time.unwrap_or(Duration::MAX));
+	// None sorts first, so reverse the order to put the most recent first
+	protect(&mut candidates, PROTECT_BY_TX, |candidate| std::cmp::Reverse(candidate.last_tx));
+	protect(&mut candidates, PROTECT_BY_BLOCK, |candidate| std::cmp::Reverse(candidate.last_block));
+	let half = candidates.len() / 2;
+	protect(&mut candidates, half, |candidate| candidate.connected);
+
+	let mut groups: HashMap<&[u8], Vec<&EvictionCandidate>> = HashMap::new();
+	for candidate in candidates.iter() {
+		groups.entry(&candidate.group).or_default().push(candidate);
+	}
+	// the largest group, ties going to the one with the newest connection
+	let newest = |group: &Vec<&EvictionCandidate>| group.iter().map(|candidate| candidate.connected).max();
+	let group = groups.values().max_by_key(|group| (group.len(), newest(group)))?;
+	group.iter().max_by_key(|candidate| candidate.connected).map(|candidate| candidate.id)
+}
+
+#[test]
+fn eviction() {
+	let start = Instant::now();
+	let candidate = |id: usize, group: u8| EvictionCandidate {
+		id,
+		connected: start + Duration::from_secs(id as u64),
+		min_ping_time: None,
+		last_tx: None,
+		last_block: None,
+		group: vec![1, group],
+	};
+
+	// too few to leave anyone unprotected
+	assert_eq!(select_peer_to_evict((0..4).map(|id| candidate(id, id as u8)).collect(), 0), None);
+
+	// four peers from groups of their own and twenty from one group: the newest
+	// of the twenty goes, whichever groups the key happens to protect
+	let candidates = || (0..24).map(|id| candidate(id, if id < 4 { id as u8 + 1 } else { 0 })).collect::<Vec<_>>();
+	assert_eq!(select_peer_to_evict(candidates(), 0), Some(23));
+	assert_eq!(select_peer_to_evict(candidates(), 1), Some(23));
+
+	// ...unless it's fast, or it's brought us a tx or a block lately
+	for protected in 0..3 {
+		let mut candidates = candidates();
+		match protected {
+			0 => candidates[23].min_ping_time = Some(Duration::from_millis(10)),
+			1 => candidates[23].last_tx = Some(start),
+			_ => candidates[23].last_block = Some(start),
+		}
+		assert_eq!(select_peer_to_evict(candidates, 0), Some(22));
+	}
+}
@@ -0,0 +1,522 @@
+// Fee estimation from how long mempool txs took to confirm, along the lines of
+// bitcoind's. Txs are sorted into feerate buckets when they enter the mempool;
+// when they confirm, each bucket learns how many blocks its txs needed. Three
+// horizons keep those counts with different decays and resolutions, so recent
+// blocks dominate short targets while long targets still have data. An
+// estimate is the lowest feerate range whose txs confirmed within the target
+// often enough.
+
+use std::{
+	collections::HashMap,
+	io::{Read, Write},
+};
+
+use crate::{
+	err::*,
+	common::*,
+	crypto::sha256::Sha256,
+};
+
+// sat/kvB. buckets are spaced 5% apart between these
+const MIN_BUCKET_FEERATE: f64 = 1000.0;
+const MAX_BUCKET_FEERATE: f64 = 10_000_000.0;
+const FEE_SPACING: f64 = 1.05;
+
+// periods tracked, blocks per period, and the decay applied every block
+const SHORT_PERIODS: usize = 12;
+const SHORT_SCALE: usize = 1;
+const SHORT_DECAY: f64 = 0.962;
+const MED_PERIODS: usize = 24;
+const MED_SCALE: usize = 2;
+const MED_DECAY: f64 = 0.9952;
+const LONG_PERIODS: usize = 42;
+const LONG_SCALE: usize = 24;
+const LONG_DECAY: f64 = 0.99931;
+
+// the fraction of a range's txs that must have confirmed in time, for half,
+// exactly and twice the target
+const HALF_SUCCESS_PCT: f64 = 0.6;
+const SUCCESS_PCT: f64 = 0.85;
+const DOUBLE_SUCCESS_PCT: f64 = 0.95;
+// how many txs (before decay) a range needs before it can pass or fail
+const SUFFICIENT_FEETXS: f64 = 0.1;
+const SUFFICIENT_TXS_SHORT: f64 = 0.5;
+
+pub const MAX_TARGET: usize = LONG_PERIODS * LONG_SCALE;
+
+const FILE_VERSION: u8 = 1;
+
+struct ConfirmStats {
+	scale: usize,
+	decay: f64,
+	// per period and bucket: txs that confirmed within that many periods
+	conf_avg: Vec<Vec<f64>>,
+	// per period and bucket: txs that left the mempool unconfirmed after at
+	// least that many periods
+	fail_avg: Vec<Vec<f64>>,
+	// per bucket: all confirmed txs, and the sum of their feerates
+	tx_ct_avg: Vec<f64>,
+	feerate_sum: Vec<f64>,
+	// per block height (mod the blocks tracked) and bucket: txs still in the
+	// mempool, and those in it for longer than that
+	unconf: Vec<Vec<usize>>,
+	old_unconf: Vec<usize>,
+}
+
+impl ConfirmStats {
+	fn new(buckets: usize, periods: usize, scale: usize, decay: f64) -> Self {
+		ConfirmStats {
+			scale,
+			decay,
+			conf_avg: vec![vec![0.0; buckets]; periods],
+			fail_avg: vec![vec![0.0; buckets]; periods],
+			tx_ct_avg: vec![0.0; buckets],
+			feerate_sum: vec![0.0; buckets],
+			unconf: vec![vec![0; buckets]; periods * scale],
+			old_unconf: vec![0; buckets],
+		}
+	}
+
+	fn max_confirms(&self) -> usize {
+		self.scale * self.conf_avg.len()
+	}
+
+	fn add_unconfirmed(&mut self, height: usize, bucket: usize) {
+		let bins = self.unconf.len();
+		self.unconf[height % bins][bucket] += 1;
+	}
+
+	fn remove_unconfirmed(&mut self, entry_height: usize, best_height: usize, bucket: usize, in_block: bool) {
+		let blocks_ago = best_height.saturating_sub(entry_height);
+		if blocks_ago >= self.unconf.len() {
+			self.old_unconf[bucket] = self.old_unconf[bucket].saturating_sub(1);
+		} else {
+			let bins = self.unconf.len();
+			let count = &mut self.unconf[entry_height % bins][bucket];
+			*count = count.saturating_sub(1);
+		}
+		if !in_block && blocks_ago >= self.scale {
+			let periods_ago = blocks_ago / self.scale;
+			for fail in self.fail_avg.iter_mut().take(periods_ago) {
+				fail[bucket] += 1.0;
+			}
+		}
+	}
+
+	// a new block: old data counts for less, and the txs that entered a full
+	// window ago join the old ones
+	fn new_block(&mut self, height: usize) {
+		for period in self.conf_avg.iter_mut().chain(self.fail_avg.iter_mut()) {
+			for value in period.iter_mut() {
+				*value *= self.decay;
+			}
+		}
+		for (count, sum) in self.tx_ct_avg.iter_mut().zip(self.feerate_sum.iter_mut()) {
+			*count *= self.decay;
+			*sum *= self.decay;
+		}
+		let bins = self.unconf.len();
+		for (old, current) in self.old_unconf.iter_mut().zip(self.unconf[height % bins].iter_mut()) {
+			*old += *current;
+			*current = 0;
+		}
+	}
+
+	fn record(&mut self, blocks_to_confirm: usize, bucket: usize, feerate: f64) {
+		let periods_to_confirm = blocks_to_confirm.div_ceil(self.scale);
+		for period in self.conf_avg.iter_mut().skip(periods_to_confirm - 1) {
+			period[bucket] += 1.0;
+		}
+		self.tx_ct_avg[bucket] += 1.0;
+		self.feerate_sum[bucket] += feerate;
+	}
+
+	// walks down from the highest feerate bucket, grouping buckets until
+	// there's enough data to judge, and keeps the last group that confirmed
+	// within the target at least `success` of the time. returns the median
+	// feerate of that group, if any passed
+	fn estimate(&self, target: usize, sufficient: f64, success: f64, height: usize) -> Option<f64> {
+		let period_target = target.div_ceil(self.scale);
+		let max_bucket = self.tx_ct_avg.len() - 1;
+		let bins = self.unconf.len();
+		let (mut conf, mut total, mut fail, mut extra) = (0.0, 0.0, 0.0, 0usize);
+		let (mut near, mut best_near, mut best_far) = (max_bucket, max_bucket, max_bucket);
+		let mut new_range = true;
+		let mut found = false;
+		for bucket in (0..=max_bucket).rev() {
+			if new_range {
+				near = bucket;
+				new_range = false;
+			}
+			conf += self.conf_avg[period_target - 1][bucket];
+			total += self.tx_ct_avg[bucket];
+			fail += self.fail_avg[period_target - 1][bucket];
+			// still unconfirmed after the target counts against the range too
+			for blocks in target..self.max_confirms() {
+				extra += self.unconf[(height + bins - blocks % bins) % bins][bucket];
+			}
+			extra += self.old_unconf[bucket];
+
+			if total >= sufficient / (1.0 - self.decay) {
+				if conf / (total + fail + extra as f64) < success {
+					continue;
+				}
+				found = true;
+				best_near = near;
+				best_far = bucket;
+				(conf, total, fail, extra) = (0.0, 0.0, 0.0, 0);
+				new_range = true;
+			}
+		}
+		if !found {
+			return None;
+		}
+
+		let range = best_far..=best_near;
+		let mut half = self.tx_ct_avg[range.clone()].iter().sum::<f64>() / 2.0;
+		if half == 0.0 {
+			return None;
+		}
+		for bucket in range {
+			if self.tx_ct_avg[bucket] < half {
+				half -= self.tx_ct_avg[bucket];
+			} else {
+				return Some(self.feerate_sum[bucket] / self.tx_ct_avg[bucket]);
+			}
+		}
+		None
+	}
+
+	fn serialize(&self, stream: &mut dyn Write) -> Result<()> {
+		write_f64s(stream, &self.tx_ct_avg)?;
+		write_f64s(stream, &self.feerate_sum)?;
+		for period in self.conf_avg.iter().chain(self.fail_avg.iter()) {
+			write_f64s(stream, period)?;
+		}
+		Ok(())
+	}
+
+	fn deserialize(&mut self, stream: &mut dyn Read) -> Result<()> {
+		let buckets = self.tx_ct_avg.len();
+		self.tx_ct_avg = read_f64s(stream, buckets)?;
+		self.feerate_sum = read_f64s(stream, buckets)?;
+		for period in self.conf_avg.iter_mut().chain(self.fail_avg.iter_mut()) {
+			*period = read_f64s(stream, buckets)?;
+		}
+		Ok(())
+	}
+}
+
+fn write_f64s(stream: &mut dyn Write, values: &[f64]) -> Result<()> {
+	for value in values {
+		write_u64(stream, value.to_bits())?;
+	}
+	Ok(())
+}
+
+fn read_f64s(stream: &mut dyn Read, count: usize) -> Result<Vec<f64>> {
+	(0..count).map(|_| Ok(f64::from_bits(read_u64(stream)?))).collect()
+}
+
+fn max_estimate(a: Option<f64>, b: Option<f64>) -> Option<f64> {
+	match (a, b) {
+		(Some(a), Some(b)) => Some(a.max(b)),
+		_ => a.or(b),
+	}
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum EstimateMode {
+	// also wants the longer horizons to agree, so is slower to come down
+	// after a spike
+	Conservative,
+	Economical,
+}
+
+pub struct FeeEstimator {
+	// the lower bound of each bucket
+	buckets: Vec<f64>,
+	short: ConfirmStats,
+	medium: ConfirmStats,
+	long: ConfirmStats,
+	// txs in the mempool we're waiting on: the height they came in at, and their bucket
+	tracked: HashMap<Sha256, (usize, usize)>,
+	best_height: usize,
+	first_height: usize,
+}
+
+impl FeeEstimator {
+	pub fn new() -> Self {
+		let mut buckets = Vec::new();
+		let mut feerate = MIN_BUCKET_FEERATE;
+		while feerate <= MAX_BUCKET_FEERATE {
+			buckets.push(feerate);
+			feerate *= FEE_SPACING;
+		}
+		let count = buckets.len();
+		FeeEstimator {
+			buckets,
+			short: ConfirmStats::new(count, SHORT_PERIODS, SHORT_SCALE, SHORT_DECAY),
+			medium: ConfirmStats::new(count, MED_PERIODS, MED_SCALE, MED_DECAY),
+			long: ConfirmStats::new(count, LONG_PERIODS, LONG_SCALE, LONG_DECAY),
+			tracked: HashMap::new(),
+			best_height: 0,
+			first_height: 0,
+		}
+	}
+
+	fn stats_mut(&mut self) -> [&mut ConfirmStats; 3] {
+		[&mut self.short, &mut self.medium, &mut self.long]
+	}
+
+	fn bucket(&self, feerate: u64) -> usize {
+		self.buckets.partition_point(|&lower| lower <= feerate as f64).saturating_sub(1)
+	}
+
+	pub fn is_tracked(&self, id: &Sha256) -> bool {
+		self.tracked.contains_key(id)
+	}
+
+	// a tx entering the mempool with the tip at `height`
+	pub fn process_tx(&mut self, id: Sha256, height: usize, feerate: u64) {
+		if height != self.best_height || self.tracked.contains_key(&id) {
+			return;
+		}
+		let bucket = self.bucket(feerate);
+		for stats in self.stats_mut() {
+			stats.add_unconfirmed(height, bucket);
+		}
+		self.tracked.insert(id, (height, bucket));
+	}
+
+	// a tx leaving the mempool without being confirmed
+	pub fn remove_tx(&mut self, id: &Sha256) {
+		if let Some((entry_height, bucket)) = self.tracked.remove(id) {
+			let best_height = self.best_height;
+			for stats in self.stats_mut() {
+				stats.remove_unconfirmed(entry_height, best_height, bucket, false);
+			}
+		}
+	}
+
+	// txs that left the mempool since the last block, other than by being
+	// confirmed, count as failures
+	pub fn remove_missing(&mut self, in_mempool: impl Fn(&Sha256) -> bool) {
+		let missing = self.tracked.keys()
+			.filter(|id| !in_mempool(id))
+			.copied()
+			.collect::<Vec<_>>();
+		for id in missing.iter() {
+			self.remove_tx(id);
+		}
+	}
+
+	// a block at `height`, with the ids and feerates of the txs it confirmed
+	// that were in the mempool
+	pub fn process_block(&mut self, height: usize, confirmed: &[(Sha256, u64)]) {
+		if height <= self.best_height {
+			return;
+		}
+		self.best_height = height;
+		for stats in self.stats_mut() {
+			stats.new_block(height);
+		}
+		let mut counted = 0;
+		for (id, feerate) in confirmed {
+			let (entry_height, bucket) = match self.tracked.remove(id) {
+				Some(entry) => entry,
+				None => continue,
+			};
+			for stats in self.stats_mut() {
+				stats.remove_unconfirmed(entry_height, height, bucket, true);
+			}
+			if height <= entry_height {
+				continue;
+			}
+			for stats in self.stats_mut() {
+				stats.record(height - entry_height, bucket, *feerate as f64);
+			}
+			counted += 1;
+		}
+		if self.first_height == 0 && counted > 0 {
+			self.first_height = height;
+		}
+	}
+
+	// with data from only a few blocks, only short targets mean anything
+	fn max_usable_target(&self) -> usize {
+		if self.first_height == 0 {
+			return 0;
+		}
+		std::cmp::min(MAX_TARGET, (self.best_height - self.first_height) / 2)
+	}
+
+	// the horizon fine enough for the target, and whether any shorter
+	// horizon's longest target comes in cheaper
+	fn estimate_combined(&self, target: usize, success: f64, check_shorter: bool) -> Option<f64> {
+		let height = self.best_height;
+		let short_max = self.short.max_confirms();
+		let medium_max = self.medium.max_confirms();
+		let mut estimate = if target <= short_max {
+			self.short.estimate(target, SUFFICIENT_TXS_SHORT, success, height)
+		} else if target <= medium_max {
+			self.medium.estimate(target, SUFFICIENT_FEETXS, success, height)
+		} else {
+			self.long.estimate(target, SUFFICIENT_FEETXS, success, height)
+		};
+		if check_shorter {
+			if target > medium_max {
+				let shorter = self.medium.estimate(medium_max, SUFFICIENT_FEETXS, success, height);
+				if shorter.is_some_and(|shorter| estimate.is_none_or(|estimate| shorter < estimate)) {
+					estimate = shorter;
+				}
+			}
+			if target > short_max {
+				let shorter = self.short.estimate(short_max, SUFFICIENT_TXS_SHORT, success, height);
+				if shorter.is_some_and(|shorter| estimate.is_none_or(|estimate| shorter < estimate)) {
+					estimate = shorter;
+				}
+			}
+		}
+		estimate
+	}
+
+	// twice the target on the longer horizons, at the strictest threshold
+	fn estimate_conservative(&self, double_target: usize) -> Option<f64> {
+		let height = self.best_height;
+		let mut estimate = None;
+		if double_target <= self.short.max_confirms() {
+			estimate = self.medium.estimate(double_target, SUFFICIENT_FEETXS, DOUBLE_SUCCESS_PCT, height);
+		}
+		if double_target <= self.medium.max_confirms() {
+			let long = self.long.estimate(double_target, SUFFICIENT_FEETXS, DOUBLE_SUCCESS_PCT, height);
+			estimate = max_estimate(estimate, long);
+		}
+		estimate
+	}
+
+	// like bitcoind's estimatesmartfee: the feerate (sat/kvB) to confirm within
+	// `target` blocks, and the target it's actually for, which can be
+	// different when there isn't the data for the one asked
+	pub fn estimate_smart_fee(&self, target: usize, mode: EstimateMode) -> Option<(u64, usize)> {
+		let target = std::cmp::min(std::cmp::max(target, 2), self.max_usable_target());
+		if target < 2 {
+			return None;
+		}
+		let half = self.estimate_combined(target / 2, HALF_SUCCESS_PCT, true);
+		let actual = self.estimate_combined(target, SUCCESS_PCT, true);
+		let double = self.estimate_combined(2 * target, DOUBLE_SUCCESS_PCT, mode == EstimateMode::Economical);
+		let mut estimate = max_estimate(max_estimate(half, actual), double);
+		if mode == EstimateMode::Conservative {
+			estimate = max_estimate(estimate, self.estimate_conservative(2 * target));
+		}
+		estimate.map(|feerate| (feerate.round() as u64, target))
+	}
+
+	pub fn load(path: &str) -> Self {
+		let mut estimator = FeeEstimator::new();
+		if !std::path::Path::new(path).is_file() {
+			return estimator;
+		}
+		let result: Result<()> = try {
+			let file = std::fs::File::open(path)
+				.map_err(|err| Err::IOError(err.to_string()))?;
+			estimator.deserialize(&mut std::io::BufReader::new(file))?;
+		};
+		match result {
+			Ok(_) => log_info!("loaded fee estimates (last block {})", estimator.best_height),
+			Err(err) => {
+				log_error!("warning: failed to load {}: {}", path, err.to_string());
+				estimator = FeeEstimator::new();
+			},
+		}
+		estimator
+	}
+
+	pub fn save(&self, path: &str) {
+		let result: Result<()> = try {
+			let file = std::fs::File::create(path)
+				.map_err(|err| Err::IOError(err.to_string()))?;
+			let mut stream = std::io::BufWriter::new(file);
+			self.serialize(&mut stream)?;
+			stream.flush().map_err(|err| Err::IOError(err.to_string()))?;
+		};
+		if let Err(err) = result {
+			log_error!("warning: failed to save fee estimates: {}", err.to_string());
+		}
+	}
+
+	// the statistics only. the txs being waited on are the mempool's, which
+	// gets reloaded without them
+	fn serialize(&self, stream: &mut dyn Write) -> Result<()> {
+		write_u8(stream, FILE_VERSION)?;
+		write_u64(stream, self.best_height as u64)?;
+		write_u64(stream, self.first_height as u64)?;
+		write_u64(stream, self.buckets.len() as u64)?;
+		for stats in [&self.short, &self.medium, &self.long] {
+			stats.serialize(stream)?;
+		}
+		Ok(())
+	}
+
+	fn deserialize(&mut self, stream: &mut dyn Read) -> Result<()> {
+		let version = read_u8(stream)?;
+		if version != FILE_VERSION {
+			return Err(Err::ValueError(format!("unsupported version: {}", version)));
+		}
+		self.best_height = read_u64(stream)? as usize;
+		self.first_height = read_u64(stream)? as usize;
+		let buckets = read_u64(stream)? as usize;
+		if buckets != self.buckets.len() {
+			return Err(Err::ValueError(format!("expected {} buckets, found {}", self.buckets.len(), buckets)));
+		}
+		for stats in self.stats_mut() {
+			stats.deserialize(stream)?;
+		}
+		Ok(())
+	}
+}
+
+#[test]
+fn fee_estimates() {
+	let id = |n: usize| {
+		let mut bytes = [0; 32];
+		bytes[..8].copy_from_slice(&(n as u64).to_le_bytes());
+		Sha256::from(bytes)
+	};
+
+	// every block, ten txs paying 20 sat/vB confirm in the next block, and ten
+	// paying 2 sat/vB only after 30
+	let mut estimator = FeeEstimator::new();
+	assert!(estimator.estimate_smart_fee(2, EstimateMode::Economical).is_none());
+	let mut next = 0;
+	let mut fast = Vec::new();
+	let mut slow = Vec::new();
+	for height in 1..=300 {
+		let mut confirmed = fast.drain(..).map(|id| (id, 20_000)).collect::<Vec<_>>();
+		if height > 30 {
+			confirmed.extend(slow[(height - 31) * 10..(height - 30) * 10].iter().map(|&id| (id, 2_000)));
+		}
+		estimator.process_block(height, &confirmed);
+		for _ in 0..10 {
+			estimator.process_tx(id(next), height, 20_000);
+			fast.push(id(next));
+			estimator.process_tx(id(next + 1), height, 2_000);
+			slow.push(id(next + 1));
+			next += 2;
+		}
+	}
+
+	let (feerate, target) = estimator.estimate_smart_fee(2, EstimateMode::Conservative).unwrap();
+	assert!(target == 2);
+	assert!((19_000..=21_000).contains(&feerate));
+	// a day out, the cheap txs are fast enough
+	let (feerate, _) = estimator.estimate_smart_fee(144, EstimateMode::Economical).unwrap();
+	assert!(feerate < 3_000);
+
+	let mut buf = Vec::new();
+	estimator.serialize(&mut buf).unwrap();
+	let mut loaded = FeeEstimator::new();
+	loaded.deserialize(&mut &buf[..]).unwrap();
+	assert!(loaded.estimate_smart_fee(2, EstimateMode::Conservative) == estimator.estimate_smart_fee(2, EstimateMode::Conservative));
+}
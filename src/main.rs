@@ -1,14 +1,17 @@
 #![feature(try_blocks)]
 
 use std::{
-	collections::{BTreeMap, HashMap, HashSet},
-	net::{TcpStream},
+	collections::{BTreeMap, HashMap, HashSet, VecDeque},
+	net::{TcpStream, TcpListener, Shutdown, SocketAddr, Ipv4Addr, ToSocketAddrs},
+	os::unix::io::AsRawFd,
 	sync::mpsc,
-	sync::mpsc::{Receiver},
+	sync::mpsc::{Sender, Receiver},
 	thread,
 	io::Write,
 	io::BufReader,
+	io::BufWriter,
 	io::BufRead,
+	time::{Duration, Instant},
 };
 
 #[macro_use]
@@ -19,6 +22,17 @@ mod json;
 mod network;
 mod script;
 mod crypto;
+mod options;
+mod signet;
+mod addrman;
+mod banlist;
+mod bloom;
+mod blockfilter;
+mod orphanage;
+mod eviction;
+mod fees;
+mod miner;
+mod policy;
 
 use crypto::{
 	sha256::Sha256,
@@ -29,30 +43,80 @@ use network::{
 	Peer,
 	Serialize,
 	Deserialize,
+	connection::Connection,
+	socks5::{self, Proxy},
+	torcontrol::TorControl,
+	poll::{self, PollFd, POLLIN, POLLOUT},
 	message::{
+		Network,
 		Message,
 		Payload,
 		Version,
 		Ping,
+		Pong,
 		Inv,
 		InvType,
 		InvItem,
 		Tx,
 		UTXOID,
+		UTXODiff,
+		UTXOState,
+		AbsoluteLockTime,
 		TxOutput,
 		FeeFilter,
 		Block,
 		Header,
 		Headers,
+		GetHeaders,
+		GetData,
+		NetAddr,
+		NetHost,
+		MAX_ADDR_TO_SEND,
 		ValidationResult,
-		GENESIS_BLOCK_HASH,
+		SendCmpct,
+		CmpctBlock,
+		GetBlockTxn,
+		BlockTxn,
+		MAX_CMPCT_TX_COUNT,
+		short_id,
+		FilterLoad,
+		FilterAdd,
+		MerkleBlock,
+		GetCFilters,
+		GetCFCheckpt,
+		services,
 	}
 };
 
+use script::Script;
+use options::Options;
+use addrman::{AddrMan, AddrKey};
+use banlist::{BanList, DEFAULT_BAN_TIME};
+use bloom::{BloomFilter, MAX_FILTER_ADD_SIZE};
+use blockfilter::{BlockFilter, BASIC_FILTER_TYPE, compute_filter_header};
+use orphanage::Orphanage;
+use eviction::{EvictionCandidate, select_peer_to_evict};
+use fees::{FeeEstimator, EstimateMode};
+use miner::{
+	BlockTemplate,
+	TemplateTx,
+	MAX_BLOCK_WEIGHT,
+	MAX_BLOCK_SIGOPS_COST,
+	COINBASE_RESERVED_WEIGHT,
+	COINBASE_RESERVED_SIGOPS,
+};
+use policy::{MAX_STANDARD_TX_SIGOPS_COST, TRUC_VERSION, TRUC_MAX_VSIZE, TRUC_CHILD_MAX_VSIZE};
 use err::*;
 use json::*;
 
 pub struct ConsensusParams {
+	network:           Network,
+	magic:             u32,
+	pow_limit:         u256,
+	genesis:           fn() -> Block,
+	default_port:      u16,
+	data_dir:          &'static str,
+	signet_challenge:  Option<Script>,
 	bip34_height:      usize,
 	cltv_height:       usize,
 	strict_der_height: usize,
@@ -63,6 +127,13 @@ pub struct ConsensusParams {
 impl Default for ConsensusParams {
 	fn default() -> Self {
 		ConsensusParams {
+			network:           Network::Main,
+			magic:             Network::Main as u32,
+			pow_limit:         u256::from_u64(0xffff) << 208,
+			genesis:           Block::genesis,
+			default_port:      8333,
+			data_dir:          "./data",
+			signet_challenge:  None,
 			bip34_height:      227931,
 			cltv_height:       388381,
 			strict_der_height: 363725,
@@ -72,9 +143,40 @@ impl Default for ConsensusParams {
 	}
 }
 
+impl ConsensusParams {
+	fn signet(challenge: Script) -> Self {
+		ConsensusParams {
+			network:           Network::SigNet,
+			magic:             signet::magic(&challenge),
+			pow_limit:         u256::from_u64(0x0377ae) << 216,
+			genesis:           Block::signet_genesis,
+			default_port:      38333,
+			data_dir:          "./data/signet",
+			signet_challenge:  Some(challenge),
+			bip34_height:      1,
+			cltv_height:       1,
+			strict_der_height: 1,
+			csv_height:        1,
+			segwit_height:     1,
+		}
+	}
+
+	fn from_options(options: &Options) -> Result<Self> {
+		if !options.signet {
+			return Ok(Default::default());
+		}
+		let challenge = options.signet_challenge.as_deref().unwrap_or(signet::DEFAULT_CHALLENGE);
+		let challenge = Script::from(common::hex_to_bytes(challenge)?);
+		signet::check_challenge(&challenge)?;
+		Ok(ConsensusParams::signet(challenge))
+	}
+}
+
 pub struct State {
 	params: ConsensusParams,
 	height: usize,
+	// validating for the mempool: every soft fork counts as active
+	policy: bool,
 }
 
 impl Default for State {
@@ -82,15 +184,28 @@ impl Default for State {
 		State {
 			params: Default::default(),
 			height: 0,
+			policy: false,
 		}
 	}
 }
 
 impl State {
+	fn new(params: ConsensusParams) -> Self {
+		State {
+			params,
+			height: 0,
+			policy: false,
+		}
+	}
+
 	fn set_height(&mut self, height: usize) {
 		self.height = height
 	}
 
+	fn set_policy(&mut self, policy: bool) {
+		self.policy = policy
+	}
+
 	fn height(&self) -> usize {
 		self.height
 	}
@@ -100,19 +215,19 @@ impl State {
 	// }
 
 	fn cltv_enabled(&self) -> bool {
-		self.height >= self.params.csv_height
+		self.policy || self.height >= self.params.csv_height
 	}
 
 	fn strict_der_enabled(&self) -> bool {
-		self.height >= self.params.strict_der_height
+		self.policy || self.height >= self.params.strict_der_height
 	}
 
 	fn csv_enabled(&self) -> bool {
-		self.height >= self.params.csv_height
+		self.policy || self.height >= self.params.csv_height
 	}
 
 	fn segwit_enabled(&self) -> bool {
-		self.height >= self.params.csv_height
+		self.policy || self.height >= self.params.csv_height
 	}
 }
 
@@ -122,43 +237,53 @@ struct Config {
 	addrv2: bool,
 	sendheaders: bool,
 	feerate: u64,
+	// the compact block version agreed on (0 if none), and whether the peer
+	// wants new blocks pushed to it as compact blocks
+	cmpct_version: u64,
+	cmpct_announce: bool,
+	// set by filterload and filterclear, which turn tx relay on whatever the version message said
+	relay_txs: bool,
 }
 
 #[derive(Debug)]
 struct BlocksDB {
-	blocks_requested: usize,
+	dir: String,
+	genesis: fn() -> Block,
 	blocks_validated: usize,
 	hashes: Vec<Sha256>,
+	heights: HashMap<Sha256, usize>,
 	headers: BTreeMap<Sha256, Header>,
 }
 
 impl BlocksDB {
-	fn new() -> Self {
-		let genesis = Block::genesis().header;
-		let genesis_hash = genesis.compute_hash();
+	fn new(data_dir: &str, genesis: fn() -> Block) -> Self {
+		let genesis_header = genesis().header;
+		let genesis_hash = genesis_header.compute_hash();
 		BlocksDB {
-			blocks_requested: 1,
+			dir: format!("{}/block_db", data_dir),
+			genesis,
 			blocks_validated: 1,
 			hashes: vec![genesis_hash],
-			headers: BTreeMap::from([(genesis_hash, genesis)]),
+			heights: HashMap::from([(genesis_hash, 0)]),
+			headers: BTreeMap::from([(genesis_hash, genesis_header)]),
 		}
 	}
 
-	fn load() -> Self {
+	fn load(data_dir: &str, genesis: fn() -> Block) -> Self {
 		use std::path::Path;
-		const HEADERS_PATH: &str = "./data/block_db/headers.dat";
-		const IDS_PATH: &str = "./data/block_db/ids.txt";
-		let mut db = BlocksDB::new();
+		let mut db = BlocksDB::new(data_dir, genesis);
+		let headers_path = format!("{}/headers.dat", db.dir);
+		let ids_path = format!("{}/ids.txt", db.dir);
 
-		if Path::new(HEADERS_PATH).is_file() && Path::new(IDS_PATH).is_file() {
-			let mut headers_file = match std::fs::File::open(HEADERS_PATH) {
+		if Path::new(&headers_path).is_file() && Path::new(&ids_path).is_file() {
+			let mut headers_file = match std::fs::File::open(&headers_path) {
 				Ok(file) => file,
 				Err(err) => {
 					log_error!("warning: failed to load headers.dat: {}", err.to_string());
 					return db;
 				}
 			};
-			let ids_file = match std::fs::File::open(IDS_PATH) {
+			let ids_file = match std::fs::File::open(&ids_path) {
 				Ok(file) => file,
 				Err(err) => {
 					log_error!("warning: failed to load ids.txt: {}", err.to_string());
@@ -194,8 +319,8 @@ impl BlocksDB {
 				headers.insert(hash, header);
 			}
 
-			db.blocks_requested = hashes.len();
 			db.blocks_validated = hashes.len();
+			db.heights = hashes.iter().enumerate().map(|(height, hash)| (*hash, height)).collect();
 			db.hashes = hashes;
 			db.headers = headers;
 		}
@@ -204,18 +329,18 @@ impl BlocksDB {
 	}
 
 	fn save(&self) {
-		if let Err(err) = std::fs::create_dir_all("./data/block_db") {
+		if let Err(err) = std::fs::create_dir_all(&self.dir) {
 			log_error!("warning: failed to save block_db state: {}", err.to_string());
 			return;
 		}
-		let mut file = match std::fs::File::create("./data/block_db/headers.dat") {
+		let mut file = match std::fs::File::create(format!("{}/headers.dat", self.dir)) {
 			Ok(file) => file,
 			Err(err) => {
 				log_error!("warning: failed to save block_db state: {}", err.to_string());
 				return;
 			}
 		};
-		let mut ids = match std::fs::File::create("./data/block_db/ids.txt") {
+		let mut ids = match std::fs::File::create(format!("{}/ids.txt", self.dir)) {
 			Ok(file) => file,
 			Err(err) => {
 				log_error!("warning: failed to save block_db state: {}", err.to_string());
@@ -236,37 +361,281 @@ impl BlocksDB {
 		}
 	}
 
-	fn store_block(&self, block: Block) -> Result<()> {
+	fn store_block(&self, block: &Block) -> Result<()> {
 		let hash = block.header.compute_hash();
 		if self.has_block(hash) {
 			return Ok(());
 		}
-		std::fs::create_dir_all("./data/block_db")
+		std::fs::create_dir_all(&self.dir)
 			.map_err(|err| Err::IOError(err.to_string()))?;
-		let mut file = std::fs::File::create(format!("./data/block_db/{}.dat", hash))
+		let mut file = std::fs::File::create(format!("{}/{}.dat", self.dir, hash))
 			.map_err(|err| Err::IOError(err.to_string()))?;
 		block.serialize(&mut file)?;
 
 		Ok(())
 	}
 
+	// what a connected block spent, so it can be disconnected again: each output
+	// with the height its tx confirmed at, if known
+	fn store_undo(&self, hash: &Sha256, spent: &[(UTXOID, TxOutput, Option<usize>)]) -> Result<()> {
+		std::fs::create_dir_all(&self.dir)
+			.map_err(|err| Err::IOError(err.to_string()))?;
+		let file = std::fs::File::create(format!("{}/{}.undo", self.dir, hash))
+			.map_err(|err| Err::IOError(err.to_string()))?;
+		let mut file = BufWriter::new(file);
+		common::write_u64(&mut file, spent.len() as u64)?;
+		for (id, output, height) in spent.iter() {
+			common::write_sha256(&mut file, &id.0)?;
+			common::write_u32(&mut file, id.1)?;
+			output.serialize(&mut file)?;
+			common::write_u64(&mut file, height.map_or(u64::MAX, |height| height as u64))?;
+		}
+		file.flush().map_err(|err| Err::IOError(err.to_string()))
+	}
+
+	fn load_undo(&self, hash: &Sha256) -> Result<Vec<(UTXOID, TxOutput, Option<usize>)>> {
+		let file = std::fs::File::open(format!("{}/{}.undo", self.dir, hash))
+			.map_err(|err| Err::IOError(err.to_string()))?;
+		let mut file = BufReader::new(file);
+		let mut spent = Vec::new();
+		for _ in 0..common::read_u64(&mut file)? {
+			let id = UTXOID(common::read_sha256(&mut file)?, common::read_u32(&mut file)?);
+			let output = TxOutput::deserialize(&mut file)?;
+			let height = common::read_u64(&mut file)?;
+			spent.push((id, output, (height != u64::MAX).then_some(height as usize)));
+		}
+		Ok(spent)
+	}
+
+	fn has_undo(&self, hash: &Sha256) -> bool {
+		std::path::Path::new(&format!("{}/{}.undo", self.dir, hash)).is_file()
+	}
+
 	fn load_block(&self, hash: &Sha256) -> Result<Block> {
-		if *hash == GENESIS_BLOCK_HASH.try_into().unwrap() {
-			return Ok(Block::genesis())
+		if *hash == self.hashes[0] {
+			return Ok((self.genesis)())
 		}
-		let mut file = std::fs::File::open(format!("./data/block_db/{}.dat", hash))
+		let mut file = std::fs::File::open(format!("{}/{}.dat", self.dir, hash))
 			.map_err(|err| Err::IOError(err.to_string()))?;
 		Block::deserialize(&mut file)
 	}
 
 	fn has_block(&self, hash: Sha256) -> bool {
-		std::path::Path::new(&format!("./data/block_db/{}.block", hash)).is_file()
+		std::path::Path::new(&format!("{}/{}.block", self.dir, hash)).is_file()
+	}
+
+	fn push_header(&mut self, hash: Sha256, header: Header) {
+		self.heights.insert(hash, self.hashes.len());
+		self.headers.insert(hash, header);
+		self.hashes.push(hash);
+	}
+
+	// undoes push_header, for a block that turned out to be invalid
+	fn pop_header(&mut self) {
+		assert!(self.hashes.len() > self.blocks_validated);
+		let hash = self.hashes.pop().unwrap();
+		self.heights.remove(&hash);
+		self.headers.remove(&hash);
+	}
+
+	fn height_of(&self, hash: &Sha256) -> Option<usize> {
+		self.heights.get(hash).copied()
+	}
+
+	// the median timestamp of the block at `height` and the ten before it (BIP 113)
+	fn median_time_past(&self, height: usize) -> u32 {
+		let mut times = self.hashes[height.saturating_sub(10)..=height].iter()
+			.map(|hash| self.headers[hash].timestamp)
+			.collect::<Vec<_>>();
+		times.sort_unstable();
+		times[times.len() / 2]
+	}
+
+	// the height of the last block we share with a peer's locator. only
+	// validated blocks count as our chain; falls back to genesis if nothing matches.
+	fn find_fork(&self, locator: &[Sha256]) -> usize {
+		locator.iter()
+			.filter_map(|hash| self.height_of(hash))
+			.find(|&height| height < self.blocks_validated)
+			.unwrap_or(0)
+	}
+}
+
+// the BIP 158 basic filter of each validated block. the filters themselves are
+// kept on disk, their hashes in memory along with the filter headers built from them
+struct FilterIndex {
+	dir: String,
+	genesis: fn() -> Block,
+	filter_hashes: Vec<Sha256>,
+	headers: Vec<Sha256>,
+}
+
+impl FilterIndex {
+	fn new(data_dir: &str, genesis: fn() -> Block) -> Self {
+		let filter = BlockFilter::basic(&genesis(), std::iter::empty());
+		let filter_hash = filter.compute_hash();
+		FilterIndex {
+			dir: format!("{}/filter_index", data_dir),
+			genesis,
+			filter_hashes: vec![filter_hash],
+			headers: vec![compute_filter_header(&filter_hash, &Sha256::default())],
+		}
+	}
+
+	fn load(data_dir: &str, genesis: fn() -> Block) -> Self {
+		let mut index = FilterIndex::new(data_dir, genesis);
+		let bytes = match std::fs::read(format!("{}/index.dat", index.dir)) {
+			Ok(bytes) => bytes,
+			Err(_) => return index,
+		};
+		// the genesis filter is always there, so the file starts at height 1
+		for chunk in bytes.chunks_exact(32) {
+			index.push_hash(Sha256::from(<[u8; 32]>::try_from(chunk).unwrap()));
+		}
+		index
+	}
+
+	fn save(&self) {
+		if let Err(err) = std::fs::create_dir_all(&self.dir) {
+			log_error!("warning: failed to save filter index: {}", err.to_string());
+			return;
+		}
+		let bytes = self.filter_hashes.iter()
+			.skip(1)
+			.flat_map(|hash| hash.as_bytes().iter().copied())
+			.collect::<Vec<_>>();
+		if let Err(err) = std::fs::write(format!("{}/index.dat", self.dir), bytes) {
+			log_error!("warning: failed to save filter index: {}", err.to_string());
+		}
+	}
+
+	fn len(&self) -> usize {
+		self.filter_hashes.len()
+	}
+
+	fn truncate(&mut self, len: usize) {
+		self.filter_hashes.truncate(len);
+		self.headers.truncate(len);
+	}
+
+	fn push_hash(&mut self, filter_hash: Sha256) {
+		let header = compute_filter_header(&filter_hash, self.headers.last().unwrap());
+		self.filter_hashes.push(filter_hash);
+		self.headers.push(header);
+	}
+
+	fn push(&mut self, block_hash: &Sha256, filter: BlockFilter) -> Result<()> {
+		std::fs::create_dir_all(&self.dir)
+			.map_err(|err| Err::IOError(err.to_string()))?;
+		std::fs::write(format!("{}/{}.dat", self.dir, block_hash), filter.as_bytes())
+			.map_err(|err| Err::IOError(err.to_string()))?;
+		self.push_hash(filter.compute_hash());
+		Ok(())
+	}
+
+	fn load_filter(&self, block_hash: &Sha256) -> Result<BlockFilter> {
+		if *block_hash == (self.genesis)().header.compute_hash() {
+			return Ok(BlockFilter::basic(&(self.genesis)(), std::iter::empty()));
+		}
+		std::fs::read(format!("{}/{}.dat", self.dir, block_hash))
+			.map(BlockFilter::from_bytes)
+			.map_err(|err| Err::IOError(err.to_string()))
+	}
+}
+
+// limits on a tx's in-mempool package, counting the tx itself. sizes in vbytes
+const MAX_ANCESTORS: usize = 25;
+const MAX_ANCESTOR_SIZE: usize = 101_000;
+const MAX_DESCENDANTS: usize = 25;
+const MAX_DESCENDANT_SIZE: usize = 101_000;
+
+// a replacement has to pay for its own relay at this rate (sat/kvB) on top of
+// the fees of everything it evicts, and can't evict more than this many txs
+const INCREMENTAL_RELAY_FEE: u64 = 1000;
+const MAX_REPLACEMENT_EVICTIONS: usize = 100;
+
+// the lowest feerate (sat/kvB) relayed at all; the mempool's minimum never
+// drops below it
+const MIN_RELAY_FEE: u64 = 1000;
+const DEFAULT_MAX_MEMPOOL: usize = 300;
+const DEFAULT_MEMPOOL_EXPIRY: u64 = 336;
+const MEMPOOL_DUMP_VERSION: u64 = 1;
+// after a block, the minimum feerate set by evictions halves this often, or
+// faster while the mempool is mostly empty
+const ROLLING_FEE_HALFLIFE: f64 = 12.0 * 60.0 * 60.0;
+
+#[derive(Clone)]
+struct MempoolEntry {
+	// in satoshis: what the tx pays, and that adjusted by its prioritisation.
+	// everything else goes by the adjusted fee
+	base_fee: u64,
+	fee: u64,
+	vsize: usize,
+	// serialized, in bytes
+	size: usize,
+	// when it was accepted
+	time: u64,
+	// BIP 141 sigop cost
+	sigops: usize,
+	// the in-mempool txs this one spends, and the ones spending it
+	parents: HashSet<Sha256>,
+	children: HashSet<Sha256>,
+	// totals over the entry and all of its in-mempool ancestors
+	ancestor_count: usize,
+	ancestor_size: usize,
+	ancestor_fee: u64,
+	// and over the entry and all of its descendants
+	descendant_count: usize,
+	descendant_size: usize,
+	descendant_fee: u64,
+}
+
+impl MempoolEntry {
+	// sat/kvB, counting the ancestors that would have to be mined with it
+	fn ancestor_feerate(&self) -> u64 {
+		feerate(self.ancestor_fee, self.ancestor_size)
 	}
+
+	fn descendant_feerate(&self) -> u64 {
+		feerate(self.descendant_fee, self.descendant_size)
+	}
+}
+
+// sat/kvB. prioritised fees can be big enough to overflow a u64 once scaled
+fn feerate(fee: u64, vsize: usize) -> u64 {
+	(fee as u128 * 1000 / vsize as u128) as u64
 }
 
+// what checking a tx for the mempool found, for adding it after
+struct MempoolCheck {
+	base_fee: u64,
+	fee: u64,
+	sigops: usize,
+	// the mempool txs it would replace
+	conflicts: HashSet<Sha256>,
+}
+
+#[derive(Clone)]
 struct Mempool {
 	pending: HashSet<Sha256>,
 	txs: BTreeMap<Sha256, Tx>,
+	entries: HashMap<Sha256, MempoolEntry>,
+	// the mempool tx spending each outpoint
+	spenders: HashMap<UTXOID, Sha256>,
+	// total serialized size of the txs, kept under max_size by evicting the
+	// packages paying the least
+	size: usize,
+	max_size: usize,
+	// txs older than this many seconds are dropped
+	expiry: u64,
+	// sat/kvB, raised by evictions to what the evicted paid. it only starts to
+	// decay once a block has come in since
+	rolling_min_feerate: f64,
+	last_rolling_update: u64,
+	block_since_bump: bool,
+	// satoshis added to (or taken from) what a tx pays whenever its fee is
+	// judged, set with `prioritisetransaction`. kept for txs not in the mempool too
+	deltas: HashMap<Sha256, i64>,
 }
 
 impl Mempool {
@@ -274,17 +643,499 @@ impl Mempool {
 		Mempool {
 			pending: HashSet::new(),
 			txs: BTreeMap::new(),
+			entries: HashMap::new(),
+			spenders: HashMap::new(),
+			size: 0,
+			max_size: DEFAULT_MAX_MEMPOOL * 1_000_000,
+			expiry: DEFAULT_MEMPOOL_EXPIRY * 60 * 60,
+			rolling_min_feerate: 0.0,
+			last_rolling_update: common::now(),
+			block_since_bump: false,
+			deltas: HashMap::new(),
 		}
 	}
 	
-	fn add_tx(&mut self, id: Sha256, tx: Tx) {
+	// returns false if the tx was already in the mempool. the caller checks the
+	// package limits first
+	fn add_tx(&mut self, id: Sha256, tx: Tx, base_fee: u64, sigops: usize) -> bool {
 		self.pending.remove(&id);
 		if self.txs.contains_key(&id) {
 			log_trace!("tx already in mempool: {}", id);
+			return false;
+		}
+		log_debug!("add tx to mempool: {}", id);
+		let vsize = tx.vsize();
+		let parents = self.parents_of(&tx);
+		let ancestors = self.ancestors(&parents);
+		let size = tx.size();
+		let fee = self.modified_fee(&id, base_fee);
+		let mut entry = MempoolEntry {
+			base_fee,
+			fee,
+			vsize,
+			size,
+			time: common::now(),
+			sigops,
+			parents: parents.clone(),
+			children: HashSet::new(),
+			ancestor_count: 1,
+			ancestor_size: vsize,
+			ancestor_fee: fee,
+			descendant_count: 1,
+			descendant_size: vsize,
+			descendant_fee: fee,
+		};
+		for ancestor in ancestors.iter() {
+			let ancestor = self.entries.get_mut(ancestor).unwrap();
+			entry.ancestor_count += 1;
+			entry.ancestor_size += ancestor.vsize;
+			entry.ancestor_fee = entry.ancestor_fee.saturating_add(ancestor.fee);
+			ancestor.descendant_count += 1;
+			ancestor.descendant_size += vsize;
+			ancestor.descendant_fee = ancestor.descendant_fee.saturating_add(fee);
+		}
+		for parent in parents.iter() {
+			self.entries.get_mut(parent).unwrap().children.insert(id);
+		}
+		for input in tx.inputs.iter() {
+			self.spenders.insert(UTXOID(input.tx_hash, input.index), id);
+		}
+		self.txs.insert(id, tx);
+		self.entries.insert(id, entry);
+		self.size += size;
+		true
+	}
+
+	fn remove_tx(&mut self, id: &Sha256) -> Option<Tx> {
+		let tx = self.txs.remove(id)?;
+		let entry = self.entries.remove(id).unwrap();
+		self.size -= entry.size;
+		// the totals above and below it no longer include it
+		for ancestor in self.ancestors(&entry.parents) {
+			let ancestor = self.entries.get_mut(&ancestor).unwrap();
+			ancestor.descendant_count -= 1;
+			ancestor.descendant_size -= entry.vsize;
+			ancestor.descendant_fee = ancestor.descendant_fee.saturating_sub(entry.fee);
+		}
+		for descendant in self.descendants(&entry.children) {
+			let descendant = self.entries.get_mut(&descendant).unwrap();
+			descendant.ancestor_count -= 1;
+			descendant.ancestor_size -= entry.vsize;
+			descendant.ancestor_fee = descendant.ancestor_fee.saturating_sub(entry.fee);
+		}
+		for parent in entry.parents.iter() {
+			self.entries.get_mut(parent).unwrap().children.remove(id);
+		}
+		for child in entry.children.iter() {
+			self.entries.get_mut(child).unwrap().parents.remove(id);
+		}
+		for input in tx.inputs.iter() {
+			self.spenders.remove(&UTXOID(input.tx_hash, input.index));
+		}
+		Some(tx)
+	}
+
+	// removes a tx along with everything in the mempool that spends its outputs
+	fn remove_with_descendants(&mut self, id: &Sha256) -> usize {
+		if !self.entries.contains_key(id) {
+			return 0;
+		}
+		let removed = self.descendants(&HashSet::from([*id]));
+		self.remove_txs(&removed);
+		removed.len()
+	}
+
+	// children before their parents: a tx's ancestors only lose it from their
+	// totals while it's still linked to them
+	fn remove_txs(&mut self, ids: &HashSet<Sha256>) {
+		let mut ids = ids.iter().copied().collect::<Vec<_>>();
+		ids.sort_by_key(|id| std::cmp::Reverse(self.entries[id].ancestor_count));
+		for id in ids.iter() {
+			self.remove_tx(id);
+		}
+	}
+
+	// drops the txs a block confirmed, and the ones it made invalid by spending
+	// their inputs along with everything depending on those. returns how many
+	// of each went
+	fn remove_for_block(&mut self, block: &Block) -> (usize, usize) {
+		self.block_since_bump = true;
+		self.last_rolling_update = common::now();
+		let (mut confirmed, mut conflicts) = (0, 0);
+		for tx in block.txs.iter().skip(1) {
+			let id = tx.compute_hash();
+			// a prioritisation has nothing left to apply to
+			self.deltas.remove(&id);
+			if self.remove_tx(&id).is_some() {
+				confirmed += 1;
+				continue;
+			}
+			for input in tx.inputs.iter() {
+				if let Some(conflict) = self.spenders.get(&UTXOID(input.tx_hash, input.index)).copied() {
+					conflicts += self.remove_with_descendants(&conflict);
+				}
+			}
+		}
+		(confirmed, conflicts)
+	}
+
+	// empties the mempool, handing back its txs parents first, each with the
+	// time it came in. prioritisations stay
+	fn take_txs(&mut self) -> Vec<(Sha256, Tx, u64)> {
+		let mut ids = self.txs.keys().copied().collect::<Vec<_>>();
+		ids.sort_by_key(|id| self.entries[id].ancestor_count);
+		let txs = ids.into_iter()
+			.map(|id| (id, self.txs.remove(&id).unwrap(), self.entries[&id].time))
+			.collect();
+		self.entries.clear();
+		self.spenders.clear();
+		self.size = 0;
+		txs
+	}
+
+	// drops expired txs, then the packages with the lowest descendant feerate
+	// until the mempool fits. returns what was removed
+	fn limit_size(&mut self) -> HashSet<Sha256> {
+		let cutoff = common::now().saturating_sub(self.expiry);
+		let expired = self.entries.iter()
+			.filter(|(_, entry)| entry.time < cutoff)
+			.map(|(id, _)| *id)
+			.collect::<HashSet<_>>();
+		let mut removed = self.descendants(&expired);
+		self.remove_txs(&removed);
+		if !removed.is_empty() {
+			log_debug!("expired {} mempool txs", removed.len());
+		}
+
+		while self.size > self.max_size {
+			let id = *self.entries.iter()
+				.min_by(|(_, a), (_, b)| {
+					(a.descendant_fee as u128 * b.descendant_size as u128).cmp(&(b.descendant_fee as u128 * a.descendant_size as u128))
+				})
+				.unwrap().0;
+			// nothing paying less than the evicted gets back in until the minimum decays
+			let feerate = (self.entries[&id].descendant_feerate() + INCREMENTAL_RELAY_FEE) as f64;
+			if feerate > self.rolling_min_feerate {
+				self.rolling_min_feerate = feerate;
+				self.block_since_bump = false;
+			}
+			let evicted = self.descendants(&HashSet::from([id]));
+			self.remove_txs(&evicted);
+			log_debug!("mempool full: evicted {} txs, min feerate now {} sat/kvB", evicted.len(), self.rolling_min_feerate);
+			removed.extend(evicted);
+		}
+		removed
+	}
+
+	// sat/kvB, the lowest feerate a tx needs to get in
+	fn min_feerate(&mut self) -> u64 {
+		if self.block_since_bump && self.rolling_min_feerate > 0.0 {
+			let now = common::now();
+			if now > self.last_rolling_update + 10 {
+				let mut halflife = ROLLING_FEE_HALFLIFE;
+				if self.size < self.max_size / 4 {
+					halflife /= 4.0;
+				} else if self.size < self.max_size / 2 {
+					halflife /= 2.0;
+				}
+				self.rolling_min_feerate /= 2f64.powf((now - self.last_rolling_update) as f64 / halflife);
+				self.last_rolling_update = now;
+				if self.rolling_min_feerate < INCREMENTAL_RELAY_FEE as f64 / 2.0 {
+					self.rolling_min_feerate = 0.0;
+				}
+			}
+		}
+		std::cmp::max(self.rolling_min_feerate.round() as u64, MIN_RELAY_FEE)
+	}
+
+	// the fee a tx paying `fee` counts as having paid, never more than all
+	// the money there is
+	fn modified_fee(&self, id: &Sha256, fee: u64) -> u64 {
+		(fee as i64).saturating_add(self.deltas.get(id).copied().unwrap_or(0)).clamp(0, MAX_MONEY as i64) as u64
+	}
+
+	fn prioritise(&mut self, id: Sha256, delta: i64) -> Result<()> {
+		let old = self.deltas.get(&id).copied().unwrap_or(0);
+		let total = old.checked_add(delta).ok_or_else(|| Err::ValueError("fee delta out of range".to_owned()))?;
+		if total == 0 {
+			self.deltas.remove(&id);
 		} else {
-			log_debug!("add tx to mempool: {}", id);
-			self.txs.insert(id, tx);
+			self.deltas.insert(id, total);
+		}
+		let entry = match self.entries.get_mut(&id) {
+			Some(entry) => entry,
+			None => return Ok(()),
+		};
+		let (base_fee, old_fee) = (entry.base_fee, entry.fee);
+		let (parents, children) = (entry.parents.clone(), entry.children.clone());
+		let fee = self.modified_fee(&id, base_fee);
+		let entry = self.entries.get_mut(&id).unwrap();
+		let adjust = |total: u64| total.saturating_sub(old_fee).saturating_add(fee);
+		entry.fee = fee;
+		entry.ancestor_fee = adjust(entry.ancestor_fee);
+		entry.descendant_fee = adjust(entry.descendant_fee);
+		for ancestor in self.ancestors(&parents) {
+			let ancestor = self.entries.get_mut(&ancestor).unwrap();
+			ancestor.descendant_fee = adjust(ancestor.descendant_fee);
+		}
+		for descendant in self.descendants(&children) {
+			let descendant = self.entries.get_mut(&descendant).unwrap();
+			descendant.ancestor_fee = adjust(descendant.ancestor_fee);
+		}
+		Ok(())
+	}
+
+	// for a tx not in the mempool, a delta saved earlier. it replaces the one
+	// we have, so that loading the same dump twice doesn't count it twice
+	fn set_delta(&mut self, id: Sha256, delta: i64) {
+		if delta != 0 {
+			self.deltas.insert(id, delta);
+		}
+	}
+
+	fn spender(&self, id: &UTXOID) -> Option<Sha256> {
+		self.spenders.get(id).copied()
+	}
+
+	fn parents_of(&self, tx: &Tx) -> HashSet<Sha256> {
+		tx.inputs.iter()
+			.map(|input| input.tx_hash)
+			.filter(|hash| self.entries.contains_key(hash))
+			.collect()
+	}
+
+	// everything reachable through `parents`, them included
+	fn ancestors(&self, parents: &HashSet<Sha256>) -> HashSet<Sha256> {
+		let mut ancestors = HashSet::new();
+		let mut queue = parents.iter().copied().collect::<Vec<_>>();
+		while let Some(id) = queue.pop() {
+			if ancestors.insert(id) {
+				queue.extend(self.entries[&id].parents.iter().copied());
+			}
+		}
+		ancestors
+	}
+
+	// likewise through `children`
+	fn descendants(&self, children: &HashSet<Sha256>) -> HashSet<Sha256> {
+		let mut descendants = HashSet::new();
+		let mut queue = children.iter().copied().collect::<Vec<_>>();
+		while let Some(id) = queue.pop() {
+			if descendants.insert(id) {
+				queue.extend(self.entries[&id].children.iter().copied());
+			}
+		}
+		descendants
+	}
+
+	// whether adding the tx would take its package, or any of its ancestors',
+	// past the limits
+	fn check_limits(&self, tx: &Tx) -> Result<()> {
+		let vsize = tx.vsize();
+		let ancestors = self.ancestors(&self.parents_of(tx));
+		if ancestors.len() + 1 > MAX_ANCESTORS {
+			return Err(Err::ValueError(format!("too many unconfirmed ancestors ({})", ancestors.len())));
+		}
+		let ancestor_size = ancestors.iter().map(|id| self.entries[id].vsize).sum::<usize>() + vsize;
+		if ancestor_size > MAX_ANCESTOR_SIZE {
+			return Err(Err::ValueError(format!("unconfirmed ancestors too large ({} vB)", ancestor_size)));
+		}
+		for id in ancestors.iter() {
+			let ancestor = &self.entries[id];
+			if ancestor.descendant_count + 1 > MAX_DESCENDANTS || ancestor.descendant_size + vsize > MAX_DESCENDANT_SIZE {
+				return Err(Err::ValueError(format!("exceeds descendant limits of {}", id)));
+			}
+		}
+		Ok(())
+	}
+
+	// BIP 431 rules on where a TRUC tx can go: one unconfirmed parent at most,
+	// which can have no other child, and no mixing with other versions either
+	// way. returns the child its parent has already, for it to replace
+	fn check_truc(&self, tx: &Tx) -> Result<Option<Sha256>> {
+		let reject = |reason: String| Err(Err::ValueError(reason));
+		let parents = self.parents_of(tx);
+		if tx.version != TRUC_VERSION {
+			return match parents.iter().find(|id| self.txs[id].version == TRUC_VERSION) {
+				Some(parent) => reject(format!("non-version=3 tx cannot spend from version=3 tx {}", parent)),
+				None => Ok(None),
+			};
+		}
+		let vsize = tx.vsize();
+		if vsize > TRUC_MAX_VSIZE {
+			return reject(format!("version=3 tx is too big ({} vB)", vsize));
+		}
+		if let Some(parent) = parents.iter().find(|id| self.txs[id].version != TRUC_VERSION) {
+			return reject(format!("version=3 tx cannot spend from non-version=3 tx {}", parent));
+		}
+		let parent = match parents.iter().next() {
+			Some(parent) => &self.entries[parent],
+			None => return Ok(None),
+		};
+		if parents.len() > 1 || parent.ancestor_count > 1 {
+			return reject("version=3 tx would have too many ancestors".to_owned());
+		}
+		if vsize > TRUC_CHILD_MAX_VSIZE {
+			return reject(format!("version=3 child tx is too big ({} vB)", vsize));
+		}
+		Ok(parent.children.iter().next().copied())
+	}
+
+	// whether paying `fee` for `vsize` vbytes, as a tx or a package, gets in
+	fn check_feerate(&mut self, fee: u64, vsize: usize) -> Result<()> {
+		let min_feerate = self.min_feerate();
+		if (fee as u128) * 1000 < min_feerate as u128 * vsize as u128 {
+			return Err(Err::FeeError(format!("feerate below the mempool minimum of {} sat/kvB", min_feerate)));
+		}
+		Ok(())
+	}
+
+	// BIP 125: whether the tx opted in to being replaced. TRUC txs always are
+	fn signals_rbf(&self, id: &Sha256) -> bool {
+		let tx = &self.txs[id];
+		tx.version == TRUC_VERSION || tx.inputs.iter().any(|input| input.sequence < 0xffff_fffe)
+	}
+
+	// checks a tx conflicting with `conflicts` against the replacement rules,
+	// returning everything it would evict. without full RBF, each conflict or
+	// one of its ancestors has to signal
+	fn check_replacement(&self, tx: &Tx, fee: u64, conflicts: &HashSet<Sha256>, full_rbf: bool) -> Result<HashSet<Sha256>> {
+		if !full_rbf {
+			for id in conflicts.iter() {
+				if !self.ancestors(&HashSet::from([*id])).iter().any(|id| self.signals_rbf(id)) {
+					return Err(Err::ValueError(format!("conflicts with non-replaceable tx {}", id)));
+				}
+			}
+		}
+		let parents = self.parents_of(tx);
+		if let Some(id) = self.ancestors(&parents).intersection(conflicts).next() {
+			return Err(Err::ValueError(format!("spends conflicting tx {}", id)));
+		}
+		// only unconfirmed inputs the originals had already
+		for parent in parents.iter() {
+			if !conflicts.iter().any(|id| self.entries[id].parents.contains(parent)) {
+				return Err(Err::ValueError(format!("adds unconfirmed input from {}", parent)));
+			}
+		}
+
+		let evicted = self.descendants(conflicts);
+		if evicted.len() > MAX_REPLACEMENT_EVICTIONS {
+			return Err(Err::ValueError(format!("would evict {} txs", evicted.len())));
+		}
+		let vsize = tx.vsize() as u64;
+		for id in conflicts.iter() {
+			let entry = &self.entries[id];
+			if fee as u128 * entry.vsize as u128 <= entry.fee as u128 * vsize as u128 {
+				return Err(Err::ValueError(format!("feerate not above that of {}", id)));
+			}
+		}
+		let evicted_fee = evicted.iter().fold(0u64, |total, id| total.saturating_add(self.entries[id].fee));
+		if fee < evicted_fee.saturating_add(INCREMENTAL_RELAY_FEE * vsize / 1000) {
+			return Err(Err::ValueError(format!("fee {} doesn't cover the {} evicted plus relay", fee, evicted_fee)));
+		}
+		Ok(evicted)
+	}
+
+	// highest first. a child paying for its parents can outrank them, so this
+	// isn't an order txs can be mined in without pulling ancestors forward
+	fn by_ancestor_feerate(&self) -> Vec<Sha256> {
+		let mut ids = self.entries.keys().copied().collect::<Vec<_>>();
+		ids.sort_by(|a, b| {
+			let (a, b) = (&self.entries[a], &self.entries[b]);
+			let a_rate = a.ancestor_fee as u128 * b.ancestor_size as u128;
+			let b_rate = b.ancestor_fee as u128 * a.ancestor_size as u128;
+			b_rate.cmp(&a_rate).then(a.ancestor_count.cmp(&b.ancestor_count))
+		});
+		ids
+	}
+
+	// the txs to mine next, parents before children, within the given weight
+	// and sigop cost. the package with the highest ancestor feerate goes in
+	// first; once some of a tx's ancestors are in, it's ranked by what's left
+	// of its package
+	fn select_packages(&self, max_weight: usize, max_sigops: usize) -> Vec<Sha256> {
+		// give up once the block is nearly full and this many packages haven't fit
+		const MAX_CONSECUTIVE_FAILURES: usize = 1000;
+
+		let sorted = self.by_ancestor_feerate();
+		let mut next = 0;
+		// the fee and vsize of the rest of the package, for txs with ancestors in
+		let mut modified: HashMap<Sha256, (u64, usize)> = HashMap::new();
+		let mut included = HashSet::new();
+		let mut failed = HashSet::new();
+		let mut selected = Vec::new();
+		let (mut weight, mut sigops, mut failures) = (0, 0, 0);
+		loop {
+			while next < sorted.len() && (included.contains(&sorted[next]) || failed.contains(&sorted[next]) || modified.contains_key(&sorted[next])) {
+				next += 1;
+			}
+			let best_modified = modified.iter()
+				.max_by(|(_, a), (_, b)| (a.0 as u128 * b.1 as u128).cmp(&(b.0 as u128 * a.1 as u128)))
+				.map(|(id, &package)| (*id, package));
+			let id = match (sorted.get(next), best_modified) {
+				(Some(id), Some((modified_id, (fee, size)))) => {
+					let entry = &self.entries[id];
+					if fee as u128 * entry.ancestor_size as u128 > entry.ancestor_fee as u128 * size as u128 {
+						modified.remove(&modified_id);
+						modified_id
+					} else {
+						next += 1;
+						*id
+					}
+				},
+				(Some(id), None) => {
+					next += 1;
+					*id
+				},
+				(None, Some((modified_id, _))) => {
+					modified.remove(&modified_id);
+					modified_id
+				},
+				(None, None) => break,
+			};
+
+			let mut package = self.ancestors(&HashSet::from([id]));
+			package.retain(|id| !included.contains(id));
+			let package_weight = package.iter().map(|id| self.entries[id].vsize * 4).sum::<usize>();
+			let package_sigops = package.iter().map(|id| self.entries[id].sigops).sum::<usize>();
+			if weight + package_weight > max_weight || sigops + package_sigops > max_sigops {
+				failed.insert(id);
+				failures += 1;
+				if failures > MAX_CONSECUTIVE_FAILURES && weight + 4000 > max_weight {
+					break;
+				}
+				continue;
+			}
+			failures = 0;
+			weight += package_weight;
+			sigops += package_sigops;
+
+			// fewer ancestors first puts parents before their children
+			let mut package = package.into_iter().collect::<Vec<_>>();
+			package.sort_by_key(|id| self.entries[id].ancestor_count);
+			for id in package {
+				included.insert(id);
+				modified.remove(&id);
+				let entry = &self.entries[&id];
+				for descendant in self.descendants(&entry.children) {
+					if included.contains(&descendant) {
+						continue;
+					}
+					let other = &self.entries[&descendant];
+					let (fee, size) = modified.entry(descendant).or_insert((other.ancestor_fee, other.ancestor_size));
+					*fee = fee.saturating_sub(entry.fee);
+					*size -= entry.vsize;
+				}
+				selected.push(id);
+			}
 		}
+		selected
+	}
+
+	// sat/kvB, the unit used by feefilter
+	fn feerate(&self, id: &Sha256) -> Option<u64> {
+		let entry = self.entries.get(id)?;
+		Some(feerate(entry.fee, entry.vsize))
 	}
 
 	fn contains(&self, id: Sha256) -> bool {
@@ -294,6 +1145,7 @@ impl Mempool {
 
 
 enum ApplicationMessage {
+	ShowPeers,
 	ShowMempool,
 	ShowBlockHashes,
 	ShowMempoolCount,
@@ -301,63 +1153,361 @@ enum ApplicationMessage {
 	ShowHeader(String),
 	ShowBlock(String),
 	ShowTx(String),
+	SetBan(String, Option<u64>),
+	Unban(String),
+	ListBanned,
+	ClearBanned,
+	Prioritise(String, i64),
+	ExportMempool(String),
+	ImportMempool(String),
+	EstimateSmartFee(usize, EstimateMode),
+	GetBlockTemplate,
+	ProposeBlock(String),
+	SubmitBlock(String),
+	TestMempoolAccept(String),
+	SubmitPackage(String),
 	Save,
 	Shutdown,
 }
 
+// results of outbound connection attempts, which are made on their own threads
+// since std can't connect without blocking
+enum PeerEvent {
+	// whether the connection opens with the v2 handshake
+	Outbound(TcpStream, NetAddr, bool),
+	OutboundFailed(NetAddr),
+	// our onion service is up, at this address
+	OnionService(NetAddr),
+}
+
+// the event loop wakes up at least this often to run timers and console commands
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+const DEFAULT_MAX_CONNECTIONS: usize = 125;
+const MAX_OUTBOUND_CONNECTIONS: usize = 8;
+
+// tx announcements are batched and sent at random (poisson) intervals so that
+// the origin of a tx can't be inferred from announcement timing. inbound peers
+// wait longer, since an attacker can open many inbound connections.
+const INBOUND_INVENTORY_BROADCAST_INTERVAL: Duration = Duration::from_secs(5);
+const OUTBOUND_INVENTORY_BROADCAST_INTERVAL: Duration = Duration::from_secs(2);
+const INVENTORY_BROADCAST_MAX: usize = 1000;
+// more new blocks than this are announced with inv rather than headers (cf. BIP 130)
+const MAX_BLOCKS_TO_ANNOUNCE: usize = 8;
+const MAX_KNOWN_INVENTORY: usize = 50_000;
+
+// BIP 133: our minimum feerate goes out this often, or within the shorter
+// delay after it moves by more than a third. during IBD it's the most anything
+// could pay, since we'd drop every tx anyway
+const AVG_FEEFILTER_BROADCAST_INTERVAL: Duration = Duration::from_secs(10 * 60);
+const MAX_FEEFILTER_CHANGE_DELAY: Duration = Duration::from_secs(5 * 60);
+const MAX_MONEY: u64 = 21_000_000 * common::SAT_PER_COIN;
+
+const AVG_ADDRESS_BROADCAST_INTERVAL: Duration = Duration::from_secs(30);
+// addresses from a peer are processed at this average rate (with bursts of up
+// to MAX_ADDR_TO_SEND allowed after we ask for them with getaddr); the rest
+// are dropped, so a peer can't flood the address manager
+const MAX_ADDR_RATE_PER_SECOND: f64 = 0.1;
+const MAX_ADDR_PROCESSING_TOKEN_BUCKET: f64 = MAX_ADDR_TO_SEND as f64;
+// unsolicited addresses are relayed to this many peers
+const ADDR_RELAY_FANOUT: usize = 2;
+const MAX_KNOWN_ADDRS: usize = 5000;
+
+// misbehavior scores add up over a connection; a peer that reaches the
+// threshold is disconnected and its host banned (cf. bitcoind's Misbehaving)
+const BAN_THRESHOLD: u32 = 100;
+const MALFORMED_MESSAGE_SCORE: u32 = 20;
+const OVERSIZED_MESSAGE_SCORE: u32 = 20;
+// headers that don't connect are normal when a peer announces a block we're
+// behind on, so only every this-many-th one counts against it
+const MAX_UNCONNECTING_HEADERS: u32 = 10;
+
+const MAX_HEADERS_RESULTS: usize = 2000;
+const MAX_INV_SIZE: usize = 50_000;
+const MAX_LOCATOR_SIZE: usize = 101;
+
+const PING_INTERVAL: Duration = Duration::from_secs(2 * 60);
+// no traffic either way (or no answer to a ping) for this long means the connection is dead
+const TIMEOUT_INTERVAL: Duration = Duration::from_secs(20 * 60);
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(60);
+// a peer that delivers none of the blocks we asked it for in this long is
+// stalling; its requests go to someone else
+const BLOCK_DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(60);
+// blocks are only requested this far past the last validated one. everything
+// that arrives ahead of the tip waits in memory, so this bounds what that costs
+const BLOCK_DOWNLOAD_WINDOW: usize = 256;
+const MAX_BLOCKS_IN_TRANSIT_PER_PEER: usize = 16;
+// how long the block at the start of the window may hold everything else up
+// before someone else is asked for it too
+const BLOCK_STALLING_TIMEOUT: Duration = Duration::from_secs(5);
+
+// compact block versions we speak, most preferred first (BIP 152)
+const CMPCT_VERSIONS: [u64; 2] = [2, 1];
+// peers asked to push new blocks to us as compact blocks, without announcing them first
+const MAX_HIGH_BANDWIDTH_PEERS: usize = 3;
+// blocks deeper than this are sent whole, even when asked for in compact form
+const MAX_CMPCT_DEPTH: usize = 10;
+
+// BIP 157 request limits, and the spacing of the filter headers in a cfcheckpt
+const MAX_GETCFILTERS_SIZE: usize = 1000;
+const MAX_GETCFHEADERS_SIZE: usize = 2000;
+const CFCHECKPT_INTERVAL: usize = 1000;
+
+// blocks a coinbase output has to wait before it can be spent
+const COINBASE_MATURITY: usize = 100;
+
+const OUTBOUND_CONNECT_INTERVAL: Duration = Duration::from_millis(500);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const DEFAULT_TOR_CONTROL: &str = "127.0.0.1:9051";
+
+struct BlockRequest {
+	peer: usize,
+	time: Instant,
+}
+
+// headers of a competing branch, checked but kept apart from our chain until
+// enough of its blocks are in to show it has more work
+#[derive(Clone)]
+struct Branch {
+	// the height of the last block it has in common with our chain
+	fork: usize,
+	hashes: Vec<Sha256>,
+	headers: Vec<Header>,
+	work: u256,
+	// whether it had more work than our headers past the fork, when last looked at
+	more_work: bool,
+}
+
+// a compact block waiting on the txs that weren't in the mempool
+struct PartialBlock {
+	peer: usize,
+	header: Header,
+	txs: Vec<Option<Tx>>,
+}
+
 struct PeerHandle {
 	addr: String,
-	writer: TcpStream,
-	// handle: thread::JoinHandle<()>,
+	conn: Connection,
+	connected: Instant,
 	info: Option<Version>,
 	config: Config,
 	handshake_complete: bool,
+	inbound: bool,
+	// txs and blocks the peer has or that we've announced to it
+	known_inventory: HashSet<Sha256>,
+	tx_queue: Vec<Sha256>,
+	block_queue: Vec<Sha256>,
+	next_tx_broadcast: Instant,
+	// the peer's address as the address manager knows it
+	endpoint: Option<AddrKey>,
+	known_addrs: HashSet<AddrKey>,
+	addr_queue: Vec<NetAddr>,
+	next_addr_broadcast: Instant,
+	addr_tokens: f64,
+	addr_tokens_time: Instant,
+	getaddr_sent: bool,
+	getaddr_answered: bool,
+	misbehavior: u32,
+	unconnecting_headers: u32,
+	// getdata requests not yet served because the send buffer was full
+	getdata_queue: VecDeque<InvItem>,
+	ping_nonce: Option<u64>,
+	ping_start: Instant,
+	next_ping: Instant,
+	ping_time: Option<Duration>,
+	// the last feerate we sent in a feefilter
+	feefilter_sent: u64,
+	next_feefilter: Instant,
+	min_ping_time: Option<Duration>,
+	last_block_time: Instant,
+	// when the peer last gave us a tx or block we didn't have, so eviction can spare it
+	last_new_tx: Option<Instant>,
+	last_new_block: Option<Instant>,
+	bloom_filter: Option<BloomFilter>,
+}
+
+impl PeerHandle {
+	fn add_known_inventory(&mut self, hash: Sha256) {
+		if self.known_inventory.len() >= MAX_KNOWN_INVENTORY {
+			self.known_inventory.clear();
+		}
+		self.known_inventory.insert(hash);
+	}
+
+	fn add_known_addr(&mut self, key: AddrKey) {
+		if self.known_addrs.len() >= MAX_KNOWN_ADDRS {
+			self.known_addrs.clear();
+		}
+		self.known_addrs.insert(key);
+	}
+
+	// the peer's address for our version message. only IP addresses are given,
+	// and nothing is looked up, so names reached through a proxy don't leak to DNS
+	fn version_addr(&self) -> SocketAddr {
+		self.endpoint
+			.and_then(|(host, port)| NetAddr::new(host, port, 0, 0).socket_addr())
+			.unwrap_or_else(|| SocketAddr::from(([0u16; 8], 0)))
+	}
+
+	fn relays_txs(&self) -> bool {
+		self.config.relay_txs || self.info.as_ref().is_some_and(|info| info.relay())
+	}
+
+	fn schedule_tx_broadcast(&mut self) {
+		let mean = if self.inbound {
+			INBOUND_INVENTORY_BROADCAST_INTERVAL
+		} else {
+			OUTBOUND_INVENTORY_BROADCAST_INTERVAL
+		};
+		self.next_tx_broadcast = Instant::now() + poisson_delay(mean);
+	}
+}
+
+// exponentially distributed delay with the given mean
+fn poisson_delay(mean: Duration) -> Duration {
+	Duration::from_secs_f64(-(1.0 - rand::random::<f64>()).ln() * mean.as_secs_f64())
 }
 
 struct Node {
 	peers: HashMap<usize, PeerHandle>,
-	recv: Option<Receiver<(usize, Message)>>,
+	next_peer_id: usize,
+	max_inbound: usize,
+	max_outbound: usize,
+	send: Option<Sender<PeerEvent>>,
+	recv: Option<Receiver<PeerEvent>>,
+	listener: Option<TcpListener>,
+	blocks_in_flight: HashMap<Sha256, BlockRequest>,
+	// blocks that arrived ahead of the tip, and who sent them
+	downloaded_blocks: HashMap<Sha256, (Block, usize)>,
+	partial_blocks: HashMap<Sha256, PartialBlock>,
+	// blocks that failed validation, and the headers that were built on them
+	invalid_blocks: HashSet<Sha256>,
+	branch: Option<Branch>,
+	high_bandwidth_peers: VecDeque<usize>,
 	mempool: Mempool,
+	orphans: Orphanage,
+	// keys the network group ordering used in choosing a peer to evict
+	eviction_key: u64,
+	fee_estimator: FeeEstimator,
 	block_db: BlocksDB,
+	filter_index: FilterIndex,
+	addrman: AddrMan,
+	banlist: BanList,
+	pending_outbound: HashSet<AddrKey>,
+	last_connect_attempt: Instant,
 	utxos: BTreeMap<UTXOID, TxOutput>,
+	// the height of each coinbase from the last COINBASE_MATURITY blocks, since
+	// the UTXO set doesn't record where an output came from
+	immature_coinbases: HashMap<Sha256, usize>,
+	// the height each tx with unspent outputs confirmed at, for BIP 68
+	coin_heights: HashMap<Sha256, usize>,
 	last_save_time: u64,
 	target: u256,
 	state: State,
+	// service bits advertised in our version message
+	services: u64,
+	// outbound connections go through these if set: onion addresses through
+	// `onion_proxy`, everything else through `proxy`
+	proxy: Option<Proxy>,
+	onion_proxy: Option<Proxy>,
+	// the address of our onion service, advertised to peers
+	onion_addr: Option<NetAddr>,
+	// replace mempool txs whether or not they signal for it
+	full_rbf: bool,
+	// turn away txs the relay policy doesn't consider standard
+	require_standard: bool,
 }
 
 impl Node {
-	pub fn new(load_utxos: bool) -> Node {
+	pub fn new(params: ConsensusParams, load_utxos: bool) -> Node {
 		log_info!("loading headers...");
-		let block_db = BlocksDB::load();
+		let block_db = BlocksDB::load(params.data_dir, params.genesis);
 		let last_hash = block_db.hashes.last().unwrap();
 		let target = block_db.headers.get(last_hash).unwrap().compute_target();
 
+		let mut filter_index = FilterIndex::load(params.data_dir, params.genesis);
+		filter_index.truncate(block_db.blocks_validated);
+		if filter_index.len() < block_db.blocks_validated {
+			log_warn!("block filter index is behind the chain (at {} of {} blocks); -rebuild-utxos rebuilds it",
+				filter_index.len(), block_db.blocks_validated);
+		}
+
+		let addrman = AddrMan::load(&format!("{}/peers.dat", params.data_dir));
+		let banlist = BanList::load(&format!("{}/banlist.dat", params.data_dir));
+		let fee_estimator = FeeEstimator::load(&format!("{}/fee_estimates.dat", params.data_dir));
+
 		let utxos = if load_utxos {
 			log_info!("loading UTXOs...");
-			Node::load_utxos()
+			Node::load_utxos(params.data_dir)
 		} else {
 			BTreeMap::new()
 		};
+		let immature_coinbases = Node::load_immature_coinbases(&block_db);
+		let coin_heights = Node::load_coin_heights(params.data_dir);
+		if coin_heights.is_empty() && !utxos.is_empty() {
+			log_warn!("no coin heights saved; txs with relative lock times are rejected until -rebuild-utxos rebuilds them");
+		}
 
 		Node {
 			peers: HashMap::new(),
+			next_peer_id: 0,
+			max_inbound: DEFAULT_MAX_CONNECTIONS - MAX_OUTBOUND_CONNECTIONS,
+			max_outbound: MAX_OUTBOUND_CONNECTIONS,
+			send: None,
 			recv: None,
+			listener: None,
+			blocks_in_flight: HashMap::new(),
+			downloaded_blocks: HashMap::new(),
+			partial_blocks: HashMap::new(),
+			invalid_blocks: HashSet::new(),
+			branch: None,
+			high_bandwidth_peers: VecDeque::new(),
 			mempool: Mempool::new(),
+			orphans: Orphanage::new(),
+			eviction_key: rand::random(),
+			fee_estimator,
 			block_db,
+			filter_index,
+			addrman,
+			banlist,
+			pending_outbound: HashSet::new(),
+			last_connect_attempt: Instant::now(),
 			utxos,
+			immature_coinbases,
+			coin_heights,
 			last_save_time: common::now(),
 			target,
-			state: Default::default(),
+			state: State::new(params),
+			services: 0,
+			proxy: None,
+			onion_proxy: None,
+			onion_addr: None,
+			full_rbf: false,
+			require_standard: true,
+		}
+	}
+
+	fn load_immature_coinbases(block_db: &BlocksDB) -> HashMap<Sha256, usize> {
+		let mut coinbases = HashMap::new();
+		let tip = block_db.blocks_validated;
+		for height in tip.saturating_sub(COINBASE_MATURITY)..tip {
+			match block_db.load_block(&block_db.hashes[height]) {
+				Ok(block) => {
+					coinbases.insert(block.txs[0].compute_hash(), height);
+				},
+				Err(err) => log_warn!("failed to load block {}: {}", height, err),
+			}
 		}
+		coinbases
 	}
 
-	fn load_utxos() -> BTreeMap<UTXOID, TxOutput> {
+	fn load_utxos(data_dir: &str) -> BTreeMap<UTXOID, TxOutput> {
 		use std::path::Path;
 
-		const UTXOS_PATH: &str = "./data/utxos.dat";
+		let utxos_path = format!("{}/utxos.dat", data_dir);
 		
-		if Path::new(UTXOS_PATH).is_file() {
-			let mut utxos_file = match std::fs::File::open(UTXOS_PATH) {
+		if Path::new(&utxos_path).is_file() {
+			let mut utxos_file = match std::fs::File::open(&utxos_path) {
 				Ok(file) => file,
 				Err(err) => {
 					log_error!("warning: failed to load utxos.dat: {}", err.to_string());
@@ -394,16 +1544,53 @@ impl Node {
 		return BTreeMap::new();
 	}
 
-	fn save_utxos(&self) {
-		if self.utxos.len() == 0 {
-			return;
-		}
-
-		let mut file = match std::fs::File::create("./data/utxos.dat") {
+	fn load_coin_heights(data_dir: &str) -> HashMap<Sha256, usize> {
+		let mut file = match std::fs::File::open(format!("{}/coin_heights.dat", data_dir)) {
 			Ok(file) => file,
-			Err(err) => {
-				log_error!("warning: failed to save utxo set: {}", err.to_string());
-				return;
+			Err(_) => return HashMap::new(),
+		};
+		let result: Result<_> = try {
+			let mut heights = HashMap::new();
+			for _ in 0..common::read_u64(&mut file)? {
+				let id = common::read_sha256(&mut file)?;
+				heights.insert(id, common::read_u64(&mut file)? as usize);
+			}
+			heights
+		};
+		result.unwrap_or_else(|err| {
+			log_error!("warning: failed to load coin heights: {}", err);
+			HashMap::new()
+		})
+	}
+
+	// drops the txs with nothing left unspent first
+	fn save_coin_heights(&mut self) {
+		let utxos = &self.utxos;
+		self.coin_heights.retain(|id, _| utxos.range(UTXOID(*id, 0)..=UTXOID(*id, u32::MAX)).next().is_some());
+		let result: Result<()> = try {
+			let mut file = std::fs::File::create(format!("{}/coin_heights.dat", self.state.params.data_dir))
+				.map_err(|err| Err::IOError(err.to_string()))?;
+			common::write_u64(&mut file, self.coin_heights.len() as u64)?;
+			for (id, &height) in self.coin_heights.iter() {
+				common::write_sha256(&mut file, id)?;
+				common::write_u64(&mut file, height as u64)?;
+			}
+		};
+		if let Err(err) = result {
+			log_error!("warning: failed to save coin heights: {}", err);
+		}
+	}
+
+	fn save_utxos(&self) {
+		if self.utxos.len() == 0 {
+			return;
+		}
+
+		let mut file = match std::fs::File::create(format!("{}/utxos.dat", self.state.params.data_dir)) {
+			Ok(file) => file,
+			Err(err) => {
+				log_error!("warning: failed to save utxo set: {}", err.to_string());
+				return;
 			}
 		};
 		let result: Result<()> = try {
@@ -422,9 +1609,94 @@ impl Node {
 		}
 	}
 
+	// the mempool's txs, parents first, each with the time it came in and its
+	// prioritisation, then the prioritisations of txs that aren't in it
+	fn save_mempool(&self, path: &str) -> Result<()> {
+		let file = std::fs::File::create(path)
+			.map_err(|err| Err::IOError(err.to_string()))?;
+		let mut file = BufWriter::new(file);
+		let mut ids = self.mempool.entries.keys().copied().collect::<Vec<_>>();
+		ids.sort_by_key(|id| self.mempool.entries[id].ancestor_count);
+		common::write_u64(&mut file, MEMPOOL_DUMP_VERSION)?;
+		common::write_u64(&mut file, ids.len() as u64)?;
+		for id in ids.iter() {
+			self.mempool.txs[id].serialize(&mut file)?;
+			common::write_u64(&mut file, self.mempool.entries[id].time)?;
+			common::write_i64(&mut file, self.mempool.deltas.get(id).copied().unwrap_or(0))?;
+		}
+		let deltas = self.mempool.deltas.iter()
+			.filter(|(id, _)| !self.mempool.entries.contains_key(id))
+			.collect::<Vec<_>>();
+		common::write_u64(&mut file, deltas.len() as u64)?;
+		for (id, delta) in deltas {
+			common::write_sha256(&mut file, id)?;
+			common::write_i64(&mut file, *delta)?;
+		}
+		file.flush().map_err(|err| Err::IOError(err.to_string()))?;
+		log_info!("saved {} mempool txs", ids.len());
+		Ok(())
+	}
+
+	// each tx is checked against the current tip like any other. returns how
+	// many made it in and how many didn't
+	fn load_mempool(&mut self, path: &str) -> Result<(usize, usize)> {
+		let file = std::fs::File::open(path)
+			.map_err(|err| Err::IOError(err.to_string()))?;
+		let mut file = BufReader::new(file);
+		let version = common::read_u64(&mut file)?;
+		if version != MEMPOOL_DUMP_VERSION {
+			return Err(Err::ValueError(format!("unknown mempool dump version: {}", version)));
+		}
+		let (mut accepted, mut failed) = (0, 0);
+		let cutoff = common::now().saturating_sub(self.mempool.expiry);
+		for _ in 0..common::read_u64(&mut file)? {
+			let tx = Tx::deserialize(&mut file)?;
+			let time = common::read_u64(&mut file)?;
+			let delta = common::read_i64(&mut file)?;
+			let id = tx.compute_hash();
+			// a tx we have already keeps its fee as it is
+			if self.mempool.txs.contains_key(&id) {
+				failed += 1;
+				continue;
+			}
+			self.mempool.set_delta(id, delta);
+			if time < cutoff {
+				failed += 1;
+				continue;
+			}
+			match self.accept_to_mempool(id, tx) {
+				Ok(()) => {
+					self.mempool.entries.get_mut(&id).unwrap().time = time;
+					accepted += 1;
+				},
+				Err(err) => {
+					log_debug!("mempool tx {} not loaded: {}", id, err);
+					failed += 1;
+				},
+			}
+		}
+		for _ in 0..common::read_u64(&mut file)? {
+			let id = common::read_sha256(&mut file)?;
+			let delta = common::read_i64(&mut file)?;
+			if !self.mempool.txs.contains_key(&id) {
+				self.mempool.set_delta(id, delta);
+			}
+		}
+		Ok((accepted, failed))
+	}
+
 	fn save_state(&mut self) {
 		self.block_db.save();
+		self.filter_index.save();
 		self.save_utxos();
+		self.save_coin_heights();
+		if let Err(err) = self.save_mempool(&format!("{}/mempool.dat", self.state.params.data_dir)) {
+			log_error!("warning: failed to save mempool: {}", err);
+		}
+		self.addrman.save(&format!("{}/peers.dat", self.state.params.data_dir));
+		self.banlist.sweep();
+		self.banlist.save(&format!("{}/banlist.dat", self.state.params.data_dir));
+		self.fee_estimator.save(&format!("{}/fee_estimates.dat", self.state.params.data_dir));
 		self.last_save_time = common::now();
 	}
 
@@ -436,11 +1708,29 @@ impl Node {
 			Payload::WTxIdRelay        => self.handle_wtxidrelay_message(peer_index),
 			Payload::SendAddrV2        => self.handle_sendaddrv2_message(peer_index),
 			Payload::Ping(payload)     => self.handle_ping_message(peer_index, payload),
+			Payload::Pong(payload)     => self.handle_pong_message(peer_index, payload),
 			Payload::Inv(payload)      => self.handle_inv_message(peer_index, payload),
 			Payload::Tx(id, payload)   => self.handle_tx_message(peer_index, id, payload),
 			Payload::SendHeaders       => self.handle_sendheaders_message(peer_index),
 			Payload::Headers(payload)  => self.handle_headers_message(peer_index, payload),
 			Payload::Block(payload)    => self.handle_block_message(peer_index, payload),
+			Payload::GetHeaders(payload) => self.handle_getheaders_message(peer_index, payload),
+			Payload::GetBlocks(payload)  => self.handle_getblocks_message(peer_index, payload),
+			Payload::GetData(payload)    => self.handle_getdata_message(peer_index, payload),
+			Payload::NotFound(payload)   => self.handle_notfound_message(peer_index, payload),
+			Payload::Addr(payload)       => self.handle_addr_message(peer_index, payload.into_iter().collect()),
+			Payload::AddrV2(payload)     => self.handle_addr_message(peer_index, payload.into_iter().collect()),
+			Payload::GetAddr             => self.handle_getaddr_message(peer_index),
+			Payload::SendCmpct(payload)   => self.handle_sendcmpct_message(peer_index, payload),
+			Payload::CmpctBlock(payload)  => self.handle_cmpctblock_message(peer_index, payload),
+			Payload::GetBlockTxn(payload) => self.handle_getblocktxn_message(peer_index, payload),
+			Payload::BlockTxn(payload)    => self.handle_blocktxn_message(peer_index, payload),
+			Payload::FilterLoad(payload)  => self.handle_filterload_message(peer_index, payload),
+			Payload::FilterAdd(payload)   => self.handle_filteradd_message(peer_index, payload),
+			Payload::FilterClear          => self.handle_filterclear_message(peer_index),
+			Payload::GetCFilters(payload)  => self.handle_getcfilters_message(peer_index, payload),
+			Payload::GetCFHeaders(payload) => self.handle_getcfheaders_message(peer_index, payload),
+			Payload::GetCFCheckpt(payload) => self.handle_getcfcheckpt_message(peer_index, payload),
 			p => {
 				log_debug!("peer {}: {}: no response implemented\n", peer_index, p.name());
 				Ok(())
@@ -450,10 +1740,18 @@ impl Node {
 
 	fn handle_version_message(&mut self, peer_index: usize, payload: Version) -> Result<()> {
 		if let Some(peer) = self.peers.get_mut(&peer_index) {
-			if !peer.handshake_complete && peer.info.is_none() {
+			if peer.info.is_some() {
+				self.misbehaving(peer_index, 1, "duplicate version message");
+			} else if !peer.handshake_complete {
 				peer.info = Some(payload);
-				peer.writer.send(Message::verack())?;
-				peer.writer.send(Message::sendheaders())?;
+				if peer.inbound {
+					// we're the responder; the initiator is waiting on our version
+					peer.conn.send(Message::version(peer.version_addr(), self.services))?;
+				}
+				// BIP 155: must come between version and verack
+				peer.conn.send(Message::sendaddrv2())?;
+				peer.conn.send(Message::verack())?;
+				peer.conn.send(Message::sendheaders())?;
 			}
 		}
 		Ok(())
@@ -461,7 +1759,26 @@ impl Node {
 	
 	fn handle_ping_message(&mut self, peer_index: usize, payload: Ping) -> Result<()> {
 		if let Some(peer) = self.peers.get_mut(&peer_index) {
-			peer.writer.send(Message::pong(payload.nonce()))?;
+			peer.conn.send(Message::pong(payload.nonce()))?;
+		}
+		Ok(())
+	}
+
+	fn handle_pong_message(&mut self, peer_index: usize, payload: Pong) -> Result<()> {
+		if let Some(peer) = self.peers.get_mut(&peer_index) {
+			match peer.ping_nonce {
+				Some(nonce) if nonce == payload.nonce() => {
+					let ping_time = peer.ping_start.elapsed();
+					peer.ping_time = Some(ping_time);
+					peer.min_ping_time = Some(peer.min_ping_time.map_or(ping_time, |min| std::cmp::min(min, ping_time)));
+					peer.ping_nonce = None;
+					log_debug!("peer {}: ping time {} ms", peer_index, ping_time.as_millis());
+				},
+				// a zero nonce cancels the ping
+				Some(_) if payload.nonce() == 0 => peer.ping_nonce = None,
+				Some(_) => log_debug!("peer {}: pong nonce mismatch", peer_index),
+				None => log_debug!("peer {}: unsolicited pong", peer_index),
+			}
 		}
 		Ok(())
 	}
@@ -474,8 +1791,116 @@ impl Node {
 				}
 				peer.handshake_complete = true;
 
-				peer.writer.send(Message::getheaders(&self.block_db.hashes))?;
+				peer.conn.send(Message::getheaders(&self.block_db.hashes))?;
+				for version in CMPCT_VERSIONS {
+					peer.conn.send(Message::sendcmpct(false, version))?;
+				}
+
+				// only outbound peers are asked for addresses, and only they vouch for
+				// their own address: inbound peers could be anyone, connecting from anywhere
+				if !peer.inbound {
+					peer.conn.send(Message::getaddr())?;
+					peer.getaddr_sent = true;
+					peer.addr_tokens += MAX_ADDR_TO_SEND as f64;
+
+					if let Some(endpoint) = peer.endpoint {
+						let addr = NetAddr::new(endpoint.0, endpoint.1, 0, common::now() as u32);
+						self.addrman.add(addr, endpoint.0, 0);
+						self.addrman.good(&endpoint);
+					}
+				}
+
+				if let Some(addr) = &self.onion_addr {
+					peer.addr_queue.push(NetAddr::new(addr.host, addr.port, self.services, common::now() as u32));
+				}
+			}
+		}
+		Ok(())
+	}
+
+	fn handle_addr_message(&mut self, peer_index: usize, addrs: Vec<NetAddr>) -> Result<()> {
+		const TIME_PENALTY: u64 = 2 * 60 * 60;
+		let now = common::now();
+
+		let peer = match self.peers.get_mut(&peer_index) {
+			Some(peer) => peer,
+			None => return Ok(()),
+		};
+		let source = match peer.endpoint {
+			Some(endpoint) => endpoint.0,
+			None => return Ok(()),
+		};
+
+		let elapsed = peer.addr_tokens_time.elapsed().as_secs_f64();
+		peer.addr_tokens = f64::min(peer.addr_tokens + elapsed * MAX_ADDR_RATE_PER_SECOND, MAX_ADDR_PROCESSING_TOKEN_BUCKET);
+		peer.addr_tokens_time = Instant::now();
+
+		// a big batch is presumably the answer to our getaddr; don't relay those
+		let solicited = peer.getaddr_sent && addrs.len() > 10;
+		if addrs.len() < MAX_ADDR_TO_SEND {
+			peer.getaddr_sent = false;
+		}
+
+		let mut added = 0;
+		let mut rate_limited = 0;
+		let mut relay = Vec::new();
+		for mut addr in addrs {
+			if peer.addr_tokens < 1.0 {
+				rate_limited += 1;
+				continue;
+			}
+			peer.addr_tokens -= 1.0;
+			peer.add_known_addr((addr.host, addr.port));
+
+			if addr.timestamp as u64 <= 100_000_000 || addr.timestamp as u64 > now + 10 * 60 {
+				addr.timestamp = (now - 5 * 24 * 60 * 60) as u32;
+			}
+			if !addr.host.is_routable() {
+				continue;
+			}
+			if !solicited && addr.timestamp as u64 + 10 * 60 > now {
+				relay.push(addr.clone());
+			}
+			if self.addrman.add(addr, source, TIME_PENALTY) {
+				added += 1;
+			}
+		}
+
+		if rate_limited > 0 {
+			log_debug!("peer {}: dropped {} addresses (rate limited)", peer_index, rate_limited);
+		}
+		log_debug!("peer {}: {} new addresses ({} known)", peer_index, added, self.addrman.len());
+
+		for addr in relay {
+			self.relay_addr(peer_index, addr);
+		}
+		Ok(())
+	}
+
+	fn relay_addr(&mut self, from: usize, addr: NetAddr) {
+		use rand::seq::IteratorRandom;
+
+		let key = (addr.host, addr.port);
+		let targets = self.peers.iter()
+			.filter(|(&id, peer)| id != from && peer.handshake_complete && !peer.known_addrs.contains(&key))
+			.map(|(&id, _)| id)
+			.choose_multiple(&mut rand::thread_rng(), ADDR_RELAY_FANOUT);
+		for id in targets {
+			self.peers.get_mut(&id).unwrap().addr_queue.push(addr.clone());
+		}
+	}
+
+	fn handle_getaddr_message(&mut self, peer_index: usize) -> Result<()> {
+		let addrs = self.addrman.get_addrs();
+		if let Some(peer) = self.peers.get_mut(&peer_index) {
+			// answering outbound peers would let them fingerprint us; answering twice
+			// would let a peer scrape the whole table
+			if !peer.inbound || peer.getaddr_answered {
+				log_debug!("peer {}: ignoring getaddr", peer_index);
+				return Ok(());
 			}
+			peer.getaddr_answered = true;
+			peer.addr_queue.extend(addrs);
 		}
 		Ok(())
 	}
@@ -508,6 +1933,147 @@ impl Node {
 		Ok(())
 	}
 
+	fn handle_sendcmpct_message(&mut self, peer_index: usize, payload: SendCmpct) -> Result<()> {
+		if let Some(peer) = self.peers.get_mut(&peer_index) {
+			if !CMPCT_VERSIONS.contains(&payload.version()) {
+				return Ok(());
+			}
+			// the first version offered is the peer's preferred one, and sticks
+			if peer.config.cmpct_version == 0 {
+				peer.config.cmpct_version = payload.version();
+				log_debug!("SET PARAM: cmpct_version = {}\n", payload.version());
+			}
+			if payload.version() == peer.config.cmpct_version {
+				peer.config.cmpct_announce = payload.announce();
+				log_debug!("SET PARAM: cmpct_announce = {}\n", payload.announce());
+			}
+		}
+		Ok(())
+	}
+
+	// filter messages are only for nodes offering NODE_BLOOM; anyone else sending
+	// them is disconnected (but not banned, as older nodes didn't check)
+	fn check_bloom_allowed(&mut self, peer_index: usize) -> bool {
+		if self.services & services::NODE_BLOOM != 0 {
+			return true;
+		}
+		log_debug!("peer {}: bloom filters are disabled; disconnecting", peer_index);
+		self.disconnect_peer(peer_index);
+		false
+	}
+
+	fn handle_filterload_message(&mut self, peer_index: usize, payload: FilterLoad) -> Result<()> {
+		if !self.check_bloom_allowed(peer_index) {
+			return Ok(());
+		}
+		match BloomFilter::new(payload) {
+			Ok(filter) => if let Some(peer) = self.peers.get_mut(&peer_index) {
+				peer.bloom_filter = Some(filter);
+				peer.config.relay_txs = true;
+			},
+			Err(e) => self.misbehaving(peer_index, BAN_THRESHOLD, &e.to_string()),
+		}
+		Ok(())
+	}
+
+	fn handle_filteradd_message(&mut self, peer_index: usize, payload: FilterAdd) -> Result<()> {
+		if !self.check_bloom_allowed(peer_index) {
+			return Ok(());
+		}
+		let added = match self.peers.get_mut(&peer_index) {
+			Some(peer) => match peer.bloom_filter.as_mut() {
+				Some(filter) if payload.data.len() <= MAX_FILTER_ADD_SIZE => {
+					filter.insert(&payload.data);
+					true
+				},
+				_ => false,
+			},
+			None => return Ok(()),
+		};
+		if !added {
+			self.misbehaving(peer_index, BAN_THRESHOLD, "bad filteradd message");
+		}
+		Ok(())
+	}
+
+	fn handle_filterclear_message(&mut self, peer_index: usize) -> Result<()> {
+		if !self.check_bloom_allowed(peer_index) {
+			return Ok(());
+		}
+		if let Some(peer) = self.peers.get_mut(&peer_index) {
+			peer.bloom_filter = None;
+			peer.config.relay_txs = true;
+		}
+		Ok(())
+	}
+
+	// the heights a BIP 157 request covers. requests we can't answer (filters not
+	// offered, an unknown filter type or stop hash, too many blocks) get the peer
+	// disconnected, as with bitcoind
+	fn check_cfilters_request(&mut self, peer_index: usize, filter_type: u8, start_height: usize, stop_hash: &Sha256, max_count: usize) -> Option<(usize, usize)> {
+		let stop_height = self.block_db.height_of(stop_hash)
+			.filter(|&height| height < self.filter_index.len());
+		match stop_height {
+			Some(stop_height) if self.services & services::NODE_COMPACT_FILTERS != 0 &&
+				filter_type == BASIC_FILTER_TYPE &&
+				start_height <= stop_height &&
+				stop_height - start_height < max_count => Some((start_height, stop_height)),
+			_ => {
+				log_debug!("peer {}: bad compact filter request; disconnecting", peer_index);
+				self.disconnect_peer(peer_index);
+				None
+			},
+		}
+	}
+
+	fn handle_getcfilters_message(&mut self, peer_index: usize, getcfilters: GetCFilters) -> Result<()> {
+		let range = self.check_cfilters_request(peer_index, getcfilters.filter_type, getcfilters.start_height as usize, &getcfilters.stop_hash, MAX_GETCFILTERS_SIZE);
+		let (start, stop) = match range {
+			Some(range) => range,
+			None => return Ok(()),
+		};
+		for hash in self.block_db.hashes[start..=stop].iter() {
+			let filter = self.filter_index.load_filter(hash)?;
+			if let Some(peer) = self.peers.get_mut(&peer_index) {
+				peer.conn.send(Message::cfilter(BASIC_FILTER_TYPE, *hash, filter.as_bytes().to_vec()))?;
+			}
+		}
+		Ok(())
+	}
+
+	fn handle_getcfheaders_message(&mut self, peer_index: usize, getcfheaders: GetCFilters) -> Result<()> {
+		let range = self.check_cfilters_request(peer_index, getcfheaders.filter_type, getcfheaders.start_height as usize, &getcfheaders.stop_hash, MAX_GETCFHEADERS_SIZE);
+		let (start, stop) = match range {
+			Some(range) => range,
+			None => return Ok(()),
+		};
+		let prev_header = match start {
+			0 => Sha256::default(),
+			_ => self.filter_index.headers[start - 1],
+		};
+		let filter_hashes = self.filter_index.filter_hashes[start..=stop].to_vec();
+		if let Some(peer) = self.peers.get_mut(&peer_index) {
+			peer.conn.send(Message::cfheaders(BASIC_FILTER_TYPE, getcfheaders.stop_hash, prev_header, filter_hashes))?;
+		}
+		Ok(())
+	}
+
+	fn handle_getcfcheckpt_message(&mut self, peer_index: usize, getcfcheckpt: GetCFCheckpt) -> Result<()> {
+		let range = self.check_cfilters_request(peer_index, getcfcheckpt.filter_type, 0, &getcfcheckpt.stop_hash, usize::MAX);
+		let stop = match range {
+			Some((_, stop)) => stop,
+			None => return Ok(()),
+		};
+		let headers = (CFCHECKPT_INTERVAL..=stop)
+			.step_by(CFCHECKPT_INTERVAL)
+			.map(|height| self.filter_index.headers[height])
+			.collect();
+		if let Some(peer) = self.peers.get_mut(&peer_index) {
+			peer.conn.send(Message::cfcheckpt(BASIC_FILTER_TYPE, getcfcheckpt.stop_hash, headers))?;
+		}
+		Ok(())
+	}
+
 	fn handle_sendheaders_message(&mut self, peer_index: usize) -> Result<()> {
 		if let Some(peer) = self.peers.get_mut(&peer_index) {
 			peer.config.sendheaders = true;
@@ -517,147 +2083,2174 @@ impl Node {
 	}
 
 	fn handle_headers_message(&mut self, peer_index: usize, headers: Headers) -> Result<()> {
+		if headers.iter().len() == 0 {
+			// nothing new; we're synced with this peer
+			return Ok(());
+		} else if headers.iter().len() > MAX_HEADERS_RESULTS {
+			self.misbehaving(peer_index, OVERSIZED_MESSAGE_SCORE, &format!("headers message size = {}", headers.iter().len()));
+			return Ok(());
+		}
+
+		let headers = headers.into_iter().collect::<Vec<_>>();
+		let full = headers.len() == MAX_HEADERS_RESULTS;
+		// whether the last header was one of the branch's, rather than our chain's
+		let mut on_branch = false;
 		for header in headers {
 			let last = *self.block_db.hashes.last().unwrap();
-			if header.prev_block == last {
-				let hash = header.compute_hash();
-				let target = header.compute_target();
-				if target != self.target {
-					return Err(Err::ConsensusError(
-						format!("received invalid header (wrong target: expected {:x}, found {:x})", self.target, target)));
-				} else if hash.to_u256() >= self.target {
-					return Err(Err::ConsensusError(
-						format!("received invalid header (hash exceeded target: {:x} >= {:x})", hash.to_u256(), self.target)));
-				}
-				
-				self.block_db.headers.insert(hash, header);
-				self.block_db.hashes.push(hash);
-
-				if self.block_db.hashes.len() % 2016 == 0 {
-					self.adjust_difficulty();
-				}
+			let hash = header.compute_hash();
+			if self.invalid_blocks.contains(&hash) || self.invalid_blocks.contains(&header.prev_block) {
+				return Err(Err::ConsensusError(format!("received invalid header (on an invalid block): {}", hash)));
+			} else if self.block_db.height_of(&hash).is_some() {
+				// already have it (e.g. another peer sent the same headers first)
+				on_branch = false;
+			} else if self.branch.as_ref().is_some_and(|branch| branch.hashes.contains(&hash)) {
+				on_branch = true;
+			} else if header.prev_block == last {
+				self.accept_header(hash, header)?;
+				on_branch = false;
+			} else if self.extend_branch(hash, header)? {
+				on_branch = true;
 			} else {
-				return Err(Err::ConsensusError(format!("received invalid header (prev_block does not match expected)")));
+				// probably a new block announced while we're behind; ask for what's missing
+				if let Some(peer) = self.peers.get_mut(&peer_index) {
+					peer.unconnecting_headers += 1;
+					let count = peer.unconnecting_headers;
+					peer.conn.send(Message::getheaders(&self.block_db.hashes))?;
+					if count % MAX_UNCONNECTING_HEADERS == 0 {
+						self.misbehaving(peer_index, 20, &format!("{} non-connecting headers messages", count));
+					}
+				}
+				return Ok(());
+			}
+		}
+
+		// a branch with more work than our chain has its blocks fetched. one the
+		// peer has sent all of that falls short is of no use
+		if let Some(fork) = self.branch.as_ref().map(|branch| branch.fork) {
+			let ours = self.chain_work(fork + 1, self.block_db.hashes.len());
+			let branch = self.branch.as_mut().unwrap();
+			if branch.work > ours && !branch.more_work {
+				log_info!("peer {}: branch from height {} has more work than our chain; fetching its blocks", peer_index, fork + 1);
+			}
+			branch.more_work = branch.work > ours;
+			if on_branch && !full && !branch.more_work {
+				log_debug!("peer {}: ignoring branch from height {} without more work than our chain", peer_index, fork + 1);
+				self.drop_branch();
+				on_branch = false;
 			}
 		}
 
+		// the blocks themselves are fetched by request_next_blocks. more headers
+		// are asked for along whichever chain these ended on
 		if let Some(peer) = self.peers.get_mut(&peer_index) {
-			let m = Message::getheaders(&self.block_db.hashes);
-			peer.writer.send(m)?;
+			let m = match self.branch.as_ref().filter(|_| on_branch) {
+				Some(branch) => Message::getheaders(&[&self.block_db.hashes[..=branch.fork], &branch.hashes[..]].concat()),
+				None => Message::getheaders(&self.block_db.hashes),
+			};
+			peer.conn.send(m)?;
+		}
 
-			let have = self.block_db.blocks_requested;
-			let need = &self.block_db.hashes[have..have+500];
-			self.block_db.blocks_requested += 500;
-			let need = need.iter()
-				.filter(|e| !self.block_db.has_block(**e))
-				.map(|e| InvItem::new(InvType::Block, e.clone()))
-				.collect::<Vec<_>>();
-			if need.len() > 0 {	
-				let m = Message::getdata(need);
-				peer.writer.send(m)?;
-			}
+		Ok(())
+	}
+
+	// appends a header that extends our best chain, once its proof of work checks out
+	fn accept_header(&mut self, hash: Sha256, header: Header) -> Result<()> {
+		Node::check_header(&hash, &header, &self.target)?;
+		self.block_db.push_header(hash, header);
+
+		if self.block_db.hashes.len() % 2016 == 0 {
+			self.adjust_difficulty();
 		}
+		Ok(())
+	}
 
+	// bits are compared in their compact form, since a header's own may not
+	// even make a valid target
+	fn check_header(hash: &Sha256, header: &Header, target: &u256) -> Result<()> {
+		let bits = Header::compact_target(target);
+		if header.bits != bits {
+			return Err(Err::ConsensusError(
+				format!("received invalid header (wrong target: expected bits {:08x}, found {:08x})", bits, header.bits)));
+		} else if hash.to_u256() >= *target {
+			return Err(Err::ConsensusError(
+				format!("received invalid header (hash exceeded target: {:x} >= {:x})", hash.to_u256(), target)));
+		}
 		Ok(())
 	}
 
-	fn handle_block_message(&mut self, _peer_index: usize, block: Block) -> Result<()> {
-		let height = self.block_db.blocks_validated;
-		let hash = &self.block_db.hashes[height];
-		self.state.set_height(height);
-		if let ValidationResult::Valid(diff) = block.validate(hash, &mut self.utxos, &self.state) {
-			self.block_db.store_block(block)?;
-			log_trace!("validated block {:010}: {}", self.block_db.blocks_validated, hash);
+	// adds a header to the branch, if it builds on the branch's tip, or starts a
+	// new branch if it builds on one of our blocks. false if it does neither.
+	// only one branch is followed at a time: one with more work than ours stays
+	// until we've switched to it or it's shown to be invalid
+	fn extend_branch(&mut self, hash: Sha256, header: Header) -> Result<bool> {
+		if !self.branch.as_ref().is_some_and(|branch| branch.hashes.last() == Some(&header.prev_block)) {
+			let fork = match self.block_db.height_of(&header.prev_block) {
+				Some(fork) => fork,
+				None => return Ok(false),
+			};
+			if self.branch.as_ref().is_some_and(|branch| branch.more_work) {
+				log_debug!("ignoring header {} for another branch from height {}", hash, fork + 1);
+				return Ok(true);
+			}
+			self.drop_branch();
+			self.branch = Some(Branch { fork, hashes: Vec::new(), headers: Vec::new(), work: 0.into(), more_work: false });
+		}
 
-			if common::now() - self.last_save_time > 600 {
-				self.save_state();
-				log_info!("saved state.");
+		let branch = self.branch.as_ref().unwrap();
+		let target = self.branch_target(branch.fork + 1 + branch.hashes.len());
+		if let Err(e) = Node::check_header(&hash, &header, &target) {
+			if branch.hashes.is_empty() {
+				self.branch = None;
 			}
+			return Err(e);
+		}
+		let branch = self.branch.as_mut().unwrap();
+		branch.work = branch.work + Header::compute_work(&target);
+		branch.hashes.push(hash);
+		branch.headers.push(header);
+		Ok(true)
+	}
 
-			diff.apply(&mut self.utxos);
-			self.block_db.blocks_validated += 1;
-		} else {
-			panic!();
+	// the header at `height` along the branch, which below the fork is ours
+	fn branch_header(&self, height: usize) -> &Header {
+		match &self.branch {
+			Some(branch) if height > branch.fork => &branch.headers[height - branch.fork - 1],
+			_ => &self.block_db.headers[&self.block_db.hashes[height]],
+		}
+	}
+
+	// the target the header at `height` along the branch must have
+	fn branch_target(&self, height: usize) -> u256 {
+		let prev = self.branch_header(height - 1);
+		if !height.is_multiple_of(2016) {
+			return prev.compute_target();
+		}
+		let period_start = self.branch_header(height - 2016).timestamp;
+		Node::retarget(prev.compute_target(), period_start, prev.timestamp, self.state.params.pow_limit)
+	}
+
+	// the work in our headers from height `from` up to `to`
+	fn chain_work(&self, from: usize, to: usize) -> u256 {
+		let mut work = u256::from(0);
+		let mut height = from;
+		while height < to {
+			// the target only changes every 2016 blocks
+			let end = to.min((height / 2016 + 1) * 2016);
+			let target = self.block_db.headers[&self.block_db.hashes[height]].compute_target();
+			work = work + Header::compute_work(&target) * u256::from((end - height) as u64);
+			height = end;
+		}
+		work
+	}
+
+	// forgets the branch, along with its blocks
+	fn drop_branch(&mut self) {
+		if let Some(branch) = self.branch.take() {
+			for hash in branch.hashes.iter() {
+				self.downloaded_blocks.remove(hash);
+				self.blocks_in_flight.remove(hash);
+			}
+		}
+	}
+
+	// drops an invalid block's header and every one after it, so that another
+	// peer's chain can take their place, and asks around for headers again
+	fn invalidate_block(&mut self, hash: Sha256) -> Result<()> {
+		let height = match self.block_db.height_of(&hash) {
+			Some(height) if height >= self.block_db.blocks_validated => height,
+			_ => return Ok(()),
+		};
+		self.truncate_headers(height, true);
+		log_info!("block {} is invalid; headers rolled back to height {}", hash, height - 1);
+
+		for peer in self.peers.values_mut().filter(|peer| peer.handshake_complete) {
+			peer.conn.send(Message::getheaders(&self.block_db.hashes))?;
 		}
 		Ok(())
 	}
 
-	fn handle_inv_message(&mut self, peer_index: usize, inv: Inv) -> Result<()> {
-		let mut items = Vec::new();
-		for item in inv.iter() {
-			match item.object_type {
-				InvType::Tx => {
-					if !self.mempool.contains(item.hash) {
-						items.push(item.clone());
+	// drops the headers from `len` on, which mustn't include connected blocks,
+	// along with anything waiting on their blocks
+	fn truncate_headers(&mut self, len: usize, invalid: bool) {
+		while self.block_db.hashes.len() > len {
+			let hash = *self.block_db.hashes.last().unwrap();
+			if invalid {
+				self.invalid_blocks.insert(hash);
+			}
+			self.downloaded_blocks.remove(&hash);
+			self.blocks_in_flight.remove(&hash);
+			self.partial_blocks.remove(&hash);
+			self.block_db.pop_header();
+		}
+		if self.branch.as_ref().is_some_and(|branch| branch.fork >= len) {
+			self.drop_branch();
+		}
+		let last = self.block_db.hashes.last().unwrap();
+		self.target = self.block_db.headers[last].compute_target();
+		if self.block_db.hashes.len().is_multiple_of(2016) {
+			self.adjust_difficulty();
+		}
+	}
+
+	// hands out the blocks in the download window that nobody has been asked for
+	// yet, filling each peer up to MAX_BLOCKS_IN_TRANSIT_PER_PEER, so that every
+	// peer is busy with its own stretch of the chain
+	fn request_next_blocks(&mut self) {
+		// while there's a branch with more work, its blocks are the ones we want.
+		// a branch has to overtake our blocks within the window to be switched to
+		let window = match self.branch.as_ref().filter(|branch| branch.more_work) {
+			Some(branch) => branch.hashes[..branch.hashes.len().min(BLOCK_DOWNLOAD_WINDOW)].to_vec(),
+			None => {
+				let start = self.block_db.blocks_validated;
+				let end = std::cmp::min(start + BLOCK_DOWNLOAD_WINDOW, self.block_db.hashes.len());
+				self.block_db.hashes[start.min(end)..end].to_vec()
+			},
+		};
+		if window.is_empty() {
+			return;
+		}
+
+		let mut load = self.peers.iter()
+			.filter(|(_, peer)| peer.handshake_complete)
+			.map(|(&id, _)| (id, 0))
+			.collect::<HashMap<_, _>>();
+		for request in self.blocks_in_flight.values() {
+			if let Some(count) = load.get_mut(&request.peer) {
+				*count += 1;
+			}
+		}
+		let mut load = load.into_iter()
+			.filter(|&(_, count)| count < MAX_BLOCKS_IN_TRANSIT_PER_PEER)
+			.collect::<Vec<_>>();
+		if load.is_empty() {
+			return;
+		}
+		load.sort();
+
+		let needed = window.iter()
+			.filter(|hash| !self.blocks_in_flight.contains_key(hash) && !self.downloaded_blocks.contains_key(hash))
+			.copied()
+			.collect::<Vec<_>>();
+		if needed.is_empty() {
+			// the whole window is in flight. if it's stuck behind its first block,
+			// ask one of the peers with room to spare for that one as well
+			let first = match window.iter().find(|hash| !self.downloaded_blocks.contains_key(hash)) {
+				Some(&first) => first,
+				None => return,
+			};
+			if let Some(request) = self.blocks_in_flight.get(&first) {
+				if request.time.elapsed() > BLOCK_STALLING_TIMEOUT {
+					log_debug!("peer {}: holding up the download window", request.peer);
+					let from = request.peer;
+					self.reassign_block_requests(vec![first], from);
+				}
+			}
+			return;
+		}
+
+		let mut needed = needed.into_iter();
+		for (id, count) in load {
+			let batch = needed.by_ref().take(MAX_BLOCKS_IN_TRANSIT_PER_PEER - count).collect::<Vec<_>>();
+			if batch.is_empty() {
+				break;
+			}
+			if let Err(err) = self.request_blocks(id, batch) {
+				log_error!("peer {}: error: {}", id, err);
+			}
+		}
+	}
+
+	fn request_blocks(&mut self, peer_index: usize, hashes: Vec<Sha256>) -> Result<()> {
+		if hashes.is_empty() {
+			return Ok(());
+		}
+		let peer = match self.peers.get_mut(&peer_index) {
+			Some(peer) => peer,
+			None => return Ok(()),
+		};
+		if !self.blocks_in_flight.values().any(|request| request.peer == peer_index) {
+			// the stall clock starts now, not at the peer's last delivery
+			peer.last_block_time = Instant::now();
+		}
+		for hash in hashes.iter() {
+			self.blocks_in_flight.insert(*hash, BlockRequest { peer: peer_index, time: Instant::now() });
+		}
+		let items = hashes.into_iter()
+			.map(|hash| InvItem::new(InvType::Block, hash))
+			.collect();
+		peer.conn.send(Message::getdata(items))
+	}
+
+	// hands requests that a peer failed to deliver to whichever other peer has
+	// the fewest blocks in flight. with nobody else around, they stay where they are
+	fn reassign_block_requests(&mut self, mut hashes: Vec<Sha256>, from: usize) {
+		let mut load = self.peers.iter()
+			.filter(|(&id, peer)| id != from && peer.handshake_complete)
+			.map(|(&id, _)| (id, 0))
+			.collect::<HashMap<_, _>>();
+		for request in self.blocks_in_flight.values() {
+			if let Some(count) = load.get_mut(&request.peer) {
+				*count += 1;
+			}
+		}
+		let to = match load.into_iter().min_by_key(|&(id, count)| (count, id)) {
+			Some((id, _)) => id,
+			None => {
+				for hash in hashes.iter() {
+					if let Some(request) = self.blocks_in_flight.get_mut(hash) {
+						request.time = Instant::now();
+					}
+				}
+				return;
+			},
+		};
+
+		// keep chain order, which is the order they'll be validated in
+		hashes.sort_by_key(|hash| self.block_db.height_of(hash));
+		log_debug!("reassigning {} block request(s) from peer {} to peer {}", hashes.len(), from, to);
+		if let Err(err) = self.request_blocks(to, hashes) {
+			log_error!("peer {}: error: {}", to, err);
+		}
+	}
+
+	fn handle_block_message(&mut self, peer_index: usize, block: Block) -> Result<()> {
+		let hash = block.header.compute_hash();
+		if let Some(request) = self.blocks_in_flight.remove(&hash) {
+			if request.peer == peer_index {
+				if let Some(peer) = self.peers.get_mut(&peer_index) {
+					peer.last_block_time = Instant::now();
+				}
+			}
+		}
+		if self.branch.as_ref().is_some_and(|branch| branch.hashes.iter().take(BLOCK_DOWNLOAD_WINDOW).any(|h| *h == hash)) {
+			self.downloaded_blocks.insert(hash, (block, peer_index));
+			return self.activate_branch();
+		}
+		let validated = self.block_db.blocks_validated;
+		if !self.block_db.height_of(&hash).is_some_and(|height| height >= validated && height < validated + BLOCK_DOWNLOAD_WINDOW) {
+			// unrequested, or one we already have
+			log_debug!("peer {}: ignoring unexpected block: {}", peer_index, hash);
+			return Ok(());
+		}
+		self.downloaded_blocks.insert(hash, (block, peer_index));
+		self.connect_downloaded_blocks()
+	}
+
+	// validates buffered blocks in chain order, for as long as the next one is there
+	fn connect_downloaded_blocks(&mut self) -> Result<()> {
+		while let Some(&hash) = self.block_db.hashes.get(self.block_db.blocks_validated) {
+			let (block, peer_index) = match self.downloaded_blocks.remove(&hash) {
+				Some(entry) => entry,
+				None => break,
+			};
+			// a block that doesn't match its header says nothing about the header
+			let mutated = !Tx::check_merkle_root(&block.txs, block.header.merkle_root);
+			match self.connect_block(peer_index, hash, block) {
+				Ok(_) => {},
+				// the block is on whoever sent it, which needn't be the peer whose
+				// message we're handling. a mutated one gets requested again from
+				// someone else; otherwise the header goes too
+				Err(e @ Err::ConsensusError(_)) => {
+					self.misbehaving(peer_index, BAN_THRESHOLD, &e.to_string());
+					if !mutated {
+						self.invalidate_block(hash)?;
 					}
 				},
-				InvType::Block => {
-					// items.push(item.clone());
-				},
-				// InvType::FilteredBlock => {},
-				// InvType::CmpctBlock => {},
-				// InvType::WitnessTx => {},
-				// InvType::WitnessBlock => {},
-				// InvType::FilteredWitnessBlock => {},
-				InvType::Error => {},
-				_ => unimplemented!("inv object type: {}", item.object_type),
+				Err(e) => return Err(e),
 			}
 		}
+		Ok(())
+	}
+
+	fn connect_block(&mut self, peer_index: usize, hash: Sha256, block: Block) -> Result<()> {
+		let height = self.block_db.blocks_validated;
+		self.state.set_height(height);
+		if let Some(challenge) = &self.state.params.signet_challenge {
+			if !signet::check_block_solution(&block, challenge, &self.state) {
+				return Err(Err::ConsensusError(format!("received invalid block (bad signet solution): {}", hash)));
+			}
+		}
+		if let ValidationResult::Valid(diff) = block.validate(&hash, &mut self.utxos, &self.state) {
+			// what goes to disk first, so a failed write leaves nothing half
+			// connected. the filter needs the spent outputs still in the UTXO set
+			let filter = self.block_filter(height, &block, &diff);
+			self.block_db.store_block(&block)?;
+			self.block_db.store_undo(&hash, &self.undo_data(&diff))?;
+			if let Some(filter) = filter {
+				self.filter_index.push(&hash, filter)?;
+			}
+			diff.apply(&mut self.utxos);
+			self.block_db.blocks_validated += 1;
+			log_trace!("validated block {:010}: {}", height, hash);
+
+			self.record_confirmations(height, &block);
+			self.update_mempool_for_block(&hash, &block);
+			self.orphans.remove_for_block(&block);
+			self.immature_coinbases.retain(|_, &mut coinbase_height| coinbase_height + COINBASE_MATURITY > height);
+			self.immature_coinbases.insert(block.txs[0].compute_hash(), height);
+			self.coin_heights.extend(block.txs.iter().map(|tx| (tx.compute_hash(), height)));
+			// orphans waiting on the block's txs get their turn
+			if self.orphans.len() > 0 {
+				self.process_orphans(block.txs.iter().map(|tx| (tx.compute_hash(), tx.outputs.len())).collect());
+			}
+
+			if common::now() - self.last_save_time > 600 {
+				self.save_state();
+				log_info!("saved state.");
+			}
+
+			self.partial_blocks.remove(&hash);
+			if let Some(peer) = self.peers.get_mut(&peer_index) {
+				peer.add_known_inventory(hash);
+				peer.last_new_block = Some(Instant::now());
+			}
+			if !self.is_initial_block_download() {
+				self.queue_block_announcement(hash);
+				self.set_high_bandwidth_peer(peer_index)?;
+			}
+		} else {
+			return Err(Err::ConsensusError(format!("received invalid block: {}", hash)));
+		}
+		Ok(())
+	}
+
+	// the outputs a block spends, from the UTXO set it's about to be applied to
+	fn undo_data(&self, diff: &UTXODiff) -> Vec<(UTXOID, TxOutput, Option<usize>)> {
+		diff.spent()
+			.map(|id| (id.clone(), self.utxos[id].clone(), self.coin_heights.get(&id.0).copied()))
+			.collect()
+	}
+
+	// undoes connect_block for the tip, leaving its header in place. putting its
+	// txs back in the mempool is up to the caller
+	fn disconnect_tip(&mut self) -> Result<Block> {
+		let height = self.block_db.blocks_validated - 1;
+		assert!(height > 0);
+		let hash = self.block_db.hashes[height];
+		let block = self.block_db.load_block(&hash)?;
+		let undo = self.block_db.load_undo(&hash)?;
+		// what the block created goes, what it spent comes back
+		for tx in block.txs.iter() {
+			let id = tx.compute_hash();
+			for index in 0..tx.outputs.len() as u32 {
+				self.utxos.remove(&UTXOID(id, index));
+			}
+			self.coin_heights.remove(&id);
+		}
+		for (id, output, coin_height) in undo {
+			if let Some(coin_height) = coin_height {
+				self.coin_heights.insert(id.0, coin_height);
+			}
+			self.utxos.insert(id, output);
+		}
+		self.block_db.blocks_validated = height;
+		self.filter_index.truncate(height);
+		self.immature_coinbases = Node::load_immature_coinbases(&self.block_db);
+		log_info!("disconnected block {:010}: {}", height, hash);
+		Ok(block)
+	}
+
+	// switches to the branch once the blocks of it we have, in a row from the
+	// fork, hold more work than our blocks past the fork. ours are disconnected
+	// but kept: if the branch turns out invalid, they're connected again
+	fn activate_branch(&mut self) -> Result<()> {
+		let branch = match &self.branch {
+			Some(branch) if branch.more_work => branch,
+			_ => return Ok(()),
+		};
+		let fork = branch.fork;
+		let mut work = u256::from(0);
+		for (hash, header) in branch.hashes.iter().zip(branch.headers.iter()) {
+			if !self.downloaded_blocks.contains_key(hash) {
+				break;
+			}
+			work = work + Header::compute_work(&header.compute_target());
+		}
+		let validated = self.block_db.blocks_validated.max(fork + 1);
+		let old_work = self.chain_work(fork + 1, validated);
+		if work <= old_work {
+			return Ok(());
+		}
+		if let Some(hash) = self.block_db.hashes[fork + 1..validated].iter().find(|hash| !self.block_db.has_undo(hash)) {
+			log_warn!("can't reorganize past block {}: no undo data", hash);
+			self.drop_branch();
+			return Ok(());
+		}
+
+		let branch = self.branch.take().unwrap();
+		log_info!("reorganizing onto a branch with more work from height {}", fork + 1);
+		let mut disconnected = Vec::new();
+		while self.block_db.blocks_validated > fork + 1 {
+			disconnected.push(self.disconnect_tip()?);
+		}
+		disconnected.reverse();
+		let old_headers = self.block_db.hashes[fork + 1..].iter()
+			.map(|hash| (*hash, self.block_db.headers[hash].clone()))
+			.collect::<Vec<_>>();
+		self.truncate_headers(fork + 1, false);
+		for (hash, header) in branch.hashes.iter().zip(branch.headers.iter()) {
+			self.accept_header(*hash, header.clone())?;
+		}
+		self.connect_downloaded_blocks()?;
+		if disconnected.is_empty() || self.chain_work(fork + 1, self.block_db.blocks_validated) > old_work {
+			self.update_mempool_for_disconnect(disconnected);
+			return Ok(());
+		}
+
+		// what connected of it has no more work than what we had: back to our blocks
+		log_warn!("branch from height {} failed to connect; going back to our chain", fork + 1);
+		let mut dropped = Vec::new();
+		while self.block_db.blocks_validated > fork + 1 {
+			dropped.push(self.disconnect_tip()?);
+		}
+		dropped.reverse();
+		self.truncate_headers(fork + 1, false);
+		for (hash, header) in old_headers {
+			self.accept_header(hash, header)?;
+		}
+		for block in disconnected {
+			let hash = block.header.compute_hash();
+			self.connect_block(usize::MAX, hash, block)?;
+		}
+		if !dropped.is_empty() {
+			self.update_mempool_for_disconnect(dropped);
+		}
+		// a block that was only mutated on the way leaves the branch to be fetched again
+		if !branch.hashes.iter().any(|hash| self.invalid_blocks.contains(hash)) {
+			self.branch = Some(branch);
+		}
+		Ok(())
+	}
+
+	// the mempool's side of connecting a block: the txs it confirmed leave, and
+	// so do ones spending the same outputs, along with everything depending on them
+	fn update_mempool_for_block(&mut self, hash: &Sha256, block: &Block) {
+		let (confirmed, conflicts) = self.mempool.remove_for_block(block);
+		if confirmed + conflicts > 0 {
+			log_debug!("block {}: removed {} confirmed and {} conflicting txs from the mempool", hash, confirmed, conflicts);
+		}
+	}
+
+	// and of disconnecting blocks, given oldest first: their txs go back in, then
+	// what the mempool already held, all of it checked against the new tip. what
+	// depended on a disconnected coinbase, or is no longer final, drops out
+	fn update_mempool_for_disconnect(&mut self, blocks: Vec<Block>) {
+		let held = self.mempool.take_txs();
+		let disconnected = blocks.into_iter()
+			.flat_map(|block| block.txs.into_iter().skip(1))
+			.map(|tx| (tx.compute_hash(), tx, None));
+		let (mut added, mut dropped) = (0, 0);
+		for (id, tx, time) in disconnected.chain(held.into_iter().map(|(id, tx, time)| (id, tx, Some(time)))) {
+			match self.accept_to_mempool(id, tx) {
+				Ok(()) => {
+					if let Some(time) = time {
+						self.mempool.entries.get_mut(&id).unwrap().time = time;
+					}
+					added += 1;
+				},
+				Err(err) => {
+					log_debug!("tx {} left out of the mempool after a reorg: {}", id, err);
+					dropped += 1;
+				},
+			}
+		}
+		log_info!("reorg: {} txs in the mempool, {} dropped", added, dropped);
+	}
+
+	// the block's filter, if the index has got up to it. needs the outputs
+	// the block spends, so has to come before they leave the UTXO set
+	fn block_filter(&self, height: usize, block: &Block, diff: &UTXODiff) -> Option<BlockFilter> {
+		if self.filter_index.len() != height {
+			return None;
+		}
+		let spent = diff.spent().map(|id| &self.utxos[id].lock);
+		Some(BlockFilter::basic(block, spent))
+	}
+
+	fn handle_cmpctblock_message(&mut self, peer_index: usize, cmpct: CmpctBlock) -> Result<()> {
+		let hash = cmpct.header.compute_hash();
+		let count = cmpct.tx_count();
+		if count > MAX_CMPCT_TX_COUNT || cmpct.prefilled.iter().any(|prefilled| prefilled.index >= count) {
+			self.misbehaving(peer_index, BAN_THRESHOLD, "invalid compact block");
+			return Ok(());
+		}
+
+		if self.block_db.height_of(&hash).is_none() {
+			if cmpct.header.prev_block != *self.block_db.hashes.last().unwrap() {
+				// we're missing the headers in between
+				if let Some(peer) = self.peers.get_mut(&peer_index) {
+					peer.conn.send(Message::getheaders(&self.block_db.hashes))?;
+				}
+				return Ok(());
+			}
+			self.accept_header(hash, cmpct.header.clone())?;
+		}
+		if let Some(peer) = self.peers.get_mut(&peer_index) {
+			peer.add_known_inventory(hash);
+		}
+		// only a block on our tip is worth reconstructing; while we're behind the
+		// download scheduler fetches it along with the rest
+		if self.block_db.hashes.get(self.block_db.blocks_validated) != Some(&hash) || self.partial_blocks.contains_key(&hash) {
+			return Ok(());
+		}
+
+		let mut txs = vec![None; count];
+		for prefilled in cmpct.prefilled.iter() {
+			txs[prefilled.index] = Some(prefilled.tx.clone());
+		}
+		let empty = (0..count).filter(|&i| txs[i].is_none()).collect::<Vec<_>>();
+		if empty.len() != cmpct.short_ids.len() {
+			// prefilled txs sharing an index
+			self.misbehaving(peer_index, BAN_THRESHOLD, "invalid compact block");
+			return Ok(());
+		}
+		let mut slots = HashMap::new();
+		for (&id, &slot) in cmpct.short_ids.iter().zip(empty.iter()) {
+			if slots.insert(id, slot).is_some() {
+				// can't tell those txs apart, so don't try
+				log_debug!("peer {}: duplicate short ids in compact block {}", peer_index, hash);
+				return self.request_blocks(peer_index, vec![hash]);
+			}
+		}
+
+		let keys = cmpct.short_id_keys();
+		let use_wtxid = self.peers.get(&peer_index).is_some_and(|peer| peer.config.cmpct_version != 1);
+		let mut collisions = HashSet::new();
+		for (id, tx) in self.mempool.txs.iter() {
+			let short_id = short_id(keys, &if use_wtxid { tx.compute_wtxid() } else { *id });
+			if let Some(&slot) = slots.get(&short_id) {
+				if txs[slot].is_some() || collisions.contains(&slot) {
+					// two mempool txs with the same short id; ask for the real one
+					txs[slot] = None;
+					collisions.insert(slot);
+				} else {
+					txs[slot] = Some(tx.clone());
+				}
+			}
+		}
+
+		let missing = (0..count).filter(|&i| txs[i].is_none()).collect::<Vec<_>>();
+		log_debug!("peer {}: compact block {}: {} of {} txs missing", peer_index, hash, missing.len(), count);
+		if missing.is_empty() {
+			let txs = txs.into_iter().map(Option::unwrap).collect();
+			return self.reconstruct_block(peer_index, hash, cmpct.header, txs);
+		}
+
+		self.partial_blocks.insert(hash, PartialBlock { peer: peer_index, header: cmpct.header, txs });
+		// keeps the download scheduler off it, and falls back to the full block if the peer stalls
+		self.blocks_in_flight.insert(hash, BlockRequest { peer: peer_index, time: Instant::now() });
+		if let Some(peer) = self.peers.get_mut(&peer_index) {
+			peer.conn.send(Message::getblocktxn(hash, missing))?;
+		}
+		Ok(())
+	}
+
+	fn handle_blocktxn_message(&mut self, peer_index: usize, blocktxn: BlockTxn) -> Result<()> {
+		let hash = blocktxn.block_hash;
+		let partial = match self.partial_blocks.get(&hash) {
+			Some(partial) if partial.peer == peer_index => self.partial_blocks.remove(&hash).unwrap(),
+			_ => {
+				log_debug!("peer {}: ignoring unexpected blocktxn for {}", peer_index, hash);
+				return Ok(());
+			},
+		};
+		if self.block_db.hashes.get(self.block_db.blocks_validated) != Some(&hash) {
+			// the full block got here first
+			return Ok(());
+		}
+
+		let mut supplied = blocktxn.txs.into_iter();
+		let txs = partial.txs.into_iter()
+			.map(|tx| tx.or_else(|| supplied.next()))
+			.collect::<Option<Vec<_>>>();
+		match txs {
+			Some(txs) if supplied.next().is_none() => self.reconstruct_block(peer_index, hash, partial.header, txs),
+			_ => {
+				self.misbehaving(peer_index, BAN_THRESHOLD, "blocktxn doesn't match the requested txs");
+				Ok(())
+			},
+		}
+	}
+
+	fn reconstruct_block(&mut self, peer_index: usize, hash: Sha256, header: Header, txs: Vec<Tx>) -> Result<()> {
+		let block = Block { header, txs };
+		if !Tx::check_merkle_root(&block.txs, block.header.merkle_root) {
+			// most likely a short id that matched the wrong mempool tx
+			log_debug!("peer {}: compact block {} didn't reconstruct; requesting it in full", peer_index, hash);
+			return self.request_blocks(peer_index, vec![hash]);
+		}
+		self.blocks_in_flight.remove(&hash);
+
+		// BIP 152: high bandwidth peers relay blocks before validating them, so
+		// an invalid block with a valid header isn't held against them
+		match self.connect_block(peer_index, hash, block) {
+			Err(Err::ConsensusError(reason)) => {
+				log_info!("peer {}: invalid compact block: {}", peer_index, reason);
+				self.invalidate_block(hash)?;
+			},
+			result => result?,
+		}
+		self.connect_downloaded_blocks()
+	}
+
+	// BIP 152: the last few peers to give us a new block first are asked to push
+	// the next ones as compact blocks, without waiting for us to request them
+	fn set_high_bandwidth_peer(&mut self, peer_index: usize) -> Result<()> {
+		let version = match self.peers.get(&peer_index) {
+			Some(peer) if peer.config.cmpct_version != 0 => peer.config.cmpct_version,
+			_ => return Ok(()),
+		};
+		if let Some(position) = self.high_bandwidth_peers.iter().position(|&id| id == peer_index) {
+			self.high_bandwidth_peers.remove(position);
+			self.high_bandwidth_peers.push_back(peer_index);
+			return Ok(());
+		}
+
+		if self.high_bandwidth_peers.len() >= MAX_HIGH_BANDWIDTH_PEERS {
+			let id = self.high_bandwidth_peers.pop_front().unwrap();
+			if let Some(peer) = self.peers.get_mut(&id) {
+				peer.conn.send(Message::sendcmpct(false, peer.config.cmpct_version))?;
+			}
+		}
+		self.high_bandwidth_peers.push_back(peer_index);
+		log_debug!("peer {}: requesting high bandwidth compact block relay", peer_index);
+		self.peers.get_mut(&peer_index).unwrap().conn.send(Message::sendcmpct(true, version))
+	}
+
+	fn handle_getblocktxn_message(&mut self, peer_index: usize, getblocktxn: GetBlockTxn) -> Result<()> {
+		let hash = getblocktxn.block_hash;
+		let validated = self.block_db.blocks_validated;
+		let height = match self.block_db.height_of(&hash) {
+			Some(height) if height < validated => height,
+			_ => {
+				log_debug!("peer {}: getblocktxn for unknown block {}", peer_index, hash);
+				return Ok(());
+			},
+		};
+		let peer = match self.peers.get_mut(&peer_index) {
+			Some(peer) => peer,
+			None => return Ok(()),
+		};
+		let witness = peer.config.cmpct_version != 1;
+		if validated - height > MAX_CMPCT_DEPTH {
+			peer.getdata_queue.push_back(InvItem::new(if witness { InvType::WitnessBlock } else { InvType::Block }, hash));
+			return self.process_getdata(peer_index);
+		}
+
+		let block = self.block_db.load_block(&hash)?;
+		let mut txs = Vec::new();
+		for &index in getblocktxn.indexes.iter() {
+			match block.txs.get(index) {
+				Some(tx) => txs.push(tx.clone()),
+				None => {
+					self.misbehaving(peer_index, BAN_THRESHOLD, "getblocktxn with out-of-bounds tx indexes");
+					return Ok(());
+				},
+			}
+		}
+		if !witness {
+			for tx in txs.iter_mut() {
+				tx.strip_witness();
+			}
+		}
+		peer.conn.send(Message::blocktxn(hash, txs))
+	}
+
+	fn handle_inv_message(&mut self, peer_index: usize, inv: Inv) -> Result<()> {
+		if inv.iter().len() > MAX_INV_SIZE {
+			self.misbehaving(peer_index, OVERSIZED_MESSAGE_SCORE, &format!("inv message size = {}", inv.iter().len()));
+			return Ok(());
+		}
+
+		let mut items = Vec::new();
+		let mut new_block = false;
+		for item in inv.iter() {
+			if let Some(peer) = self.peers.get_mut(&peer_index) {
+				peer.add_known_inventory(item.hash);
+			}
+			match item.object_type {
+				InvType::Tx => {
+					if !self.mempool.contains(item.hash) && !self.orphans.contains(&item.hash) {
+						self.mempool.pending.insert(item.hash);
+						// ask for the witness serialization so it can be relayed intact
+						items.push(InvItem::new(InvType::WitnessTx, item.hash));
+					}
+				},
+				InvType::Block => {
+					// headers first: fetch the headers leading up to it; the block itself
+					// gets requested once they connect
+					new_block |= self.block_db.height_of(&item.hash).is_none();
+				},
+				// only plain tx and block announcements are valid in an inv
+				_ => log_debug!("peer {}: ignoring inv of type {}", peer_index, item.object_type),
+			}
+		}
+
+		if let Some(peer) = self.peers.get_mut(&peer_index) {
+			if items.len() > 0 {
+				peer.conn.send(Message::getdata(items))?;
+			}
+			if new_block {
+				peer.conn.send(Message::getheaders(&self.block_db.hashes))?;
+			}
+		}
+
+		Ok(())
+	}
+
+	fn handle_tx_message(&mut self, peer_index: usize, id: Sha256, tx: Tx) -> Result<()> {
+		if let Some(peer) = self.peers.get_mut(&peer_index) {
+			peer.add_known_inventory(id);
+		}
+		self.mempool.pending.remove(&id);
+		if self.mempool.txs.contains_key(&id) {
+			log_trace!("tx already in mempool: {}", id);
+			return Ok(());
+		}
+		let missing = self.missing_parents(&tx);
+		if !missing.is_empty() {
+			return self.add_orphan(peer_index, id, tx, missing);
+		}
+		let outputs = tx.outputs.len();
+		// txs depending on others in the mempool confirm when those do, so
+		// would say little about their own feerate
+		let has_parents = !self.mempool.parents_of(&tx).is_empty();
+		// an orphan from the same peer waiting on just this tx can pay for it,
+		// should it fall short on its own (1-parent-1-child package relay)
+		let child = self.orphans.children_of(&id, outputs).into_iter().find(|child| {
+			self.orphans.peer(child) == Some(peer_index) && self.missing_parents(self.orphans.get(child).unwrap()) == [id]
+		});
+		let parent = child.map(|_| tx.clone());
+		match self.accept_to_mempool(id, tx) {
+			Ok(()) => {
+				if let Some(peer) = self.peers.get_mut(&peer_index) {
+					peer.last_new_tx = Some(Instant::now());
+				}
+				if !has_parents && !self.is_initial_block_download() {
+					if let Some(entry) = self.mempool.entries.get(&id) {
+						let feerate = entry.base_fee * 1000 / entry.vsize as u64;
+						self.fee_estimator.process_tx(id, self.block_db.blocks_validated - 1, feerate);
+					}
+				}
+				self.queue_tx_announcement(id);
+				self.process_orphans(vec![(id, outputs)]);
+			},
+			Err(err @ Err::FeeError(_)) if child.is_some() => {
+				log_debug!("peer {}: tx {} {}, trying it with orphan {}", peer_index, id, err, child.unwrap());
+				self.accept_orphan_package(peer_index, parent.unwrap(), child.unwrap());
+			},
+			Err(err) => log_debug!("peer {}: rejected tx {}: {}", peer_index, id, err),
+		}
+		Ok(())
+	}
+
+	// a parent short on feerate and its orphan child go in together, if the
+	// two pay enough between them
+	fn accept_orphan_package(&mut self, peer_index: usize, parent: Tx, child: Sha256) {
+		let (child_tx, _) = self.orphans.remove(&child).unwrap();
+		let outputs = [parent.outputs.len(), child_tx.outputs.len()];
+		let results = match self.accept_package(vec![parent, child_tx]) {
+			Ok(results) => results,
+			Err(err) => {
+				log_debug!("peer {}: rejected package with child {}: {}", peer_index, child, err);
+				return;
+			},
+		};
+		let mut accepted = Vec::new();
+		for ((id, result), outputs) in results.into_iter().zip(outputs) {
+			match result {
+				Ok(()) => {
+					self.queue_tx_announcement(id);
+					accepted.push((id, outputs));
+				},
+				Err(err) => log_debug!("peer {}: rejected package tx {}: {}", peer_index, id, err),
+			}
+		}
+		self.process_orphans(accepted);
+	}
+
+	// tells the fee estimator which of the txs it's waiting on a block confirmed,
+	// and which went from the mempool some other way since the last one
+	fn record_confirmations(&mut self, height: usize, block: &Block) {
+		self.fee_estimator.remove_missing(|id| self.mempool.txs.contains_key(id));
+		let confirmed = block.txs.iter().skip(1)
+			.map(|tx| tx.compute_hash())
+			.filter(|id| self.fee_estimator.is_tracked(id))
+			.filter_map(|id| {
+				let entry = self.mempool.entries.get(&id)?;
+				Some((id, entry.base_fee * 1000 / entry.vsize as u64))
+			})
+			.collect::<Vec<_>>();
+		self.fee_estimator.process_block(height, &confirmed);
+	}
+
+	// the txs with outputs a tx spends that are neither unspent nor in the mempool
+	fn missing_parents(&self, tx: &Tx) -> Vec<Sha256> {
+		let mut missing = Vec::new();
+		for input in tx.inputs.iter() {
+			let known = self.utxos.contains_key(&UTXOID(input.tx_hash, input.index)) ||
+				self.mempool.txs.get(&input.tx_hash).is_some_and(|parent| (input.index as usize) < parent.outputs.len());
+			if !known && !missing.contains(&input.tx_hash) {
+				missing.push(input.tx_hash);
+			}
+		}
+		missing
+	}
+
+	// holds on to a tx until its parents arrive, asking the peer that sent it for them
+	fn add_orphan(&mut self, peer_index: usize, id: Sha256, tx: Tx, missing: Vec<Sha256>) -> Result<()> {
+		if !self.orphans.add(id, tx, peer_index) {
+			return Ok(());
+		}
+		log_debug!("peer {}: orphan tx {} ({} in pool), missing {} parents", peer_index, id, self.orphans.len(), missing.len());
+		let mut items = Vec::new();
+		for parent in missing {
+			if !self.mempool.contains(parent) && !self.orphans.contains(&parent) {
+				self.mempool.pending.insert(parent);
+				items.push(InvItem::new(InvType::WitnessTx, parent));
+			}
+		}
+		if let Some(peer) = self.peers.get_mut(&peer_index) {
+			if !items.is_empty() {
+				peer.conn.send(Message::getdata(items))?;
+			}
+		}
+		Ok(())
+	}
+
+	// orphans spending the outputs of txs that just went into the mempool or a
+	// block get another try. the ones that make it in may be parents in turn.
+	// takes each parent's id and output count
+	fn process_orphans(&mut self, mut parents: Vec<(Sha256, usize)>) {
+		while let Some((parent, outputs)) = parents.pop() {
+			for id in self.orphans.children_of(&parent, outputs) {
+				if !self.orphans.get(&id).is_some_and(|tx| self.missing_parents(tx).is_empty()) {
+					continue;
+				}
+				let (tx, peer_index) = self.orphans.remove(&id).unwrap();
+				let outputs = tx.outputs.len();
+				match self.accept_to_mempool(id, tx) {
+					Ok(()) => {
+						log_debug!("orphan tx {} accepted", id);
+						self.queue_tx_announcement(id);
+						parents.push((id, outputs));
+					},
+					Err(err) => log_debug!("peer {}: rejected orphan tx {}: {}", peer_index, id, err),
+				}
+			}
+		}
+	}
+
+	// checks a tx against the UTXO set and the mempool as if it were going in
+	// the next block, and adds it, evicting whatever it replaces
+	fn accept_to_mempool(&mut self, id: Sha256, tx: Tx) -> Result<()> {
+		let check = self.check_tx(id, &tx)?;
+		self.mempool.check_feerate(check.fee, tx.vsize())?;
+		self.add_checked_tx(id, tx, check)?;
+		if self.mempool.limit_size().contains(&id) {
+			return Err(Err::ValueError("mempool full".to_owned()));
+		}
+		Ok(())
+	}
+
+	// a child and its unconfirmed parents, parents first. each tx that pays
+	// the mempool minimum goes in on its own; the rest, the child among them
+	// if it spends any, go in together if they pay it between them. that
+	// way a child can pay for parents that couldn't get in alone, but not
+	// the other way around. returns what became of each tx
+	fn accept_package(&mut self, txs: Vec<Tx>) -> Result<Vec<(Sha256, Result<()>)>> {
+		policy::check_package(&txs)?;
+		let mut results = Vec::new();
+		// in for now, on the strength of the package feerate. by index
+		let mut deferred: Vec<usize> = Vec::new();
+		let (mut package_fee, mut package_vsize) = (0u64, 0);
+		for tx in txs {
+			let id = tx.compute_hash();
+			if self.mempool.txs.contains_key(&id) {
+				results.push((id, Ok(())));
+				continue;
+			}
+			let check = match self.check_tx(id, &tx) {
+				Ok(check) => check,
+				Err(err) => {
+					results.push((id, Err(err)));
+					continue;
+				},
+			};
+			let vsize = tx.vsize();
+			let spends_deferred = tx.inputs.iter().any(|input| deferred.iter().any(|&i| results[i].0 == input.tx_hash));
+			let result = match self.mempool.check_feerate(check.fee, vsize) {
+				Ok(()) if !spends_deferred => self.add_checked_tx(id, tx, check),
+				// a replacement has to pay its own way
+				Err(err) if !check.conflicts.is_empty() => Err(err),
+				_ if !check.conflicts.is_empty() => Err(Err::ValueError("package RBF not supported".to_owned())),
+				_ => {
+					package_fee = package_fee.saturating_add(check.fee);
+					package_vsize += vsize;
+					deferred.push(results.len());
+					self.mempool.add_tx(id, tx, check.base_fee, check.sigops);
+					Ok(())
+				},
+			};
+			results.push((id, result));
+		}
+
+		if !deferred.is_empty() {
+			let paid = if deferred.last() == Some(&(results.len() - 1)) {
+				self.mempool.check_feerate(package_fee, package_vsize)
+			} else {
+				Err(Err::FeeError("not paid for by the package's child".to_owned()))
+			};
+			match paid {
+				Ok(()) => log_debug!("{} txs accepted at a package feerate of {} sat/kvB", deferred.len(), feerate(package_fee, package_vsize)),
+				// children first
+				Err(err) => for &i in deferred.iter().rev() {
+					self.mempool.remove_tx(&results[i].0);
+					results[i].1 = Err(err.clone());
+				},
+			}
+		}
+		let evicted = self.mempool.limit_size();
+		for (id, result) in results.iter_mut() {
+			if result.is_ok() && evicted.contains(id) {
+				*result = Err(Err::ValueError("mempool full".to_owned()));
+			}
+		}
+		Ok(results)
+	}
+
+	// everything about a tx the mempool goes by bar its feerate, which a
+	// package can make up for
+	fn check_tx(&mut self, id: Sha256, tx: &Tx) -> Result<MempoolCheck> {
+		let reject = |reason: &str| Err(Err::ValueError(reason.to_owned()));
+		if tx.inputs.is_empty() || tx.outputs.is_empty() {
+			return reject("no inputs or outputs");
+		}
+		if tx.inputs.iter().any(|input| input.tx_hash == Sha256::default() && input.index == 0xffff_ffff) {
+			return reject("coinbase");
+		}
+		if self.require_standard {
+			policy::check_standard_tx(tx)?;
+		}
+
+		let height = self.block_db.blocks_validated;
+		let time = self.block_db.median_time_past(height - 1);
+		if !Node::is_final(tx, height, time) {
+			return reject("non-final");
+		}
+
+		// in-mempool parents' outputs count as unspent for now
+		let mut utxos = UTXOState::new(&self.utxos);
+		let mut conflicts = HashSet::new();
+		let mut prevouts = Vec::new();
+		for input in tx.inputs.iter() {
+			let id = UTXOID(input.tx_hash, input.index);
+			if let Some(spender) = self.mempool.spender(&id) {
+				conflicts.insert(spender);
+			}
+			if let Some(output) = self.utxos.get(&id) {
+				if let Some(&coinbase_height) = self.immature_coinbases.get(&input.tx_hash) {
+					if height - coinbase_height < COINBASE_MATURITY {
+						return reject("spends immature coinbase");
+					}
+				}
+				prevouts.push(output.clone());
+				continue;
+			}
+			match self.mempool.txs.get(&input.tx_hash).and_then(|parent| parent.outputs.get(input.index as usize)) {
+				Some(output) => {
+					prevouts.push(output.clone());
+					utxos.add(id, output.clone());
+				},
+				None => return reject("missing inputs"),
+			}
+		}
+		// witness scripts aren't checked, so a spend that needs them would be
+		// taken on its unlock script alone. those, and any witness data, are refused
+		for (input, prevout) in tx.inputs.iter().zip(prevouts.iter()) {
+			let redeem = match prevout.lock.is_p2sh() {
+				true => input.unlock.ops().filter_map(|op| op.push_data().map(|data| Script::from(data.to_vec()))).last(),
+				false => None,
+			};
+			if !input.witness.is_empty() || prevout.lock.witness_program().is_some() ||
+				redeem.is_some_and(|redeem| redeem.witness_program().is_some()) {
+				return reject("witness spends not supported");
+			}
+		}
+		let sigops = tx.sigop_cost(&prevouts);
+		if self.require_standard {
+			policy::check_standard_inputs(tx, &prevouts)?;
+			if sigops > MAX_STANDARD_TX_SIGOPS_COST {
+				return reject("bad-txns-too-many-sigops");
+			}
+		}
+
+		self.mempool.check_limits(tx)?;
+		// a TRUC tx can take the place of its parent's other child
+		if let Some(sibling) = self.mempool.check_truc(tx)? {
+			conflicts.insert(sibling);
+		}
+
+		// coins in the mempool count as confirming in the next block
+		let sequence_locks = tx.check_sequence_locks(height, time, |id| {
+			let coin_height = if self.mempool.txs.contains_key(id) { height } else { *self.coin_heights.get(id)? };
+			Some((coin_height, self.block_db.median_time_past(coin_height.saturating_sub(1))))
+		});
+		if !sequence_locks {
+			return reject("non-BIP68-final");
+		}
+
+		self.state.set_height(height);
+		self.state.set_policy(true);
+		let valid = tx.validate(&mut utxos, false, &self.state);
+		self.state.set_policy(false);
+		if !valid {
+			return reject("invalid inputs or scripts");
+		}
+		let base_fee = utxos.tx_fee;
+		let fee = self.mempool.modified_fee(&id, base_fee);
+		Ok(MempoolCheck { base_fee, fee, sigops, conflicts })
+	}
+
+	// adds a checked tx, evicting whatever it replaces if it pays enough to.
+	// trimming the mempool back to size is left to the caller
+	fn add_checked_tx(&mut self, id: Sha256, tx: Tx, check: MempoolCheck) -> Result<()> {
+		if !check.conflicts.is_empty() {
+			let evicted = self.mempool.check_replacement(&tx, check.fee, &check.conflicts, self.full_rbf)?;
+			for conflict in check.conflicts.iter() {
+				self.mempool.remove_with_descendants(conflict);
+			}
+			log_debug!("tx {} replaced {} mempool txs", id, evicted.len());
+		}
+		self.mempool.add_tx(id, tx, check.base_fee, check.sigops);
+		Ok(())
+	}
+
+	// the next block on our tip, with the mempool txs paying the most that fit
+	fn create_block_template(&mut self) -> Result<BlockTemplate> {
+		if self.is_initial_block_download() {
+			return Err(Err::ValueError("still downloading blocks".to_owned()));
+		}
+		let height = self.block_db.blocks_validated;
+		let min_time = self.block_db.median_time_past(height - 1) + 1;
+		let time = std::cmp::max(common::now() as u32, min_time);
+		// check_tx keeps witness spends out of the mempool, and so out of here
+		let txs = self.mempool.select_packages(MAX_BLOCK_WEIGHT - COINBASE_RESERVED_WEIGHT, MAX_BLOCK_SIGOPS_COST - COINBASE_RESERVED_SIGOPS)
+			.into_iter()
+			.map(|id| {
+				let entry = &self.mempool.entries[&id];
+				TemplateTx { tx: self.mempool.txs[&id].clone(), fee: entry.base_fee, sigops: entry.sigops }
+			})
+			.collect::<Vec<_>>();
+		log_debug!("block template at height {}: {} txs", height, txs.len());
+		Ok(BlockTemplate::new(self.block_db.hashes[height - 1], height, Header::compact_target(&self.target),
+			time, min_time, txs, self.state.params.signet_challenge.clone()))
+	}
+
+	// comma-separated
+	fn parse_txs(hexes: &str) -> Result<Vec<Tx>> {
+		hexes.split(',').map(|hex| {
+			let bytes = common::hex_to_bytes(hex).map_err(|_| Err::ValueError("tx decode failed".to_owned()))?;
+			Tx::deserialize(&mut &bytes[..]).map_err(|_| Err::ValueError("tx decode failed".to_owned()))
+		}).collect()
+	}
+
+	// a package from the console is relayed like any tx once it's in
+	fn submit_package(&mut self, txs: Vec<Tx>) -> Result<Vec<(Sha256, Result<()>)>> {
+		let results = self.accept_package(txs)?;
+		let mut accepted = Vec::new();
+		for (id, result) in results.iter() {
+			if result.is_ok() {
+				self.queue_tx_announcement(*id);
+				accepted.push((*id, self.mempool.txs[id].outputs.len()));
+			}
+		}
+		self.process_orphans(accepted);
+		Ok(results)
+	}
+
+	// what would become of a package, leaving the mempool as it was
+	fn test_mempool_accept(&mut self, txs: Vec<Tx>) -> Result<Vec<(Sha256, Result<()>)>> {
+		let mempool = self.mempool.clone();
+		let results = self.accept_package(txs);
+		self.mempool = mempool;
+		results
+	}
+
+	fn parse_block(hex: &str) -> Result<Block> {
+		let bytes = common::hex_to_bytes(hex).map_err(|_| Err::ValueError("block decode failed".to_owned()))?;
+		Block::deserialize(&mut &bytes[..]).map_err(|_| Err::ValueError("block decode failed".to_owned()))
+	}
+
+	// whether a block could go on our tip as far as its header goes. reasons
+	// are worded as in BIP 22
+	fn check_block_header(&self, hash: &Sha256, header: &Header, check_pow: bool) -> Result<()> {
+		let reject = |reason: &str| Err(Err::ValueError(reason.to_owned()));
+		if self.block_db.height_of(hash).is_some() {
+			return reject("duplicate");
+		}
+		let tip = self.block_db.hashes[self.block_db.blocks_validated - 1];
+		if header.prev_block != tip || self.block_db.hashes.len() != self.block_db.blocks_validated {
+			return reject("inconclusive-not-best-prevblk");
+		}
+		if header.bits != Header::compact_target(&self.target) {
+			return reject("bad-diffbits");
+		}
+		if check_pow && hash.to_u256() >= self.target {
+			return reject("high-hash");
+		}
+		if header.timestamp <= self.block_db.median_time_past(self.block_db.blocks_validated - 1) {
+			return reject("time-too-old");
+		}
+		Ok(())
+	}
+
+	// block validation doesn't look at witnesses, so a block from a miner can't
+	// have any past the coinbase's, and its witness commitment has to hold
+	fn check_block_witnesses(block: &Block) -> Result<()> {
+		let reject = |reason: &str| Err(Err::ValueError(reason.to_owned()));
+		if block.txs.iter().skip(1).any(|tx| tx.inputs.iter().any(|input| !input.witness.is_empty())) {
+			return reject("unexpected-witness");
+		}
+		if let Some(index) = block.witness_commitment_index() {
+			let reserved = match block.txs[0].inputs.first().map(|input| &input.witness[..]) {
+				Some([reserved]) if reserved.len() == 32 => reserved,
+				_ => return reject("bad-witness-nonce-size"),
+			};
+			if block.txs[0].outputs[index].lock.as_bytes()[6..38] != *block.compute_witness_commitment(reserved).as_bytes() {
+				return reject("bad-witness-merkle-match");
+			}
+		}
+		Ok(())
+	}
+
+	// BIP 23: checks a block as submitblock would, save for proof of work,
+	// without keeping it
+	fn check_block_proposal(&mut self, block: &Block) -> Result<()> {
+		let hash = block.header.compute_hash();
+		self.check_block_header(&hash, &block.header, false)?;
+		Node::check_block_witnesses(block)?;
+		self.state.set_height(self.block_db.blocks_validated);
+		match block.validate(&hash, &mut self.utxos, &self.state) {
+			ValidationResult::Valid(_) => Ok(()),
+			ValidationResult::Invalid => Err(Err::ValueError("rejected".to_owned())),
+		}
+	}
+
+	// a block from a miner goes through the same checks as one from a peer, and
+	// is announced like one once connected
+	fn submit_block(&mut self, block: Block) -> Result<()> {
+		let hash = block.header.compute_hash();
+		self.check_block_header(&hash, &block.header, true)?;
+		Node::check_block_witnesses(&block)?;
+		let target = self.target;
+		self.accept_header(hash, block.header.clone())?;
+		if let Err(err) = self.connect_block(usize::MAX, hash, block) {
+			self.block_db.pop_header();
+			self.target = target;
+			log_debug!("submitted block {} rejected: {}", hash, err);
+			return Err(Err::ValueError("rejected".to_owned()));
+		}
+		log_info!("submitted block {} accepted at height {}", hash, self.block_db.blocks_validated - 1);
+		Ok(())
+	}
+
+	// a tx can go in a block at `height` with median time past `time` once its
+	// lock time has passed, or if every input opts out of it
+	fn is_final(tx: &Tx, height: usize, time: u32) -> bool {
+		let unlocked = match tx.abs_lock_time {
+			AbsoluteLockTime::None => true,
+			AbsoluteLockTime::BlockNumber(n) => (n as usize) < height,
+			AbsoluteLockTime::Timestamp(n) => n < time,
+		};
+		unlocked || tx.inputs.iter().all(|input| input.sequence == 0xffff_ffff)
+	}
+
+	fn is_initial_block_download(&self) -> bool {
+		const MAX_TIP_AGE: u64 = 24 * 60 * 60;
+		let tip = &self.block_db.hashes[self.block_db.blocks_validated - 1];
+		let tip_time = self.block_db.headers[tip].timestamp as u64;
+		self.block_db.blocks_validated < self.block_db.hashes.len() || tip_time + MAX_TIP_AGE < common::now()
+	}
+
+	fn queue_tx_announcement(&mut self, id: Sha256) {
+		for peer in self.peers.values_mut() {
+			if !peer.known_inventory.contains(&id) {
+				peer.tx_queue.push(id);
+			}
+		}
+	}
+
+	fn queue_block_announcement(&mut self, hash: Sha256) {
+		for peer in self.peers.values_mut() {
+			if !peer.known_inventory.contains(&hash) {
+				peer.block_queue.push(hash);
+			}
+		}
+	}
+
+	fn send_block_announcements(&mut self, peer_index: usize) -> Result<()> {
+		let peer = self.peers.get_mut(&peer_index).unwrap();
+		if peer.block_queue.is_empty() {
+			return Ok(());
+		}
+		let hashes = std::mem::take(&mut peer.block_queue).into_iter()
+			.filter(|hash| !peer.known_inventory.contains(hash))
+			.collect::<Vec<_>>();
+		for hash in hashes.iter() {
+			peer.add_known_inventory(*hash);
+		}
+		if hashes.is_empty() {
+			return Ok(());
+		}
+
+		if peer.config.cmpct_announce && hashes.len() == 1 {
+			if let Ok(block) = self.block_db.load_block(&hashes[0]) {
+				return peer.conn.send(Message::cmpctblock(CmpctBlock::new(&block, peer.config.cmpct_version)));
+			}
+		}
+		if peer.config.sendheaders && hashes.len() <= MAX_BLOCKS_TO_ANNOUNCE {
+			let headers = hashes.iter()
+				.map(|hash| self.block_db.headers[hash].clone())
+				.collect();
+			peer.conn.send(Message::headers(headers))
+		} else {
+			// per BIP 130, the inv only needs to name the tip
+			let tip = *hashes.last().unwrap();
+			peer.conn.send(Message::inv(vec![InvItem::new(InvType::Block, tip)]))
+		}
+	}
+
+	fn send_tx_announcements(&mut self, peer_index: usize) -> Result<()> {
+		let peer = self.peers.get_mut(&peer_index).unwrap();
+		if peer.tx_queue.is_empty() || Instant::now() < peer.next_tx_broadcast {
+			return Ok(());
+		}
+		peer.schedule_tx_broadcast();
+
+		if !peer.relays_txs() {
+			peer.tx_queue.clear();
+			return Ok(());
+		}
+
+		let count = std::cmp::min(peer.tx_queue.len(), INVENTORY_BROADCAST_MAX);
+		let mut items = Vec::new();
+		for id in peer.tx_queue.drain(..count).collect::<Vec<_>>() {
+			if peer.known_inventory.contains(&id) {
+				continue;
+			}
+			// the tx may have left the mempool since it was queued
+			let feerate = match self.mempool.feerate(&id) {
+				Some(feerate) => feerate,
+				None => continue,
+			};
+			if feerate < peer.config.feerate {
+				continue;
+			}
+			if let Some(filter) = peer.bloom_filter.as_mut() {
+				if !filter.is_relevant_and_update(&self.mempool.txs[&id]) {
+					continue;
+				}
+			}
+			peer.add_known_inventory(id);
+			items.push(InvItem::new(InvType::Tx, id));
+		}
+
+		if !items.is_empty() {
+			log_trace!("peer {}: announcing {} txs", peer_index, items.len());
+			peer.conn.send(Message::inv(items))?;
+		}
+		Ok(())
+	}
+
+	fn send_addr_announcements(&mut self, peer_index: usize) -> Result<()> {
+		let peer = self.peers.get_mut(&peer_index).unwrap();
+		if peer.addr_queue.is_empty() || Instant::now() < peer.next_addr_broadcast {
+			return Ok(());
+		}
+		peer.next_addr_broadcast = Instant::now() + poisson_delay(AVG_ADDRESS_BROADCAST_INTERVAL);
+
+		let mut addrs = Vec::new();
+		for addr in std::mem::take(&mut peer.addr_queue) {
+			let key = (addr.host, addr.port);
+			if peer.known_addrs.contains(&key) || (!peer.config.addrv2 && !addr.host.is_v1_compatible()) {
+				continue;
+			}
+			peer.add_known_addr(key);
+			addrs.push(addr);
+			if addrs.len() == MAX_ADDR_TO_SEND {
+				break;
+			}
+		}
+
+		if addrs.is_empty() {
+			Ok(())
+		} else if peer.config.addrv2 {
+			peer.conn.send(Message::addrv2(addrs))
+		} else {
+			peer.conn.send(Message::addr(addrs))
+		}
+	}
+
+	fn send_announcements(&mut self) {
+		let ids = self.peers.iter()
+			.filter(|(_, peer)| peer.handshake_complete)
+			.map(|(&id, _)| id)
+			.collect::<Vec<_>>();
+		for id in ids {
+			let result: Result<()> = try {
+				self.send_block_announcements(id)?;
+				self.send_tx_announcements(id)?;
+				self.send_addr_announcements(id)?;
+			};
+			if let Err(e) = result {
+				log_error!("peer {}: error: {}", id, e);
+			}
+		}
+	}
+
+	fn handle_getheaders_message(&mut self, peer_index: usize, getheaders: GetHeaders) -> Result<()> {
+		if getheaders.hashes().len() > MAX_LOCATOR_SIZE {
+			log_debug!("peer {}: getheaders locator size {} > {}", peer_index, getheaders.hashes().len(), MAX_LOCATOR_SIZE);
+			self.disconnect_peer(peer_index);
+			return Ok(());
+		}
+
+		let headers = if getheaders.hashes().is_empty() {
+			// no locator: the peer wants just the header of `hash_stop`
+			getheaders.hash_stop()
+				.filter(|hash| self.block_db.height_of(hash).is_some_and(|h| h < self.block_db.blocks_validated))
+				.map(|hash| vec![self.block_db.headers[&hash].clone()])
+				.unwrap_or_default()
+		} else {
+			let start = self.block_db.find_fork(getheaders.hashes()) + 1;
+			let mut headers = Vec::new();
+			for hash in self.block_db.hashes.iter().take(self.block_db.blocks_validated).skip(start).take(MAX_HEADERS_RESULTS) {
+				headers.push(self.block_db.headers[hash].clone());
+				if Some(*hash) == getheaders.hash_stop() {
+					break;
+				}
+			}
+			headers
+		};
+
+		if let Some(peer) = self.peers.get_mut(&peer_index) {
+			log_debug!("peer {}: sending {} headers", peer_index, headers.len());
+			peer.conn.send(Message::headers(headers))?;
+		}
+		Ok(())
+	}
+
+	fn handle_getblocks_message(&mut self, peer_index: usize, getblocks: GetHeaders) -> Result<()> {
+		const MAX_BLOCKS: usize = 500;
+
+		if getblocks.hashes().len() > MAX_LOCATOR_SIZE {
+			log_debug!("peer {}: getblocks locator size {} > {}", peer_index, getblocks.hashes().len(), MAX_LOCATOR_SIZE);
+			self.disconnect_peer(peer_index);
+			return Ok(());
+		}
+
+		let start = self.block_db.find_fork(getblocks.hashes()) + 1;
+		let mut items = Vec::new();
+		for hash in self.block_db.hashes.iter().take(self.block_db.blocks_validated).skip(start).take(MAX_BLOCKS) {
+			if Some(*hash) == getblocks.hash_stop() {
+				break;
+			}
+			items.push(InvItem::new(InvType::Block, *hash));
+		}
+
+		if !items.is_empty() {
+			if let Some(peer) = self.peers.get_mut(&peer_index) {
+				peer.conn.send(Message::inv(items))?;
+			}
+		}
+		Ok(())
+	}
+
+	fn handle_getdata_message(&mut self, peer_index: usize, getdata: GetData) -> Result<()> {
+		let items = getdata.into_iter().collect::<Vec<_>>();
+		if items.len() > MAX_INV_SIZE {
+			self.misbehaving(peer_index, OVERSIZED_MESSAGE_SCORE, &format!("getdata message size = {}", items.len()));
+			return Ok(());
+		}
+
+		if let Some(peer) = self.peers.get_mut(&peer_index) {
+			peer.getdata_queue.extend(items);
+		}
+		self.process_getdata(peer_index)
+	}
+
+	// serves queued getdata requests until the peer's send buffer fills up; the
+	// rest wait for it to drain
+	fn process_getdata(&mut self, peer_index: usize) -> Result<()> {
+		let mut notfound = Vec::new();
+		loop {
+			let item = match self.peers.get_mut(&peer_index) {
+				Some(peer) if !peer.conn.send_paused() => match peer.getdata_queue.pop_front() {
+					Some(item) => item,
+					None => break,
+				},
+				_ => break,
+			};
+
+			// sent right after the response: the matched txs of a merkleblock
+			let mut txs = Vec::new();
+			let response = match item.object_type {
+				InvType::Tx | InvType::WitnessTx => {
+					self.mempool.txs.get(&item.hash).cloned().map(|mut tx| {
+						if let InvType::Tx = item.object_type {
+							tx.strip_witness();
+						}
+						Message::tx(tx)
+					})
+				},
+				InvType::Block | InvType::WitnessBlock | InvType::CmpctBlock => {
+					let validated = self.block_db.blocks_validated;
+					let depth = self.block_db.height_of(&item.hash)
+						.filter(|&height| height < validated)
+						.map(|height| validated - height);
+					let block = if depth.is_some() {
+						self.block_db.load_block(&item.hash).ok()
+					} else {
+						None
+					};
+					let version = self.peers[&peer_index].config.cmpct_version;
+					block.map(|mut block| match item.object_type {
+						InvType::CmpctBlock if version != 0 && depth.unwrap() <= MAX_CMPCT_DEPTH => {
+							Message::cmpctblock(CmpctBlock::new(&block, version))
+						},
+						InvType::Block => {
+							block.strip_witness();
+							Message::block(block)
+						},
+						_ => Message::block(block),
+					})
+				},
+				// peers without a filter loaded get nothing, not even a notfound
+				InvType::FilteredBlock if self.peers[&peer_index].bloom_filter.is_none() => continue,
+				InvType::FilteredBlock => {
+					let validated = self.block_db.blocks_validated;
+					let block = match self.block_db.height_of(&item.hash) {
+						Some(height) if height < validated => self.block_db.load_block(&item.hash).ok(),
+						_ => None,
+					};
+					block.map(|block| {
+						let filter = self.peers.get_mut(&peer_index).unwrap().bloom_filter.as_mut().unwrap();
+						let matches = block.txs.iter()
+							.map(|tx| filter.is_relevant_and_update(tx))
+							.collect::<Vec<_>>();
+						for (tx, _) in block.txs.iter().zip(matches.iter()).filter(|(_, &matched)| matched) {
+							let mut tx = tx.clone();
+							tx.strip_witness();
+							txs.push(Message::tx(tx));
+						}
+						Message::merkleblock(MerkleBlock::new(&block, &matches))
+					})
+				},
+				_ => None,
+			};
+			match response {
+				Some(m) => {
+					let peer = self.peers.get_mut(&peer_index).unwrap();
+					peer.conn.send(m)?;
+					for tx in txs {
+						peer.conn.send(tx)?;
+					}
+				},
+				None => notfound.push(item),
+			}
+		}
+
+		if let Some(peer) = self.peers.get_mut(&peer_index) {
+			if !notfound.is_empty() {
+				log_debug!("peer {}: {} requested item(s) not found", peer_index, notfound.len());
+				peer.conn.send(Message::notfound(notfound))?;
+			}
+		}
+		Ok(())
+	}
+
+	fn handle_notfound_message(&mut self, peer_index: usize, notfound: Inv) -> Result<()> {
+		let mut blocks = Vec::new();
+		for item in notfound.iter() {
+			log_debug!("peer {}: not found: {} {}", peer_index, item.object_type, item.hash);
+			match item.object_type {
+				InvType::Tx | InvType::WitnessTx => {
+					self.mempool.pending.remove(&item.hash);
+				},
+				InvType::Block | InvType::WitnessBlock
+					if self.blocks_in_flight.get(&item.hash).is_some_and(|request| request.peer == peer_index) => {
+					blocks.push(item.hash);
+				},
+				_ => {},
+			}
+		}
+		if !blocks.is_empty() {
+			self.reassign_block_requests(blocks, peer_index);
+		}
+		Ok(())
+	}
+
+	fn adjust_difficulty(&mut self) {
+		assert!(self.block_db.hashes.len() % 2016 == 0);
+
+		let height = self.block_db.hashes.len();
+		
+		let hash_start   = &self.block_db.hashes[height - 2016];
+		let hash_end     = &self.block_db.hashes[height - 1];
+
+		let period_start = self.block_db.headers.get(hash_start).unwrap().timestamp;
+		let period_end   = self.block_db.headers.get(hash_end).unwrap().timestamp;
+
+		self.target = Node::retarget(self.target, period_start, period_end, self.state.params.pow_limit);
+	}
+
+	// the target for the period after one that took from `period_start` to `period_end`
+	fn retarget(target: u256, period_start: u32, period_end: u32, pow_limit: u256) -> u256 {
+		let expected_duration = 2016.0 * 10.0 * 60.0;
+		let actual_duration   = (period_end - period_start) as f64;
+
+		let ratio = (actual_duration / expected_duration).clamp(0.25, 4.0);
+		let mut target = u256::from_f64(target.to_f64() * ratio);
+		if target > pow_limit {
+			target = pow_limit;
+		}
+
+		let mut i = 0;
+		for &b in target.as_bytes().iter().rev() {
+			if b != 0 {
+				assert!(i > 0);
+				if b >= 0x80 {
+					i -= 1;
+				}
+				break;
+			}
+			i += 1;
+		}
+
+		let trunc = (29 - i) * 8;
+
+		
+		// println!("new: {:x}", target);
+		// println!("trunc: {}", trunc);
+		
+		target >> trunc << trunc
+		// println!("average time per block: {} minutes", (period_end - period_start) as f64 / 2016.0 / 60.0);
+	}
+
+	fn inbound_count(&self) -> usize {
+		self.peers.values().filter(|peer| peer.inbound).count()
+	}
+
+	fn outbound_count(&self) -> usize {
+		self.peers.values().filter(|peer| !peer.inbound).count()
+	}
+
+	fn add_peer(&mut self, stream: TcpStream, addr: String, inbound: bool, v2: bool) -> Result<usize> {
+		let conn = if v2 {
+			Connection::new_v2(stream, inbound)?
+		} else {
+			Connection::new(stream)?
+		};
+		let endpoint = conn.peer_addr()
+			.map(|addr| (NetHost::from(addr.ip()), addr.port()));
+
+		let id = self.next_peer_id;
+		self.next_peer_id += 1;
+
+		self.peers.insert(id, PeerHandle {
+			conn,
+			addr,
+			info: None,
+			handshake_complete: false,
+			config: Config::default(),
+			inbound,
+			known_inventory: HashSet::new(),
+			tx_queue: Vec::new(),
+			block_queue: Vec::new(),
+			next_tx_broadcast: Instant::now(),
+			endpoint,
+			known_addrs: HashSet::new(),
+			addr_queue: Vec::new(),
+			next_addr_broadcast: Instant::now() + poisson_delay(AVG_ADDRESS_BROADCAST_INTERVAL),
+			addr_tokens: 1.0,
+			addr_tokens_time: Instant::now(),
+			getaddr_sent: false,
+			getaddr_answered: false,
+			misbehavior: 0,
+			unconnecting_headers: 0,
+			getdata_queue: VecDeque::new(),
+			connected: Instant::now(),
+			ping_nonce: None,
+			ping_start: Instant::now(),
+			next_ping: Instant::now(),
+			ping_time: None,
+			feefilter_sent: 0,
+			next_feefilter: Instant::now(),
+			min_ping_time: None,
+			last_block_time: Instant::now(),
+			last_new_tx: None,
+			last_new_block: None,
+			bloom_filter: None,
+		});
+
+		Ok(id)
+	}
+
+	fn disconnect_peer(&mut self, peer_index: usize) {
+		self.high_bandwidth_peers.retain(|&id| id != peer_index);
+		if let Some(peer) = self.peers.remove(&peer_index) {
+			peer.conn.shutdown();
+			log_info!("peer {}: disconnected ({} {})", peer_index, if peer.inbound { "inbound" } else { "outbound" }, peer.addr);
+			self.orphans.remove_for_peer(peer_index);
+
+			// a peer that never answered the v2 handshake probably only speaks v1
+			if let (false, true, Some((host, port))) = (peer.inbound, peer.conn.v2_handshake_failed(), peer.endpoint) {
+				self.reconnect_v1(NetAddr::new(host, port, 0, 0));
+			}
+		}
+	}
+
+	// makes room for a new inbound connection. only inbound peers are candidates
+	// (outbound peers are the ones we chose, so they're harder for an attacker to
+	// occupy). one that hasn't finished the handshake goes first; otherwise the
+	// choice is left to select_peer_to_evict, which may spare everyone, and then
+	// the newcomer is turned away instead.
+	fn evict_inbound_peer(&mut self) -> bool {
+		let unfinished = self.peers.iter()
+			.filter(|(_, peer)| peer.inbound && !peer.handshake_complete)
+			.map(|(&id, _)| id)
+			.min();
+		let candidate = unfinished.or_else(|| {
+			let candidates = self.peers.iter()
+				.filter(|(_, peer)| peer.inbound)
+				.map(|(&id, peer)| EvictionCandidate {
+					id,
+					connected: peer.connected,
+					min_ping_time: peer.min_ping_time,
+					last_tx: peer.last_new_tx,
+					last_block: peer.last_new_block,
+					group: peer.endpoint.map_or_else(Vec::new, |(host, _)| host.group()),
+				})
+				.collect();
+			select_peer_to_evict(candidates, self.eviction_key)
+		});
+		if let Some(id) = candidate {
+			log_debug!("peer {}: evicting to free an inbound slot", id);
+			self.disconnect_peer(id);
+			true
+		} else {
+			false
+		}
+	}
+
+	// counts misbehavior against a peer; at BAN_THRESHOLD it's disconnected and banned
+	fn misbehaving(&mut self, peer_index: usize, howmuch: u32, reason: &str) {
+		let peer = match self.peers.get_mut(&peer_index) {
+			Some(peer) => peer,
+			None => return,
+		};
+		peer.misbehavior += howmuch;
+		log_warn!("peer {}: misbehaving (+{} -> {}): {}", peer_index, howmuch, peer.misbehavior, reason);
+
+		if peer.misbehavior >= BAN_THRESHOLD {
+			if let Some((host, _)) = peer.endpoint {
+				log_warn!("peer {}: banning {}", peer_index, host);
+				self.banlist.ban(host, DEFAULT_BAN_TIME);
+			}
+			self.disconnect_peer(peer_index);
+		}
+	}
+
+	fn is_banned(&self, addr: &SocketAddr) -> bool {
+		self.banlist.is_banned(&NetHost::from(addr.ip()))
+	}
+
+	fn set_ban(&mut self, host: NetHost, duration: u64) {
+		self.banlist.ban(host, duration);
+		let banned = self.peers.iter()
+			.filter(|(_, peer)| peer.endpoint.is_some_and(|endpoint| endpoint.0 == host))
+			.map(|(&id, _)| id)
+			.collect::<Vec<_>>();
+		for id in banned {
+			self.disconnect_peer(id);
+		}
+	}
+
+	fn accept_inbound(&mut self, stream: TcpStream, addr: SocketAddr) {
+		if self.is_banned(&addr) {
+			log_debug!("rejecting inbound connection from {}: banned", addr);
+			let _ = stream.shutdown(Shutdown::Both);
+			return;
+		}
+		if self.inbound_count() >= self.max_inbound && !self.evict_inbound_peer() {
+			log_debug!("rejecting inbound connection from {}: no free slots", addr);
+			let _ = stream.shutdown(Shutdown::Both);
+			return;
+		}
+		match self.add_peer(stream, addr.to_string(), true, self.services & services::NODE_P2P_V2 != 0) {
+			Ok(id) => log_info!("peer {}: accepted inbound connection from {}", id, addr),
+			Err(err) => log_error!("failed to accept connection from {}: {}", addr, err),
+		}
+	}
+
+	fn handle_peer_event(&mut self, event: PeerEvent) {
+		match event {
+			PeerEvent::Outbound(stream, addr, v2) => {
+				self.pending_outbound.remove(&(addr.host, addr.port));
+				let result: Result<()> = try {
+					let id = self.add_peer(stream, addr.to_string(), false, v2)?;
+					let peer = self.peers.get_mut(&id).unwrap();
+					peer.endpoint = Some((addr.host, addr.port));
+					peer.conn.send(Message::version(peer.version_addr(), self.services))?;
+					log_info!("peer {}: connected to {}{}", id, addr, if v2 { " (v2)" } else { "" });
+				};
+				if let Err(err) = result {
+					log_error!("failed to connect to {}: {}", addr, err);
+				}
+			},
+			PeerEvent::OutboundFailed(addr) => {
+				self.pending_outbound.remove(&(addr.host, addr.port));
+			},
+			PeerEvent::OnionService(addr) => {
+				log_info!("onion service published at {}", addr);
+				for peer in self.peers.values_mut().filter(|peer| peer.handshake_complete) {
+					peer.addr_queue.push(NetAddr::new(addr.host, addr.port, self.services, common::now() as u32));
+				}
+				self.onion_addr = Some(addr);
+			},
+		}
+	}
+
+	// handles every complete message the peer has sent, unless its send buffer is
+	// full: then its requests wait until we've caught up with the replies
+	fn process_messages(&mut self, peer_index: usize) {
+		loop {
+			let result = match self.peers.get_mut(&peer_index) {
+				Some(peer) if !peer.conn.send_paused() => peer.conn.receive(),
+				_ => return,
+			};
+			match result {
+				Ok(Some(m)) => {
+					// invalid headers and blocks are on the peer that sent them. a tx
+					// we reject may just be one we can't fully validate, or one our
+					// policy doesn't like, so that costs the peer nothing
+					let chain_message = matches!(m.payload(),
+						Payload::Headers(_) | Payload::Block(_) | Payload::CmpctBlock(_) | Payload::BlockTxn(_));
+					match self.handle_message(peer_index, m) {
+						Ok(_) => {},
+						Err(e @ Err::ConsensusError(_)) if chain_message => self.misbehaving(peer_index, BAN_THRESHOLD, &e.to_string()),
+						Err(e) => log_error!("peer {}: error: {}", peer_index, e),
+					}
+				},
+				Ok(None) => return,
+				Err(Err::ValueError(reason)) => self.misbehaving(peer_index, MALFORMED_MESSAGE_SCORE, &reason),
+				Err(e) => {
+					log_error!("peer {}: error: {}", peer_index, e);
+					self.disconnect_peer(peer_index);
+					return;
+				},
+			}
+		}
+	}
+
+	// waits (up to `timeout`) for network activity, then accepts new connections,
+	// reads and handles whatever has arrived, and writes out what's been queued
+	fn poll_network(&mut self, timeout: Duration) {
+		let mut fds = Vec::new();
+		if let Some(listener) = &self.listener {
+			fds.push(PollFd::new(listener.as_raw_fd(), POLLIN));
+		}
+		let ids = self.peers.keys().copied().collect::<Vec<_>>();
+		for id in ids.iter() {
+			let conn = &self.peers[id].conn;
+			let mut events = 0;
+			if !conn.send_paused() {
+				events |= POLLIN;
+			}
+			if conn.wants_write() {
+				events |= POLLOUT;
+			}
+			fds.push(PollFd::new(conn.as_raw_fd(), events));
+		}
+
+		if let Err(err) = poll::poll(&mut fds, timeout) {
+			log_error!("poll failed: {}", err);
+			return;
+		}
+
+		let mut fds = fds.into_iter();
+		if self.listener.is_some() && fds.next().is_some_and(|fd| fd.readable()) {
+			self.accept_connections();
+		}
+
+		for (&id, fd) in ids.iter().zip(fds) {
+			let peer = match self.peers.get_mut(&id) {
+				Some(peer) => peer,
+				None => continue,
+			};
+			let mut open = !fd.invalid();
+			if open && fd.readable() {
+				open = match peer.conn.fill() {
+					Ok(open) => open,
+					Err(err) => {
+						log_debug!("peer {}: {}", id, err);
+						false
+					},
+				};
+			}
+			if open && fd.writable() {
+				if let Err(err) = peer.conn.flush() {
+					log_debug!("peer {}: {}", id, err);
+					open = false;
+				}
+			}
+
+			// whatever arrived before a hangup still gets handled
+			self.process_messages(id);
+			if !open {
+				self.disconnect_peer(id);
+			}
+		}
+
+		// peers whose send buffers have drained can be served again
+		for id in ids {
+			let result = match self.peers.get(&id) {
+				Some(peer) if !peer.getdata_queue.is_empty() => self.process_getdata(id),
+				_ => continue,
+			};
+			if let Err(err) = result {
+				log_error!("peer {}: error: {}", id, err);
+			}
+			self.process_messages(id);
+		}
+	}
+
+	fn accept_connections(&mut self) {
+		loop {
+			let result = match &self.listener {
+				Some(listener) => listener.accept(),
+				None => return,
+			};
+			match result {
+				Ok((stream, addr)) => self.accept_inbound(stream, addr),
+				Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => return,
+				Err(err) => {
+					log_error!("failed to accept connection: {}", err.to_string());
+					return;
+				},
+			}
+		}
+	}
+
+	fn send_pings(&mut self) {
+		for (id, peer) in self.peers.iter_mut() {
+			if !peer.handshake_complete || peer.ping_nonce.is_some() || Instant::now() < peer.next_ping {
+				continue;
+			}
+			// zero would read as a cancellation in the pong
+			let nonce = rand::random::<u64>().max(1);
+			peer.ping_nonce = Some(nonce);
+			peer.ping_start = Instant::now();
+			peer.next_ping = Instant::now() + PING_INTERVAL;
+			if let Err(err) = peer.conn.send(Message::ping(nonce)) {
+				log_error!("peer {}: error: {}", id, err);
+			}
+		}
+	}
+
+	fn send_feefilters(&mut self) {
+		let feerate = if self.is_initial_block_download() { MAX_MONEY } else { self.mempool.min_feerate() };
+		let now = Instant::now();
+		for (id, peer) in self.peers.iter_mut() {
+			if !peer.handshake_complete {
+				continue;
+			}
+			if now >= peer.next_feefilter {
+				if feerate != peer.feefilter_sent {
+					if let Err(err) = peer.conn.send(Message::feefilter(feerate)) {
+						log_error!("peer {}: error: {}", id, err);
+					}
+					peer.feefilter_sent = feerate;
+				}
+				peer.next_feefilter = now + poisson_delay(AVG_FEEFILTER_BROADCAST_INTERVAL);
+			} else if peer.next_feefilter > now + MAX_FEEFILTER_CHANGE_DELAY
+				&& (feerate * 4 < peer.feefilter_sent * 3 || feerate * 3 > peer.feefilter_sent * 4) {
+				peer.next_feefilter = now + MAX_FEEFILTER_CHANGE_DELAY.mul_f64(rand::random::<f64>());
+			}
+		}
+	}
+
+	fn check_timeouts(&mut self) {
+		let mut timed_out = Vec::new();
+		for (&id, peer) in self.peers.iter() {
+			let reason = if !peer.handshake_complete && peer.connected.elapsed() > HANDSHAKE_TIMEOUT {
+				"handshake timeout"
+			} else if peer.conn.last_recv().elapsed() > TIMEOUT_INTERVAL || peer.conn.last_send().elapsed() > TIMEOUT_INTERVAL {
+				"inactivity timeout"
+			} else if peer.ping_nonce.is_some() && peer.ping_start.elapsed() > TIMEOUT_INTERVAL {
+				"ping timeout"
+			} else {
+				continue;
+			};
+			timed_out.push((id, reason));
+		}
+		for (id, reason) in timed_out {
+			log_info!("peer {}: {}", id, reason);
+			self.disconnect_peer(id);
+		}
+
+		// requests to peers that stalled (or are gone) go to someone else
+		let mut stalled = HashMap::<usize, Vec<Sha256>>::new();
+		for (hash, request) in self.blocks_in_flight.iter() {
+			let deadline = match self.peers.get(&request.peer) {
+				Some(peer) => std::cmp::max(request.time, peer.last_block_time) + BLOCK_DOWNLOAD_TIMEOUT,
+				None => request.time,
+			};
+			if Instant::now() >= deadline {
+				stalled.entry(request.peer).or_default().push(*hash);
+			}
+		}
+		for (peer_index, hashes) in stalled {
+			if self.peers.contains_key(&peer_index) {
+				log_info!("peer {}: stalled on {} block request(s)", peer_index, hashes.len());
+			}
+			self.reassign_block_requests(hashes, peer_index);
+		}
+	}
+
+	// fills free outbound slots with addresses picked by the address manager
+	fn open_outbound_connections(&mut self) {
+		const MAX_TRIES: usize = 100;
+
+		if self.last_connect_attempt.elapsed() < OUTBOUND_CONNECT_INTERVAL {
+			return;
+		}
+		self.last_connect_attempt = Instant::now();
+		if self.outbound_count() + self.pending_outbound.len() >= self.max_outbound {
+			return;
+		}
 
-		if items.len() > 0 {
-			self.peers.get_mut(&peer_index).unwrap().writer.send(Message::getdata(items))?;
-		}
+		// one outbound connection per network group, so that an attacker holding a
+		// few address ranges can't take all our outbound slots
+		let groups = self.peers.values()
+			.filter(|peer| !peer.inbound)
+			.filter_map(|peer| peer.endpoint.map(|endpoint| endpoint.0.group()))
+			.chain(self.pending_outbound.iter().map(|key| key.0.group()))
+			.collect::<HashSet<_>>();
 
-		Ok(())
-	}
+		for _ in 0..MAX_TRIES {
+			let addr = match self.addrman.select() {
+				Some(addr) => addr,
+				None => return,
+			};
+			let key = (addr.host, addr.port);
+			let proxy = match self.route(&addr.host) {
+				Some(proxy) => proxy,
+				None => continue,
+			};
+			if groups.contains(&addr.host.group())
+				|| self.banlist.is_banned(&addr.host)
+				|| self.pending_outbound.contains(&key)
+				|| self.peers.values().any(|peer| peer.endpoint == Some(key)) {
+				continue;
+			}
 
-	fn handle_tx_message(&mut self, _peer_index: usize, id: Sha256, tx: Tx) -> Result<()> {
-		self.mempool.add_tx(id, tx);
-		Ok(())
+			self.addrman.attempt(&key);
+			self.pending_outbound.insert(key);
+			let send = self.send.as_ref().unwrap().clone();
+			let v2 = self.services & services::NODE_P2P_V2 != 0;
+			thread::spawn(move || Node::connect_thread(addr, proxy, v2, send));
+			return;
+		}
 	}
 
-	fn adjust_difficulty(&mut self) {
-		assert!(self.block_db.hashes.len() % 2016 == 0);
-
-		let height = self.block_db.hashes.len();
-		
-		let hash_start   = &self.block_db.hashes[height - 2016];
-		let hash_end     = &self.block_db.hashes[height - 1];
+	// how to reach a host: None if we can't, else the proxy to go through, if any.
+	// onion addresses need a proxy; i2p and cjdns aren't supported
+	fn route(&self, host: &NetHost) -> Option<Option<Proxy>> {
+		match host {
+			NetHost::IPv4(_) | NetHost::IPv6(_) => Some(self.proxy.clone()),
+			NetHost::TorV3(_) => self.onion_proxy.clone().map(Some),
+			NetHost::I2P(_) | NetHost::Cjdns(_) => None,
+		}
+	}
 
-		let period_start = self.block_db.headers.get(hash_start).unwrap().timestamp;
-		let period_end   = self.block_db.headers.get(hash_end).unwrap().timestamp;
-		
-		let expected_duration = 2016.0 * 10.0 * 60.0;
-		let actual_duration   = (period_end - period_start) as f64;
+	fn reconnect_v1(&mut self, addr: NetAddr) {
+		let (proxy, send) = match (self.route(&addr.host), &self.send) {
+			(Some(proxy), Some(send)) => (proxy, send.clone()),
+			_ => return,
+		};
+		log_debug!("retrying {} with a v1 connection", addr);
+		self.pending_outbound.insert((addr.host, addr.port));
+		thread::spawn(move || Node::connect_thread(addr, proxy, false, send));
+	}
 
-		let ratio = (actual_duration / expected_duration).clamp(0.25, 4.0);
-		self.target = u256::from_f64(self.target.to_f64() * ratio);
-		let max_target = u256::hex("ffff0000000000000000000000000000000000000000000000000000");
-		if self.target > max_target {
-			self.target = max_target;
-		}
+	fn connect_thread(addr: NetAddr, proxy: Option<Proxy>, v2: bool, send: Sender<PeerEvent>) {
+		log_debug!("trying to connect to: {}", addr);
+		let result = match (&proxy, addr.socket_addr()) {
+			(Some(proxy), _) => socks5::connect(proxy, &addr.host.to_string(), addr.port, CONNECT_TIMEOUT),
+			(None, Some(socket_addr)) => TcpStream::connect_timeout(&socket_addr, CONNECT_TIMEOUT)
+				.map_err(|err| Err::NetworkError(err.to_string())),
+			(None, None) => Err(Err::NetworkError("no route".to_owned())),
+		};
+		let event = match result {
+			Ok(stream) => PeerEvent::Outbound(stream, addr, v2),
+			Err(err) => {
+				log_debug!("failed to connect to {}: {}", addr, err);
+				PeerEvent::OutboundFailed(addr)
+			},
+		};
+		let _ = send.send(event);
+	}
 
-		let mut i = 0;
-		for &b in self.target.as_bytes().iter().rev() {
-			if b != 0 {
-				assert!(i > 0);
-				if b >= 0x80 {
-					i -= 1;
+	// publishes an onion service forwarding to `target`, then holds the control
+	// connection open for as long as the service should live
+	fn tor_control_thread(control: SocketAddr, password: Option<String>, key_file: String, port: u16, target: SocketAddr, send: Sender<PeerEvent>) {
+		let result: Result<TorControl> = try {
+			let mut tor = TorControl::connect(control, CONNECT_TIMEOUT)?;
+			tor.authenticate(password.as_deref())?;
+			// reusing the key keeps the onion address the same across restarts
+			let saved_key = std::fs::read_to_string(&key_file).ok();
+			let (name, new_key) = tor.add_onion(saved_key.as_deref().map(str::trim), port, target)?;
+			if let Some(key) = new_key {
+				if let Err(err) = std::fs::write(&key_file, key) {
+					log_warn!("failed to save the onion service key to {}: {}", key_file, err);
 				}
-				break;
 			}
-			i += 1;
+			let _ = send.send(PeerEvent::OnionService(NetAddr::new(NetHost::parse(&name)?, port, 0, 0)));
+			tor
+		};
+		match result {
+			Ok(tor) => {
+				tor.wait_closed();
+				log_warn!("lost the tor control connection; the onion service is down");
+			},
+			Err(err) => log_error!("failed to publish an onion service: {}", err),
 		}
+	}
 
-		let trunc = (29 - i) * 8;
-
-		
-		// println!("new: {:x}", self.target);
-		// println!("trunc: {}", trunc);
-		
-		self.target = self.target >> trunc << trunc;
-		// println!("{:x}", self.target);
-		// println!("average time per block: {} minutes", (period_end - period_start) as f64 / 2016.0 / 60.0);
+	// a peer given on the command line, which may be a name rather than an address
+	fn connect_to(&self, addr: &str) -> Result<(TcpStream, Option<NetAddr>)> {
+		let target = NetAddr::parse(addr, self.state.params.default_port).ok();
+		let proxy = match &target {
+			Some(target) => self.route(&target.host)
+				.ok_or_else(|| Err::NetworkError(format!("no way to reach {}", addr)))?,
+			None => self.proxy.clone(),
+		};
+		match proxy {
+			Some(proxy) => {
+				let (host, port) = match addr.rsplit_once(':') {
+					Some((host, port)) if port.parse::<u16>().is_ok() => (host, port.parse().unwrap()),
+					_ => (addr, self.state.params.default_port),
+				};
+				let stream = socks5::connect(&proxy, host, port, CONNECT_TIMEOUT)?;
+				Ok((stream, target))
+			},
+			None => {
+				let stream = TcpStream::connect(addr).map_err(|err| Err::NetworkError(err.to_string()))?;
+				Ok((stream, None))
+			},
+		}
 	}
 
 	fn show_object<T, F>(id: String, f: F)
@@ -685,16 +4278,26 @@ impl Node {
 			let mut buf = String::new();
 			stdout.write(">> ".as_bytes()).unwrap();
 			stdout.flush().unwrap();
-			stdin.read_line(&mut buf).unwrap();
+			if stdin.read_line(&mut buf).unwrap() == 0 {
+				// stdin closed (e.g. running detached); keep serving peers without a console
+				break;
+			}
 			let tok: Vec<_> = buf.split_ascii_whitespace().collect();
 			
 			let result: Result<()> = try {
 				match *tok {
 					["help"] => {
 						println!("list of commands:");
-						println!("    exit\n    save\n    mempool\n    db");
+						println!("    exit\n    save\n    peers\n    mempool\n    db");
 						println!("    header <ID>\n    block <ID>\n    tx <ID>");
 						println!("    utxos\n    count db\n    count mempool");
+						println!("    setban <host> add [<seconds>]\n    setban <host> remove");
+						println!("    listbanned\n    clearbanned");
+						println!("    prioritisetransaction <ID> <satoshis>");
+						println!("    exportmempool <path>\n    importmempool <path>");
+						println!("    estimatesmartfee <blocks> [conservative|economical]");
+						println!("    getblocktemplate\n    getblocktemplate proposal <hex>\n    submitblock <hex>");
+						println!("    testmempoolaccept <hex>[,<hex>...]\n    submitpackage <hex>[,<hex>...]");
 					},
 					["exit"] => {
 						send_cmd.send(ApplicationMessage::Shutdown).or(Err(Err::ChannelError))?;
@@ -704,6 +4307,10 @@ impl Node {
 						send_cmd.send(ApplicationMessage::Save).or(Err(Err::ChannelError))?;
 						recv_cmd_done.recv().or(Err(Err::ChannelError))?;
 					},
+					["peers"] => {
+						send_cmd.send(ApplicationMessage::ShowPeers).or(Err(Err::ChannelError))?;
+						recv_cmd_done.recv().or(Err(Err::ChannelError))?;
+					},
 					["mempool"] => {
 						send_cmd.send(ApplicationMessage::ShowMempool).or(Err(Err::ChannelError))?;
 						recv_cmd_done.recv().or(Err(Err::ChannelError))?;
@@ -732,6 +4339,91 @@ impl Node {
 						send_cmd.send(ApplicationMessage::ShowTx(id.into())).or(Err(Err::ChannelError))?;
 						recv_cmd_done.recv().or(Err(Err::ChannelError))?;
 					},
+					["setban", host, "add"] => {
+						send_cmd.send(ApplicationMessage::SetBan(host.into(), None)).or(Err(Err::ChannelError))?;
+						recv_cmd_done.recv().or(Err(Err::ChannelError))?;
+					},
+					["setban", host, "add", seconds] => {
+						let seconds = match seconds.parse() {
+							Ok(seconds) => seconds,
+							Err(_) => {
+								println!("<invalid ban time>");
+								continue;
+							},
+						};
+						send_cmd.send(ApplicationMessage::SetBan(host.into(), Some(seconds))).or(Err(Err::ChannelError))?;
+						recv_cmd_done.recv().or(Err(Err::ChannelError))?;
+					},
+					["setban", host, "remove"] => {
+						send_cmd.send(ApplicationMessage::Unban(host.into())).or(Err(Err::ChannelError))?;
+						recv_cmd_done.recv().or(Err(Err::ChannelError))?;
+					},
+					["listbanned"] => {
+						send_cmd.send(ApplicationMessage::ListBanned).or(Err(Err::ChannelError))?;
+						recv_cmd_done.recv().or(Err(Err::ChannelError))?;
+					},
+					["clearbanned"] => {
+						send_cmd.send(ApplicationMessage::ClearBanned).or(Err(Err::ChannelError))?;
+						recv_cmd_done.recv().or(Err(Err::ChannelError))?;
+					},
+					["prioritisetransaction", id, delta] => {
+						let delta = match delta.parse() {
+							Ok(delta) => delta,
+							Err(_) => {
+								println!("<invalid fee delta>");
+								continue;
+							}
+						};
+						send_cmd.send(ApplicationMessage::Prioritise(id.into(), delta)).or(Err(Err::ChannelError))?;
+						recv_cmd_done.recv().or(Err(Err::ChannelError))?;
+					},
+					["exportmempool", path] => {
+						send_cmd.send(ApplicationMessage::ExportMempool(path.into())).or(Err(Err::ChannelError))?;
+						recv_cmd_done.recv().or(Err(Err::ChannelError))?;
+					},
+					["importmempool", path] => {
+						send_cmd.send(ApplicationMessage::ImportMempool(path.into())).or(Err(Err::ChannelError))?;
+						recv_cmd_done.recv().or(Err(Err::ChannelError))?;
+					},
+					["estimatesmartfee", target] | ["estimatesmartfee", target, _] => {
+						let target = match target.parse() {
+							Ok(target) if (1..=fees::MAX_TARGET).contains(&target) => target,
+							_ => {
+								println!("<invalid target, expected 1 to {}>", fees::MAX_TARGET);
+								continue;
+							},
+						};
+						let mode = match tok.get(2) {
+							None | Some(&"conservative") => EstimateMode::Conservative,
+							Some(&"economical") => EstimateMode::Economical,
+							Some(_) => {
+								println!("<invalid estimate mode>");
+								continue;
+							},
+						};
+						send_cmd.send(ApplicationMessage::EstimateSmartFee(target, mode)).or(Err(Err::ChannelError))?;
+						recv_cmd_done.recv().or(Err(Err::ChannelError))?;
+					},
+					["getblocktemplate"] => {
+						send_cmd.send(ApplicationMessage::GetBlockTemplate).or(Err(Err::ChannelError))?;
+						recv_cmd_done.recv().or(Err(Err::ChannelError))?;
+					},
+					["getblocktemplate", "proposal", hex] => {
+						send_cmd.send(ApplicationMessage::ProposeBlock(hex.into())).or(Err(Err::ChannelError))?;
+						recv_cmd_done.recv().or(Err(Err::ChannelError))?;
+					},
+					["submitblock", hex] => {
+						send_cmd.send(ApplicationMessage::SubmitBlock(hex.into())).or(Err(Err::ChannelError))?;
+						recv_cmd_done.recv().or(Err(Err::ChannelError))?;
+					},
+					["testmempoolaccept", hexes] => {
+						send_cmd.send(ApplicationMessage::TestMempoolAccept(hexes.into())).or(Err(Err::ChannelError))?;
+						recv_cmd_done.recv().or(Err(Err::ChannelError))?;
+					},
+					["submitpackage", hexes] => {
+						send_cmd.send(ApplicationMessage::SubmitPackage(hexes.into())).or(Err(Err::ChannelError))?;
+						recv_cmd_done.recv().or(Err(Err::ChannelError))?;
+					},
 					[] => { continue; },
 					_ => {
 						println!("<invalid command>");
@@ -749,11 +4441,16 @@ impl Node {
 
 	fn message_thread(&mut self, recv_cmd: mpsc::Receiver<ApplicationMessage>, send_cmd_done: mpsc::Sender<()>) {
 		loop {
-			if let Ok((i, m)) = self.recv.as_ref().unwrap().try_recv() {
-				if let Err(e) = self.handle_message(i, m) {
-					log_error!("{}", e);
-				}
+			self.poll_network(POLL_INTERVAL);
+			while let Ok(event) = self.recv.as_ref().unwrap().try_recv() {
+				self.handle_peer_event(event);
 			}
+			self.send_announcements();
+			self.send_pings();
+			self.send_feefilters();
+			self.check_timeouts();
+			self.request_next_blocks();
+			self.open_outbound_connections();
 			if let Ok(m) = recv_cmd.try_recv() {
 				match m {
 					ApplicationMessage::Shutdown => {		
@@ -767,12 +4464,28 @@ impl Node {
 						println!("state saved!");
 						send_cmd_done.send(()).unwrap();
 					},
+					ApplicationMessage::ShowPeers => {
+						if self.peers.is_empty() {
+							println!("<none>");
+						}
+						let mut ids = self.peers.keys().copied().collect::<Vec<_>>();
+						ids.sort();
+						for id in ids {
+							let peer = &self.peers[&id];
+							let ping = peer.ping_time.map(|t| format!(" ping {} ms", t.as_millis())).unwrap_or_default();
+							println!("{:4}: {} {}{}{}{}", id, if peer.inbound { "in " } else { "out" }, peer.addr,
+								if peer.conn.is_v2() { " v2" } else { "" },
+								if peer.handshake_complete { "" } else { " (connecting)" }, ping);
+						}
+					},
 					ApplicationMessage::ShowMempool => {
 						if self.mempool.txs.len() == 0 {
 							println!("<empty>");
 						}
-						for id in self.mempool.txs.keys() {
-							println!("{}", id);
+						for id in self.mempool.by_ancestor_feerate() {
+							let entry = &self.mempool.entries[&id];
+							println!("{} {} sat/kvB ({} with {} ancestors), {} descendants", id, feerate(entry.fee, entry.vsize),
+								entry.ancestor_feerate(), entry.ancestor_count - 1, entry.descendant_count - 1);
 						}
 					},
 					ApplicationMessage::ShowBlockHashes => {
@@ -795,21 +4508,127 @@ impl Node {
 					ApplicationMessage::ShowTx(id) => {
 						Node::show_object(id, |id| self.mempool.txs.get(&id).map(|e| e.clone()));
 					},
+					ApplicationMessage::SetBan(host, seconds) => {
+						match NetHost::parse(&host) {
+							Ok(host) => self.set_ban(host, seconds.unwrap_or(DEFAULT_BAN_TIME)),
+							Err(err) => println!("{}", err),
+						}
+					},
+					ApplicationMessage::Unban(host) => {
+						match NetHost::parse(&host) {
+							Ok(host) if self.banlist.unban(&host) => {},
+							Ok(_) => println!("<not banned>"),
+							Err(err) => println!("{}", err),
+						}
+					},
+					ApplicationMessage::ListBanned => {
+						let now = common::now();
+						let entries = self.banlist.entries().into_iter()
+							.filter(|(_, entry)| entry.until > now)
+							.collect::<Vec<_>>();
+						if entries.is_empty() {
+							println!("<none>");
+						}
+						for (host, entry) in entries {
+							println!("{} (banned {}s ago, {}s left)", host, now.saturating_sub(entry.created), entry.until - now);
+						}
+					},
+					ApplicationMessage::ClearBanned => {
+						self.banlist.clear();
+					},
+					ApplicationMessage::Prioritise(id, delta) => {
+						match Sha256::try_from(id.as_str()) {
+							Ok(id) => if let Err(err) = self.mempool.prioritise(id, delta) {
+								println!("{}", err);
+							},
+							Err(_) => println!("<invalid ID>"),
+						}
+					},
+					ApplicationMessage::ExportMempool(path) => {
+						match self.save_mempool(&path) {
+							Ok(()) => println!("exported {} txs", self.mempool.txs.len()),
+							Err(err) => println!("{}", err),
+						}
+					},
+					ApplicationMessage::ImportMempool(path) => {
+						match self.load_mempool(&path) {
+							Ok((accepted, failed)) => println!("imported {} txs ({} failed or expired)", accepted, failed),
+							Err(err) => println!("{}", err),
+						}
+					},
+					ApplicationMessage::EstimateSmartFee(target, mode) => {
+						match self.fee_estimator.estimate_smart_fee(target, mode) {
+							// no use paying less than it takes to get into the mempool
+							Some((feerate, blocks)) => {
+								let feerate = std::cmp::max(feerate, self.mempool.min_feerate());
+								println!("feerate: {} sat/kvB, blocks: {}", feerate, blocks);
+							},
+							None => println!("<insufficient data>"),
+						}
+					},
+					ApplicationMessage::GetBlockTemplate => {
+						match self.create_block_template() {
+							Ok(template) => println!("{}", template.to_json()),
+							Err(err) => println!("{}", err),
+						}
+					},
+					// BIP 22 results: nothing once the block's accepted, otherwise why not
+					ApplicationMessage::ProposeBlock(hex) => {
+						match Node::parse_block(&hex).and_then(|block| self.check_block_proposal(&block)) {
+							Ok(()) => println!("null"),
+							Err(err) => println!("{}", err),
+						}
+					},
+					ApplicationMessage::SubmitBlock(hex) => {
+						match Node::parse_block(&hex).and_then(|block| self.submit_block(block)) {
+							Ok(()) => println!("null"),
+							Err(err) => println!("{}", err),
+						}
+					},
+					// the same as submitpackage, against a copy of the mempool
+					// that's put back after
+					ApplicationMessage::TestMempoolAccept(hexes) => {
+						match Node::parse_txs(&hexes).and_then(|txs| self.test_mempool_accept(txs)) {
+							Ok(results) => for (id, result) in results {
+								match result {
+									Ok(()) => println!("{}: allowed", id),
+									Err(err) => println!("{}: {}", id, err),
+								}
+							},
+							Err(err) => println!("{}", err),
+						}
+					},
+					ApplicationMessage::SubmitPackage(hexes) => {
+						match Node::parse_txs(&hexes).and_then(|txs| self.submit_package(txs)) {
+							Ok(results) => for (id, result) in results {
+								match result {
+									Ok(()) => println!("{}: accepted", id),
+									Err(err) => println!("{}: {}", id, err),
+								}
+							},
+							Err(err) => println!("{}", err),
+						}
+					},
 				}
 				send_cmd_done.send(()).unwrap();
 			}
-			
-			thread::yield_now();
 		}
 	}
 
 	pub fn rebuild_utxo_set(mut self) -> Result<()> {
 		self.utxos = BTreeMap::new();
-		for (i, hash) in self.block_db.hashes.iter().enumerate() {
+		self.coin_heights = HashMap::new();
+		self.filter_index.truncate(1);
+		for (i, hash) in self.block_db.hashes.clone().iter().enumerate() {
 			let block = self.block_db.load_block(hash).unwrap();
 			self.state.set_height(i);
 			let diff = block.build_utxo_diff(&mut self.utxos);
+			if let Some(filter) = self.block_filter(i, &block, &diff) {
+				self.filter_index.push(hash, filter)?;
+			}
+			self.block_db.store_undo(hash, &self.undo_data(&diff))?;
 			diff.apply(&mut self.utxos);
+			self.coin_heights.extend(block.txs.iter().map(|tx| (tx.compute_hash(), i)));
 			log_info!("validated block {:010}: {}", i, hash);
 		}
 
@@ -818,52 +4637,86 @@ impl Node {
 		Ok(())
 	}
 	
-	pub fn run(mut self, addrs: Vec<String>) -> Result<()> {
-		let (send_to_parent, recv) = mpsc::channel();
+	pub fn run(mut self, options: Options) -> Result<()> {
+		let (send, recv) = mpsc::channel();
+		self.send = Some(send);
 		self.recv = Some(recv);
 		self.peers = HashMap::new();
 
-		for (i, addr) in addrs.iter().enumerate() {
+		let max_connections = options.max_connections.unwrap_or(DEFAULT_MAX_CONNECTIONS);
+		self.max_outbound = std::cmp::min(max_connections, MAX_OUTBOUND_CONNECTIONS);
+		self.max_inbound = max_connections - self.max_outbound;
+		if options.peer_bloom_filters {
+			self.services |= services::NODE_BLOOM;
+		}
+		if options.peer_block_filters {
+			self.services |= services::NODE_COMPACT_FILTERS;
+		}
+		if options.v2_transport {
+			self.services |= services::NODE_P2P_V2;
+		}
+		self.full_rbf = options.mempool_full_rbf;
+		if options.accept_non_std_txn && self.state.params.network == Network::Main {
+			return Err(Err::ValueError("-acceptnonstdtxn is only for test networks".to_owned()));
+		}
+		self.require_standard = !options.accept_non_std_txn;
+		// in MB and hours, like bitcoind
+		self.mempool.max_size = options.max_mempool.unwrap_or(DEFAULT_MAX_MEMPOOL) * 1_000_000;
+		self.mempool.expiry = options.mempool_expiry.unwrap_or(DEFAULT_MEMPOOL_EXPIRY) * 60 * 60;
+		let mempool_path = format!("{}/mempool.dat", self.state.params.data_dir);
+		if std::path::Path::new(&mempool_path).is_file() {
+			match self.load_mempool(&mempool_path) {
+				Ok((accepted, failed)) => log_info!("loaded {} mempool txs ({} failed or expired)", accepted, failed),
+				Err(err) => log_error!("warning: failed to load mempool.dat: {}", err),
+			}
+		}
+
+		let parse_proxy = |addr: &str| -> Result<Proxy> {
+			let addr = addr.to_socket_addrs().ok().and_then(|mut addrs| addrs.next())
+				.ok_or_else(|| Err::ValueError(format!("invalid proxy address: {}", addr)))?;
+			Ok(Proxy { addr, randomize_credentials: options.proxy_randomize })
+		};
+		self.proxy = options.proxy.as_deref().map(parse_proxy).transpose()?;
+		self.onion_proxy = match options.onion_proxy.as_deref() {
+			Some(addr) => Some(parse_proxy(addr)?),
+			None => self.proxy.clone(),
+		};
+
+		// seed nodes only go into the address manager; the connections
+		// get made from there like any others
+		for seed in options.seed_nodes.iter() {
+			match NetAddr::parse(seed, self.state.params.default_port) {
+				Ok(addr) => {
+					self.addrman.add(addr.clone(), addr.host, 0);
+				},
+				Err(e) => log_warn!("ignoring -seednode: {}", e),
+			}
+		}
+
+		for addr in options.addrs.iter() {
+			if self.outbound_count() >= self.max_outbound {
+				log_warn!("outbound slots full; not connecting to: {}", addr);
+				continue;
+			}
 			log_info!("trying to connect to: {}", addr);
-			let writer = match TcpStream::connect(addr.clone()) {
-				Ok(stream) => stream,
+			let (writer, target) = match self.connect_to(addr) {
+				Ok(result) => result,
 				Err(e) => {
 					log_error!("failed to connect to peer: {}: {}", addr, e.to_string());
 					continue;
 				}
 			};
 
-			let mut reader = writer.try_clone().unwrap();
-			let send_to_parent = send_to_parent.clone();
-			
-			thread::spawn(move || {
-				loop {
-					match reader.receive() {
-						Ok(Some(message)) => {
-							if let Err(_) = send_to_parent.send((i, message)) {
-								break;
-							}
-						},
-						Ok(None) => {
-							break;
-						},
-						Err(err) => {
-							log_error!("error: {}", err.to_string());
-							break;
-						},
+			match self.add_peer(writer, addr.clone(), false, self.services & services::NODE_P2P_V2 != 0) {
+				Ok(id) => {
+					// through a proxy, the socket's peer is the proxy
+					if let Some(target) = target {
+						self.peers.get_mut(&id).unwrap().endpoint = Some((target.host, target.port));
 					}
-				}
-			});
-			
-			self.peers.insert(i, PeerHandle {
-				writer,
-				addr: addr.clone(),
-				info: None,
-				handshake_complete: false,
-				config: Config::default(),
-			});
-
-			log_info!("Connected to: {}", addr);
+					log_info!("Connected to: {}", addr)
+				},
+				Err(e) => log_error!("failed to connect to peer: {}: {}", addr, e),
+			}
 		}
 
 		if self.peers.len() == 0 {
@@ -873,11 +4726,45 @@ impl Node {
 		log_debug!("{} peers conntected.", self.peers.len());
 
 		for (i, peer) in self.peers.iter_mut() {
-			if let Err(e) = peer.writer.send(Message::version(peer.addr.clone())) {
+			if let Err(e) = peer.conn.send(Message::version(peer.version_addr(), self.services)) {
 				log_error!("peer {}: error: {}", i, e);
 			}
 		}
-		
+
+		if !options.nolisten && self.max_inbound > 0 {
+			let bind = options.bind.clone()
+				.unwrap_or_else(|| format!("0.0.0.0:{}", self.state.params.default_port));
+			match TcpListener::bind(&bind).and_then(|listener| listener.set_nonblocking(true).map(|_| listener)) {
+				Ok(listener) => {
+					log_info!("listening for connections on: {}", bind);
+					self.listener = Some(listener);
+				},
+				Err(e) => {
+					log_error!("failed to listen on {}: {}", bind, e.to_string());
+				},
+			}
+		}
+
+		if options.listen_onion {
+			let control = options.tor_control.as_deref().unwrap_or(DEFAULT_TOR_CONTROL);
+			let control = control.to_socket_addrs().ok().and_then(|mut addrs| addrs.next());
+			match (self.listener.as_ref().and_then(|listener| listener.local_addr().ok()), control) {
+				(Some(mut target), Some(control)) => {
+					// tor forwards to us over loopback
+					if target.ip().is_unspecified() {
+						target.set_ip(Ipv4Addr::LOCALHOST.into());
+					}
+					let password = options.tor_password.clone();
+					let key_file = format!("{}/onion_v3_private_key", self.state.params.data_dir);
+					let port = self.state.params.default_port;
+					let send = self.send.as_ref().unwrap().clone();
+					thread::spawn(move || Node::tor_control_thread(control, password, key_file, port, target, send));
+				},
+				(None, _) => log_warn!("-listenonion needs a listening socket; not publishing an onion service"),
+				(_, None) => log_error!("invalid -torcontrol address"),
+			}
+		}
+
 		let (send_cmd, recv_cmd) = mpsc::channel();
 		let (send_cmd_done, recv_cmd_done) = mpsc::channel();
 		
@@ -893,13 +4780,637 @@ impl Node {
 	}
 }
 
+fn sign_signet_block(params: &ConsensusParams, keys: &str, block: &str) -> Result<()> {
+	use crypto::ecdsa::ECDSAPrivKey;
+
+	let keys = keys.split(',')
+		.map(ECDSAPrivKey::try_from)
+		.collect::<Result<Vec<_>>>()?;
+	let mut block = Block::deserialize(&mut &*common::hex_to_bytes(block.trim())?)?;
+	let challenge = params.signet_challenge.as_ref().unwrap();
+
+	signet::sign_block(&mut block, challenge, &keys)?;
+	signet::mine(&mut block)?;
+
+	println!("{}", common::bytes_to_hex(&common::serialize(&block)?));
+	Ok(())
+}
+
 fn main() -> Result<()> {
-	let addrs = std::env::args().skip(1).collect();
-	if addrs == vec!["--rebuild-utxos"] {
-		let node = Node::new(false);
+	let options = Options::parse(std::env::args().skip(1))?;
+	let params = ConsensusParams::from_options(&options)?;
+	network::message::set_magic(params.magic);
+	if params.network != Network::Main {
+		log_info!("using network: {}", params.network);
+	}
+
+	if let Some((keys, block)) = &options.signet_sign {
+		sign_signet_block(&params, keys, block)
+	} else if options.rebuild_utxos {
+		let node = Node::new(params, false);
 		node.rebuild_utxo_set()
 	} else {
-		let node = Node::new(true);
-		node.run(addrs)
+		let node = Node::new(params, true);
+		node.run(options)
+	}
+}
+
+// blocks of an OP_TRUE signet chain: block 1; on it a block 2 whose coinbase
+// claims too much, with a block 3 on top; a valid block 2; a block 2 with a tx
+// spending TEST_COIN, a made up coin; and a block 2 and 3 without that tx
+#[cfg(test)]
+const TEST_BLOCKS: [&str; 7] = [
+	"00000020f61eee3b63a380a477a063af32b2bbc97c9ff9f01f2c4225e9739881080000004938d53148bd15fc2f62ccc1bc142f055addad04dd46385ae21787c80d3f31ee58914d5fae77031e5a34370001010000000001010000000000000000000000000000000000000000000000000000000000000000ffffffff03010100ffffffff0200f2052a01000000015100000000000000002d6a24aa21a9ede2f61c3f71d1defd3fa999dfa36953755c690689799962b48bebd836974e8cf906ecc7daa200000120000000000000000000000000000000000000000000000000000000000000000000000000",
+	"00000020cb2d901ed975c69f48eecdd5efc0c27a719e459cc9bc400492a70b7dd3020000fc4a810ca37befda491a8068d693ce79bc684febfc8bb49904deb8caa701c5cbb0934d5fae77031ee8d5300001010000000001010000000000000000000000000000000000000000000000000000000000000000ffffffff03010200ffffffff0200d3fb2f01000000015100000000000000002d6a24aa21a9ede2f61c3f71d1defd3fa999dfa36953755c690689799962b48bebd836974e8cf906ecc7daa200000120000000000000000000000000000000000000000000000000000000000000000000000000",
+	"00000020a1d71ae459ebdc26b0f72434ec77f34f199dce449bd6ed31a2aa0eee3f020000b87bda4dad92b10cf78f3e940817f2bf388bcfcab1ef9cf6dd2c753ec2daa2b308964d5fae77031ed7eddf0001010000000001010000000000000000000000000000000000000000000000000000000000000000ffffffff03010300ffffffff0200f2052a01000000015100000000000000002d6a24aa21a9ede2f61c3f71d1defd3fa999dfa36953755c690689799962b48bebd836974e8cf906ecc7daa200000120000000000000000000000000000000000000000000000000000000000000000000000000",
+	"00000020cb2d901ed975c69f48eecdd5efc0c27a719e459cc9bc400492a70b7dd3020000938b432616183103f863dd1572663eded39426bd3bcd073ec5faeee5a38494b3b1934d5fae77031e8389140101010000000001010000000000000000000000000000000000000000000000000000000000000000ffffffff03010200ffffffff0200f2052a01000000015100000000000000002d6a24aa21a9ede2f61c3f71d1defd3fa999dfa36953755c690689799962b48bebd836974e8cf906ecc7daa200000120000000000000000000000000000000000000000000000000000000000000000000000000",
+	"00000020cb2d901ed975c69f48eecdd5efc0c27a719e459cc9bc400492a70b7dd3020000b588a81dead8802d2bcad6929cccdadbe175f8ac317827a1623877d8fa49b1dbb0934d5fae77031ed610000002010000000001010000000000000000000000000000000000000000000000000000000000000000ffffffff03010200ffffffff0200f2052a01000000015100000000000000002d6a24aa21a9edfc008579525ed1cacc10102f072347c1c114d5bec45d87d9e9bf6832347905b506ecc7daa200000120000000000000000000000000000000000000000000000000000000000000000000000000010000000111111111111111111111111111111111111111111111111111111111111111110000000000ffffffff01f0ca052a01000000015100000000",
+	"00000020cb2d901ed975c69f48eecdd5efc0c27a719e459cc9bc400492a70b7dd3020000938b432616183103f863dd1572663eded39426bd3bcd073ec5faeee5a38494b3b2934d5fae77031ed4b97a0001010000000001010000000000000000000000000000000000000000000000000000000000000000ffffffff03010200ffffffff0200f2052a01000000015100000000000000002d6a24aa21a9ede2f61c3f71d1defd3fa999dfa36953755c690689799962b48bebd836974e8cf906ecc7daa200000120000000000000000000000000000000000000000000000000000000000000000000000000",
+	"0000002003dbe139302f9c0a7d12010c9d0ebdd71a5d47d3036f40237871f56c1a010000b87bda4dad92b10cf78f3e940817f2bf388bcfcab1ef9cf6dd2c753ec2daa2b308964d5fae77031e3074470001010000000001010000000000000000000000000000000000000000000000000000000000000000ffffffff03010300ffffffff0200f2052a01000000015100000000000000002d6a24aa21a9ede2f61c3f71d1defd3fa999dfa36953755c690689799962b48bebd836974e8cf906ecc7daa200000120000000000000000000000000000000000000000000000000000000000000000000000000",
+];
+#[cfg(test)]
+const TEST_COIN: [u8; 32] = [0x11; 32];
+
+#[cfg(test)]
+fn test_block(i: usize) -> Block {
+	Block::deserialize(&mut &*common::hex_to_bytes(TEST_BLOCKS[i]).unwrap()).unwrap()
+}
+
+#[cfg(test)]
+fn test_headers(blocks: &[usize]) -> Headers {
+	Headers::new(blocks.iter().map(|&i| test_block(i).header).collect())
+}
+
+// a node on the OP_TRUE signet chain, in a data dir of its own that goes when it does
+#[cfg(test)]
+struct TestNode {
+	node: Node,
+	dir: std::path::PathBuf,
+}
+
+#[cfg(test)]
+impl TestNode {
+	fn new() -> TestNode {
+		let dir = std::env::temp_dir().join(format!("frabjous-test-{}", rand::random::<u64>()));
+		let mut params = ConsensusParams::signet(Script::from(vec![0x51]));
+		params.data_dir = Box::leak(dir.to_str().unwrap().to_owned().into_boxed_str());
+		TestNode { node: Node::new(params, false), dir }
+	}
+}
+
+#[cfg(test)]
+impl std::ops::Deref for TestNode {
+	type Target = Node;
+	fn deref(&self) -> &Node {
+		&self.node
+	}
+}
+
+#[cfg(test)]
+impl std::ops::DerefMut for TestNode {
+	fn deref_mut(&mut self) -> &mut Node {
+		&mut self.node
+	}
+}
+
+#[cfg(test)]
+impl Drop for TestNode {
+	fn drop(&mut self) {
+		let _ = std::fs::remove_dir_all(&self.dir);
+	}
+}
+
+#[test]
+fn invalid_block_rollback() {
+	let hashes = [0, 1, 3].map(|i| test_block(i).header.compute_hash());
+	let mut node = TestNode::new();
+	node.handle_headers_message(0, test_headers(&[0, 1, 2])).unwrap();
+	assert_eq!(node.block_db.hashes.len(), 4);
+	for i in 0..3 {
+		node.handle_block_message(0, test_block(i)).unwrap();
+	}
+	// back to block 1, with 2 and everything after it refused from then on
+	assert_eq!(node.block_db.hashes, [node.block_db.hashes[0], hashes[0]]);
+	assert_eq!(node.block_db.blocks_validated, 2);
+	assert!(node.handle_headers_message(0, test_headers(&[2])).is_err());
+
+	// the valid block 2 takes its place
+	node.handle_headers_message(0, test_headers(&[3])).unwrap();
+	node.handle_block_message(0, test_block(3)).unwrap();
+	assert_eq!(node.block_db.hashes[1..], [hashes[0], hashes[2]]);
+	assert_eq!(node.block_db.blocks_validated, 3);
+}
+
+// a node at block 2 with the tx in it, the tx and the coin it spends
+#[cfg(test)]
+fn reorg_node() -> (TestNode, Tx, UTXOID) {
+	let mut node = TestNode::new();
+	node.require_standard = false;
+	let coin = UTXOID(Sha256::from(TEST_COIN), 0);
+	node.utxos.insert(coin.clone(), TxOutput { value: 50 * 100_000_000, lock: Script::from(vec![0x51]) });
+	node.handle_headers_message(0, test_headers(&[0, 4])).unwrap();
+	node.handle_block_message(0, test_block(0)).unwrap();
+	node.handle_block_message(0, test_block(4)).unwrap();
+	assert_eq!(node.block_db.blocks_validated, 3);
+	assert!(!node.utxos.contains_key(&coin) && node.mempool.txs.is_empty());
+	(node, test_block(4).txs.pop().unwrap(), coin)
+}
+
+#[test]
+fn reorg() {
+	let (mut node, tx, coin) = reorg_node();
+	let id = tx.compute_hash();
+	let tip = node.block_db.hashes.clone();
+	let hashes = [5, 6].map(|i| test_block(i).header.compute_hash());
+
+	// the branch's headers alone change nothing, nor does the first of its
+	// blocks, which only brings it level with our chain
+	node.handle_headers_message(0, test_headers(&[5, 6])).unwrap();
+	assert!(node.branch.as_ref().is_some_and(|branch| branch.more_work));
+	node.handle_block_message(0, test_block(5)).unwrap();
+	assert_eq!(node.block_db.hashes, tip);
+	assert_eq!(node.block_db.blocks_validated, 3);
+
+	// with both, block 2 is disconnected and the branch connected. the coin is
+	// spent again, by the tx back in the mempool
+	node.handle_block_message(0, test_block(6)).unwrap();
+	assert_eq!(node.block_db.hashes[1..], [tip[1], hashes[0], hashes[1]]);
+	assert_eq!(node.block_db.blocks_validated, 4);
+	assert!(node.branch.is_none());
+	assert!(node.utxos.contains_key(&coin) && node.mempool.txs.contains_key(&id));
+}
+
+#[test]
+fn reorg_refused() {
+	let (mut node, tx, coin) = reorg_node();
+	let tip = node.block_db.hashes.clone();
+
+	// a branch with no more work than ours is let go once the peer's sent all of it
+	node.handle_headers_message(0, test_headers(&[5])).unwrap();
+	assert!(node.branch.is_none());
+	node.handle_block_message(0, test_block(5)).unwrap();
+	assert_eq!(node.block_db.blocks_validated, 3);
+
+	// as is one whose header claims an easier target than it should
+	let mut header = test_block(5).header;
+	header.bits = 0x207fffff;
+	assert!(node.handle_headers_message(0, Headers::new(vec![header])).is_err());
+	assert!(node.branch.is_none());
+
+	// one with more work that turns out invalid gets as far as being connected,
+	// then we're back on our chain with the tx confirmed in it
+	node.handle_headers_message(0, test_headers(&[1, 2])).unwrap();
+	node.handle_block_message(0, test_block(1)).unwrap();
+	node.handle_block_message(0, test_block(2)).unwrap();
+	assert_eq!(node.block_db.hashes, tip);
+	assert_eq!(node.block_db.blocks_validated, 3);
+	assert!(node.branch.is_none() && node.invalid_blocks.contains(&test_block(1).header.compute_hash()));
+	assert!(!node.utxos.contains_key(&coin) && node.utxos.contains_key(&UTXOID(tx.compute_hash(), 0)));
+	assert!(node.mempool.txs.is_empty());
+}
+
+#[test]
+fn witness_spend_refused() {
+	// a P2WPKH coin, spent with a witness that signs nothing
+	let mut node = TestNode::new();
+	let coin = UTXOID(Sha256::from(TEST_COIN), 0);
+	let lock = Script::from([&[0x00, 0x14][..], &[0x22; 20]].concat());
+	node.utxos.insert(coin.clone(), TxOutput { value: 100_000, lock: lock.clone() });
+	let mut tx = Tx::spending(&[(coin.0, coin.1)]);
+	tx.outputs[0].lock = lock;
+	tx.segwit = true;
+	tx.inputs[0].witness = vec![vec![0x30; 72], vec![0x02; 33]];
+	assert!(node.accept_to_mempool(tx.compute_hash(), tx).is_err());
+	assert!(node.mempool.txs.is_empty());
+}
+
+#[test]
+fn submitted_block_witnesses() {
+	let template = |txs: Vec<Tx>| BlockTemplate::new(Sha256::from([2; 32]), 1000, 0x1d00ffff, 1000, 900,
+		txs.into_iter().map(|tx| TemplateTx { tx, fee: 0, sigops: 0 }).collect(), None).block;
+	let tx = Tx::spending(&[(Sha256::from(TEST_COIN), 0)]);
+	assert!(Node::check_block_witnesses(&template(vec![tx.clone()])).is_ok());
+
+	// witness data in a tx, and a commitment to txs other than the block's
+	let mut witness_tx = tx.clone();
+	witness_tx.segwit = true;
+	witness_tx.inputs[0].witness = vec![vec![1; 72]];
+	assert!(Node::check_block_witnesses(&template(vec![witness_tx])).is_err());
+	let mut block = template(vec![tx.clone()]);
+	block.txs.push(tx);
+	block.update_merkle_root();
+	assert!(Node::check_block_witnesses(&block).is_err());
+}
+
+// a version 2 tx spending `outpoints` into `outputs` 1000 sat OP_TRUE outputs
+#[cfg(test)]
+fn test_tx(outpoints: &[(Sha256, u32)], outputs: usize) -> Tx {
+	let mut tx = Tx::spending(outpoints);
+	tx.outputs = vec![tx.outputs[0].clone(); outputs];
+	tx
+}
+
+#[test]
+fn package_limits() {
+	// a chain of three, then a second child of the first
+	let mut mempool = Mempool::new();
+	let a = test_tx(&[(Sha256::from(TEST_COIN), 0)], 2);
+	let (a_id, vsize) = (a.compute_hash(), a.vsize());
+	let b = test_tx(&[(a_id, 0)], 1);
+	let b_id = b.compute_hash();
+	let c = test_tx(&[(b_id, 0)], 1);
+	let c_id = c.compute_hash();
+	let d = test_tx(&[(a_id, 1)], 1);
+	let d_id = d.compute_hash();
+	let (b_vsize, c_vsize, d_vsize) = (b.vsize(), c.vsize(), d.vsize());
+	mempool.add_tx(a_id, a, 100, 0);
+	mempool.add_tx(b_id, b, 200, 0);
+	mempool.add_tx(c_id, c, 400, 0);
+	mempool.add_tx(d_id, d, 800, 0);
+	let totals = |mempool: &Mempool, id| {
+		let entry: &MempoolEntry = &mempool.entries[id];
+		(entry.ancestor_count, entry.ancestor_size, entry.ancestor_fee,
+			entry.descendant_count, entry.descendant_size, entry.descendant_fee)
+	};
+	assert_eq!(totals(&mempool, &a_id), (1, vsize, 100, 4, vsize + b_vsize + c_vsize + d_vsize, 1500));
+	assert_eq!(totals(&mempool, &b_id), (2, vsize + b_vsize, 300, 2, b_vsize + c_vsize, 600));
+	assert_eq!(totals(&mempool, &c_id), (3, vsize + b_vsize + c_vsize, 700, 1, c_vsize, 400));
+
+	// with b and what spends it gone, only a and d are left to count
+	assert_eq!(mempool.remove_with_descendants(&b_id), 2);
+	assert_eq!(totals(&mempool, &a_id), (1, vsize, 100, 2, vsize + d_vsize, 900));
+	assert_eq!(totals(&mempool, &d_id), (2, vsize + d_vsize, 900, 1, d_vsize, 800));
+	// as if a were confirmed
+	mempool.remove_tx(&a_id);
+	assert_eq!(totals(&mempool, &d_id), (1, d_vsize, 800, 1, d_vsize, 800));
+	assert!(mempool.entries[&d_id].parents.is_empty());
+
+	// 25 in a row is as long as a chain gets
+	let mut mempool = Mempool::new();
+	let mut prev = (Sha256::from(TEST_COIN), 0);
+	for _ in 0..25 {
+		let tx = test_tx(&[prev], 1);
+		mempool.check_limits(&tx).unwrap();
+		prev = (tx.compute_hash(), 0);
+		mempool.add_tx(prev.0, tx, 1000, 0);
+	}
+	assert!(mempool.check_limits(&test_tx(&[prev], 1)).is_err());
+
+	// and 25 is as many as a tx and its descendants can be: the 26th is refused
+	let mut mempool = Mempool::new();
+	let parent = test_tx(&[(Sha256::from(TEST_COIN), 0)], 26);
+	let parent_id = parent.compute_hash();
+	mempool.add_tx(parent_id, parent, 1000, 0);
+	for i in 0..24 {
+		let tx = test_tx(&[(parent_id, i)], 1);
+		mempool.check_limits(&tx).unwrap();
+		mempool.add_tx(tx.compute_hash(), tx, 1000, 0);
+	}
+	assert_eq!(mempool.entries[&parent_id].descendant_count, 25);
+	assert!(mempool.check_limits(&test_tx(&[(parent_id, 25)], 1)).is_err());
+
+	// 101 kvB to a tx and its ancestors, and likewise its descendants
+	let mut mempool = Mempool::new();
+	let mut parent = test_tx(&[(Sha256::from(TEST_COIN), 0)], 2);
+	parent.outputs[1].lock = Script::from(vec![0x6a; 100_800]);
+	let parent_id = parent.compute_hash();
+	let parent_vsize = parent.vsize();
+	mempool.add_tx(parent_id, parent, 1000, 0);
+	let child = test_tx(&[(parent_id, 0)], 1);
+	assert!(parent_vsize + child.vsize() <= MAX_ANCESTOR_SIZE);
+	mempool.check_limits(&child).unwrap();
+	let mut big_child = test_tx(&[(parent_id, 0)], 1);
+	big_child.outputs[0].lock = Script::from(vec![0x6a; MAX_ANCESTOR_SIZE - parent_vsize]);
+	assert!(mempool.check_limits(&big_child).is_err());
+	// a sibling's size counts against their parent's descendants
+	let child_id = child.compute_hash();
+	mempool.add_tx(child_id, child, 1000, 0);
+	let mut sibling = test_tx(&[(parent_id, 1)], 1);
+	sibling.outputs[0].lock = Script::from(vec![0x6a; MAX_ANCESTOR_SIZE - parent_vsize - 100]);
+	assert!(parent_vsize + sibling.vsize() <= MAX_ANCESTOR_SIZE);
+	assert!(mempool.check_limits(&sibling).is_err());
+}
+
+#[test]
+fn replacement() {
+	let coin = (Sha256::from(TEST_COIN), 0);
+	let mut mempool = Mempool::new();
+	let mut original = test_tx(&[coin], 2);
+	original.inputs[0].sequence = 0xffff_fffd;
+	let id = original.compute_hash();
+	mempool.add_tx(id, original, 1000, 0);
+	let conflicts = HashSet::from([id]);
+	// told apart from one another by what they pay out
+	let replacement = |outputs: usize, value: u64| {
+		let mut tx = test_tx(&[coin], outputs);
+		tx.outputs[0].value = value;
+		tx
+	};
+	let tx = replacement(2, 1);
+	let vsize = tx.vsize() as u64;
+
+	// a higher feerate than the original's, and on top of its fee, the
+	// incremental relay fee for the replacement's size
+	assert!(mempool.check_replacement(&tx, 1000, &conflicts, false).is_err());
+	assert!(mempool.check_replacement(&tx, 1000 + vsize - 1, &conflicts, false).is_err());
+	assert!(mempool.check_replacement(&tx, 1000 + vsize, &conflicts, false).unwrap() == conflicts);
+	// a bigger one can pay more in all and still fall short on feerate
+	let big = replacement(10, 1);
+	assert!(mempool.check_replacement(&big, 1000 + big.vsize() as u64, &conflicts, false).is_err());
+
+	// a child of the original goes too, and has to be paid for
+	let child = test_tx(&[(id, 0)], 98);
+	let child_id = child.compute_hash();
+	mempool.add_tx(child_id, child, 3000, 0);
+	assert!(mempool.check_replacement(&tx, 1000 + vsize, &conflicts, false).is_err());
+	assert!(mempool.check_replacement(&tx, 4000 + vsize, &conflicts, false).unwrap() == HashSet::from([id, child_id]));
+
+	// up to 100 txs can be evicted, the original included
+	for i in 0..98 {
+		let grandchild = test_tx(&[(child_id, i)], 1);
+		mempool.add_tx(grandchild.compute_hash(), grandchild, 0, 0);
+	}
+	assert!(mempool.check_replacement(&tx, 10_000, &conflicts, false).unwrap().len() == 100);
+	let last = test_tx(&[(id, 1)], 1);
+	mempool.add_tx(last.compute_hash(), last, 0, 0);
+	assert!(mempool.check_replacement(&tx, 10_000, &conflicts, false).is_err());
+}
+
+#[test]
+fn replacement_signalling() {
+	// neither the tx nor its parent signal, so only full RBF replaces it
+	let mut node = TestNode::new();
+	let coins = [[1; 32], [2; 32]].map(|hash| (Sha256::from(hash), 0));
+	let parent = test_tx(&coins[..1], 1);
+	let parent_id = parent.compute_hash();
+	let original = test_tx(&[(parent_id, 0), coins[1]], 1);
+	let id = original.compute_hash();
+	node.mempool.add_tx(parent_id, parent, 1000, 0);
+	node.mempool.add_tx(id, original.clone(), 1000, 0);
+	let mut tx = original;
+	tx.outputs[0].value = 1;
+	let conflicts = HashSet::from([id]);
+	assert!(node.mempool.check_replacement(&tx, 10_000, &conflicts, false).is_err());
+	assert!(node.mempool.check_replacement(&tx, 10_000, &conflicts, true).is_ok());
+
+	// signalling is inherited from an ancestor
+	node.mempool.remove_tx(&id);
+	node.mempool.remove_tx(&parent_id);
+	let mut parent = test_tx(&coins[..1], 1);
+	parent.inputs[0].sequence = 0xffff_fffd;
+	let parent_id = parent.compute_hash();
+	let original = test_tx(&[(parent_id, 0), coins[1]], 1);
+	let id = original.compute_hash();
+	node.mempool.add_tx(parent_id, parent, 1000, 0);
+	node.mempool.add_tx(id, original.clone(), 1000, 0);
+	let child = test_tx(&[(id, 0)], 1);
+	let child_id = child.compute_hash();
+	node.mempool.add_tx(child_id, child, 1000, 0);
+
+	// the replacement takes the original's place, and its child goes with it
+	let mut tx = original;
+	tx.outputs[0].value = 1;
+	let tx_id = tx.compute_hash();
+	let check = MempoolCheck { base_fee: 10_000, fee: 10_000, sigops: 0, conflicts: HashSet::from([id]) };
+	node.add_checked_tx(tx_id, tx, check).unwrap();
+	assert!(node.mempool.txs.keys().copied().collect::<HashSet<_>>() == HashSet::from([parent_id, tx_id]));
+	assert!(node.mempool.spender(&UTXOID(id, 0)).is_none());
+	assert!(node.mempool.entries[&parent_id].descendant_count == 2);
+}
+
+#[test]
+fn mempool_size_limit() {
+	// room for two of three txs the same size: one paying little, one paying
+	// little with a child paying plenty, and one in between
+	let start = 1_700_000_000;
+	common::set_mock_time(Some(start));
+	let mut mempool = Mempool::new();
+	let coins = [[1; 32], [2; 32], [3; 32]].map(|hash| (Sha256::from(hash), 0));
+	let (low, parent, middle) = (test_tx(&coins[..1], 1), test_tx(&coins[1..2], 1), test_tx(&coins[2..], 1));
+	let child = test_tx(&[(parent.compute_hash(), 0)], 1);
+	let ids = [&low, &parent, &middle, &child].map(|tx| tx.compute_hash());
+	let (size, vsize) = (low.size(), low.vsize() as u64);
+	mempool.max_size = 3 * size;
+	for (tx, fee) in [(low, 100), (parent, 100), (middle, 2000), (child, 10_000)] {
+		mempool.add_tx(tx.compute_hash(), tx, fee, 0);
+	}
+	assert_eq!(mempool.min_feerate(), MIN_RELAY_FEE);
+
+	// the one paying least goes; the parent's package pays more than the one in
+	// between. nothing paying less than it did gets in now
+	assert_eq!(mempool.limit_size(), HashSet::from([ids[0]]));
+	let evicted_feerate = 100 * 1000 / vsize + INCREMENTAL_RELAY_FEE;
+	assert_eq!(mempool.min_feerate(), evicted_feerate);
+	mempool.max_size = 2 * size;
+	assert_eq!(mempool.limit_size(), HashSet::from([ids[2]]));
+	let evicted_feerate = 2000 * 1000 / vsize + INCREMENTAL_RELAY_FEE;
+	assert_eq!(mempool.min_feerate(), evicted_feerate);
+	assert_eq!(mempool.size, 2 * size);
+
+	// which lasts until a block comes in, then halves every half-life
+	common::set_mock_time(Some(start + 86_400));
+	assert_eq!(mempool.min_feerate(), evicted_feerate);
+	mempool.remove_for_block(&test_block(0));
+	common::set_mock_time(Some(start + 86_400 + ROLLING_FEE_HALFLIFE as u64));
+	assert_eq!(mempool.min_feerate(), evicted_feerate / 2);
+	// four times as fast with the mempool under a quarter full, and gone once
+	// it's below half the incremental relay fee
+	mempool.remove_tx(&ids[3]);
+	mempool.max_size = 8 * size;
+	common::set_mock_time(Some(start + 86_400 + 2 * ROLLING_FEE_HALFLIFE as u64));
+	assert_eq!(mempool.min_feerate(), (evicted_feerate as f64 / 32.0).round() as u64);
+	common::set_mock_time(Some(start + 86_400 + 3 * ROLLING_FEE_HALFLIFE as u64));
+	assert_eq!(mempool.min_feerate(), MIN_RELAY_FEE);
+	assert_eq!(mempool.rolling_min_feerate, 0.0);
+	common::set_mock_time(None);
+}
+
+#[test]
+fn mempool_expiry() {
+	let start = 1_700_000_000;
+	common::set_mock_time(Some(start));
+	let mut mempool = Mempool::new();
+	let parent = test_tx(&[(Sha256::from(TEST_COIN), 0)], 1);
+	let parent_id = parent.compute_hash();
+	mempool.add_tx(parent_id, parent, 1000, 0);
+	common::set_mock_time(Some(start + mempool.expiry / 2));
+	let child = test_tx(&[(parent_id, 0)], 1);
+	let child_id = child.compute_hash();
+	mempool.add_tx(child_id, child, 1000, 0);
+	let other = test_tx(&[(Sha256::from([2; 32]), 0)], 1);
+	let other_id = other.compute_hash();
+	mempool.add_tx(other_id, other, 1000, 0);
+
+	// the parent expires, taking its newer child along; the fee floor stays put
+	common::set_mock_time(Some(start + mempool.expiry));
+	assert!(mempool.limit_size().is_empty());
+	common::set_mock_time(Some(start + mempool.expiry + 1));
+	assert_eq!(mempool.limit_size(), HashSet::from([parent_id, child_id]));
+	assert!(mempool.txs.keys().eq([&other_id]) && mempool.entries[&other_id].ancestor_count == 1);
+	assert_eq!(mempool.min_feerate(), MIN_RELAY_FEE);
+	common::set_mock_time(None);
+}
+
+#[test]
+fn packages() {
+	let mut node = TestNode::new();
+	node.require_standard = false;
+	// OP_TRUE coins worth the txs' outputs plus `fee`
+	let mut coin = |n: u8, fee: u64| {
+		let id = UTXOID(Sha256::from([n; 32]), 0);
+		node.utxos.insert(id.clone(), TxOutput { value: 1000 + fee, lock: Script::from(vec![0x51]) });
+		(id.0, id.1)
+	};
+	let coins = [coin(1, 0), coin(2, 2000)];
+	// a parent paying nothing, and a child paying `fee` for both
+	let parent = test_tx(&coins[..1], 1);
+	let parent_id = parent.compute_hash();
+	let child = |fee: u64| {
+		let mut tx = test_tx(&[(parent_id, 0)], 1);
+		tx.outputs[0].value = 1000 - fee;
+		tx
+	};
+	assert!(node.accept_to_mempool(parent_id, parent.clone()).is_err());
+
+	// testmempoolaccept says what would happen without it happening
+	let results = node.test_mempool_accept(vec![parent.clone(), child(500)]).unwrap();
+	assert!(results.iter().all(|(_, result)| result.is_ok()) && node.mempool.txs.is_empty());
+
+	// a child that doesn't pay enough for both takes its parent down with it
+	let results = node.accept_package(vec![parent.clone(), child(100)]).unwrap();
+	assert!(results.iter().all(|(_, result)| result.is_err()) && node.mempool.txs.is_empty());
+
+	// nor can a child paying for its parent replace anything
+	let mut other = test_tx(&coins[1..], 1);
+	other.inputs[0].sequence = 0xffff_fffd;
+	node.accept_to_mempool(other.compute_hash(), other.clone()).unwrap();
+	let mut replacing = test_tx(&[(parent_id, 0), coins[1]], 1);
+	replacing.outputs[0].value = 1;
+	let results = node.accept_package(vec![parent.clone(), replacing]).unwrap();
+	assert!(results[1].1.as_ref().is_err_and(|err| err.to_string().contains("package RBF not supported")));
+	assert!(results[0].1.is_err() && node.mempool.txs.keys().eq([&other.compute_hash()]));
+
+	// one that does pays its parent's way in
+	let results = node.accept_package(vec![parent, child(500)]).unwrap();
+	assert!(results.iter().all(|(_, result)| result.is_ok()));
+	assert!(node.mempool.txs.contains_key(&parent_id) && node.mempool.entries[&parent_id].descendant_fee == 500);
+}
+
+#[test]
+fn truc_sibling_eviction() {
+	let mut node = TestNode::new();
+	node.require_standard = false;
+	let coin = UTXOID(Sha256::from(TEST_COIN), 0);
+	node.utxos.insert(coin.clone(), TxOutput { value: 3000, lock: Script::from(vec![0x51]) });
+	let truc = |outpoint: (Sha256, u32), outputs: usize, value: u64| {
+		let mut tx = test_tx(&[outpoint], outputs);
+		tx.version = TRUC_VERSION;
+		tx.outputs[0].value = value;
+		tx
+	};
+	let parent = truc((coin.0, coin.1), 2, 1000);
+	let parent_id = parent.compute_hash();
+	node.accept_to_mempool(parent_id, parent).unwrap();
+	let child = truc((parent_id, 0), 1, 500);
+	let child_id = child.compute_hash();
+	node.accept_to_mempool(child_id, child).unwrap();
+
+	// the parent can have one child, so a second one paying enough replaces
+	// the first, though it spends another output
+	let sibling = truc((parent_id, 1), 1, 100);
+	assert_eq!(node.mempool.check_truc(&sibling).unwrap(), Some(child_id));
+	let sibling_id = sibling.compute_hash();
+	node.accept_to_mempool(sibling_id, sibling).unwrap();
+	assert!(node.mempool.txs.keys().copied().collect::<HashSet<_>>() == HashSet::from([parent_id, sibling_id]));
+	// one paying too little doesn't
+	let low = truc((parent_id, 0), 1, 800);
+	assert!(node.accept_to_mempool(low.compute_hash(), low).is_err());
+	assert!(node.mempool.txs.contains_key(&sibling_id));
+}
+
+#[test]
+fn mempool_dump() {
+	let mut node = TestNode::new();
+	node.require_standard = false;
+	let coin = UTXOID(Sha256::from(TEST_COIN), 0);
+	node.utxos.insert(coin.clone(), TxOutput { value: 2000, lock: Script::from(vec![0x51]) });
+	let tx = test_tx(&[(coin.0, coin.1)], 1);
+	let id = tx.compute_hash();
+	let absent = Sha256::from([2; 32]);
+	node.accept_to_mempool(id, tx).unwrap();
+	node.mempool.prioritise(id, 500).unwrap();
+	node.mempool.prioritise(absent, 700).unwrap();
+	let path = node.dir.join("mempool.dat").to_str().unwrap().to_owned();
+	std::fs::create_dir_all(&node.dir).unwrap();
+	node.save_mempool(&path).unwrap();
+
+	// loading it back, as often as you like, leaves the prioritisations as they were
+	for _ in 0..2 {
+		assert_eq!(node.load_mempool(&path).unwrap(), (0, 1));
+		assert_eq!(node.mempool.entries[&id].fee, 1500);
+		assert_eq!(node.mempool.deltas[&absent], 700);
+	}
+	node.mempool.remove_tx(&id);
+	node.mempool.deltas.clear();
+	for _ in 0..2 {
+		node.load_mempool(&path).unwrap();
+		assert_eq!(node.mempool.entries[&id].fee, 1500);
+		assert_eq!(node.mempool.deltas[&id], 500);
+		assert_eq!(node.mempool.deltas[&absent], 700);
+	}
+}
+
+// adds peers done with their handshakes, on connections to ourselves. the
+// other ends come back with them, to keep the connections open
+#[cfg(test)]
+fn test_peers(node: &mut Node, count: usize) -> Vec<(usize, TcpStream)> {
+	let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+	(0..count).map(|_| {
+		let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+		let (accepted, addr) = listener.accept().unwrap();
+		let id = node.add_peer(accepted, addr.to_string(), true, false).unwrap();
+		node.peers.get_mut(&id).unwrap().handshake_complete = true;
+		(id, stream)
+	}).collect()
+}
+
+#[test]
+fn block_download() {
+	let mut node = TestNode::new();
+	let peers = test_peers(&mut node, 3);
+	let [a, b, c] = [peers[0].0, peers[1].0, peers[2].0];
+	let in_flight = |node: &Node| [a, b, c].map(|peer| node.blocks_in_flight.values().filter(|request| request.peer == peer).count());
+
+	// the first peer with room gets all three
+	node.handle_headers_message(a, test_headers(&[0, 5, 6])).unwrap();
+	node.request_next_blocks();
+	assert_eq!(in_flight(&node), [3, 0, 0]);
+
+	// until it's delivered none of them for too long
+	let stalled = Instant::now().checked_sub(BLOCK_DOWNLOAD_TIMEOUT * 2).unwrap();
+	for request in node.blocks_in_flight.values_mut() {
+		request.time = stalled;
 	}
-}
\ No newline at end of file
+	node.peers.get_mut(&a).unwrap().last_block_time = stalled;
+	node.check_timeouts();
+	assert_eq!(in_flight(&node), [0, 3, 0]);
+
+	// blocks that come in out of order wait for the ones before them
+	node.handle_block_message(b, test_block(6)).unwrap();
+	node.handle_block_message(b, test_block(5)).unwrap();
+	assert_eq!(node.block_db.blocks_validated, 1);
+	assert_eq!(node.downloaded_blocks.len(), 2);
+	node.handle_block_message(b, test_block(0)).unwrap();
+	assert_eq!(node.block_db.blocks_validated, 4);
+	assert!(node.downloaded_blocks.is_empty() && node.blocks_in_flight.is_empty());
+
+	// no peer is asked for more than MAX_BLOCKS_IN_TRANSIT_PER_PEER at once
+	let mut prev = *node.block_db.hashes.last().unwrap();
+	for time in 0..40 {
+		let header = Header::new(0x2000_0000, prev, Sha256::default(), time, 0, 0, 0);
+		prev = header.compute_hash();
+		node.block_db.push_header(prev, header);
+	}
+	node.request_next_blocks();
+	assert_eq!(in_flight(&node), [MAX_BLOCKS_IN_TRANSIT_PER_PEER, MAX_BLOCKS_IN_TRANSIT_PER_PEER, 8]);
+}
@@ -0,0 +1,206 @@
+// Block templates for miners (BIP 22/23). A template is the next block on our
+// tip: mempool txs chosen by the node, and a coinbase claiming the subsidy and
+// their fees that commits to their witnesses. Miners swap in a coinbase of
+// their own (keeping the commitment), grind the nonce and send the result back
+// with submitblock.
+
+use std::collections::HashMap;
+
+use crate::{
+	json::*,
+	common::{bytes_to_hex, serialize},
+	crypto::sha256::Sha256,
+	network::message::{Block, Header, Tx, TxInput, TxOutput, AbsoluteLockTime, block_subsidy},
+	script::Script,
+};
+
+pub const MAX_BLOCK_WEIGHT: usize = 4_000_000;
+pub const MAX_BLOCK_SIGOPS_COST: usize = 80_000;
+// kept free for the coinbase, which miners tend to grow
+pub const COINBASE_RESERVED_WEIGHT: usize = 4000;
+pub const COINBASE_RESERVED_SIGOPS: usize = 400;
+
+// the BIP 9 top bits, with no deployments signalled
+const BLOCK_VERSION: i32 = 0x2000_0000;
+
+pub struct TemplateTx {
+	pub tx: Tx,
+	pub fee: u64,
+	pub sigops: usize,
+}
+
+pub struct BlockTemplate {
+	// the coinbase pays everything to OP_TRUE
+	pub block: Block,
+	pub height: usize,
+	// per tx after the coinbase
+	pub fees: Vec<u64>,
+	pub sigops: Vec<usize>,
+	pub coinbase_value: u64,
+	// the earliest timestamp the block can have
+	pub min_time: u32,
+	pub signet_challenge: Option<Script>,
+}
+
+impl BlockTemplate {
+	// `txs` in the order they're to be mined, parents first
+	pub fn new(prev_block: Sha256, height: usize, bits: u32, time: u32, min_time: u32,
+		txs: Vec<TemplateTx>, signet_challenge: Option<Script>) -> Self {
+		let fees = txs.iter().map(|entry| entry.fee).collect::<Vec<_>>();
+		let sigops = txs.iter().map(|entry| entry.sigops).collect::<Vec<_>>();
+		let coinbase_value = block_subsidy(height) + fees.iter().sum::<u64>();
+
+		let mut block_txs = vec![create_coinbase(height, coinbase_value, Script::from(vec![0x51]))];
+		block_txs.extend(txs.into_iter().map(|entry| entry.tx));
+		let mut block = Block {
+			header: Header::new(BLOCK_VERSION, prev_block, Sha256::default(), time, bits, 0, block_txs.len()),
+			txs: block_txs,
+		};
+		block.add_witness_commitment();
+
+		BlockTemplate {
+			block,
+			height,
+			fees,
+			sigops,
+			coinbase_value,
+			min_time,
+			signet_challenge,
+		}
+	}
+
+	pub fn witness_commitment(&self) -> &Script {
+		let index = self.block.witness_commitment_index().unwrap();
+		&self.block.txs[0].outputs[index].lock
+	}
+}
+
+// BIP 34: the coinbase's unlock script starts with the height
+pub fn create_coinbase(height: usize, value: u64, lock: Script) -> Tx {
+	let mut unlock = height_push(height);
+	// OP_0, so the script is never shorter than the two bytes required
+	unlock.push(0x00);
+	let input = TxInput {
+		tx_hash: Sha256::default(),
+		index: 0xffff_ffff,
+		unlock: Script::from(unlock),
+		witness: Vec::new(),
+		sequence: 0xffff_ffff,
+	};
+	Tx {
+		version: 2,
+		segwit: false,
+		inputs: vec![input],
+		outputs: vec![TxOutput { value, lock }],
+		abs_lock_time: AbsoluteLockTime::None,
+	}
+}
+
+// the height as a minimal script number push, OP_1 to OP_16 included
+fn height_push(height: usize) -> Vec<u8> {
+	if (1..=16).contains(&height) {
+		return vec![0x50 + height as u8];
+	}
+	let mut bytes = Vec::new();
+	let mut n = height;
+	while n > 0 {
+		bytes.push(n as u8);
+		n >>= 8;
+	}
+	if bytes.last().is_some_and(|&last| last & 0x80 != 0) {
+		bytes.push(0);
+	}
+	let mut push = vec![bytes.len() as u8];
+	push.extend(bytes);
+	push
+}
+
+impl ToJson for BlockTemplate {
+	fn to_json(&self) -> JsonValue {
+		let header = &self.block.header;
+		let txs = &self.block.txs[1..];
+		let positions = txs.iter()
+			.enumerate()
+			.map(|(i, tx)| (tx.compute_hash(), i + 1))
+			.collect::<HashMap<_, _>>();
+		let transactions = txs.iter().enumerate().map(|(i, tx)| {
+			// the in-template txs this one spends, by their (1-based) position
+			let mut depends = tx.inputs.iter()
+				.filter_map(|input| positions.get(&input.tx_hash).copied())
+				.collect::<Vec<_>>();
+			depends.sort_unstable();
+			depends.dedup();
+			JsonValue::object([
+				("data",    JsonValue::string(bytes_to_hex(&serialize(tx).unwrap()))),
+				("txid",    JsonValue::string(format!("{}", tx.compute_hash()))),
+				("hash",    JsonValue::string(format!("{}", tx.compute_wtxid()))),
+				("depends", JsonValue::array(depends.into_iter().map(|i| JsonValue::number(i as u64)))),
+				("fee",     JsonValue::number(self.fees[i])),
+				("sigops",  JsonValue::number(self.sigops[i] as u64)),
+				("weight",  JsonValue::number(tx.weight() as u64)),
+			])
+		});
+
+		let mut rules = vec![JsonValue::string("csv"), JsonValue::string("!segwit")];
+		if self.signet_challenge.is_some() {
+			rules.push(JsonValue::string("!signet"));
+		}
+		let target = header.compute_target().as_bytes().iter().rev().copied().collect::<Vec<_>>();
+		let mut fields = vec![
+			("capabilities",       JsonValue::array([JsonValue::string("proposal")])),
+			("version",            JsonValue::number(header.version)),
+			("rules",              JsonValue::array(rules)),
+			("vbavailable",        JsonValue::object(Vec::<(String, JsonValue)>::new())),
+			("vbrequired",         JsonValue::number(0)),
+			("previousblockhash",  JsonValue::string(format!("{}", header.prev_block))),
+			("transactions",       JsonValue::array(transactions)),
+			("coinbaseaux",        JsonValue::object(Vec::<(String, JsonValue)>::new())),
+			("coinbasevalue",      JsonValue::number(self.coinbase_value)),
+			("target",             JsonValue::string(bytes_to_hex(&target))),
+			("mintime",            JsonValue::number(self.min_time)),
+			("mutable",            JsonValue::array(["time", "transactions", "prevblock"].map(JsonValue::string))),
+			("noncerange",         JsonValue::string("00000000ffffffff")),
+			("sigoplimit",         JsonValue::number(MAX_BLOCK_SIGOPS_COST as u64)),
+			("sizelimit",          JsonValue::number(MAX_BLOCK_WEIGHT as u64)),
+			("weightlimit",        JsonValue::number(MAX_BLOCK_WEIGHT as u64)),
+			("curtime",            JsonValue::number(header.timestamp)),
+			("bits",               JsonValue::string(format!("{:08x}", header.bits))),
+			("height",             JsonValue::number(self.height as u64)),
+			("default_witness_commitment", JsonValue::string(bytes_to_hex(self.witness_commitment().as_bytes()))),
+		];
+		if let Some(challenge) = &self.signet_challenge {
+			fields.push(("signet_challenge", JsonValue::string(bytes_to_hex(challenge.as_bytes()))));
+		}
+		JsonValue::object(fields)
+	}
+}
+
+#[test]
+fn block_template() {
+	use crate::crypto::big_int::u256;
+
+	assert!(height_push(1) == vec![0x51]);
+	assert!(height_push(17) == vec![0x01, 0x11]);
+	assert!(height_push(128) == vec![0x02, 0x80, 0x00]);
+	assert!(height_push(840_000) == vec![0x03, 0x40, 0xd1, 0x0c]);
+	assert!(block_subsidy(209_999) == 5_000_000_000 && block_subsidy(840_000) == 312_500_000);
+
+	let parent = Tx::spending(&[(Sha256::from([1; 32]), 0)]);
+	let child = Tx::spending(&[(parent.compute_hash(), 0)]);
+	let txs = vec![
+		TemplateTx { tx: parent, fee: 300, sigops: 1 },
+		TemplateTx { tx: child, fee: 500, sigops: 1 },
+	];
+	let template = BlockTemplate::new(Sha256::from([2; 32]), 210_000, 0x1d00ffff, 1000, 900, txs, None);
+	let block = &template.block;
+	assert!(template.coinbase_value == 2_500_000_800);
+	assert!(block.txs.len() == 3 && block.header.tx_count() == 3);
+	assert!(Tx::check_merkle_root(&block.txs, block.header.merkle_root));
+	// the commitment covers the wtxids, and the coinbase carries the reserved value
+	let reserved = &block.txs[0].inputs[0].witness[0];
+	let commitment = block.compute_witness_commitment(reserved);
+	assert!(template.witness_commitment().as_bytes()[6..] == *commitment.as_bytes());
+
+	assert!(Header::compact_target(&block.header.compute_target()) == 0x1d00ffff);
+	assert!(Header::compact_target(&(u256::from_u64(0x80) << 64)) == 0x0a008000);
+}
@@ -0,0 +1,257 @@
+// A non-blocking peer connection. Incoming bytes are buffered as they arrive and
+// split into messages once a whole frame is there; outgoing messages are buffered
+// and written out whenever the socket will take them, so neither a slow nor a
+// silent peer can block the thread serving everyone else. Messages are framed
+// either the original (v1) way or in BIP 324 encrypted packets (v2).
+
+use std::{
+	collections::VecDeque,
+	io::{ErrorKind, Read, Write},
+	net::{Shutdown, SocketAddr, TcpStream},
+	os::unix::io::{AsRawFd, RawFd},
+	time::Instant,
+};
+
+use crate::err::*;
+
+use super::{
+	Deserialize,
+	Peer,
+	Serialize,
+	message::{self, Message, MAX_PAYLOAD_SIZE},
+	v2transport::{self, V2Transport},
+};
+
+// magic, command, payload size, checksum
+const MESSAGE_HEADER_SIZE: usize = 24;
+const READ_CHUNK_SIZE: usize = 64 * 1024;
+// once this much is waiting to be sent we stop reading from the peer, so that it
+// can't make us queue up more (cf. bitcoind's -maxsendbuffer)
+const MAX_SEND_BUFFER: usize = 5_000_000;
+
+pub struct Connection {
+	stream: TcpStream,
+	recv_buf: Vec<u8>,
+	send_buf: VecDeque<u8>,
+	// when bytes last actually moved in each direction
+	last_recv: Instant,
+	last_send: Instant,
+	transport: Transport,
+	// messages sent before the v2 handshake lets us encrypt them
+	pending: Vec<Message>,
+}
+
+enum Transport {
+	V1,
+	// an inbound peer that may speak either, told apart by its first bytes
+	Detect,
+	V2(Box<V2Transport>),
+}
+
+impl Connection {
+	pub fn new(stream: TcpStream) -> Result<Self> {
+		Self::with_transport(stream, Transport::V1)
+	}
+
+	// outbound connections open with the v2 handshake; inbound ones accept either
+	pub fn new_v2(stream: TcpStream, inbound: bool) -> Result<Self> {
+		if inbound {
+			return Self::with_transport(stream, Transport::Detect);
+		}
+		let v2 = V2Transport::new(true);
+		let handshake = v2.handshake();
+		let mut conn = Self::with_transport(stream, Transport::V2(Box::new(v2)))?;
+		conn.send_buf.extend(handshake);
+		conn.flush()?;
+		Ok(conn)
+	}
+
+	fn with_transport(stream: TcpStream, transport: Transport) -> Result<Self> {
+		stream.set_nonblocking(true)
+			.and_then(|_| stream.set_nodelay(true))
+			.map_err(|err| Err::NetworkError(err.to_string()))?;
+		Ok(Connection {
+			stream,
+			recv_buf: Vec::new(),
+			send_buf: VecDeque::new(),
+			last_recv: Instant::now(),
+			last_send: Instant::now(),
+			transport,
+			pending: Vec::new(),
+		})
+	}
+
+	pub fn peer_addr(&self) -> Option<SocketAddr> {
+		self.stream.peer_addr().ok()
+	}
+
+	pub fn shutdown(&self) {
+		let _ = self.stream.shutdown(Shutdown::Both);
+	}
+
+	// reads everything available without blocking. false once the peer has closed
+	// the connection (messages that arrived before that can still be received)
+	pub fn fill(&mut self) -> Result<bool> {
+		let mut chunk = [0; READ_CHUNK_SIZE];
+		loop {
+			match self.stream.read(&mut chunk) {
+				Ok(0) => return Ok(false),
+				Ok(n) => {
+					self.recv_buf.extend_from_slice(&chunk[..n]);
+					self.last_recv = Instant::now();
+				},
+				Err(err) if err.kind() == ErrorKind::WouldBlock => return Ok(true),
+				Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+				Err(err) => return Err(Err::NetworkError(err.to_string())),
+			}
+		}
+	}
+
+	// writes as much of the send buffer as the socket takes without blocking
+	pub fn flush(&mut self) -> Result<()> {
+		while !self.send_buf.is_empty() {
+			match self.stream.write(self.send_buf.as_slices().0) {
+				Ok(0) => return Err(Err::NetworkError("connection closed".to_owned())),
+				Ok(n) => {
+					self.send_buf.drain(..n);
+					self.last_send = Instant::now();
+				},
+				Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+				Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+				Err(err) => return Err(Err::NetworkError(err.to_string())),
+			}
+		}
+		Ok(())
+	}
+
+	pub fn wants_write(&self) -> bool {
+		!self.send_buf.is_empty()
+	}
+
+	pub fn send_paused(&self) -> bool {
+		self.send_buf.len() >= MAX_SEND_BUFFER
+	}
+
+	pub fn last_recv(&self) -> Instant {
+		self.last_recv
+	}
+
+	pub fn last_send(&self) -> Instant {
+		self.last_send
+	}
+
+	pub fn is_v2(&self) -> bool {
+		matches!(self.transport, Transport::V2(_))
+	}
+
+	// a v2 connection the peer never sent a key on; worth retrying with v1
+	pub fn v2_handshake_failed(&self) -> bool {
+		match &self.transport {
+			Transport::V2(v2) => !v2.key_received(),
+			_ => false,
+		}
+	}
+
+	fn detect_transport(&mut self) -> Result<()> {
+		let prefix = v2transport::v1_prefix();
+		let n = self.recv_buf.len().min(prefix.len());
+		if self.recv_buf[..n] == prefix[..n] {
+			if n < prefix.len() {
+				return Ok(());
+			}
+			self.transport = Transport::V1;
+			for m in std::mem::take(&mut self.pending) {
+				self.send(m)?;
+			}
+		} else {
+			let v2 = V2Transport::new(false);
+			self.send_buf.extend(v2.handshake());
+			self.transport = Transport::V2(Box::new(v2));
+			self.flush()?;
+		}
+		Ok(())
+	}
+
+	fn receive_v1(&mut self) -> Result<Option<Message>> {
+		if self.recv_buf.len() < MESSAGE_HEADER_SIZE {
+			return Ok(None);
+		}
+
+		// check the header before waiting on a payload that might never come
+		let magic = u32::from_le_bytes(self.recv_buf[0..4].try_into().unwrap());
+		if magic != message::magic() {
+			return Err(Err::NetworkError(format!("invalid magic number in message: {:08x}", magic)));
+		}
+		let size = u32::from_le_bytes(self.recv_buf[16..20].try_into().unwrap()) as usize;
+		if size > MAX_PAYLOAD_SIZE {
+			return Err(Err::NetworkError(format!("max payload size exceeded: {}", size)));
+		}
+		if self.recv_buf.len() < MESSAGE_HEADER_SIZE + size {
+			return Ok(None);
+		}
+
+		let frame = self.recv_buf.drain(..MESSAGE_HEADER_SIZE + size).collect::<Vec<_>>();
+		Ok(Some(Message::deserialize(&mut frame.as_slice())?))
+	}
+
+	fn receive_v2(&mut self) -> Result<Option<Message>> {
+		let v2 = match &mut self.transport {
+			Transport::V2(v2) => v2,
+			_ => unreachable!(),
+		};
+		let mut handshake = Vec::new();
+		let contents = v2.receive(&mut self.recv_buf, &mut handshake)?;
+		if !handshake.is_empty() {
+			// the handshake is done on our side, so what was held back can go
+			self.send_buf.extend(handshake);
+			for m in std::mem::take(&mut self.pending) {
+				let packet = v2.encrypt(&v2transport::encode_message(&m)?);
+				self.send_buf.extend(packet);
+			}
+			self.flush()?;
+		}
+		contents.map(|contents| v2transport::decode_message(&contents)).transpose()
+	}
+}
+
+impl Peer for Connection {
+	fn send(&mut self, m: Message) -> Result<()> {
+		log_trace!("SENT: {}", m.payload().name());
+		match &mut self.transport {
+			Transport::V2(v2) if v2.key_received() => {
+				let packet = v2.encrypt(&v2transport::encode_message(&m)?);
+				self.send_buf.extend(packet);
+			},
+			Transport::V2(_) | Transport::Detect => self.pending.push(m),
+			Transport::V1 => {
+				let mut bytes = Vec::new();
+				m.serialize(&mut bytes)?;
+				self.send_buf.extend(bytes);
+			},
+		}
+		self.flush()
+	}
+
+	// the next complete message, if one has arrived. a ValueError means the message
+	// was malformed but has been consumed; any other error leaves the stream unusable
+	fn receive(&mut self) -> Result<Option<Message>> {
+		if let Transport::Detect = self.transport {
+			self.detect_transport()?;
+		}
+		let m = match self.transport {
+			Transport::V1 => self.receive_v1()?,
+			Transport::V2(_) => self.receive_v2()?,
+			Transport::Detect => None,
+		};
+		if let Some(m) = &m {
+			log_trace!("RECEIVED: {}", m.payload().name());
+		}
+		Ok(m)
+	}
+}
+
+impl AsRawFd for Connection {
+	fn as_raw_fd(&self) -> RawFd {
+		self.stream.as_raw_fd()
+	}
+}
@@ -1,59 +1,440 @@
 use std::{
 	io::{Read, Write},
+	net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+	fmt,
 };
 
 use crate::{
 	err::*,
 	json::*,
+	crypto::sha3::compute_sha3_256,
 	common::{
+		read_u8,
+		read_u16_be,
+		read_u32,
+		read_u64,
 		read_var_int,
+		read_buf_exact,
+		write_u8,
+		write_u16_be,
+		write_u32,
+		write_u64,
 		write_var_int,
+		write_buf_exact,
+		bytes_to_base32,
+		base32_to_bytes,
+		now,
 	},
 };
 
 use super::{
 	Deserialize,
 	Serialize,
-    NetAddr,
 };
 
+// an addr message may carry at most this many entries
+pub const MAX_ADDR_TO_SEND: usize = 1000;
+
+// BIP 155 caps the length of any address at 512 bytes
+const MAX_ADDRV2_SIZE: u64 = 512;
+
+const TORV3_VERSION: u8 = 3;
+
+// the host part of a peer address, in any of the networks BIP 155 can describe
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum NetHost {
+	IPv4(Ipv4Addr),
+	IPv6(Ipv6Addr),
+	TorV3([u8; 32]),
+	I2P([u8; 32]),
+	Cjdns(Ipv6Addr),
+}
+
+impl NetHost {
+	// network ids from BIP 155 (TorV2, id 3, is no longer supported)
+	pub fn network_id(&self) -> u8 {
+		match self {
+			NetHost::IPv4(_)  => 1,
+			NetHost::IPv6(_)  => 2,
+			NetHost::TorV3(_) => 4,
+			NetHost::I2P(_)   => 5,
+			NetHost::Cjdns(_) => 6,
+		}
+	}
+
+	pub fn as_bytes(&self) -> Vec<u8> {
+		match self {
+			NetHost::IPv4(ip)       => ip.octets().to_vec(),
+			NetHost::IPv6(ip)       => ip.octets().to_vec(),
+			NetHost::TorV3(pubkey)  => pubkey.to_vec(),
+			NetHost::I2P(hash)      => hash.to_vec(),
+			NetHost::Cjdns(ip)      => ip.octets().to_vec(),
+		}
+	}
+
+	fn from_network_id(id: u8, bytes: &[u8]) -> Option<NetHost> {
+		match id {
+			1 => Some(NetHost::IPv4(<[u8; 4]>::try_from(bytes).ok()?.into())),
+			2 => Some(NetHost::from(IpAddr::V6(<[u8; 16]>::try_from(bytes).ok()?.into()))),
+			4 => Some(NetHost::TorV3(bytes.try_into().ok()?)),
+			5 => Some(NetHost::I2P(bytes.try_into().ok()?)),
+			6 => Some(NetHost::Cjdns(<[u8; 16]>::try_from(bytes).ok()?.into())),
+			_ => None,
+		}
+	}
+
+	// the pre-BIP 155 encoding only has room for IP addresses
+	pub fn is_v1_compatible(&self) -> bool {
+		matches!(self, NetHost::IPv4(_) | NetHost::IPv6(_))
+	}
+
+	pub fn is_routable(&self) -> bool {
+		match self {
+			NetHost::IPv4(ip) => {
+				let [a, b, c, _] = ip.octets();
+				!(ip.is_unspecified() || ip.is_loopback() || ip.is_private() || ip.is_link_local()
+					|| ip.is_broadcast() || ip.is_multicast() || ip.is_documentation()
+					|| a == 0                                  // "this" network
+					|| a >= 240                                // reserved
+					|| (a == 100 && (b & 0xc0) == 64)          // shared address space (RFC 6598)
+					|| (a == 198 && (b & 0xfe) == 18)          // benchmarking (RFC 2544)
+					|| (a == 192 && b == 0 && c == 0))         // IETF protocol assignments
+			},
+			NetHost::IPv6(ip) => {
+				let segments = ip.segments();
+				!(ip.is_unspecified() || ip.is_loopback() || ip.is_multicast()
+					|| (segments[0] & 0xfe00) == 0xfc00                  // unique local (RFC 4193)
+					|| (segments[0] & 0xffc0) == 0xfe80                  // link local
+					|| (segments[0] == 0x2001 && segments[1] == 0x0db8)  // documentation
+					|| (segments[0] == 0x2001 && (segments[1] & 0xfff0) == 0x0010)) // ORCHID
+			},
+			NetHost::TorV3(_) | NetHost::I2P(_) | NetHost::Cjdns(_) => true,
+		}
+	}
+
+	// addresses in the same group are likely controlled by the same entity. the
+	// address manager limits how much of its tables any one group can occupy, and
+	// outbound connections are made to distinct groups.
+	pub fn group(&self) -> Vec<u8> {
+		if !self.is_routable() {
+			return vec![0];
+		}
+		let bytes = self.as_bytes();
+		match self {
+			NetHost::IPv4(_) => vec![self.network_id(), bytes[0], bytes[1]],
+			NetHost::IPv6(_) => {
+				let mut group = vec![self.network_id()];
+				group.extend_from_slice(&bytes[0..4]);
+				group
+			},
+			_ => vec![self.network_id(), bytes[0] & 0xf0],
+		}
+	}
+
+	pub fn parse(s: &str) -> Result<NetHost> {
+		let invalid = || Err::ValueError(format!("invalid host: {}", s));
+		if let Some(name) = s.strip_suffix(".onion") {
+			let bytes = base32_to_bytes(name)?;
+			if bytes.len() != 35 || bytes[34] != TORV3_VERSION {
+				return Err(invalid());
+			}
+			let pubkey: [u8; 32] = bytes[0..32].try_into().unwrap();
+			if bytes[32..34] != onion_checksum(&pubkey) {
+				return Err(invalid());
+			}
+			Ok(NetHost::TorV3(pubkey))
+		} else if let Some(name) = s.strip_suffix(".b32.i2p") {
+			let hash = base32_to_bytes(name)?;
+			Ok(NetHost::I2P(hash.as_slice().try_into().map_err(|_| invalid())?))
+		} else {
+			let ip = s.trim_start_matches('[').trim_end_matches(']');
+			let ip = ip.parse::<IpAddr>().map_err(|_| invalid())?;
+			Ok(NetHost::from(ip))
+		}
+	}
+
+	// BIP 155 encoding: network id, then the length-prefixed address
+	pub fn serialize_v2(&self, stream: &mut dyn Write) -> Result<()> {
+		let bytes = self.as_bytes();
+		write_u8(stream, self.network_id())?;
+		write_var_int(stream, bytes.len() as u64)?;
+		write_buf_exact(stream, &bytes)
+	}
+
+	// None for networks we don't know (or no longer support); those are skipped, not errors
+	pub fn deserialize_v2(stream: &mut dyn Read) -> Result<Option<NetHost>> {
+		let id = read_u8(stream)?;
+		let len = read_var_int(stream)?;
+		if len > MAX_ADDRV2_SIZE {
+			return Err(Err::NetworkError(format!("address too long: {} bytes", len)));
+		}
+		let mut bytes = vec![0; len as usize];
+		read_buf_exact(stream, &mut bytes)?;
+		Ok(NetHost::from_network_id(id, &bytes))
+	}
+}
+
+fn onion_checksum(pubkey: &[u8; 32]) -> [u8; 2] {
+	let mut buf = b".onion checksum".to_vec();
+	buf.extend_from_slice(pubkey);
+	buf.push(TORV3_VERSION);
+	let hash = compute_sha3_256(&buf);
+	[hash[0], hash[1]]
+}
+
+impl From<IpAddr> for NetHost {
+	fn from(ip: IpAddr) -> Self {
+		match ip {
+			IpAddr::V4(ip) => NetHost::IPv4(ip),
+			IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+				Some(ip) => NetHost::IPv4(ip),
+				None => NetHost::IPv6(ip),
+			},
+		}
+	}
+}
+
+impl fmt::Display for NetHost {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			NetHost::IPv4(ip) => write!(f, "{}", ip),
+			NetHost::IPv6(ip) | NetHost::Cjdns(ip) => write!(f, "[{}]", ip),
+			NetHost::TorV3(pubkey) => {
+				let mut bytes = pubkey.to_vec();
+				bytes.extend_from_slice(&onion_checksum(pubkey));
+				bytes.push(TORV3_VERSION);
+				write!(f, "{}.onion", bytes_to_base32(&bytes))
+			},
+			NetHost::I2P(hash) => write!(f, "{}.b32.i2p", bytes_to_base32(hash)),
+		}
+	}
+}
+
+#[derive(Clone, Debug)]
+pub struct NetAddr {
+	pub timestamp: u32,
+	pub services: u64,
+	pub host: NetHost,
+	pub port: u16,
+}
+
+impl NetAddr {
+	pub fn new(host: NetHost, port: u16, services: u64, timestamp: u32) -> Self {
+		NetAddr {
+			timestamp,
+			services,
+			host,
+			port,
+		}
+	}
+
+	// `host`, `host:port` or `[ipv6]:port`
+	pub fn parse(s: &str, default_port: u16) -> Result<NetAddr> {
+		let (host, port) = match s.rsplit_once(':') {
+			// a bare IPv6 address has colons but no port
+			Some((host, port)) if !host.contains(':') || host.ends_with(']') => {
+				let port = port.parse().map_err(|_| Err::ValueError(format!("invalid port: {}", s)))?;
+				(host, port)
+			},
+			_ => (s, default_port),
+		};
+		Ok(NetAddr::new(NetHost::parse(host)?, port, 0, now() as u32))
+	}
+
+	pub fn socket_addr(&self) -> Option<SocketAddr> {
+		match self.host {
+			NetHost::IPv4(ip) => Some(SocketAddr::new(IpAddr::V4(ip), self.port)),
+			NetHost::IPv6(ip) => Some(SocketAddr::new(IpAddr::V6(ip), self.port)),
+			_ => None,
+		}
+	}
+
+	fn serialize_v2(&self, stream: &mut dyn Write) -> Result<()> {
+		write_u32(stream, self.timestamp)?;
+		write_var_int(stream, self.services)?;
+		self.host.serialize_v2(stream)?;
+		write_u16_be(stream, self.port)
+	}
+
+	fn deserialize_v2(stream: &mut dyn Read) -> Result<Option<Self>> {
+		let timestamp = read_u32(stream)?;
+		let services = read_var_int(stream)?;
+		let host = NetHost::deserialize_v2(stream)?;
+		let port = read_u16_be(stream)?;
+		Ok(host.map(|host| NetAddr::new(host, port, services, timestamp)))
+	}
+}
+
+impl fmt::Display for NetAddr {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{}:{}", self.host, self.port)
+	}
+}
+
+impl ToJson for NetAddr {
+	fn to_json(&self) -> JsonValue {
+		JsonValue::object([
+			("timestamp", JsonValue::number(self.timestamp)),
+			("services", JsonValue::number(self.services)),
+			("addr",     JsonValue::string(format!("{}", self))),
+		])
+	}
+}
+
+// the pre-BIP 155 encoding, with every host stored as 16 bytes of IPv6
+impl Serialize for NetAddr {
+	fn serialize(&self, stream: &mut dyn Write) -> Result<()> {
+		write_u32(stream, self.timestamp)?;
+		write_u64(stream, self.services)?;
+		let bytes = match self.host {
+			NetHost::IPv4(ip) => ip.to_ipv6_mapped().octets(),
+			NetHost::IPv6(ip) => ip.octets(),
+			_ => return Err(Err::ValueError(format!("cannot encode {} in an addr message", self.host))),
+		};
+		write_buf_exact(stream, &bytes)?;
+		write_u16_be(stream, self.port)
+	}
+}
+
+impl Deserialize for NetAddr {
+	fn deserialize(stream: &mut dyn Read) -> Result<Self> {
+		let timestamp = read_u32(stream)?;
+		let services = read_u64(stream)?;
+		let mut octets = [0; 16];
+		read_buf_exact(stream, &mut octets)?;
+		let host = NetHost::from(IpAddr::from(octets));
+		let port = read_u16_be(stream)?;
+
+		Ok(NetAddr::new(host, port, services, timestamp))
+	}
+}
+
+fn read_addr_count(stream: &mut dyn Read) -> Result<usize> {
+	let count = read_var_int(stream)? as usize;
+	if count > MAX_ADDR_TO_SEND {
+		return Err(Err::NetworkError(format!("too many addresses in message: {}", count)));
+	}
+	Ok(count)
+}
+
 #[derive(Clone)]
 pub struct Addr {
 	addrs: Vec<NetAddr>,
 }
 
 impl Addr {
-	// pub fn new() -> Self {
-	// 	Addr {
-	// 		addrs: Vec::new(),
-	// 	}
-	// }
+	pub fn new(addrs: Vec<NetAddr>) -> Self {
+		Addr {
+			addrs: addrs.into_iter().filter(|addr| addr.host.is_v1_compatible()).collect(),
+		}
+	}
+}
+
+impl IntoIterator for Addr {
+	type Item = NetAddr;
+	type IntoIter = std::vec::IntoIter<NetAddr>;
+
+	fn into_iter(self) -> Self::IntoIter {
+		self.addrs.into_iter()
+	}
 }
 
 impl ToJson for Addr {
 	fn to_json(&self) -> JsonValue {
-        JsonValue::Array(self.addrs.iter().map(|e| e.to_json()).collect())
+		JsonValue::Array(self.addrs.iter().map(|e| e.to_json()).collect())
 	}
 }
 
 impl Deserialize for Addr {
 	fn deserialize(stream: &mut dyn Read) -> Result<Addr> {
-		let count = read_var_int(stream)? as usize;
-        let mut addrs = Vec::new();
-        for _ in 0..count {
-            addrs.push(NetAddr::deserialize(stream)?);
-        }
+		let count = read_addr_count(stream)?;
+		let mut addrs = Vec::new();
+		for _ in 0..count {
+			addrs.push(NetAddr::deserialize(stream)?);
+		}
 
-        Ok(Addr {addrs})
+		Ok(Addr {addrs})
 	}
 }
 
 impl Serialize for Addr {
 	fn serialize(&self, stream: &mut dyn Write) -> Result<()> {
-        write_var_int(stream, self.addrs.len() as u64)?;
+		write_var_int(stream, self.addrs.len() as u64)?;
+		for addr in &self.addrs {
+			addr.serialize(stream)?;
+		}
+		Ok(())
+	}
+}
+
+// BIP 155
+#[derive(Clone)]
+pub struct AddrV2 {
+	addrs: Vec<NetAddr>,
+}
+
+impl AddrV2 {
+	pub fn new(addrs: Vec<NetAddr>) -> Self {
+		AddrV2 {
+			addrs,
+		}
+	}
+}
+
+impl IntoIterator for AddrV2 {
+	type Item = NetAddr;
+	type IntoIter = std::vec::IntoIter<NetAddr>;
+
+	fn into_iter(self) -> Self::IntoIter {
+		self.addrs.into_iter()
+	}
+}
+
+impl ToJson for AddrV2 {
+	fn to_json(&self) -> JsonValue {
+		JsonValue::Array(self.addrs.iter().map(|e| e.to_json()).collect())
+	}
+}
+
+impl Deserialize for AddrV2 {
+	fn deserialize(stream: &mut dyn Read) -> Result<AddrV2> {
+		let count = read_addr_count(stream)?;
+		let mut addrs = Vec::new();
+		for _ in 0..count {
+			if let Some(addr) = NetAddr::deserialize_v2(stream)? {
+				addrs.push(addr);
+			}
+		}
+
+		Ok(AddrV2 {addrs})
+	}
+}
+
+impl Serialize for AddrV2 {
+	fn serialize(&self, stream: &mut dyn Write) -> Result<()> {
+		write_var_int(stream, self.addrs.len() as u64)?;
 		for addr in &self.addrs {
-            addr.serialize(stream)?;
-        }
-        Ok(())
+			addr.serialize_v2(stream)?;
+		}
+		Ok(())
 	}
-}
\ No newline at end of file
+}
+
+#[test]
+fn parse_hosts() {
+	let onion = "pg6mmjiyjmcrsslvykfwnntlaru7p5svn6y2ymmju6nubxndf4pscryd.onion";
+	let host = NetHost::parse(onion).unwrap();
+	assert!(matches!(host, NetHost::TorV3(_)));
+	assert!(host.to_string() == onion);
+	assert!(NetHost::parse("ag6mmjiyjmcrsslvykfwnntlaru7p5svn6y2ymmju6nubxndf4pscryd.onion").is_err());
+
+	let addr = NetAddr::parse("[::ffff:1.2.3.4]:8333", 0).unwrap();
+	assert!(addr.host == NetHost::IPv4(Ipv4Addr::new(1, 2, 3, 4)) && addr.port == 8333);
+	let addr = NetAddr::parse("2001:db8::1", 38333).unwrap();
+	assert!(addr.host.is_v1_compatible() && addr.port == 38333);
+
+	let mut bytes = Vec::new();
+	AddrV2::new(vec![NetAddr::new(host, 9050, 1, 0)]).serialize(&mut bytes).unwrap();
+	let addrs = AddrV2::deserialize(&mut bytes.as_slice()).unwrap().into_iter().collect::<Vec<_>>();
+	assert!(addrs.len() == 1 && addrs[0].host == host && addrs[0].port == 9050);
+}
@@ -17,13 +17,14 @@ use crate::{
 	},
 	err::*,
 	common::*,
-	crypto::sha256::Sha256,
+	crypto::sha256::{Sha256, compute_double_sha256},
 	script::*,
 };
 
 use super::{
 	Header,
 	Tx,
+	compute_merkle_root,
 	TxInput,
 	TxOutput,
 };
@@ -88,6 +89,11 @@ impl <'a> UTXOState<'a> {
 }
 
 impl UTXODiff {
+	// the outputs spent from the UTXO set the diff applies to
+	pub fn spent(&self) -> impl Iterator<Item = &UTXOID> {
+		self.removed.iter()
+	}
+
 	pub fn apply(self, utxos: &mut BTreeMap<UTXOID, TxOutput>) {
 		for k in self.removed {
 			// println!("removed UTXO: {:?}", k);
@@ -112,9 +118,21 @@ pub struct Block {
 }
 
 pub const GENESIS_BLOCK_HASH: &str = "000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f";
+pub const SIGNET_GENESIS_BLOCK_HASH: &str = "00000008819873e925422c1ff0f99f7cc9bbb232af63a077a480a3633bee1ef6";
+
+// OP_RETURN, push 36 bytes, 0xaa21a9ed
+const WITNESS_COMMITMENT_HEADER: [u8; 6] = [0x6a, 0x24, 0xaa, 0x21, 0xa9, 0xed];
 
 impl Block {
 	pub fn genesis() -> Self {
+		Block::create_genesis(1231006505, 0x1d00ffff, 2083236893, GENESIS_BLOCK_HASH)
+	}
+
+	pub fn signet_genesis() -> Self {
+		Block::create_genesis(1598918400, 0x1e0377ae, 52613770, SIGNET_GENESIS_BLOCK_HASH)
+	}
+
+	fn create_genesis(timestamp: u32, bits: u32, nonce: u32, expected_hash: &str) -> Self {
 		let mut input = TxInput::default();
 		input.index = 0xffff_ffff;
 		input.unlock
@@ -137,16 +155,16 @@ impl Block {
 				1,
 				Sha256::default(),
 				merkle_root,
-				1231006505,
-				0x1d00ffff,
-				2083236893,
+				timestamp,
+				bits,
+				nonce,
 				1,
 			),
 			txs: vec![tx],
 		};
 
 		let genesis_merkle_root = Sha256::try_from("4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b").unwrap();
-		let genesis_block_hash = Sha256::try_from(GENESIS_BLOCK_HASH).unwrap();
+		let genesis_block_hash = Sha256::try_from(expected_hash).unwrap();
 		let block_hash = block.header.compute_hash();
 
 		assert!(merkle_root == genesis_merkle_root, "{} != {}", merkle_root, genesis_merkle_root);
@@ -155,6 +173,57 @@ impl Block {
 		block
 	}
 
+	// index of the coinbase output carrying the segwit commitment (the last one wins, cf. BIP 141)
+	pub fn witness_commitment_index(&self) -> Option<usize> {
+		self.txs.first()?.outputs.iter()
+			.rposition(|output| {
+				let lock = output.lock.as_bytes();
+				lock.len() >= 38 && lock[0..6] == WITNESS_COMMITMENT_HEADER
+			})
+	}
+
+	pub fn compute_witness_commitment(&self, witness_reserved_value: &[u8]) -> Sha256 {
+		let mut wtxids = vec![Sha256::default()];
+		wtxids.extend(self.txs.iter().skip(1).map(|tx| tx.compute_wtxid()));
+		let witness_root = compute_merkle_root(wtxids);
+
+		let mut buf = witness_root.as_bytes().to_vec();
+		buf.extend_from_slice(witness_reserved_value);
+		compute_double_sha256(&*buf)
+	}
+
+	// adds a witness commitment to the coinbase (if it doesn't have one already)
+	pub fn add_witness_commitment(&mut self) {
+		if self.witness_commitment_index().is_some() {
+			return;
+		}
+		let coinbase = &mut self.txs[0];
+		if coinbase.inputs[0].witness.is_empty() {
+			coinbase.inputs[0].witness = vec![vec![0; 32]];
+		}
+		coinbase.segwit = true;
+		let reserved = coinbase.inputs[0].witness[0].clone();
+		let commitment = self.compute_witness_commitment(&reserved);
+
+		let mut lock = WITNESS_COMMITMENT_HEADER.to_vec();
+		lock.extend_from_slice(commitment.as_bytes());
+		self.txs[0].outputs.push(TxOutput { value: 0, lock: Script::from(lock) });
+		self.update_merkle_root();
+	}
+
+	pub fn update_merkle_root(&mut self) {
+		let merkle_root = Tx::compute_merkle_root(&self.txs).unwrap();
+		self.header.set_merkle_root(merkle_root);
+		self.header.tx_count = self.txs.len();
+	}
+
+	// for peers that asked for a block without witness data
+	pub fn strip_witness(&mut self) {
+		for tx in self.txs.iter_mut() {
+			tx.strip_witness();
+		}
+	}
+
 	#[must_use]
 	pub fn validate(&self, hash: &Sha256, utxos: &mut BTreeMap<UTXOID, TxOutput>, state: &State) -> ValidationResult {
 		if *hash != self.header.compute_hash() {
@@ -0,0 +1,121 @@
+use std::{
+	io::{Read, Write},
+};
+
+use crate::{
+	err::*,
+	json::*,
+	crypto::sha256::Sha256,
+};
+
+use crate::common::{
+	read_sha256,
+	read_var_size,
+	write_sha256,
+	write_var_int,
+};
+
+use super::{
+	Deserialize,
+	Serialize,
+	Tx,
+	cmpctblock::{read_indexes, write_indexes},
+};
+
+// the txs of a compact block that the receiver couldn't find in its mempool (BIP 152)
+#[derive(Clone)]
+pub struct GetBlockTxn {
+	pub block_hash: Sha256,
+	pub indexes: Vec<usize>,
+}
+
+impl GetBlockTxn {
+	pub fn new(block_hash: Sha256, indexes: Vec<usize>) -> Self {
+		GetBlockTxn {
+			block_hash,
+			indexes,
+		}
+	}
+}
+
+impl ToJson for GetBlockTxn {
+	fn to_json(&self) -> JsonValue {
+		JsonValue::object([
+			("block_hash", JsonValue::string(format!("{}", self.block_hash))),
+			("indexes", JsonValue::array(self.indexes.iter().map(|&i| JsonValue::number(i as u64)))),
+		])
+	}
+}
+
+impl Deserialize for GetBlockTxn {
+	fn deserialize(stream: &mut dyn Read) -> Result<Self> {
+		let block_hash = read_sha256(stream)?;
+		let indexes = read_indexes(stream, |_| Ok(()))?
+			.into_iter()
+			.map(|(index, _)| index)
+			.collect();
+
+		Ok(GetBlockTxn {
+			block_hash,
+			indexes,
+		})
+	}
+}
+
+impl Serialize for GetBlockTxn {
+	fn serialize(&self, stream: &mut dyn Write) -> Result<()> {
+		write_sha256(stream, &self.block_hash)?;
+		write_indexes(stream, &self.indexes, |_, _| Ok(()))
+	}
+}
+
+#[derive(Clone)]
+pub struct BlockTxn {
+	pub block_hash: Sha256,
+	pub txs: Vec<Tx>,
+}
+
+impl BlockTxn {
+	pub fn new(block_hash: Sha256, txs: Vec<Tx>) -> Self {
+		BlockTxn {
+			block_hash,
+			txs,
+		}
+	}
+}
+
+impl ToJson for BlockTxn {
+	fn to_json(&self) -> JsonValue {
+		JsonValue::object([
+			("block_hash", JsonValue::string(format!("{}", self.block_hash))),
+			("txs", JsonValue::array(self.txs.iter().map(|e| e.to_json()))),
+		])
+	}
+}
+
+impl Deserialize for BlockTxn {
+	fn deserialize(stream: &mut dyn Read) -> Result<Self> {
+		let block_hash = read_sha256(stream)?;
+		let count = read_var_size(stream)?;
+		let mut txs = Vec::new();
+		for _ in 0..count {
+			txs.push(Tx::deserialize(stream)?);
+		}
+
+		Ok(BlockTxn {
+			block_hash,
+			txs,
+		})
+	}
+}
+
+impl Serialize for BlockTxn {
+	fn serialize(&self, stream: &mut dyn Write) -> Result<()> {
+		write_sha256(stream, &self.block_hash)?;
+		write_var_int(stream, self.txs.len() as u64)?;
+		for tx in self.txs.iter() {
+			tx.serialize(stream)?;
+		}
+		Ok(())
+	}
+}
@@ -0,0 +1,246 @@
+use std::{
+	io::{Read, Write},
+};
+
+use crate::{
+	err::*,
+	json::*,
+	common::bytes_to_hex,
+	crypto::sha256::Sha256,
+};
+
+use crate::common::{
+	read_u8,
+	read_u32,
+	read_sha256,
+	read_var_size,
+	read_buf_exact,
+	write_u8,
+	write_u32,
+	write_sha256,
+	write_var_int,
+	write_buf_exact,
+};
+
+use super::{
+	Deserialize,
+	Serialize,
+};
+
+// BIP 157: compact block filters for light clients. getcfilters and getcfheaders
+// both ask for a range of blocks, from a height up to a block hash
+#[derive(Clone)]
+pub struct GetCFilters {
+	pub filter_type: u8,
+	pub start_height: u32,
+	pub stop_hash: Sha256,
+}
+
+impl ToJson for GetCFilters {
+	fn to_json(&self) -> JsonValue {
+		JsonValue::object([
+			("filter_type", JsonValue::number(self.filter_type)),
+			("start_height", JsonValue::number(self.start_height)),
+			("stop_hash", JsonValue::string(format!("{}", self.stop_hash))),
+		])
+	}
+}
+
+impl Deserialize for GetCFilters {
+	fn deserialize(stream: &mut dyn Read) -> Result<Self> {
+		let filter_type = read_u8(stream)?;
+		let start_height = read_u32(stream)?;
+		let stop_hash = read_sha256(stream)?;
+
+		Ok(GetCFilters {
+			filter_type,
+			start_height,
+			stop_hash,
+		})
+	}
+}
+
+impl Serialize for GetCFilters {
+	fn serialize(&self, stream: &mut dyn Write) -> Result<()> {
+		write_u8(stream, self.filter_type)?;
+		write_u32(stream, self.start_height)?;
+		write_sha256(stream, &self.stop_hash)
+	}
+}
+
+#[derive(Clone)]
+pub struct CFilter {
+	pub filter_type: u8,
+	pub block_hash: Sha256,
+	pub filter: Vec<u8>,
+}
+
+impl ToJson for CFilter {
+	fn to_json(&self) -> JsonValue {
+		JsonValue::object([
+			("filter_type", JsonValue::number(self.filter_type)),
+			("block_hash", JsonValue::string(format!("{}", self.block_hash))),
+			("filter", JsonValue::string(bytes_to_hex(&self.filter))),
+		])
+	}
+}
+
+impl Deserialize for CFilter {
+	fn deserialize(stream: &mut dyn Read) -> Result<Self> {
+		let filter_type = read_u8(stream)?;
+		let block_hash = read_sha256(stream)?;
+		let mut filter = vec![0; read_var_size(stream)?];
+		read_buf_exact(stream, &mut filter)?;
+
+		Ok(CFilter {
+			filter_type,
+			block_hash,
+			filter,
+		})
+	}
+}
+
+impl Serialize for CFilter {
+	fn serialize(&self, stream: &mut dyn Write) -> Result<()> {
+		write_u8(stream, self.filter_type)?;
+		write_sha256(stream, &self.block_hash)?;
+		write_var_int(stream, self.filter.len() as u64)?;
+		write_buf_exact(stream, &self.filter)
+	}
+}
+
+// the filter hashes of a range of blocks, and the filter header before them:
+// enough to rebuild the headers of the whole range
+#[derive(Clone)]
+pub struct CFHeaders {
+	pub filter_type: u8,
+	pub stop_hash: Sha256,
+	pub prev_header: Sha256,
+	pub filter_hashes: Vec<Sha256>,
+}
+
+impl ToJson for CFHeaders {
+	fn to_json(&self) -> JsonValue {
+		JsonValue::object([
+			("filter_type", JsonValue::number(self.filter_type)),
+			("stop_hash", JsonValue::string(format!("{}", self.stop_hash))),
+			("prev_header", JsonValue::string(format!("{}", self.prev_header))),
+			("filter_hashes", JsonValue::number(self.filter_hashes.len() as u64)),
+		])
+	}
+}
+
+impl Deserialize for CFHeaders {
+	fn deserialize(stream: &mut dyn Read) -> Result<Self> {
+		let filter_type = read_u8(stream)?;
+		let stop_hash = read_sha256(stream)?;
+		let prev_header = read_sha256(stream)?;
+		let count = read_var_size(stream)?;
+		let mut filter_hashes = Vec::new();
+		for _ in 0..count {
+			filter_hashes.push(read_sha256(stream)?);
+		}
+
+		Ok(CFHeaders {
+			filter_type,
+			stop_hash,
+			prev_header,
+			filter_hashes,
+		})
+	}
+}
+
+impl Serialize for CFHeaders {
+	fn serialize(&self, stream: &mut dyn Write) -> Result<()> {
+		write_u8(stream, self.filter_type)?;
+		write_sha256(stream, &self.stop_hash)?;
+		write_sha256(stream, &self.prev_header)?;
+		write_var_int(stream, self.filter_hashes.len() as u64)?;
+		for hash in self.filter_hashes.iter() {
+			write_sha256(stream, hash)?;
+		}
+		Ok(())
+	}
+}
+
+#[derive(Clone)]
+pub struct GetCFCheckpt {
+	pub filter_type: u8,
+	pub stop_hash: Sha256,
+}
+
+impl ToJson for GetCFCheckpt {
+	fn to_json(&self) -> JsonValue {
+		JsonValue::object([
+			("filter_type", JsonValue::number(self.filter_type)),
+			("stop_hash", JsonValue::string(format!("{}", self.stop_hash))),
+		])
+	}
+}
+
+impl Deserialize for GetCFCheckpt {
+	fn deserialize(stream: &mut dyn Read) -> Result<Self> {
+		let filter_type = read_u8(stream)?;
+		let stop_hash = read_sha256(stream)?;
+
+		Ok(GetCFCheckpt {
+			filter_type,
+			stop_hash,
+		})
+	}
+}
+
+impl Serialize for GetCFCheckpt {
+	fn serialize(&self, stream: &mut dyn Write) -> Result<()> {
+		write_u8(stream, self.filter_type)?;
+		write_sha256(stream, &self.stop_hash)
+	}
+}
+
+// the filter headers at evenly spaced heights up to the stop hash
+#[derive(Clone)]
+pub struct CFCheckpt {
+	pub filter_type: u8,
+	pub stop_hash: Sha256,
+	pub headers: Vec<Sha256>,
+}
+
+impl ToJson for CFCheckpt {
+	fn to_json(&self) -> JsonValue {
+		JsonValue::object([
+			("filter_type", JsonValue::number(self.filter_type)),
+			("stop_hash", JsonValue::string(format!("{}", self.stop_hash))),
+			("headers", JsonValue::array(self.headers.iter().map(|e| JsonValue::string(format!("{}", e))))),
+		])
+	}
+}
+
+impl Deserialize for CFCheckpt {
+	fn deserialize(stream: &mut dyn Read) -> Result<Self> {
+		let filter_type = read_u8(stream)?;
+		let stop_hash = read_sha256(stream)?;
+		let count = read_var_size(stream)?;
+		let mut headers = Vec::new();
+		for _ in 0..count {
+			headers.push(read_sha256(stream)?);
+		}
+
+		Ok(CFCheckpt {
+			filter_type,
+			stop_hash,
+			headers,
+		})
+	}
+}
+
+impl Serialize for CFCheckpt {
+	fn serialize(&self, stream: &mut dyn Write) -> Result<()> {
+		write_u8(stream, self.filter_type)?;
+		write_sha256(stream, &self.stop_hash)?;
+		write_var_int(stream, self.headers.len() as u64)?;
+		for header in self.headers.iter() {
+			write_sha256(stream, header)?;
+		}
+		Ok(())
+	}
+}
@@ -0,0 +1,196 @@
+use std::{
+	io::{Read, Write},
+};
+
+use crate::{
+	err::*,
+	json::*,
+	crypto::sha256::*,
+	crypto::siphash::compute_siphash,
+};
+
+use crate::common::{
+	read_u64,
+	read_var_int,
+	read_var_size,
+	read_buf_exact,
+	write_u64,
+	write_var_int,
+	write_buf_exact,
+};
+
+use super::{
+	Deserialize,
+	Serialize,
+	Block,
+	Header,
+	Tx,
+};
+
+// BIP 152 compact blocks: a header plus a 6-byte short id for each tx, which the
+// receiver matches against its mempool. txs it can't be expected to have (at
+// least the coinbase) are sent along in full.
+
+// the largest tx count a block could have (its weight limit over the smallest
+// possible tx's weight); a compact block claiming more is bogus
+pub const MAX_CMPCT_TX_COUNT: usize = 4_000_000 / 40;
+
+const SHORT_ID_SIZE: usize = 6;
+
+#[derive(Clone)]
+pub struct PrefilledTx {
+	pub index: usize,
+	pub tx: Tx,
+}
+
+#[derive(Clone)]
+pub struct CmpctBlock {
+	pub header: Header,
+	nonce: u64,
+	pub short_ids: Vec<u64>,
+	pub prefilled: Vec<PrefilledTx>,
+}
+
+impl CmpctBlock {
+	// version 1 compact blocks identify txs by txid and carry no witness data;
+	// version 2 uses wtxids and keeps it
+	pub fn new(block: &Block, version: u64) -> Self {
+		let mut cmpct = CmpctBlock {
+			header: block.header.clone(),
+			nonce: rand::random(),
+			short_ids: Vec::new(),
+			prefilled: vec![PrefilledTx { index: 0, tx: block.txs[0].clone() }],
+		};
+		let keys = cmpct.short_id_keys();
+		cmpct.short_ids = block.txs.iter().skip(1)
+			.map(|tx| short_id(keys, &if version >= 2 { tx.compute_wtxid() } else { tx.compute_hash() }))
+			.collect();
+		if version < 2 {
+			for prefilled in cmpct.prefilled.iter_mut() {
+				prefilled.tx.strip_witness();
+			}
+		}
+		cmpct
+	}
+
+	pub fn tx_count(&self) -> usize {
+		self.short_ids.len() + self.prefilled.len()
+	}
+
+	// the siphash key: the first 16 bytes of sha256(header || nonce)
+	pub fn short_id_keys(&self) -> (u64, u64) {
+		let mut buf = Vec::new();
+		self.header.serialize_without_tx_count(&mut buf).unwrap();
+		buf.extend_from_slice(&self.nonce.to_le_bytes());
+		let hash = compute_sha256(&*buf);
+		let bytes = hash.as_bytes();
+		(u64::from_le_bytes(bytes[0..8].try_into().unwrap()), u64::from_le_bytes(bytes[8..16].try_into().unwrap()))
+	}
+}
+
+pub fn short_id(keys: (u64, u64), id: &Sha256) -> u64 {
+	compute_siphash(keys.0, keys.1, id.as_bytes()) & 0xffff_ffff_ffff
+}
+
+impl ToJson for CmpctBlock {
+	fn to_json(&self) -> JsonValue {
+		JsonValue::object([
+			("header", self.header.to_json()),
+			("nonce", JsonValue::number(self.nonce)),
+			("short_ids", JsonValue::number(self.short_ids.len() as u64)),
+			("prefilled", JsonValue::array(self.prefilled.iter().map(|e| JsonValue::object([
+				("index", JsonValue::number(e.index as u64)),
+				("tx", e.tx.to_json()),
+			])))),
+		])
+	}
+}
+
+impl Deserialize for CmpctBlock {
+	fn deserialize(stream: &mut dyn Read) -> Result<Self> {
+		let mut header = Header::deserialize_without_tx_count(stream)?;
+		let nonce = read_u64(stream)?;
+
+		let count = read_var_size(stream)?;
+		let mut short_ids = Vec::new();
+		for _ in 0..count {
+			let mut bytes = [0; 8];
+			read_buf_exact(stream, &mut bytes[..SHORT_ID_SIZE])?;
+			short_ids.push(u64::from_le_bytes(bytes));
+		}
+
+		let prefilled = read_indexes(stream, |stream| Tx::deserialize(stream))?
+			.into_iter()
+			.map(|(index, tx)| PrefilledTx { index, tx })
+			.collect::<Vec<_>>();
+
+		header.tx_count = short_ids.len() + prefilled.len();
+		Ok(CmpctBlock {
+			header,
+			nonce,
+			short_ids,
+			prefilled,
+		})
+	}
+}
+
+impl Serialize for CmpctBlock {
+	fn serialize(&self, stream: &mut dyn Write) -> Result<()> {
+		self.header.serialize_without_tx_count(stream)?;
+		write_u64(stream, self.nonce)?;
+		write_var_int(stream, self.short_ids.len() as u64)?;
+		for id in self.short_ids.iter() {
+			write_buf_exact(stream, &id.to_le_bytes()[..SHORT_ID_SIZE])?;
+		}
+		let indexes = self.prefilled.iter().map(|e| e.index).collect::<Vec<_>>();
+		write_indexes(stream, &indexes, |stream, i| self.prefilled[i].tx.serialize(stream))
+	}
+}
+
+// indexes into a block's txs are sent differentially encoded: each as the gap
+// since the one before it. `item` reads whatever follows each index
+pub(super) fn read_indexes<T>(stream: &mut dyn Read, mut item: impl FnMut(&mut dyn Read) -> Result<T>) -> Result<Vec<(usize, T)>> {
+	let count = read_var_size(stream)?;
+	let mut items = Vec::new();
+	let mut next = 0u64;
+	for _ in 0..count {
+		let index = next.saturating_add(read_var_int(stream)?);
+		if index > u16::MAX as u64 {
+			return Err(Err::ValueError(format!("tx index out of range: {}", index)));
+		}
+		items.push((index as usize, item(stream)?));
+		next = index + 1;
+	}
+	Ok(items)
+}
+
+pub(super) fn write_indexes(stream: &mut dyn Write, indexes: &[usize], mut item: impl FnMut(&mut dyn Write, usize) -> Result<()>) -> Result<()> {
+	write_var_int(stream, indexes.len() as u64)?;
+	let mut next = 0;
+	for (i, &index) in indexes.iter().enumerate() {
+		write_var_int(stream, (index - next) as u64)?;
+		item(stream, i)?;
+		next = index + 1;
+	}
+	Ok(())
+}
+
+#[test]
+fn cmpctblock_roundtrip() {
+	use crate::common::serialize;
+	use super::GetBlockTxn;
+
+	let block = Block::signet_genesis();
+	let cmpct = CmpctBlock::new(&block, 2);
+	let decoded = CmpctBlock::deserialize(&mut &*serialize(&cmpct).unwrap()).unwrap();
+	assert!(decoded.header.compute_hash() == block.header.compute_hash());
+	assert!(decoded.short_id_keys() == cmpct.short_id_keys());
+	assert!(decoded.tx_count() == 1 && decoded.prefilled[0].index == 0);
+	assert!(decoded.prefilled[0].tx.compute_hash() == block.txs[0].compute_hash());
+
+	// indexes go over the wire as gaps
+	let getblocktxn = GetBlockTxn::new(block.header.compute_hash(), vec![0, 3, 4, 10]);
+	let bytes = serialize(&getblocktxn).unwrap();
+	assert!(bytes[32..] == [4, 0, 2, 0, 5]);
+	assert!(GetBlockTxn::deserialize(&mut &*bytes).unwrap().indexes == [0, 3, 4, 10]);
+}
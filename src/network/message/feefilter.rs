@@ -23,9 +23,9 @@ pub struct FeeFilter {
 }
 
 impl FeeFilter {
-	// pub fn new(feerate: u64) -> Self {
-	// 	FeeFilter { feerate }
-	// }
+	pub fn new(feerate: u64) -> Self {
+		FeeFilter { feerate }
+	}
 
 	pub fn feerate(&self) -> u64 {
 		self.feerate
@@ -0,0 +1,99 @@
+use std::{
+	io::{Read, Write},
+};
+
+use crate::{
+	err::*,
+	json::*,
+	common::bytes_to_hex,
+};
+
+use crate::common::{
+	read_u8,
+	read_u32,
+	read_var_size,
+	read_buf_exact,
+	write_u8,
+	write_u32,
+	write_var_int,
+	write_buf_exact,
+};
+
+use super::{
+	Deserialize,
+	Serialize,
+};
+
+// BIP 37: a bloom filter for the sender's txs, which is then all it gets told about
+#[derive(Clone)]
+pub struct FilterLoad {
+	pub data: Vec<u8>,
+	pub hash_funcs: u32,
+	pub tweak: u32,
+	pub flags: u8,
+}
+
+impl ToJson for FilterLoad {
+	fn to_json(&self) -> JsonValue {
+		JsonValue::object([
+			("data",       JsonValue::string(bytes_to_hex(&self.data))),
+			("hash_funcs", JsonValue::number(self.hash_funcs)),
+			("tweak",      JsonValue::number(self.tweak)),
+			("flags",      JsonValue::number(self.flags)),
+		])
+	}
+}
+
+impl Deserialize for FilterLoad {
+	fn deserialize(stream: &mut dyn Read) -> Result<Self> {
+		let mut data = vec![0; read_var_size(stream)?];
+		read_buf_exact(stream, &mut data)?;
+		let hash_funcs = read_u32(stream)?;
+		let tweak = read_u32(stream)?;
+		let flags = read_u8(stream)?;
+
+		Ok(FilterLoad {
+			data,
+			hash_funcs,
+			tweak,
+			flags,
+		})
+	}
+}
+
+impl Serialize for FilterLoad {
+	fn serialize(&self, stream: &mut dyn Write) -> Result<()> {
+		write_var_int(stream, self.data.len() as u64)?;
+		write_buf_exact(stream, &self.data)?;
+		write_u32(stream, self.hash_funcs)?;
+		write_u32(stream, self.tweak)?;
+		write_u8(stream, self.flags)
+	}
+}
+
+// one more element for the filter that's loaded
+#[derive(Clone)]
+pub struct FilterAdd {
+	pub data: Vec<u8>,
+}
+
+impl ToJson for FilterAdd {
+	fn to_json(&self) -> JsonValue {
+		JsonValue::string(bytes_to_hex(&self.data))
+	}
+}
+
+impl Deserialize for FilterAdd {
+	fn deserialize(stream: &mut dyn Read) -> Result<Self> {
+		let mut data = vec![0; read_var_size(stream)?];
+		read_buf_exact(stream, &mut data)?;
+		Ok(FilterAdd { data })
+	}
+}
+
+impl Serialize for FilterAdd {
+	fn serialize(&self, stream: &mut dyn Write) -> Result<()> {
+		write_var_int(stream, self.data.len() as u64)?;
+		write_buf_exact(stream, &self.data)
+	}
+}
@@ -52,6 +52,15 @@ impl GetHeaders {
             hash_stop: None,
 		}
 	}
+
+	// the block locator: hashes of the sender's chain, densest near its tip
+	pub fn hashes(&self) -> &[Sha256] {
+		&self.hashes
+	}
+
+	pub fn hash_stop(&self) -> Option<Sha256> {
+		self.hash_stop
+	}
 }
 
 impl ToJson for GetHeaders {
@@ -63,6 +63,16 @@ impl Header {
 		self.tx_count
 	}
 
+	pub fn set_nonce(&mut self, nonce: u32) {
+		self.nonce = nonce;
+		self.hash.set(None);
+	}
+
+	pub fn set_merkle_root(&mut self, merkle_root: Sha256) {
+		self.merkle_root = merkle_root;
+		self.hash.set(None);
+	}
+
 	pub fn compute_hash(&self) -> Sha256 {
 		if let Some(hash) = self.hash.get() {
 			return hash;
@@ -80,7 +90,30 @@ impl Header {
 		u256::from_u64(base) << exp
 	}
 
-	fn serialize_without_tx_count(&self, stream: &mut dyn Write) -> Result<()> {
+	// the inverse of compute_target, for targets it can represent
+	pub fn compact_target(target: &u256) -> u32 {
+		let bytes = target.as_bytes();
+		let mut size = bytes.iter().rposition(|&b| b != 0).map_or(0, |i| i + 1);
+		let mut mantissa = (0..3)
+			.map(|i| if size > i { bytes[size - 1 - i] as u32 } else { 0 })
+			.fold(0, |n, b| n << 8 | b);
+		// the top bit would be read as a sign
+		if mantissa & 0x80_0000 != 0 {
+			mantissa >>= 8;
+			size += 1;
+		}
+		(size as u32) << 24 | mantissa
+	}
+
+	// the work a block with this target proves: the expected number of hashes
+	// to find one, 2^256 / (target + 1)
+	pub fn compute_work(target: &u256) -> u256 {
+		let max = u256::from([0xff; 32]);
+		(max - *target) / (*target + 1.into()) + 1.into()
+	}
+
+	// the bare 80 bytes, as hashed (and as sent in a compact block)
+	pub fn serialize_without_tx_count(&self, stream: &mut dyn Write) -> Result<()> {
 		write_i32(stream, self.version)?;
 		write_sha256(stream, &self.prev_block)?;
 		write_sha256(stream, &self.merkle_root)?;
@@ -88,30 +121,14 @@ impl Header {
 		write_u32(stream, self.bits)?;
 		write_u32(stream, self.nonce)
 	}
-}
-
-impl ToJson for Header {
-	fn to_json(&self) -> JsonValue {
-		JsonValue::object([
-			("version", JsonValue::number(self.version)),
-			("prev_block", JsonValue::string(format!("{}", self.prev_block))),
-			("merkle_root", JsonValue::string(format!("{}", self.merkle_root))),
-			("timestamp", JsonValue::number(self.timestamp)),
-			("bits", JsonValue::number(self.bits)),
-			("nonce", JsonValue::number(self.nonce)),
-		])
-	}
-}
 
-impl Deserialize for Header {
-	fn deserialize(stream: &mut dyn Read) -> Result<Header> {
+	pub fn deserialize_without_tx_count(stream: &mut dyn Read) -> Result<Header> {
 		let version = read_i32(stream)?;
 		let prev_block = read_sha256(stream)?;
 		let merkle_root = read_sha256(stream)?;
 		let timestamp = read_u32(stream)?;
 		let bits = read_u32(stream)?;
 		let nonce = read_u32(stream)?;
-		let tx_count = read_var_int(stream)? as usize;
 
 		Ok(Header { 
 			version,
@@ -120,12 +137,33 @@ impl Deserialize for Header {
 			timestamp,
 			bits,
 			nonce,
-			tx_count,
+			tx_count: 0,
 			hash: Cell::new(None),
 		})
 	}
 }
 
+impl ToJson for Header {
+	fn to_json(&self) -> JsonValue {
+		JsonValue::object([
+			("version", JsonValue::number(self.version)),
+			("prev_block", JsonValue::string(format!("{}", self.prev_block))),
+			("merkle_root", JsonValue::string(format!("{}", self.merkle_root))),
+			("timestamp", JsonValue::number(self.timestamp)),
+			("bits", JsonValue::number(self.bits)),
+			("nonce", JsonValue::number(self.nonce)),
+		])
+	}
+}
+
+impl Deserialize for Header {
+	fn deserialize(stream: &mut dyn Read) -> Result<Header> {
+		let mut header = Header::deserialize_without_tx_count(stream)?;
+		header.tx_count = read_var_int(stream)? as usize;
+		Ok(header)
+	}
+}
+
 impl Serialize for Header {
 	fn serialize(&self, stream: &mut dyn Write) -> Result<()> {
 		self.serialize_without_tx_count(stream)?;
@@ -137,11 +175,9 @@ impl Serialize for Header {
 pub struct Headers(Vec<Header>);
 
 impl Headers {
-	// pub fn new() -> Self {
-	// 	Headers {
-	// 		???
-	// 	}
-	// }
+	pub fn new(headers: Vec<Header>) -> Self {
+		Headers(headers)
+	}
 
 	pub fn iter(&self) -> std::slice::Iter<Header> {
 		self.0.iter()
@@ -178,8 +214,19 @@ impl Serialize for Headers {
 	fn serialize(&self, stream: &mut dyn Write) -> Result<()> {
 		write_var_int(stream, self.0.len() as u64)?;
 		for header in self.0.iter() {
-			header.serialize(stream)?;
+			// headers messages never carry transactions, so the count is always zero
+			header.serialize_without_tx_count(stream)?;
+			write_var_int(stream, 0)?;
 		}
 		Ok(())
 	}
-}
\ No newline at end of file
+}
+
+#[test]
+fn work() {
+	// as in bitcoind's chainwork: a mainnet block at the minimum difficulty, and a regtest one
+	let header = |bits| Header::new(1, Sha256::default(), Sha256::default(), 0, bits, 0, 0);
+	assert!(Header::compute_work(&header(0x1d00ffff).compute_target()) == u256::from(0x1_0001_0001));
+	assert!(Header::compute_work(&header(0x207fffff).compute_target()) == u256::from(2));
+	assert_eq!(Header::compact_target(&header(0x1d00ffff).compute_target()), 0x1d00ffff);
+}
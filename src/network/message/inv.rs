@@ -174,9 +174,15 @@ impl GetData {
 		})
 	}
 
-	// pub fn items(&self) -> &[InvItem] {
-	//     self.0.items()
-	// }
+}
+
+impl IntoIterator for GetData {
+	type Item = InvItem;
+	type IntoIter = std::vec::IntoIter<InvItem>;
+	
+	fn into_iter(self) -> std::vec::IntoIter<InvItem> {
+		self.0.into_iter()
+	}
 }
 
 impl ToJson for GetData {
@@ -0,0 +1,199 @@
+use std::{
+	io::{Read, Write},
+};
+
+use crate::{
+	err::*,
+	json::*,
+	crypto::sha256::*,
+};
+
+use crate::common::{
+	read_u32,
+	read_sha256,
+	read_var_size,
+	read_buf_exact,
+	write_u32,
+	write_sha256,
+	write_var_int,
+	write_buf_exact,
+};
+
+use super::{
+	Deserialize,
+	Serialize,
+	Block,
+	Header,
+};
+
+// BIP 37: a block header plus the part of its merkle tree that proves which txs
+// matched the peer's filter. the tree is walked depth first; each node gets a flag
+// bit saying whether a matched tx is under it, and nodes with nothing matched under
+// them (and the leaves) are sent as just their hash.
+#[derive(Clone)]
+pub struct MerkleBlock {
+	pub header: Header,
+	total_txs: u32,
+	hashes: Vec<Sha256>,
+	bits: Vec<bool>,
+}
+
+impl MerkleBlock {
+	pub fn new(block: &Block, matches: &[bool]) -> Self {
+		let txids = block.txs.iter().map(|tx| tx.compute_hash()).collect::<Vec<_>>();
+		let mut merkle_block = MerkleBlock {
+			header: block.header.clone(),
+			total_txs: txids.len() as u32,
+			hashes: Vec::new(),
+			bits: Vec::new(),
+		};
+		merkle_block.build(merkle_block.tree_height(), 0, &txids, matches);
+		merkle_block
+	}
+
+	// the number of nodes at `height` above the leaves
+	fn width(&self, height: usize) -> usize {
+		(self.total_txs as usize + (1 << height) - 1) >> height
+	}
+
+	fn tree_height(&self) -> usize {
+		let mut height = 0;
+		while self.width(height) > 1 {
+			height += 1;
+		}
+		height
+	}
+
+	fn compute_hash(&self, height: usize, pos: usize, txids: &[Sha256]) -> Sha256 {
+		if height == 0 {
+			return txids[pos];
+		}
+		let left = self.compute_hash(height - 1, pos * 2, txids);
+		let right = if pos * 2 + 1 < self.width(height - 1) {
+			self.compute_hash(height - 1, pos * 2 + 1, txids)
+		} else {
+			left
+		};
+		combine(&left, &right)
+	}
+
+	fn build(&mut self, height: usize, pos: usize, txids: &[Sha256], matches: &[bool]) {
+		let first = pos << height;
+		let last = std::cmp::min((pos + 1) << height, txids.len());
+		let matched = matches[first..last].iter().any(|&e| e);
+		self.bits.push(matched);
+		if height == 0 || !matched {
+			let hash = self.compute_hash(height, pos, txids);
+			self.hashes.push(hash);
+		} else {
+			self.build(height - 1, pos * 2, txids, matches);
+			if pos * 2 + 1 < self.width(height - 1) {
+				self.build(height - 1, pos * 2 + 1, txids, matches);
+			}
+		}
+	}
+}
+
+fn combine(left: &Sha256, right: &Sha256) -> Sha256 {
+	let mut combined = Vec::with_capacity(64);
+	combined.extend_from_slice(left.as_bytes());
+	combined.extend_from_slice(right.as_bytes());
+	compute_double_sha256(&*combined)
+}
+
+impl ToJson for MerkleBlock {
+	fn to_json(&self) -> JsonValue {
+		JsonValue::object([
+			("header", self.header.to_json()),
+			("total_txs", JsonValue::number(self.total_txs)),
+			("hashes", JsonValue::array(self.hashes.iter().map(|e| JsonValue::string(format!("{}", e))))),
+			("bits", JsonValue::string(self.bits.iter().map(|&e| if e { '1' } else { '0' }).collect::<String>())),
+		])
+	}
+}
+
+impl Deserialize for MerkleBlock {
+	fn deserialize(stream: &mut dyn Read) -> Result<Self> {
+		let header = Header::deserialize_without_tx_count(stream)?;
+		let total_txs = read_u32(stream)?;
+
+		let count = read_var_size(stream)?;
+		let mut hashes = Vec::new();
+		for _ in 0..count {
+			hashes.push(read_sha256(stream)?);
+		}
+
+		let mut bytes = vec![0; read_var_size(stream)?];
+		read_buf_exact(stream, &mut bytes)?;
+		let bits = (0..bytes.len() * 8)
+			.map(|i| bytes[i / 8] & (1 << (i % 8)) != 0)
+			.collect();
+
+		Ok(MerkleBlock {
+			header,
+			total_txs,
+			hashes,
+			bits,
+		})
+	}
+}
+
+impl Serialize for MerkleBlock {
+	fn serialize(&self, stream: &mut dyn Write) -> Result<()> {
+		self.header.serialize_without_tx_count(stream)?;
+		write_u32(stream, self.total_txs)?;
+		write_var_int(stream, self.hashes.len() as u64)?;
+		for hash in self.hashes.iter() {
+			write_sha256(stream, hash)?;
+		}
+		let mut bytes = vec![0u8; self.bits.len().div_ceil(8)];
+		for (i, _) in self.bits.iter().enumerate().filter(|(_, &e)| e) {
+			bytes[i / 8] |= 1 << (i % 8);
+		}
+		write_var_int(stream, bytes.len() as u64)?;
+		write_buf_exact(stream, &bytes)
+	}
+}
+
+#[test]
+fn merkleblock() {
+	use crate::common::serialize;
+	use super::compute_merkle_root;
+
+	// walks the partial tree the way a client would, giving back the root and the matched txids
+	fn extract(mb: &MerkleBlock, height: usize, pos: usize, bits: &mut std::slice::Iter<bool>, hashes: &mut std::slice::Iter<Sha256>, matched: &mut Vec<Sha256>) -> Sha256 {
+		let parent_of_match = *bits.next().unwrap();
+		if height == 0 || !parent_of_match {
+			let hash = *hashes.next().unwrap();
+			if height == 0 && parent_of_match {
+				matched.push(hash);
+			}
+			return hash;
+		}
+		let left = extract(mb, height - 1, pos * 2, bits, hashes, matched);
+		let right = if pos * 2 + 1 < mb.width(height - 1) {
+			extract(mb, height - 1, pos * 2 + 1, bits, hashes, matched)
+		} else {
+			left
+		};
+		combine(&left, &right)
+	}
+
+	let mut block = Block::signet_genesis();
+	for i in 1..7 {
+		let mut tx = block.txs[0].clone();
+		tx.inputs[0].index = i;
+		block.txs.push(tx);
+	}
+	block.update_merkle_root();
+	let txids = block.txs.iter().map(|tx| tx.compute_hash()).collect::<Vec<_>>();
+
+	let matches = [false, true, false, false, false, true, false];
+	let mb = MerkleBlock::deserialize(&mut &*serialize(&MerkleBlock::new(&block, &matches)).unwrap()).unwrap();
+	assert!(mb.total_txs == 7);
+
+	let mut matched = Vec::new();
+	let root = extract(&mb, mb.tree_height(), 0, &mut mb.bits.iter(), &mut mb.hashes.iter(), &mut matched);
+	assert!(root == compute_merkle_root(txids.clone()) && root == mb.header.merkle_root);
+	assert!(matched == [txids[1], txids[5]]);
+}
@@ -1,5 +1,6 @@
 use std::net::{ToSocketAddrs, SocketAddr, IpAddr};
 use std::io::{Write, Read};
+use std::sync::atomic::{AtomicU32, Ordering};
 use std::fmt;
 
 use crate::{
@@ -40,6 +41,11 @@ mod tx;
 mod getheaders;
 mod headers;
 mod block;
+mod cmpctblock;
+mod blocktxn;
+mod filter;
+mod merkleblock;
+mod cfilters;
 
 pub use {
     version::*,
@@ -53,6 +59,11 @@ pub use {
 	getheaders::*,
 	headers::*,
 	block::*,
+	cmpctblock::*,
+	blocktxn::*,
+	filter::*,
+	merkleblock::*,
+	cfilters::*,
 };
 
 #[derive(Clone)]
@@ -84,7 +95,7 @@ impl Serialize for ShortNetAddr {
 	fn serialize(&self, stream: &mut dyn Write) -> Result<()> {
 		write_u64(stream, self.services)?;
 		let bytes = match self.addr.ip() {
-			IpAddr::V4(ip) => ip.to_ipv6_compatible().octets(),
+			IpAddr::V4(ip) => ip.to_ipv6_mapped().octets(),
 			IpAddr::V6(ip) => ip.octets(),
 		};
 		write_buf_exact(stream, &bytes)?;
@@ -109,67 +120,9 @@ impl Deserialize for ShortNetAddr {
 	}
 }
 
-#[derive(Clone)]
-struct NetAddr {
-	timestamp: u32,
-	services: u64,
-	addr: SocketAddr,
-}
-
-impl NetAddr {
-	// fn from<A: ToSocketAddrs>(addr: A) -> Self {
-	// 	let addr = addr.to_socket_addrs().unwrap().next().unwrap();
-	// 	NetAddr {
-	// 		timestamp: now() as u32,
-	// 		services: 0,
-	// 		addr,
-	// 	}
-	// }
-}
-
-impl ToJson for NetAddr {
-	fn to_json(&self) -> JsonValue {
-		JsonValue::object([
-			("timestamp", JsonValue::number(self.timestamp)),
-			("services", JsonValue::number(self.services)),
-			("addr",     JsonValue::string(format!("{}", self.addr))),
-		])
-	}
-}
-
-impl Serialize for NetAddr {
-	fn serialize(&self, stream: &mut dyn Write) -> Result<()> {
-		write_u32(stream, self.timestamp)?;
-		write_u64(stream, self.services)?;
-		let bytes = match self.addr.ip() {
-			IpAddr::V4(ip) => ip.to_ipv6_compatible().octets(),
-			IpAddr::V6(ip) => ip.octets(),
-		};
-		write_buf_exact(stream, &bytes)?;
-		write_u16_be(stream, self.addr.port())
-	}
-}
-
-impl Deserialize for NetAddr {
-	fn deserialize(stream: &mut dyn Read) -> Result<Self> {
-		let timestamp = read_u32(stream)?;
-		let services = read_u64(stream)?;
-		let mut octets = [0; 16];
-		read_buf_exact(stream, &mut octets)?;
-		let ip = IpAddr::from(octets);
-		let port = read_u16_be(stream)?;
-		
-		Ok(NetAddr {
-			timestamp,
-			services,
-			addr: SocketAddr::new(ip, port),
-		})
-	}
-}
-
 #[repr(u32)]
-#[derive(Clone, Copy)]
-enum Network {
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Network {
 	Main = 0xD9B4BEF9,
 	TestNet = 0xDAB5BFFA,
 	TestNet3 = 0x0709110B,
@@ -204,6 +157,19 @@ impl fmt::Display for Network {
 	}
 }
 
+// the message start bytes of the chain this node is running on. this is a
+// process-wide setting: custom signets derive theirs from the block challenge,
+// so it can't be a fixed `Network` discriminant.
+static MAGIC: AtomicU32 = AtomicU32::new(Network::Main as u32);
+
+pub fn set_magic(magic: u32) {
+	MAGIC.store(magic, Ordering::Relaxed);
+}
+
+pub fn magic() -> u32 {
+	MAGIC.load(Ordering::Relaxed)
+}
+
 pub enum Payload {
 	Version(Version),
 	Verack,
@@ -211,9 +177,10 @@ pub enum Payload {
 	SendAddrV2,
 	SendHeaders,
 	Addr(Addr),
+	AddrV2(AddrV2),
 	Inv(Inv),
 	GetData(GetData),
-	NotFound,
+	NotFound(Inv),
 	GetHeaders(GetHeaders),
 	GetBlocks(GetHeaders),
 	Tx(Sha256, Tx),
@@ -227,16 +194,24 @@ pub enum Payload {
 	Ping(Ping),
 	Pong(Pong),
 	Reject,
-	FilterLoad,
-	FilterAdd,
+	FilterLoad(FilterLoad),
+	FilterAdd(FilterAdd),
 	FilterClear,
-	MerkleBlock,
+	MerkleBlock(MerkleBlock),
 	Alert,
 	FeeFilter(FeeFilter),
 	SendCmpct(SendCmpct),
-	CmpctBlock,
-	GetBlockTxn,
-	BlockTxn,
+	CmpctBlock(CmpctBlock),
+	GetBlockTxn(GetBlockTxn),
+	BlockTxn(BlockTxn),
+	GetCFilters(GetCFilters),
+	CFilter(CFilter),
+	GetCFHeaders(GetCFilters),
+	CFHeaders(CFHeaders),
+	GetCFCheckpt(GetCFCheckpt),
+	CFCheckpt(CFCheckpt),
+	// a message type we don't know; ignored
+	Unknown,
 }
 
 impl Payload {
@@ -248,9 +223,10 @@ impl Payload {
 			Payload::SendAddrV2 => "sendaddrv2",
 			Payload::SendHeaders => "sendheaders",
 			Payload::Addr(_) => "addr",
+			Payload::AddrV2(_) => "addrv2",
 			Payload::Inv(_) => "inv",
 			Payload::GetData(_) => "getdata",
-			Payload::NotFound => "notfound",
+			Payload::NotFound(_) => "notfound",
 			Payload::GetBlocks(_) => "getblocks",
 			Payload::GetHeaders(_) => "getheaders",
 			Payload::Tx(..) => "tx",
@@ -264,16 +240,23 @@ impl Payload {
 			Payload::Ping(_) => "ping",
 			Payload::Pong(_) => "pong",
 			Payload::Reject => "reject",
-			Payload::FilterLoad => "filterload",
-			Payload::FilterAdd => "filteradd",
+			Payload::FilterLoad(_) => "filterload",
+			Payload::FilterAdd(_) => "filteradd",
 			Payload::FilterClear => "filterclear",
-			Payload::MerkleBlock => "merkleblock",
+			Payload::MerkleBlock(_) => "merkleblock",
 			Payload::Alert => "alert",
 			Payload::FeeFilter(_) => "feefilter",
 			Payload::SendCmpct(_) => "sendcmpct",
-			Payload::CmpctBlock => "cmpctblock",
-			Payload::GetBlockTxn => "getblocktxn",
-			Payload::BlockTxn => "blocktxn",
+			Payload::CmpctBlock(_) => "cmpctblock",
+			Payload::GetBlockTxn(_) => "getblocktxn",
+			Payload::BlockTxn(_) => "blocktxn",
+			Payload::GetCFilters(_) => "getcfilters",
+			Payload::CFilter(_) => "cfilter",
+			Payload::GetCFHeaders(_) => "getcfheaders",
+			Payload::CFHeaders(_) => "cfheaders",
+			Payload::GetCFCheckpt(_) => "getcfcheckpt",
+			Payload::CFCheckpt(_) => "cfcheckpt",
+			Payload::Unknown => "unknown",
 		}
 	}
 }
@@ -287,9 +270,10 @@ impl ToJson for Payload {
 			Payload::SendAddrV2 => JsonValue::null(),
 			Payload::SendHeaders => JsonValue::null(),
 			Payload::Addr(x) => x.to_json(),
+			Payload::AddrV2(x) => x.to_json(),
 			Payload::Inv(x) => x.to_json(),
 			Payload::GetData(x) => x.to_json(),
-			Payload::NotFound => JsonValue::null(),
+			Payload::NotFound(x) => x.to_json(),
 			Payload::GetBlocks(x) => x.to_json(),
 			Payload::GetHeaders(x) => x.to_json(),
 			Payload::Tx(_, x) => x.to_json(),
@@ -303,16 +287,23 @@ impl ToJson for Payload {
 			Payload::Ping(x) => x.to_json(),
 			Payload::Pong(x) => x.to_json(),
 			Payload::Reject => JsonValue::null(),
-			Payload::FilterLoad => JsonValue::null(),
-			Payload::FilterAdd => JsonValue::null(),
+			Payload::FilterLoad(x) => x.to_json(),
+			Payload::FilterAdd(x) => x.to_json(),
 			Payload::FilterClear => JsonValue::null(),
-			Payload::MerkleBlock => JsonValue::null(),
+			Payload::MerkleBlock(x) => x.to_json(),
 			Payload::Alert => JsonValue::null(),
 			Payload::FeeFilter(x) => x.to_json(),
 			Payload::SendCmpct(x) => x.to_json(),
-			Payload::CmpctBlock => JsonValue::null(),
-			Payload::GetBlockTxn => JsonValue::null(),
-			Payload::BlockTxn => JsonValue::null(),
+			Payload::CmpctBlock(x) => x.to_json(),
+			Payload::GetBlockTxn(x) => x.to_json(),
+			Payload::BlockTxn(x) => x.to_json(),
+			Payload::GetCFilters(x) => x.to_json(),
+			Payload::CFilter(x) => x.to_json(),
+			Payload::GetCFHeaders(x) => x.to_json(),
+			Payload::CFHeaders(x) => x.to_json(),
+			Payload::GetCFCheckpt(x) => x.to_json(),
+			Payload::CFCheckpt(x) => x.to_json(),
+			Payload::Unknown => JsonValue::null(),
 		}
 	}
 }
@@ -326,9 +317,10 @@ impl Serialize for Payload {
 			Payload::SendAddrV2 => Ok(()),
 			Payload::SendHeaders => Ok(()),
 			Payload::Addr(x) => x.serialize(stream),
+			Payload::AddrV2(x) => x.serialize(stream),
 			Payload::Inv(x) => x.serialize(stream),
 			Payload::GetData(x) => x.serialize(stream),
-			Payload::NotFound => Ok(()),
+			Payload::NotFound(x) => x.serialize(stream),
 			Payload::GetHeaders(x) => x.serialize(stream),
 			Payload::GetBlocks(x) => x.serialize(stream),
 			Payload::Tx(_, x) => x.serialize(stream),
@@ -342,50 +334,64 @@ impl Serialize for Payload {
 			Payload::Ping(x) => x.serialize(stream),
 			Payload::Pong(x) => x.serialize(stream),
 			Payload::Reject => Ok(()),
-			Payload::FilterLoad => Ok(()),
-			Payload::FilterAdd => Ok(()),
+			Payload::FilterLoad(x) => x.serialize(stream),
+			Payload::FilterAdd(x) => x.serialize(stream),
 			Payload::FilterClear => Ok(()),
-			Payload::MerkleBlock => Ok(()),
+			Payload::MerkleBlock(x) => x.serialize(stream),
 			Payload::Alert => Ok(()),
 			Payload::FeeFilter(x) => x.serialize(stream),
 			Payload::SendCmpct(x) => x.serialize(stream),
-			Payload::CmpctBlock => Ok(()),
-			Payload::GetBlockTxn => Ok(()),
-			Payload::BlockTxn => Ok(()),
+			Payload::CmpctBlock(x) => x.serialize(stream),
+			Payload::GetBlockTxn(x) => x.serialize(stream),
+			Payload::BlockTxn(x) => x.serialize(stream),
+			Payload::GetCFilters(x) => x.serialize(stream),
+			Payload::CFilter(x) => x.serialize(stream),
+			Payload::GetCFHeaders(x) => x.serialize(stream),
+			Payload::CFHeaders(x) => x.serialize(stream),
+			Payload::GetCFCheckpt(x) => x.serialize(stream),
+			Payload::CFCheckpt(x) => x.serialize(stream),
+			Payload::Unknown => Err(Err::ValueError("cannot serialize an unknown message".to_owned())),
 		}
 	}
 }
 
 pub struct Message {
-    network: Network,
+	magic: u32,
 	payload: Payload,
 }
 
 impl Message {
-	pub fn version<A: ToSocketAddrs>(addr: A) -> Self {
+	pub fn version<A: ToSocketAddrs>(addr: A, services: u64) -> Self {
 		Message {
-			network: Network::Main,
-			payload: Payload::Version(Version::new(&addr)),
+			magic: magic(),
+			payload: Payload::Version(Version::new(&addr, services)),
 		}
 	}
 
 	pub fn verack() -> Self {
 		Message {
-			network: Network::Main,
+			magic: magic(),
 			payload: Payload::Verack,
 		}
 	}
 
+	pub fn ping(nonce: u64) -> Self {
+		Message {
+			magic: magic(),
+			payload: Payload::Ping(Ping::new(nonce)),
+		}
+	}
+
 	pub fn pong(nonce: u64) -> Self {
 		Message {
-			network: Network::Main,
+			magic: magic(),
 			payload: Payload::Pong(Pong::new(nonce)),
 		}
 	}
 	
 	pub fn getheaders(hashes: &[Sha256]) -> Self {
 		Message {
-			network: Network::Main,
+			magic: magic(),
 			payload: Payload::GetHeaders(GetHeaders::new(hashes)),
 		}
 	}
@@ -397,20 +403,146 @@ impl Message {
 	// 	}
 	// }
 
+	pub fn sendaddrv2() -> Self {
+		Message {
+			magic: magic(),
+			payload: Payload::SendAddrV2,
+		}
+	}
+
+	pub fn getaddr() -> Self {
+		Message {
+			magic: magic(),
+			payload: Payload::GetAddr,
+		}
+	}
+
+	pub fn addr(addrs: Vec<NetAddr>) -> Self {
+		Message {
+			magic: magic(),
+			payload: Payload::Addr(Addr::new(addrs)),
+		}
+	}
+
+	pub fn addrv2(addrs: Vec<NetAddr>) -> Self {
+		Message {
+			magic: magic(),
+			payload: Payload::AddrV2(AddrV2::new(addrs)),
+		}
+	}
+
+	pub fn feefilter(feerate: u64) -> Self {
+		Message {
+			magic: magic(),
+			payload: Payload::FeeFilter(FeeFilter::new(feerate)),
+		}
+	}
+
 	pub fn sendheaders() -> Self {
 		Message {
-			network: Network::Main,
+			magic: magic(),
 			payload: Payload::SendHeaders,
 		}
 	}
 
 	pub fn getdata(inv: Vec<InvItem>) -> Self {
 		Message {
-			network: Network::Main,
+			magic: magic(),
 			payload: Payload::GetData(GetData::new(inv)),
 		}
 	}
 
+	pub fn inv(inv: Vec<InvItem>) -> Self {
+		Message {
+			magic: magic(),
+			payload: Payload::Inv(Inv::new(inv)),
+		}
+	}
+
+	pub fn notfound(inv: Vec<InvItem>) -> Self {
+		Message {
+			magic: magic(),
+			payload: Payload::NotFound(Inv::new(inv)),
+		}
+	}
+
+	pub fn headers(headers: Vec<Header>) -> Self {
+		Message {
+			magic: magic(),
+			payload: Payload::Headers(Headers::new(headers)),
+		}
+	}
+
+	pub fn tx(tx: Tx) -> Self {
+		Message {
+			magic: magic(),
+			payload: Payload::Tx(tx.compute_hash(), tx),
+		}
+	}
+
+	pub fn block(block: Block) -> Self {
+		Message {
+			magic: magic(),
+			payload: Payload::Block(block),
+		}
+	}
+
+	pub fn sendcmpct(announce: bool, version: u64) -> Self {
+		Message {
+			magic: magic(),
+			payload: Payload::SendCmpct(SendCmpct::new(announce, version)),
+		}
+	}
+
+	pub fn cmpctblock(cmpct: CmpctBlock) -> Self {
+		Message {
+			magic: magic(),
+			payload: Payload::CmpctBlock(cmpct),
+		}
+	}
+
+	pub fn getblocktxn(block_hash: Sha256, indexes: Vec<usize>) -> Self {
+		Message {
+			magic: magic(),
+			payload: Payload::GetBlockTxn(GetBlockTxn::new(block_hash, indexes)),
+		}
+	}
+
+	pub fn blocktxn(block_hash: Sha256, txs: Vec<Tx>) -> Self {
+		Message {
+			magic: magic(),
+			payload: Payload::BlockTxn(BlockTxn::new(block_hash, txs)),
+		}
+	}
+
+	pub fn merkleblock(merkle_block: MerkleBlock) -> Self {
+		Message {
+			magic: magic(),
+			payload: Payload::MerkleBlock(merkle_block),
+		}
+	}
+
+	pub fn cfilter(filter_type: u8, block_hash: Sha256, filter: Vec<u8>) -> Self {
+		Message {
+			magic: magic(),
+			payload: Payload::CFilter(CFilter { filter_type, block_hash, filter }),
+		}
+	}
+
+	pub fn cfheaders(filter_type: u8, stop_hash: Sha256, prev_header: Sha256, filter_hashes: Vec<Sha256>) -> Self {
+		Message {
+			magic: magic(),
+			payload: Payload::CFHeaders(CFHeaders { filter_type, stop_hash, prev_header, filter_hashes }),
+		}
+	}
+
+	pub fn cfcheckpt(filter_type: u8, stop_hash: Sha256, headers: Vec<Sha256>) -> Self {
+		Message {
+			magic: magic(),
+			payload: Payload::CFCheckpt(CFCheckpt { filter_type, stop_hash, headers }),
+		}
+	}
+
 	pub fn payload(&self) -> &Payload {
 		&self.payload
 	}
@@ -418,12 +550,74 @@ impl Message {
 	pub fn take_payload(self) -> Payload {
 		self.payload
 	}
+
+	// a message from its command and payload bytes, however they were framed. a
+	// ValueError means the payload was malformed
+	pub fn from_parts(name: &str, payload_bytes: &[u8]) -> Result<Self> {
+		let payload_stream = &mut &*payload_bytes as &mut dyn Read;
+		let payload: Result<Payload> = try { match name {
+			"version" => Payload::Version(Version::deserialize(payload_stream)?),
+			"verack" => Payload::Verack,
+			"wtxidrelay" => Payload::WTxIdRelay,
+			"sendaddrv2" => Payload::SendAddrV2,
+			"sendheaders" => Payload::SendHeaders,
+			"addr" => Payload::Addr(Addr::deserialize(payload_stream)?),
+			"addrv2" => Payload::AddrV2(AddrV2::deserialize(payload_stream)?),
+			"inv" => Payload::Inv(Inv::deserialize(payload_stream)?),
+			"getdata" => Payload::GetData(GetData::deserialize(payload_stream)?),
+			"notfound" => Payload::NotFound(Inv::deserialize(payload_stream)?),
+			"getblocks" => Payload::GetBlocks(GetHeaders::deserialize(payload_stream)?),
+			"getheaders" => Payload::GetHeaders(GetHeaders::deserialize(payload_stream)?),
+			"tx" => {
+				let tx = Tx::deserialize(payload_stream)?;
+				Payload::Tx(tx.compute_hash(), tx)
+			},
+			"block" => Payload::Block(Block::deserialize(payload_stream)?),
+			"headers" => Payload::Headers(Headers::deserialize(payload_stream)?),
+			"getaddr" => Payload::GetAddr,
+			"mempool" => Payload::MemPool,
+			"checkorder" => Payload::CheckOrder,
+			"submitorder" => Payload::SubmitOrder,
+			"reply" => Payload::Reply,
+			"ping" => Payload::Ping(Ping::deserialize(payload_stream)?),
+			"pong" => Payload::Pong(Pong::deserialize(payload_stream)?),
+			"reject" => Payload::Reject,
+			"filterload" => Payload::FilterLoad(FilterLoad::deserialize(payload_stream)?),
+			"filteradd" => Payload::FilterAdd(FilterAdd::deserialize(payload_stream)?),
+			"filterclear" => Payload::FilterClear,
+			"merkleblock" => Payload::MerkleBlock(MerkleBlock::deserialize(payload_stream)?),
+			"alert" => Payload::Alert,
+			"feefilter" => Payload::FeeFilter(FeeFilter::deserialize(payload_stream)?),
+			"sendcmpct" => Payload::SendCmpct(SendCmpct::deserialize(payload_stream)?),
+			"cmpctblock" => Payload::CmpctBlock(CmpctBlock::deserialize(payload_stream)?),
+			"getblocktxn" => Payload::GetBlockTxn(GetBlockTxn::deserialize(payload_stream)?),
+			"blocktxn" => Payload::BlockTxn(BlockTxn::deserialize(payload_stream)?),
+			"getcfilters" => Payload::GetCFilters(GetCFilters::deserialize(payload_stream)?),
+			"cfilter" => Payload::CFilter(CFilter::deserialize(payload_stream)?),
+			"getcfheaders" => Payload::GetCFHeaders(GetCFilters::deserialize(payload_stream)?),
+			"cfheaders" => Payload::CFHeaders(CFHeaders::deserialize(payload_stream)?),
+			"getcfcheckpt" => Payload::GetCFCheckpt(GetCFCheckpt::deserialize(payload_stream)?),
+			"cfcheckpt" => Payload::CFCheckpt(CFCheckpt::deserialize(payload_stream)?),
+
+			_ => {
+				log_debug!("ignoring unknown message: {}", name);
+				Payload::Unknown
+			},
+		}};
+		let payload = payload.map_err(|err| Err::ValueError(format!("malformed {} message: {}", name, err)))?;
+
+		Ok(Message { magic: magic(), payload })
+	}
 }
 
 impl fmt::Display for Message {
 	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		let network = match Network::try_from(self.magic) {
+			Ok(network) => format!("{}", network),
+			Err(_) => format!("{:08x}", self.magic),
+		};
 		let mut props = vec![
-			("network", JsonValue::string(format!("{}", self.network))),
+			("network", JsonValue::string(network)),
 			("message", JsonValue::string(self.payload.name())),
 		];
 		if let Payload::Tx(id, _) = self.payload {
@@ -437,7 +631,7 @@ impl fmt::Display for Message {
 
 impl Serialize for Message {
 	fn serialize(&self, stream: &mut dyn Write) -> Result<()> {
-		write_u32(stream, self.network as u32)?;
+		write_u32(stream, self.magic)?;
 		write_str_exact(stream, self.payload().name(), 12)?;
 		let mut payload_bytes = Vec::new();
 		self.payload.serialize(&mut payload_bytes)?;
@@ -454,7 +648,9 @@ pub const MAX_PAYLOAD_SIZE: usize = 32 * 1024 * 1024;
 impl Deserialize for Message {
 	fn deserialize(stream: &mut dyn Read) -> Result<Self> {
 		let magic = read_u32(stream)?;
-		let network = Network::try_from(magic)?;
+		if magic != self::magic() {
+			return Err(Err::NetworkError(format!("invalid magic number in message: {:08x}", magic)));
+		}
 		let name = read_str_exact(stream, 12)?;
 		let size = read_u32(stream)? as usize;
 		let checksum = read_u32_be(stream)?;
@@ -469,48 +665,13 @@ impl Deserialize for Message {
 		let sha256 = compute_double_sha256(&*payload_bytes);
 		let real_checksum = u32::from_be_bytes(sha256.as_bytes()[0..4].try_into().unwrap());
 		
+		// from here on the whole message has been read, so errors are the sender's
+		// fault (ValueError) rather than a broken connection, and the stream is
+		// still good for the next message
 		if checksum != real_checksum {
-			return Err(Err::NetworkError(format!("checksum failure: expected {}, found {}", real_checksum, checksum)));
+			return Err(Err::ValueError(format!("checksum failure: expected {}, found {}", real_checksum, checksum)));
 		}
 
-		let payload_stream = &mut &*payload_bytes as &mut dyn Read;
-		let payload = match name.as_str() {
-			"version" => Payload::Version(Version::deserialize(payload_stream)?),
-			"verack" => Payload::Verack,
-			"wtxidrelay" => Payload::WTxIdRelay,
-			"sendaddrv2" => Payload::SendAddrV2,
-			"sendheaders" => Payload::SendHeaders,
-			"addr" => Payload::Addr(Addr::deserialize(payload_stream)?),
-			"inv" => Payload::Inv(Inv::deserialize(payload_stream)?),
-			"getdata" => Payload::GetData(GetData::deserialize(payload_stream)?),
-			"notfound" => Payload::NotFound,
-			"getblocks" => Payload::GetBlocks(GetHeaders::deserialize(payload_stream)?),
-			"getheaders" => Payload::GetHeaders(GetHeaders::deserialize(payload_stream)?),
-			"tx" => Payload::Tx(sha256, Tx::deserialize(payload_stream)?),
-			"block" => Payload::Block(Block::deserialize(payload_stream)?),
-			"headers" => Payload::Headers(Headers::deserialize(payload_stream)?),
-			"getaddr" => Payload::GetAddr,
-			"mempool" => Payload::MemPool,
-			"checkorder" => Payload::CheckOrder,
-			"submitorder" => Payload::SubmitOrder,
-			"reply" => Payload::Reply,
-			"ping" => Payload::Ping(Ping::deserialize(payload_stream)?),
-			"pong" => Payload::Pong(Pong::deserialize(payload_stream)?),
-			"reject" => Payload::Reject,
-			"filterload" => Payload::FilterLoad,
-			"filteradd" => Payload::FilterAdd,
-			"filterclear" => Payload::FilterClear,
-			"merkleblock" => Payload::MerkleBlock,
-			"alert" => Payload::Alert,
-			"feefilter" => Payload::FeeFilter(FeeFilter::deserialize(payload_stream)?),
-			"sendcmpct" => Payload::SendCmpct(SendCmpct::deserialize(payload_stream)?),
-			"cmpctblock" => Payload::CmpctBlock,
-			"getblocktxn" => Payload::GetBlockTxn,
-			"blocktxn" => Payload::BlockTxn,
-
-			_ => Err(Err::NetworkError(format!("invalid message name: {}", name)))?,
-		};
-
-		Ok(Message { network, payload })
+		Message::from_parts(&name, &payload_bytes)
 	}
 }
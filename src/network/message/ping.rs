@@ -23,11 +23,11 @@ pub struct Ping {
 }
 
 impl Ping {
-	// pub fn new() -> Self {
-	// 	Ping {
-	// 		nonce: rand::random(),
-	// 	}
-	// }
+	pub fn new(nonce: u64) -> Self {
+		Ping {
+			nonce,
+		}
+	}
 
 	pub fn nonce(&self) -> u64 {
 		self.nonce
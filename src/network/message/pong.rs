@@ -26,6 +26,10 @@ impl Pong {
 	pub fn new(nonce: u64) -> Self {
 		Pong { nonce }
 	}
+
+	pub fn nonce(&self) -> u64 {
+		self.nonce
+	}
 }
 
 impl ToJson for Pong {
@@ -26,12 +26,22 @@ pub struct SendCmpct {
 }
 
 impl SendCmpct {
-	// pub fn new() -> Self {
-	// 	SendCmpct {
-	// 		set: true,
-	// 		version: 1,
-	// 	}
-	// }
+	pub fn new(announce: bool, version: u64) -> Self {
+		SendCmpct {
+			set: announce,
+			version,
+		}
+	}
+
+	// whether the sender wants new blocks pushed as compact blocks (high
+	// bandwidth mode) rather than announced first
+	pub fn announce(&self) -> bool {
+		self.set
+	}
+
+	pub fn version(&self) -> u64 {
+		self.version
+	}
 }
 
 impl ToJson for SendCmpct {
@@ -17,6 +17,7 @@ use crate::common::{
 	read_u64,
 	read_sha256,
 	read_var_int,
+	read_var_size,
 	read_buf_exact,
 	write_u16_be,
 	write_u32,
@@ -162,7 +163,7 @@ impl Deserialize for Input {
 	fn deserialize(stream: &mut dyn Read) -> Result<Self> {
         let tx_hash = read_sha256(stream)?;
 		let index = read_u32(stream)?;
-		let unlock_size = read_var_int(stream)? as usize;
+		let unlock_size = read_var_size(stream)?;
 		let mut unlock = vec![0; unlock_size];
 		read_buf_exact(stream, &mut unlock)?;
 		let sequence = read_u32(stream)?;
@@ -222,7 +223,7 @@ impl Default for Output {
 impl Deserialize for Output {
 	fn deserialize(stream: &mut dyn Read) -> Result<Self> {
 		let value = read_u64(stream)?;
-		let lock_length = read_var_int(stream)? as usize;
+		let lock_length = read_var_size(stream)?;
 		let mut lock = vec![0; lock_length];
 		read_buf_exact(stream, &mut lock)?;
 
@@ -253,45 +254,57 @@ pub struct Tx {
 impl Tx {
 	#[must_use]
 	pub fn check_merkle_root(txs: &[Tx], expected: Sha256) -> bool {
-		if let Some(merkle_root) = Tx::compute_merkle_root(txs) {
+		if txs.is_empty() {
+			false
+		} else if let Some(merkle_root) = Tx::compute_merkle_root(txs) {
 			merkle_root == expected
 		} else {
 			false
 		}
 	}
 
-	pub fn compute_merkle_root(txs: &[Tx]) -> Option<Sha256> {
-		assert!(txs.len() > 0);
-		if txs.len() == 1 {
-			Some(txs[0].compute_hash())
-		} else {
-			if txs[txs.len() - 1].compute_hash() == txs[txs.len() - 2].compute_hash() {
-				return None
+	// BIP 68: whether each input's relative lock time has passed for a block at
+	// `height` with median time past `time`. `coin` gives the height a spent
+	// output's tx confirmed at and the median time past of the block before,
+	// or None if that isn't known, which fails any lock
+	pub fn check_sequence_locks(&self, height: usize, time: u32, coin: impl Fn(&Sha256) -> Option<(usize, u32)>) -> bool {
+		if (self.version as i32) < 2 {
+			return true;
+		}
+		self.inputs.iter().all(|input| {
+			let lock = input.rel_lock_time();
+			if let RelativeLockTime::None = lock {
+				return true;
 			}
-
-			let mut hashes = txs.iter().map(|e| e.compute_hash()).collect::<Vec<_>>();
-			
-			while hashes.len() > 1 {
-				if hashes.len() % 2 != 0 {
-					hashes.push(hashes.last().unwrap().clone());
-				}
-				hashes = hashes.chunks(2)
-					.map(|e| {
-						let mut combined = Vec::with_capacity(e[0].as_bytes().len() + e[1].as_bytes().len());
-						combined.extend_from_slice(e[0].as_bytes());
-						combined.extend_from_slice(e[1].as_bytes());
-						compute_double_sha256(&*combined)
-					})
-					.collect::<Vec<_>>();
+			let (coin_height, coin_time) = match coin(&input.tx_hash) {
+				Some(coin) => coin,
+				None => return false,
+			};
+			match lock {
+				RelativeLockTime::Blocks(n) => coin_height + n as usize <= height,
+				RelativeLockTime::Seconds(n) => coin_time as u64 + n as u64 <= time as u64,
+				RelativeLockTime::None => true,
 			}
+		})
+	}
 
-			Some(hashes[0])
+	// None for a list padded out with repeats, which has the root of the shorter
+	// list (CVE-2012-2459): a pair of equal hashes at any level of the tree
+	pub fn compute_merkle_root(txs: &[Tx]) -> Option<Sha256> {
+		assert!(txs.len() > 0);
+		let mut hashes = txs.iter().map(|e| e.compute_hash()).collect::<Vec<_>>();
+		while hashes.len() > 1 {
+			if hashes.chunks_exact(2).any(|pair| pair[0] == pair[1]) {
+				return None;
+			}
+			hashes = merkle_level(hashes);
 		}
+		Some(hashes[0])
 	}
 
 	#[must_use]
 	pub fn validate(&self, utxos: &mut UTXOState, is_coinbase: bool, state: &State) -> bool {
-		let txid = compute_double_sha256(&*serialize(self).unwrap());
+		let txid = self.compute_hash();
 		if is_coinbase {
 			if self.inputs.len() != 1 {
 				return false;
@@ -304,12 +317,7 @@ impl Tx {
 				return false;
 			}
 
-			let mut available = 50 * SAT_PER_COIN;
-			for _ in 0..(state.height()/210_000) {
-				available /= 2;
-			}
-
-			available += utxos.tx_fee;
+			let mut available = block_subsidy(state.height()) + utxos.tx_fee;
 			for (i, output) in self.outputs.iter().cloned().enumerate() {
 				if available < output.value {
 					return false;
@@ -357,7 +365,7 @@ impl Tx {
 	}
 
 	pub fn build_utxo_diff(&self, utxos: &mut UTXOState, is_coinbase: bool) {
-		let txid = compute_double_sha256(&*serialize(self).unwrap());
+		let txid = self.compute_hash();
 		if !is_coinbase {
 			for input in self.inputs.iter() {
 				let id = input.utxo_id();
@@ -370,8 +378,119 @@ impl Tx {
 	}
 
 	pub fn compute_hash(&self) -> Sha256 {
+		let mut buf = Vec::new();
+		self.serialize_without_witness(&mut buf).unwrap();
+		compute_double_sha256(&*buf)
+	}
+
+	pub fn compute_wtxid(&self) -> Sha256 {
 		compute_double_sha256(&*serialize(self).unwrap())
 	}
+
+	// serialized, witness included
+	pub fn size(&self) -> usize {
+		serialize(self).unwrap().len()
+	}
+
+	// BIP 141: non-witness bytes count four times, witness bytes once
+	pub fn weight(&self) -> usize {
+		let mut base = Vec::new();
+		self.serialize_without_witness(&mut base).unwrap();
+		base.len() * 3 + serialize(self).unwrap().len()
+	}
+
+	pub fn vsize(&self) -> usize {
+		self.weight().div_ceil(4)
+	}
+
+	// BIP 141 sigop cost, given the outputs spent by each input in turn. sigops
+	// in legacy and P2SH scripts cost four times those in witness scripts
+	pub fn sigop_cost(&self, prevouts: &[TxOutput]) -> usize {
+		let mut legacy = self.inputs.iter().map(|input| input.unlock.sigop_count(false)).sum::<usize>();
+		legacy += self.outputs.iter().map(|output| output.lock.sigop_count(false)).sum::<usize>();
+		let mut cost = legacy * 4;
+		for (input, prevout) in self.inputs.iter().zip(prevouts.iter()) {
+			let redeem_script;
+			let mut program_script = &prevout.lock;
+			if prevout.lock.is_p2sh() {
+				// the redeem script is the last push of the unlock script
+				let redeem = input.unlock.ops().filter_map(|op| op.push_data().map(|data| data.to_vec())).last();
+				if let Some(redeem) = redeem {
+					redeem_script = Script::from(redeem);
+					cost += redeem_script.sigop_count(true) * 4;
+					program_script = &redeem_script;
+				}
+			}
+			match program_script.witness_program() {
+				Some((0, program)) if program.len() == 20 => cost += 1,
+				Some((0, program)) if program.len() == 32 => {
+					if let Some(witness_script) = input.witness.last() {
+						cost += Script::from(witness_script.clone()).sigop_count(true);
+					}
+				},
+				_ => {},
+			}
+		}
+		cost
+	}
+
+	// drops witness data so the tx serializes in the pre-segwit format
+	pub fn strip_witness(&mut self) {
+		self.segwit = false;
+		for input in self.inputs.iter_mut() {
+			input.witness.clear();
+		}
+	}
+
+	pub fn serialize_without_witness(&self, stream: &mut dyn Write) -> Result<()> {
+		write_u32(stream, self.version)?;
+		
+		write_var_int(stream, self.inputs.len() as u64)?;
+		for input in self.inputs.iter() {
+			input.serialize(stream)?;
+		}
+
+		write_var_int(stream, self.outputs.len() as u64)?;
+		for output in self.outputs.iter() {
+			output.serialize(stream)?;
+		}
+
+		self.abs_lock_time.serialize(stream)
+	}
+}
+
+// what a block at `height` may create on top of its fees: 50 coins, halved
+// every 210,000 blocks
+pub fn block_subsidy(height: usize) -> u64 {
+	let halvings = height / 210_000;
+	if halvings >= 64 {
+		return 0;
+	}
+	(50 * SAT_PER_COIN) >> halvings
+}
+
+// the next level up the tree, the last hash paired with itself if left over
+fn merkle_level(mut hashes: Vec<Sha256>) -> Vec<Sha256> {
+	if hashes.len() % 2 == 1 {
+		hashes.push(*hashes.last().unwrap());
+	}
+	hashes.chunks(2)
+		.map(|e| {
+			let mut combined = Vec::with_capacity(e[0].as_bytes().len() + e[1].as_bytes().len());
+			combined.extend_from_slice(e[0].as_bytes());
+			combined.extend_from_slice(e[1].as_bytes());
+			compute_double_sha256(&*combined)
+		})
+		.collect()
+}
+
+pub fn compute_merkle_root(mut hashes: Vec<Sha256>) -> Sha256 {
+	assert!(!hashes.is_empty());
+	while hashes.len() > 1 {
+		hashes = merkle_level(hashes);
+	}
+
+	hashes[0]
 }
 
 impl ToJson for Tx {
@@ -398,6 +517,22 @@ impl Default for Tx {
 	}
 }
 
+#[cfg(test)]
+impl Tx {
+	// a version 2 tx spending `outpoints` into one 1000 sat OP_TRUE output
+	pub fn spending(outpoints: &[(Sha256, u32)]) -> Tx {
+		Tx {
+			version: 2,
+			segwit: false,
+			inputs: outpoints.iter()
+				.map(|&(tx_hash, index)| TxInput { tx_hash, index, unlock: Script::new(), witness: vec![], sequence: 0xffff_ffff })
+				.collect(),
+			outputs: vec![TxOutput { value: 1000, lock: Script::from(vec![0x51]) }],
+			abs_lock_time: AbsoluteLockTime::None,
+		}
+	}
+}
+
 impl Deserialize for Tx {
 	fn deserialize(stream: &mut dyn Read) -> Result<Tx> {
 		let version = read_u32(stream)?;
@@ -430,7 +565,7 @@ impl Deserialize for Tx {
 				let item_count = read_var_int(stream)?;
 				let mut items = Vec::new();
 				for _ in 0..item_count {
-					let size = read_var_int(stream)? as usize;
+					let size = read_var_size(stream)?;
 					let mut buf = vec![0; size];
 					read_buf_exact(stream, &mut buf)?;
 					items.push(buf);
@@ -481,4 +616,41 @@ impl Serialize for Tx {
 
 		self.abs_lock_time.serialize(stream)
 	}
-}
\ No newline at end of file
+}
+
+#[test]
+fn sequence_locks() {
+	let parent = Sha256::from([1; 32]);
+	// confirmed at height 100, after a block with median time past 1000
+	let coin = |id: &Sha256| (*id == parent).then_some((100, 1000));
+	let mut tx = Tx::spending(&[(parent, 0)]);
+	tx.inputs[0].sequence = 10;
+	assert!(!tx.check_sequence_locks(109, 0, coin));
+	assert!(tx.check_sequence_locks(110, 0, coin));
+	// 2 units of 512 seconds
+	tx.inputs[0].sequence = RELATIVE_LOCK_TIME_SECONDS | 2;
+	assert!(!tx.check_sequence_locks(200, 2023, coin));
+	assert!(tx.check_sequence_locks(200, 2024, coin));
+	// disabled, or a version 1 tx, and the lock doesn't apply
+	tx.inputs[0].sequence = RELATIVE_LOCK_TIME_DISABLE | 10;
+	assert!(tx.check_sequence_locks(0, 0, coin));
+	tx.inputs[0].sequence = 10;
+	tx.version = 1;
+	assert!(tx.check_sequence_locks(0, 0, coin));
+	// nothing known about the coin
+	tx.version = 2;
+	tx.inputs[0].tx_hash = Sha256::from([2; 32]);
+	assert!(!tx.check_sequence_locks(1_000_000, u32::MAX, coin));
+}
+
+#[test]
+fn mutated_merkle_root() {
+	let txs = (0..6).map(|i| Tx::spending(&[(Sha256::from([i; 32]), 0)])).collect::<Vec<_>>();
+	let root = Tx::compute_merkle_root(&txs).unwrap();
+	// repeating the last two txs gives the same root, but is caught
+	let mut padded = txs.clone();
+	padded.extend_from_slice(&txs[4..]);
+	assert_eq!(compute_merkle_root(padded.iter().map(|tx| tx.compute_hash()).collect()), root);
+	assert!(Tx::compute_merkle_root(&padded).is_none());
+	assert!(!Tx::check_merkle_root(&padded, root));
+}
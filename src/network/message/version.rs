@@ -33,15 +33,17 @@ use super::{
 pub const PROTOCOL_VERSION: i32 = 70016;
 pub const BIP_0037: i32         = 70001;
 
-// mod Services {
-// 	const NODE_NETWORK: u64         = 0x0001;
-// 	const NODE_GETUTXO: u64         = 0x0002;
-// 	const NODE_BLOOM: u64           = 0x0004;
-// 	const NODE_WITNESS: u64         = 0x0008;
-// 	const NODE_XTHIN: u64           = 0x0010;
-// 	const NODE_COMPACT_FILTERS: u64 = 0x0040;
-// 	const NODE_NETWORK_LIMITED: u64 = 0x0400;
-// }
+#[allow(dead_code)]
+pub mod services {
+	pub const NODE_NETWORK: u64         = 0x0001;
+	pub const NODE_GETUTXO: u64         = 0x0002;
+	pub const NODE_BLOOM: u64           = 0x0004;
+	pub const NODE_WITNESS: u64         = 0x0008;
+	pub const NODE_XTHIN: u64           = 0x0010;
+	pub const NODE_COMPACT_FILTERS: u64 = 0x0040;
+	pub const NODE_NETWORK_LIMITED: u64 = 0x0400;
+	pub const NODE_P2P_V2: u64          = 0x0800;
+}
 
 #[derive(Clone)]
 pub struct Version {
@@ -57,10 +59,10 @@ pub struct Version {
 }
 
 impl Version {
-	pub fn new<A: ToSocketAddrs>(addr: A) -> Self {
+	pub fn new<A: ToSocketAddrs>(addr: A, services: u64) -> Self {
 		Version {
 			version: PROTOCOL_VERSION,
-			services: 0,
+			services,
 			timestamp: now() as i64,
 			addr_recv: ShortNetAddr::from(&addr),
 			addr_from: ShortNetAddr::from("[::0]:0"),
@@ -70,6 +72,11 @@ impl Version {
 			relay: true,
 		}
 	}
+
+	// false if the peer asked not to be sent transaction announcements (BIP 37)
+	pub fn relay(&self) -> bool {
+		self.relay
+	}
 }
 
 impl ToJson for Version {
@@ -98,7 +105,7 @@ impl Deserialize for Version {
 		let nonce = read_u64(stream)?;
 		let user_agent = read_var_str(stream)?;
 		let start_height = read_u32(stream)?;
-		let relay = if version > BIP_0037 {
+		let relay = if version >= BIP_0037 {
 			read_bool(stream)?
 		} else {
 			true
@@ -128,7 +135,7 @@ impl Serialize for Version {
 		write_u64(stream, self.nonce)?;
 		write_var_str(stream, &self.user_agent)?;
 		write_u32(stream, self.start_height)?;
-		if self.version > BIP_0037 {
+		if self.version >= BIP_0037 {
 			write_bool(stream, self.relay)?;
 		}
 		Ok(())
@@ -0,0 +1,68 @@
+// readiness notification via poll(2). std has no API for this, and pulling in an
+// async runtime (or even libc) for one syscall isn't worth it.
+
+use std::{
+	os::raw::{c_int, c_short, c_ulong},
+	os::unix::io::RawFd,
+	time::Duration,
+};
+
+use crate::err::*;
+
+pub const POLLIN: c_short = 0x001;
+pub const POLLOUT: c_short = 0x004;
+const POLLERR: c_short = 0x008;
+const POLLHUP: c_short = 0x010;
+const POLLNVAL: c_short = 0x020;
+
+// layout of `struct pollfd`
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct PollFd {
+	fd: c_int,
+	events: c_short,
+	revents: c_short,
+}
+
+impl PollFd {
+	pub fn new(fd: RawFd, events: c_short) -> Self {
+		PollFd {
+			fd,
+			events,
+			revents: 0,
+		}
+	}
+
+	// errors and hangups count as readable: the read is what reports them
+	pub fn readable(&self) -> bool {
+		self.revents & (POLLIN | POLLERR | POLLHUP) != 0
+	}
+
+	pub fn writable(&self) -> bool {
+		self.revents & POLLOUT != 0
+	}
+
+	pub fn invalid(&self) -> bool {
+		self.revents & POLLNVAL != 0
+	}
+}
+
+extern "C" {
+	#[link_name = "poll"]
+	fn c_poll(fds: *mut PollFd, nfds: c_ulong, timeout: c_int) -> c_int;
+}
+
+// waits until one of `fds` is ready or the timeout passes; returns how many are ready
+pub fn poll(fds: &mut [PollFd], timeout: Duration) -> Result<usize> {
+	let timeout = std::cmp::min(timeout.as_millis(), c_int::MAX as u128) as c_int;
+	// SAFETY: `fds` is a valid, exclusively borrowed array of `struct pollfd`
+	let ready = unsafe { c_poll(fds.as_mut_ptr(), fds.len() as c_ulong, timeout) };
+	if ready < 0 {
+		let err = std::io::Error::last_os_error();
+		if err.kind() == std::io::ErrorKind::Interrupted {
+			return Ok(0);
+		}
+		return Err(Err::IOError(err.to_string()));
+	}
+	Ok(ready as usize)
+}
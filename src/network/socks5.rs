@@ -0,0 +1,184 @@
+// A SOCKS5 client (RFC 1928), for making outbound connections through a proxy
+// such as Tor. Targets are passed to the proxy by name, so that .onion addresses
+// work and no DNS lookups leak around it. With credentials randomized per
+// connection (RFC 1929), Tor puts every connection on its own circuit.
+
+use std::{
+	net::{IpAddr, SocketAddr, TcpStream},
+	time::Duration,
+};
+
+use crate::{
+	err::*,
+	common::{read_u8, read_u16_be, read_buf_exact, write_buf_exact},
+};
+
+const VERSION: u8 = 5;
+const AUTH_NONE: u8 = 0x00;
+const AUTH_PASSWORD: u8 = 0x02;
+const AUTH_NO_ACCEPTABLE: u8 = 0xff;
+const PASSWORD_AUTH_VERSION: u8 = 1;
+const CMD_CONNECT: u8 = 1;
+const ATYP_IPV4: u8 = 1;
+const ATYP_DOMAIN: u8 = 3;
+const ATYP_IPV6: u8 = 4;
+
+#[derive(Clone)]
+pub struct Proxy {
+	pub addr: SocketAddr,
+	// a fresh username and password for every connection (stream isolation)
+	pub randomize_credentials: bool,
+}
+
+fn reply_error(code: u8) -> &'static str {
+	match code {
+		0x01 => "general failure",
+		0x02 => "connection not allowed",
+		0x03 => "network unreachable",
+		0x04 => "host unreachable",
+		0x05 => "connection refused",
+		0x06 => "TTL expired",
+		0x07 => "command not supported",
+		0x08 => "address type not supported",
+		// Tor's extended codes for onion services
+		0xf0 => "onion service descriptor not found",
+		0xf1 => "onion service descriptor invalid",
+		0xf2 => "onion service introduction failed",
+		0xf3 => "onion service rendezvous failed",
+		0xf4 => "onion service missing client authorization",
+		0xf5 => "onion service wrong client authorization",
+		0xf6 => "onion service invalid address",
+		0xf7 => "onion service introduction timed out",
+		_ => "unknown error",
+	}
+}
+
+// connects to `host` (an IP address or a name, .onion included) through the proxy
+pub fn connect(proxy: &Proxy, host: &str, port: u16, timeout: Duration) -> Result<TcpStream> {
+	let net_err = |err: std::io::Error| Err::NetworkError(err.to_string());
+	let mut stream = TcpStream::connect_timeout(&proxy.addr, timeout).map_err(net_err)?;
+	stream.set_read_timeout(Some(timeout)).map_err(net_err)?;
+	stream.set_write_timeout(Some(timeout)).map_err(net_err)?;
+
+	let credentials = proxy.randomize_credentials
+		.then(|| (format!("{:016x}", rand::random::<u64>()), format!("{:016x}", rand::random::<u64>())));
+	handshake(&mut stream, host, port, credentials)?;
+
+	stream.set_read_timeout(None).map_err(net_err)?;
+	stream.set_write_timeout(None).map_err(net_err)?;
+	Ok(stream)
+}
+
+fn handshake(stream: &mut TcpStream, host: &str, port: u16, credentials: Option<(String, String)>) -> Result<()> {
+	let method = if credentials.is_some() { AUTH_PASSWORD } else { AUTH_NONE };
+	write_buf_exact(stream, &[VERSION, 1, method])?;
+	if read_u8(stream)? != VERSION {
+		return Err(Err::NetworkError("proxy doesn't speak SOCKS5".to_owned()));
+	}
+	match read_u8(stream)? {
+		AUTH_NONE => {},
+		AUTH_PASSWORD if credentials.is_some() => {
+			let (username, password) = credentials.unwrap();
+			let mut request = vec![PASSWORD_AUTH_VERSION, username.len() as u8];
+			request.extend_from_slice(username.as_bytes());
+			request.push(password.len() as u8);
+			request.extend_from_slice(password.as_bytes());
+			write_buf_exact(stream, &request)?;
+			let _version = read_u8(stream)?;
+			if read_u8(stream)? != 0 {
+				return Err(Err::NetworkError("proxy rejected the credentials".to_owned()));
+			}
+		},
+		AUTH_NO_ACCEPTABLE => return Err(Err::NetworkError("proxy requires authentication".to_owned())),
+		_ => return Err(Err::NetworkError("proxy chose an authentication method we didn't offer".to_owned())),
+	}
+
+	let mut request = vec![VERSION, CMD_CONNECT, 0];
+	match host.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>() {
+		Ok(IpAddr::V4(ip)) => {
+			request.push(ATYP_IPV4);
+			request.extend_from_slice(&ip.octets());
+		},
+		Ok(IpAddr::V6(ip)) => {
+			request.push(ATYP_IPV6);
+			request.extend_from_slice(&ip.octets());
+		},
+		Err(_) if host.len() <= u8::MAX as usize => {
+			request.push(ATYP_DOMAIN);
+			request.push(host.len() as u8);
+			request.extend_from_slice(host.as_bytes());
+		},
+		Err(_) => return Err(Err::ValueError(format!("host name too long: {}", host))),
+	}
+	request.extend_from_slice(&port.to_be_bytes());
+	write_buf_exact(stream, &request)?;
+
+	let reply = [read_u8(stream)?, read_u8(stream)?, read_u8(stream)?];
+	if reply[0] != VERSION {
+		return Err(Err::NetworkError("malformed proxy reply".to_owned()));
+	}
+	if reply[1] != 0 {
+		return Err(Err::NetworkError(format!("proxy failed to connect to {}:{}: {}", host, port, reply_error(reply[1]))));
+	}
+	// the address the proxy bound, which we've no use for
+	let size = match read_u8(stream)? {
+		ATYP_IPV4 => 4,
+		ATYP_IPV6 => 16,
+		ATYP_DOMAIN => read_u8(stream)? as usize,
+		_ => return Err(Err::NetworkError("malformed proxy reply".to_owned())),
+	};
+	read_buf_exact(stream, &mut vec![0; size])?;
+	read_u16_be(stream)?;
+	Ok(())
+}
+
+#[test]
+fn socks5() {
+	use std::io::{Read, Write};
+	use std::net::TcpListener;
+
+	// a stand-in proxy that checks the handshake, then echoes
+	let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+	let proxy = Proxy { addr: listener.local_addr().unwrap(), randomize_credentials: true };
+	let server = std::thread::spawn(move || {
+		let (mut stream, _) = listener.accept().unwrap();
+		let mut buf = [0; 3];
+		stream.read_exact(&mut buf).unwrap();
+		assert_eq!(buf, [VERSION, 1, AUTH_PASSWORD]);
+		stream.write_all(&[VERSION, AUTH_PASSWORD]).unwrap();
+
+		let mut buf = [0; 2];
+		stream.read_exact(&mut buf).unwrap();
+		let mut username = vec![0; buf[1] as usize];
+		stream.read_exact(&mut username).unwrap();
+		let mut size = [0; 1];
+		stream.read_exact(&mut size).unwrap();
+		let mut password = vec![0; size[0] as usize];
+		stream.read_exact(&mut password).unwrap();
+		stream.write_all(&[PASSWORD_AUTH_VERSION, 0]).unwrap();
+
+		let mut buf = [0; 5];
+		stream.read_exact(&mut buf).unwrap();
+		assert_eq!(buf[..4], [VERSION, CMD_CONNECT, 0, ATYP_DOMAIN]);
+		let mut host = vec![0; buf[4] as usize + 2];
+		stream.read_exact(&mut host).unwrap();
+		stream.write_all(&[VERSION, 0, 0, ATYP_IPV4, 127, 0, 0, 1, 0x4a, 0x38]).unwrap();
+
+		let mut buf = [0; 4];
+		stream.read_exact(&mut buf).unwrap();
+		stream.write_all(&buf).unwrap();
+		(username, host)
+	});
+
+	let host = "pg6mmjiyjmcrsslvykfwnntlaru7p5svn6y2ymmju6nubxndf4pscryd.onion";
+	let mut stream = connect(&proxy, host, 8333, Duration::from_secs(5)).unwrap();
+	stream.write_all(b"ping").unwrap();
+	let mut buf = [0; 4];
+	stream.read_exact(&mut buf).unwrap();
+	assert_eq!(&buf, b"ping");
+
+	let (username, target) = server.join().unwrap();
+	assert_eq!(username.len(), 16);
+	assert_eq!(&target[..target.len() - 2], host.as_bytes());
+	assert_eq!(target[target.len() - 2..], 8333u16.to_be_bytes());
+}
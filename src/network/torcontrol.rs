@@ -0,0 +1,188 @@
+// A client for Tor's control protocol, enough to authenticate and publish an
+// onion service that forwards to our listening port. The service lives as long
+// as the control connection does, so the caller keeps it open.
+
+use std::{
+	io::{BufRead, BufReader, Write},
+	net::{SocketAddr, TcpStream},
+	time::Duration,
+};
+
+use crate::{
+	err::*,
+	common::{bytes_to_hex, hex_to_bytes},
+	crypto::hkdf::compute_hmac_sha256,
+};
+
+const SAFECOOKIE_SERVER_KEY: &[u8] = b"Tor safe cookie authentication server-to-controller hash";
+const SAFECOOKIE_CLIENT_KEY: &[u8] = b"Tor safe cookie authentication controller-to-server hash";
+
+pub struct TorControl {
+	reader: BufReader<TcpStream>,
+	writer: TcpStream,
+}
+
+// the value of `key=...` in a reply line, unquoted if it was quoted
+fn reply_value(line: &str, key: &str) -> Option<String> {
+	let start = line.find(&format!("{}=", key))? + key.len() + 1;
+	let rest = &line[start..];
+	match rest.strip_prefix('"') {
+		Some(quoted) => {
+			let mut value = String::new();
+			let mut chars = quoted.chars();
+			while let Some(c) = chars.next() {
+				match c {
+					'"' => return Some(value),
+					'\\' => value.push(chars.next()?),
+					c => value.push(c),
+				}
+			}
+			None
+		},
+		None => Some(rest.split(' ').next().unwrap_or_default().to_owned()),
+	}
+}
+
+impl TorControl {
+	pub fn connect(addr: SocketAddr, timeout: Duration) -> Result<Self> {
+		let net_err = |err: std::io::Error| Err::NetworkError(err.to_string());
+		let stream = TcpStream::connect_timeout(&addr, timeout).map_err(net_err)?;
+		stream.set_read_timeout(Some(timeout)).map_err(net_err)?;
+		let writer = stream.try_clone().map_err(net_err)?;
+		Ok(TorControl { reader: BufReader::new(stream), writer })
+	}
+
+	// sends a command and returns the lines of its reply, without the status codes
+	fn command(&mut self, command: &str) -> Result<Vec<String>> {
+		self.writer.write_all(format!("{}\r\n", command).as_bytes())
+			.map_err(|err| Err::NetworkError(err.to_string()))?;
+		let mut lines = Vec::new();
+		loop {
+			let mut line = String::new();
+			match self.reader.read_line(&mut line) {
+				Ok(0) => return Err(Err::NetworkError("tor control connection closed".to_owned())),
+				Ok(_) => {},
+				Err(err) => return Err(Err::NetworkError(err.to_string())),
+			}
+			let line = line.trim_end();
+			if line.len() < 4 {
+				return Err(Err::NetworkError(format!("malformed tor control reply: {}", line)));
+			}
+			let (status, text) = line.split_at(3);
+			// asynchronous events aren't replies
+			if status == "650" {
+				continue;
+			}
+			if status != "250" {
+				return Err(Err::NetworkError(format!("tor control command failed: {}", line)));
+			}
+			lines.push(text[1..].to_owned());
+			if text.starts_with(' ') {
+				return Ok(lines);
+			}
+		}
+	}
+
+	// uses the password if there is one, else whichever cookie method tor offers
+	pub fn authenticate(&mut self, password: Option<&str>) -> Result<()> {
+		let info = self.command("PROTOCOLINFO 1")?;
+		let auth = info.iter().find(|line| line.starts_with("AUTH "))
+			.ok_or_else(|| Err::NetworkError("tor didn't list its authentication methods".to_owned()))?;
+		let methods = reply_value(auth, "METHODS").unwrap_or_default();
+		let methods = methods.split(',').collect::<Vec<_>>();
+		let cookie = || -> Result<Vec<u8>> {
+			let path = reply_value(auth, "COOKIEFILE")
+				.ok_or_else(|| Err::NetworkError("tor didn't say where its cookie is".to_owned()))?;
+			std::fs::read(&path).map_err(|err| Err::IOError(format!("failed to read {}: {}", path, err)))
+		};
+
+		if let Some(password) = password {
+			let escaped = password.replace('\\', "\\\\").replace('"', "\\\"");
+			self.command(&format!("AUTHENTICATE \"{}\"", escaped))?;
+		} else if methods.contains(&"NULL") {
+			self.command("AUTHENTICATE")?;
+		} else if methods.contains(&"SAFECOOKIE") {
+			let cookie = cookie()?;
+			let client_nonce = rand::random::<[u8; 32]>();
+			let reply = self.command(&format!("AUTHCHALLENGE SAFECOOKIE {}", bytes_to_hex(&client_nonce)))?;
+			let value = |key| reply_value(&reply[0], key).and_then(|hex| hex_to_bytes(&hex).ok())
+				.ok_or_else(|| Err::NetworkError("malformed AUTHCHALLENGE reply".to_owned()));
+			let (server_hash, server_nonce) = (value("SERVERHASH")?, value("SERVERNONCE")?);
+
+			let mut message = cookie;
+			message.extend_from_slice(&client_nonce);
+			message.extend_from_slice(&server_nonce);
+			// tor proves it knows the cookie too, so we're not handing it to an impostor
+			if compute_hmac_sha256(SAFECOOKIE_SERVER_KEY, &message)[..] != server_hash[..] {
+				return Err(Err::NetworkError("tor's cookie proof doesn't match".to_owned()));
+			}
+			let client_hash = compute_hmac_sha256(SAFECOOKIE_CLIENT_KEY, &message);
+			self.command(&format!("AUTHENTICATE {}", bytes_to_hex(&client_hash)))?;
+		} else if methods.contains(&"COOKIE") {
+			self.command(&format!("AUTHENTICATE {}", bytes_to_hex(&cookie()?)))?;
+		} else {
+			return Err(Err::NetworkError(format!("no usable tor authentication method (offered: {})", methods.join(","))));
+		}
+		Ok(())
+	}
+
+	// publishes an onion service, with the key from an earlier run if there is
+	// one. returns the service's name and, for a new service, its key
+	pub fn add_onion(&mut self, private_key: Option<&str>, virtual_port: u16, target: SocketAddr) -> Result<(String, Option<String>)> {
+		let key = private_key.unwrap_or("NEW:ED25519-V3");
+		let reply = self.command(&format!("ADD_ONION {} Port={},{}", key, virtual_port, target))?;
+		let service_id = reply.iter().find_map(|line| reply_value(line, "ServiceID"))
+			.ok_or_else(|| Err::NetworkError("ADD_ONION reply has no ServiceID".to_owned()))?;
+		let new_key = reply.iter().find_map(|line| reply_value(line, "PrivateKey"));
+		Ok((format!("{}.onion", service_id), new_key))
+	}
+
+	// blocks until tor closes the connection (which takes the service down)
+	pub fn wait_closed(mut self) {
+		let _ = self.reader.get_ref().set_read_timeout(None);
+		let mut line = String::new();
+		while let Ok(n) = self.reader.read_line(&mut line) {
+			if n == 0 {
+				break;
+			}
+			line.clear();
+		}
+	}
+}
+
+#[test]
+fn tor_control() {
+	use std::net::TcpListener;
+
+	// a stand-in for tor that accepts the password and publishes a service
+	let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+	let addr = listener.local_addr().unwrap();
+	let server = std::thread::spawn(move || {
+		let (stream, _) = listener.accept().unwrap();
+		let mut reader = BufReader::new(stream.try_clone().unwrap());
+		let mut writer = stream;
+		let mut commands = Vec::new();
+		for reply in [
+			"250-PROTOCOLINFO 1\r\n250-AUTH METHODS=HASHEDPASSWORD COOKIEFILE=\"/var/run/tor/control.authcookie\"\r\n250-VERSION Tor=\"0.4.8.9\"\r\n250 OK\r\n",
+			"250 OK\r\n",
+			"250-ServiceID=pg6mmjiyjmcrsslvykfwnntlaru7p5svn6y2ymmju6nubxndf4pscryd\r\n250-PrivateKey=ED25519-V3:secret\r\n250 OK\r\n",
+		] {
+			let mut line = String::new();
+			reader.read_line(&mut line).unwrap();
+			commands.push(line.trim_end().to_owned());
+			writer.write_all(reply.as_bytes()).unwrap();
+		}
+		commands
+	});
+
+	let mut control = TorControl::connect(addr, Duration::from_secs(5)).unwrap();
+	control.authenticate(Some("pass\"word")).unwrap();
+	let target = "127.0.0.1:8333".parse().unwrap();
+	let (name, key) = control.add_onion(None, 8333, target).unwrap();
+	assert_eq!(name, "pg6mmjiyjmcrsslvykfwnntlaru7p5svn6y2ymmju6nubxndf4pscryd.onion");
+	assert_eq!(key.as_deref(), Some("ED25519-V3:secret"));
+
+	let commands = server.join().unwrap();
+	assert_eq!(commands[1], "AUTHENTICATE \"pass\\\"word\"");
+	assert_eq!(commands[2], "ADD_ONION NEW:ED25519-V3 Port=8333,127.0.0.1:8333");
+}
@@ -0,0 +1,154 @@
+use crate::err::*;
+
+// command line options. flags follow bitcoind's `-name` / `-name=value` form;
+// anything without a leading dash is a peer address to connect to.
+#[derive(Default)]
+pub struct Options {
+	pub addrs: Vec<String>,
+	pub rebuild_utxos: bool,
+	pub signet: bool,
+	pub signet_challenge: Option<String>,
+	pub signet_sign: Option<(String, String)>,
+	pub nolisten: bool,
+	pub bind: Option<String>,
+	pub max_connections: Option<usize>,
+	pub seed_nodes: Vec<String>,
+	pub peer_bloom_filters: bool,
+	pub peer_block_filters: bool,
+	pub v2_transport: bool,
+	pub mempool_full_rbf: bool,
+	pub accept_non_std_txn: bool,
+	pub max_mempool: Option<usize>,
+	pub mempool_expiry: Option<u64>,
+	pub proxy: Option<String>,
+	pub onion_proxy: Option<String>,
+	pub proxy_randomize: bool,
+	pub listen_onion: bool,
+	pub tor_control: Option<String>,
+	pub tor_password: Option<String>,
+}
+
+impl Options {
+	pub fn parse<I: Iterator<Item = String>>(mut args: I) -> Result<Options> {
+		let mut options = Options {
+			proxy_randomize: true,
+			..Options::default()
+		};
+		while let Some(arg) = args.next() {
+			if !arg.starts_with('-') {
+				options.addrs.push(arg);
+				continue;
+			}
+
+			let (name, value) = match arg.trim_start_matches('-').split_once('=') {
+				Some((name, value)) => (name.to_owned(), Some(value.to_owned())),
+				None => (arg.trim_start_matches('-').to_owned(), None),
+			};
+
+			match (name.as_str(), value) {
+				("rebuild-utxos", None) => {
+					options.rebuild_utxos = true;
+				},
+				("signet", None) => {
+					options.signet = true;
+				},
+				("signetchallenge", Some(challenge)) => {
+					options.signet = true;
+					options.signet_challenge = Some(challenge);
+				},
+				("listen", Some(value)) => {
+					options.nolisten = value == "0";
+				},
+				("nolisten", None) => {
+					options.nolisten = true;
+				},
+				("bind", Some(addr)) => {
+					options.bind = Some(addr);
+				},
+				("maxconnections", Some(n)) => {
+					let n = n.parse()
+						.map_err(|_| Err::ValueError(format!("invalid value for -maxconnections: {}", n)))?;
+					options.max_connections = Some(n);
+				},
+				("seednode", Some(addr)) => {
+					options.seed_nodes.push(addr);
+				},
+				("peerbloomfilters", None) => {
+					options.peer_bloom_filters = true;
+				},
+				("peerbloomfilters", Some(value)) => {
+					options.peer_bloom_filters = value != "0";
+				},
+				("peerblockfilters", None) => {
+					options.peer_block_filters = true;
+				},
+				("peerblockfilters", Some(value)) => {
+					options.peer_block_filters = value != "0";
+				},
+				("v2transport", None) => {
+					options.v2_transport = true;
+				},
+				("v2transport", Some(value)) => {
+					options.v2_transport = value != "0";
+				},
+				("mempoolfullrbf", None) => {
+					options.mempool_full_rbf = true;
+				},
+				("mempoolfullrbf", Some(value)) => {
+					options.mempool_full_rbf = value != "0";
+				},
+				("acceptnonstdtxn", None) => {
+					options.accept_non_std_txn = true;
+				},
+				("acceptnonstdtxn", Some(value)) => {
+					options.accept_non_std_txn = value != "0";
+				},
+				("maxmempool", Some(n)) => {
+					let n = n.parse()
+						.map_err(|_| Err::ValueError(format!("invalid value for -maxmempool: {}", n)))?;
+					options.max_mempool = Some(n);
+				},
+				("mempoolexpiry", Some(n)) => {
+					let n = n.parse()
+						.map_err(|_| Err::ValueError(format!("invalid value for -mempoolexpiry: {}", n)))?;
+					options.mempool_expiry = Some(n);
+				},
+				("proxy", Some(addr)) => {
+					options.proxy = Some(addr);
+				},
+				("onion", Some(addr)) => {
+					options.onion_proxy = Some(addr);
+				},
+				("proxyrandomize", Some(value)) => {
+					options.proxy_randomize = value != "0";
+				},
+				("listenonion", None) => {
+					options.listen_onion = true;
+				},
+				("listenonion", Some(value)) => {
+					options.listen_onion = value != "0";
+				},
+				("torcontrol", Some(addr)) => {
+					options.tor_control = Some(addr);
+				},
+				("torpassword", Some(password)) => {
+					options.tor_password = Some(password);
+				},
+				("signet-sign", None) => {
+					let keys = args.next();
+					let block = args.next();
+					match (keys, block) {
+						(Some(keys), Some(block)) => {
+							options.signet = true;
+							options.signet_sign = Some((keys, block));
+						},
+						_ => return Err(Err::ValueError("usage: --signet-sign <privkey>[,<privkey>...] <block hex>".to_owned())),
+					}
+				},
+				_ => return Err(Err::ValueError(format!("invalid option: {}", arg))),
+			}
+		}
+
+		Ok(options)
+	}
+}
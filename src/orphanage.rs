@@ -0,0 +1,189 @@
+// Txs whose inputs we can't find yet. They wait here, indexed by the outpoints
+// they spend, until a parent turns up in a tx message or a block; then they get
+// another try at the mempool. The pool is small and each entry expires, since
+// anyone can make up txs spending outputs that don't exist.
+
+use std::collections::{HashMap, HashSet};
+
+use crate::{
+	common,
+	crypto::sha256::Sha256,
+	network::message::{Block, Tx, UTXOID},
+};
+
+pub const MAX_ORPHANS: usize = 100;
+// anything heavier couldn't be standard, so isn't worth holding on to
+pub const MAX_ORPHAN_WEIGHT: usize = 400_000;
+
+const ORPHAN_EXPIRY: u64 = 20 * 60;
+const SWEEP_INTERVAL: u64 = 5 * 60;
+
+struct Orphan {
+	tx: Tx,
+	// who sent it, and so who gets asked for the parents
+	peer: usize,
+	expires: u64,
+}
+
+pub struct Orphanage {
+	orphans: HashMap<Sha256, Orphan>,
+	// the orphans spending each outpoint
+	by_outpoint: HashMap<UTXOID, HashSet<Sha256>>,
+	per_peer: HashMap<usize, usize>,
+	next_sweep: u64,
+}
+
+impl Orphanage {
+	pub fn new() -> Self {
+		Orphanage {
+			orphans: HashMap::new(),
+			by_outpoint: HashMap::new(),
+			per_peer: HashMap::new(),
+			next_sweep: common::now() + SWEEP_INTERVAL,
+		}
+	}
+
+	pub fn len(&self) -> usize {
+		self.orphans.len()
+	}
+
+	pub fn contains(&self, id: &Sha256) -> bool {
+		self.orphans.contains_key(id)
+	}
+
+	pub fn get(&self, id: &Sha256) -> Option<&Tx> {
+		self.orphans.get(id).map(|orphan| &orphan.tx)
+	}
+
+	// the peer that sent it
+	pub fn peer(&self, id: &Sha256) -> Option<usize> {
+		self.orphans.get(id).map(|orphan| orphan.peer)
+	}
+
+	// returns false if the tx was already here or is too big to keep. the pool
+	// is trimmed back to MAX_ORPHANS afterwards, so it may not stay
+	pub fn add(&mut self, id: Sha256, tx: Tx, peer: usize) -> bool {
+		if self.orphans.contains_key(&id) || tx.weight() > MAX_ORPHAN_WEIGHT {
+			return false;
+		}
+		for input in tx.inputs.iter() {
+			self.by_outpoint.entry(UTXOID(input.tx_hash, input.index)).or_default().insert(id);
+		}
+		*self.per_peer.entry(peer).or_default() += 1;
+		self.orphans.insert(id, Orphan { tx, peer, expires: common::now() + ORPHAN_EXPIRY });
+		self.limit();
+		true
+	}
+
+	pub fn remove(&mut self, id: &Sha256) -> Option<(Tx, usize)> {
+		let orphan = self.orphans.remove(id)?;
+		for input in orphan.tx.inputs.iter() {
+			let outpoint = UTXOID(input.tx_hash, input.index);
+			if let Some(ids) = self.by_outpoint.get_mut(&outpoint) {
+				ids.remove(id);
+				if ids.is_empty() {
+					self.by_outpoint.remove(&outpoint);
+				}
+			}
+		}
+		if let Some(count) = self.per_peer.get_mut(&orphan.peer) {
+			*count -= 1;
+			if *count == 0 {
+				self.per_peer.remove(&orphan.peer);
+			}
+		}
+		Some((orphan.tx, orphan.peer))
+	}
+
+	// the orphans spending any of the first `outputs` outputs of tx `id`
+	pub fn children_of(&self, id: &Sha256, outputs: usize) -> HashSet<Sha256> {
+		(0..outputs as u32)
+			.filter_map(|index| self.by_outpoint.get(&UTXOID(*id, index)))
+			.flatten()
+			.copied()
+			.collect()
+	}
+
+	// a disconnected peer's orphans go with it
+	pub fn remove_for_peer(&mut self, peer: usize) -> usize {
+		let ids = self.orphans.iter()
+			.filter(|(_, orphan)| orphan.peer == peer)
+			.map(|(id, _)| *id)
+			.collect::<Vec<_>>();
+		for id in ids.iter() {
+			self.remove(id);
+		}
+		ids.len()
+	}
+
+	// drops orphans a block confirmed, or made invalid by spending their inputs
+	pub fn remove_for_block(&mut self, block: &Block) -> usize {
+		let mut ids = HashSet::new();
+		for tx in block.txs.iter() {
+			ids.insert(tx.compute_hash());
+			for input in tx.inputs.iter() {
+				if let Some(spenders) = self.by_outpoint.get(&UTXOID(input.tx_hash, input.index)) {
+					ids.extend(spenders.iter().copied());
+				}
+			}
+		}
+		ids.iter().filter(|id| self.remove(id).is_some()).count()
+	}
+
+	fn limit(&mut self) {
+		let now = common::now();
+		if now >= self.next_sweep {
+			let expired = self.orphans.iter()
+				.filter(|(_, orphan)| orphan.expires <= now)
+				.map(|(id, _)| *id)
+				.collect::<Vec<_>>();
+			for id in expired.iter() {
+				self.remove(id);
+			}
+			if !expired.is_empty() {
+				log_debug!("expired {} orphan txs", expired.len());
+			}
+			self.next_sweep = now + SWEEP_INTERVAL;
+		}
+
+		// a random orphan of whoever has the most: one peer flooding the pool
+		// mostly pushes out its own, and can't choose which
+		while self.orphans.len() > MAX_ORPHANS {
+			let (&peer, &count) = self.per_peer.iter().max_by_key(|(_, &count)| count).unwrap();
+			let index = rand::random::<usize>() % count;
+			let id = *self.orphans.iter()
+				.filter(|(_, orphan)| orphan.peer == peer)
+				.nth(index).unwrap().0;
+			self.remove(&id);
+		}
+	}
+}
+
+#[test]
+fn orphanage() {
+	let spending = |parent: Sha256, index: u32| Tx::spending(&[(parent, index)]);
+	let parent = Sha256::from([1; 32]);
+
+	let mut orphans = Orphanage::new();
+	let first = spending(parent, 0);
+	let first_id = first.compute_hash();
+	assert!(orphans.add(first_id, first.clone(), 7));
+	assert!(!orphans.add(first_id, first, 7));
+	let second = spending(parent, 1);
+	assert!(orphans.add(second.compute_hash(), second, 8));
+	assert!(orphans.children_of(&parent, 1) == HashSet::from([first_id]));
+	assert!(orphans.children_of(&parent, 2).len() == 2);
+	assert!(orphans.per_peer[&7] == 1);
+
+	assert!(orphans.remove_for_peer(7) == 1);
+	assert!(!orphans.contains(&first_id));
+	assert!(orphans.children_of(&parent, 1).is_empty());
+
+	for index in 0..2 * MAX_ORPHANS as u32 {
+		let tx = spending(Sha256::from([2; 32]), index);
+		orphans.add(tx.compute_hash(), tx, 9);
+	}
+	// the flooding peer's orphans went, not the other one's
+	assert!(orphans.len() == MAX_ORPHANS);
+	assert!(orphans.per_peer[&8] == 1);
+}
@@ -0,0 +1,278 @@
+// Relay policy: what a tx has to look like, beyond being valid, to get into the
+// mempool (bitcoind's IsStandardTx and friends). Blocks aren't held to any of
+// it. Keeping relay to well-understood forms leaves unused versions and opcodes
+// free for soft forks, and keeps txs cheap to validate and worth spending.
+
+use std::collections::HashSet;
+
+use crate::{
+	err::*,
+	network::message::{Tx, TxOutput, UTXOID},
+	script::{Script, ScriptType},
+};
+
+pub const MIN_STANDARD_TX_VERSION: u32 = 1;
+pub const MAX_STANDARD_TX_VERSION: u32 = 3;
+pub const MAX_STANDARD_TX_WEIGHT: usize = 400_000;
+// anything smaller could be mistaken for an inner node of a merkle tree
+const MIN_STANDARD_TX_NONWITNESS_SIZE: usize = 65;
+pub const MAX_STANDARD_TX_SIGOPS_COST: usize = 16_000;
+// enough for a 15-of-15 P2SH multisig with compressed keys
+const MAX_STANDARD_SCRIPTSIG_SIZE: usize = 1650;
+const MAX_P2SH_SIGOPS: usize = 15;
+const MAX_BARE_MULTISIG_KEYS: usize = 3;
+// the whole OP_RETURN script, and only one per tx
+const MAX_OP_RETURN_RELAY: usize = 83;
+// sat/kvB. an output worth less than it'd cost to spend at this rate is dust
+const DUST_RELAY_FEE: u64 = 3000;
+const MAX_STANDARD_P2WSH_SCRIPT_SIZE: usize = 3600;
+const MAX_STANDARD_P2WSH_STACK_ITEMS: usize = 100;
+const MAX_STANDARD_P2WSH_STACK_ITEM_SIZE: usize = 80;
+const MAX_STANDARD_TAPSCRIPT_STACK_ITEM_SIZE: usize = 80;
+const TAPROOT_LEAF_TAPSCRIPT: u8 = 0xc0;
+const ANNEX_TAG: u8 = 0x50;
+
+// BIP 431: version 3 txs are TRUC ("topologically restricted until
+// confirmation"), kept to one unconfirmed parent or child and small sizes so
+// they're always cheap to replace or pay for with a child. sizes in vbytes
+pub const TRUC_VERSION: u32 = 3;
+pub const TRUC_MAX_VSIZE: usize = 10_000;
+pub const TRUC_CHILD_MAX_VSIZE: usize = 1000;
+
+// a package is a child and the parents it spends, judged together
+const MAX_PACKAGE_COUNT: usize = 25;
+const MAX_PACKAGE_WEIGHT: usize = 404_000;
+
+fn reject<T>(reason: &str) -> Result<T> {
+	Err(Err::ValueError(reason.to_owned()))
+}
+
+// the checks that need only the tx itself
+pub fn check_standard_tx(tx: &Tx) -> Result<()> {
+	if !(MIN_STANDARD_TX_VERSION..=MAX_STANDARD_TX_VERSION).contains(&tx.version) {
+		return reject("version");
+	}
+	let weight = tx.weight();
+	if weight > MAX_STANDARD_TX_WEIGHT {
+		return reject("tx-size");
+	}
+	// weight counts the bytes outside the witness four times, size once
+	if (weight - tx.size()) / 3 < MIN_STANDARD_TX_NONWITNESS_SIZE {
+		return reject("tx-size-small");
+	}
+
+	for input in tx.inputs.iter() {
+		if input.unlock.len() > MAX_STANDARD_SCRIPTSIG_SIZE {
+			return reject("scriptsig-size");
+		}
+		if !input.unlock.is_push_only() {
+			return reject("scriptsig-not-pushonly");
+		}
+	}
+
+	let mut null_data = 0;
+	for output in tx.outputs.iter() {
+		match output.lock.classify() {
+			ScriptType::NonStandard => return reject("scriptpubkey"),
+			ScriptType::Multisig(m, n) if m < 1 || m > n || n > MAX_BARE_MULTISIG_KEYS => return reject("scriptpubkey"),
+			ScriptType::NullData if output.lock.len() > MAX_OP_RETURN_RELAY => return reject("scriptpubkey"),
+			ScriptType::NullData => null_data += 1,
+			_ if is_dust(output) => return reject("dust"),
+			_ => {},
+		}
+	}
+	if null_data > 1 {
+		return reject("multi-op-return");
+	}
+	Ok(())
+}
+
+// the checks that need the outputs spent by each input in turn: what's being
+// spent, and with how much in the witness
+pub fn check_standard_inputs(tx: &Tx, prevouts: &[TxOutput]) -> Result<()> {
+	for (input, prevout) in tx.inputs.iter().zip(prevouts.iter()) {
+		let mut program_script = prevout.lock.clone();
+		let mut wrapped = false;
+		match prevout.lock.classify() {
+			ScriptType::NonStandard | ScriptType::WitnessUnknown => return reject("bad-txns-nonstandard-inputs"),
+			ScriptType::ScriptHash => {
+				// pushes only, as checked already, so the last one is the redeem script
+				let redeem = match input.unlock.ops().filter_map(|op| op.push_data().map(|data| data.to_vec())).last() {
+					Some(redeem) => Script::from(redeem),
+					None => return reject("bad-txns-nonstandard-inputs"),
+				};
+				if redeem.sigop_count(true) > MAX_P2SH_SIGOPS {
+					return reject("bad-txns-nonstandard-inputs");
+				}
+				program_script = redeem;
+				wrapped = true;
+			},
+			_ => {},
+		}
+
+		if input.witness.is_empty() {
+			continue;
+		}
+		let stack = &input.witness;
+		match program_script.witness_program() {
+			Some((0, program)) if program.len() == 32 => {
+				let (script, items) = stack.split_last().unwrap();
+				if script.len() > MAX_STANDARD_P2WSH_SCRIPT_SIZE || items.len() > MAX_STANDARD_P2WSH_STACK_ITEMS ||
+					items.iter().any(|item| item.len() > MAX_STANDARD_P2WSH_STACK_ITEM_SIZE) {
+					return reject("bad-witness-nonstandard");
+				}
+			},
+			Some((1, program)) if program.len() == 32 && !wrapped => {
+				// the annex has no meaning yet
+				if stack.len() >= 2 && stack.last().unwrap().first() == Some(&ANNEX_TAG) {
+					return reject("bad-witness-nonstandard");
+				}
+				// a script path spend ends with the script and its control block
+				if stack.len() >= 2 {
+					let control = &stack[stack.len() - 1];
+					let items = &stack[..stack.len() - 2];
+					if control.first().is_some_and(|&leaf| leaf & 0xfe == TAPROOT_LEAF_TAPSCRIPT) &&
+						items.iter().any(|item| item.len() > MAX_STANDARD_TAPSCRIPT_STACK_ITEM_SIZE) {
+						return reject("bad-witness-nonstandard");
+					}
+				}
+			},
+			Some((0, _)) => {},
+			// witness data where none is expected
+			_ => return reject("bad-witness-nonstandard"),
+		}
+	}
+	Ok(())
+}
+
+// whether txs make a package we take: a child and any of its parents, each
+// after the txs it spends, with no two spending the same output
+pub fn check_package(txs: &[Tx]) -> Result<()> {
+	if txs.is_empty() || txs.len() > MAX_PACKAGE_COUNT {
+		return reject("package-too-many-transactions");
+	}
+	if txs.iter().map(|tx| tx.weight()).sum::<usize>() > MAX_PACKAGE_WEIGHT {
+		return reject("package-too-large");
+	}
+	let ids = txs.iter().map(|tx| tx.compute_hash()).collect::<Vec<_>>();
+	let mut spent = HashSet::new();
+	for (i, tx) in txs.iter().enumerate() {
+		if ids[..i].contains(&ids[i]) {
+			return reject("package-contains-duplicates");
+		}
+		for input in tx.inputs.iter() {
+			if ids[i + 1..].contains(&input.tx_hash) {
+				return reject("package-not-sorted");
+			}
+			if !spent.insert(UTXOID(input.tx_hash, input.index)) {
+				return reject("conflict-in-package");
+			}
+		}
+	}
+	let child = txs.last().unwrap();
+	if !ids[..ids.len() - 1].iter().all(|id| child.inputs.iter().any(|input| input.tx_hash == *id)) {
+		return reject("package-not-child-with-parents");
+	}
+	Ok(())
+}
+
+// below what it'd cost to spend at DUST_RELAY_FEE, counting the output itself
+// and a typical input spending it
+pub fn is_dust(output: &TxOutput) -> bool {
+	if output.lock.classify() == ScriptType::NullData {
+		return false;
+	}
+	let mut size = 8 + var_int_size(output.lock.len()) + output.lock.len();
+	size += if output.lock.witness_program().is_some() {
+		// outpoint, empty unlock script and sequence, plus a discounted signature and key
+		32 + 4 + 1 + 107 / 4 + 4
+	} else {
+		32 + 4 + 1 + 107 + 4
+	};
+	output.value < size as u64 * DUST_RELAY_FEE / 1000
+}
+
+fn var_int_size(n: usize) -> usize {
+	match n {
+		0..=0xfc => 1,
+		0xfd..=0xffff => 3,
+		_ => 5,
+	}
+}
+
+#[test]
+fn standardness() {
+	use crate::{
+		common::{hex_to_bytes, SAT_PER_COIN},
+		crypto::sha256::Sha256,
+	};
+
+	let p2wpkh = Script::from(hex_to_bytes("0014000102030405060708090a0b0c0d0e0f10111213").unwrap());
+	let p2pkh = Script::from(hex_to_bytes("76a914000102030405060708090a0b0c0d0e0f1011121388ac").unwrap());
+	let mut tx = Tx::spending(&[(Sha256::from([1; 32]), 0)]);
+	tx.inputs[0].unlock = Script::from(vec![0x01, 0x01]);
+	tx.outputs[0] = TxOutput { value: SAT_PER_COIN, lock: p2pkh.clone() };
+	assert!(check_standard_tx(&tx).is_ok());
+
+	tx.version = 4;
+	assert!(check_standard_tx(&tx).is_err());
+	tx.version = 2;
+	tx.inputs[0].unlock = Script::from(vec![0x01, 0x01, 0xac]);
+	assert!(check_standard_tx(&tx).is_err());
+	tx.inputs[0].unlock = Script::from(vec![0x01, 0x01]);
+
+	// 546 and 294 sats are the smallest P2PKH and P2WPKH outputs that aren't dust
+	assert!(!is_dust(&TxOutput { value: 546, lock: p2pkh.clone() }) && is_dust(&TxOutput { value: 545, lock: p2pkh.clone() }));
+	assert!(!is_dust(&TxOutput { value: 294, lock: p2wpkh.clone() }) && is_dust(&TxOutput { value: 293, lock: p2wpkh.clone() }));
+
+	// bare multisig needs 1 <= m <= n <= 3
+	let key = "21".to_owned() + &"02".repeat(33);
+	for (hex, standard) in [(format!("51{}{}52ae", key, key), true), (format!("00{}{}52ae", key, key), false),
+		(format!("53{}{}52ae", key, key), false), (format!("51{}54ae", key.repeat(4)), false)] {
+		tx.outputs[0].lock = Script::from(hex_to_bytes(&hex).unwrap());
+		assert!(check_standard_tx(&tx).is_ok() == standard);
+	}
+	tx.outputs[0].lock = p2pkh;
+
+	let null_data = TxOutput { value: 0, lock: Script::from(hex_to_bytes("6a0401020304").unwrap()) };
+	tx.outputs.push(null_data.clone());
+	assert!(check_standard_tx(&tx).is_ok());
+	tx.outputs.push(null_data);
+	assert!(check_standard_tx(&tx).is_err());
+	tx.outputs.pop();
+
+	// spending a P2WPKH output with a legacy unlock script is fine; witness
+	// data on a P2PKH spend isn't
+	let prevout = TxOutput { value: SAT_PER_COIN, lock: p2wpkh };
+	assert!(check_standard_inputs(&tx, &[prevout]).is_ok());
+	tx.inputs[0].witness = vec![vec![1; 72]];
+	tx.segwit = true;
+	let prevout = TxOutput { value: SAT_PER_COIN, lock: tx.outputs[0].lock.clone() };
+	assert!(check_standard_inputs(&tx, &[prevout]).is_err());
+	let prevout = TxOutput { value: SAT_PER_COIN, lock: Script::from(vec![0x51]) };
+	assert!(check_standard_inputs(&tx, &[prevout]).is_err());
+}
+
+#[test]
+fn packages() {
+	use crate::crypto::sha256::Sha256;
+
+	let spend = Tx::spending;
+	let first = spend(&[(Sha256::from([1; 32]), 0)]);
+	let second = spend(&[(Sha256::from([1; 32]), 1)]);
+	let child = spend(&[(first.compute_hash(), 0), (second.compute_hash(), 0)]);
+	assert!(check_package(std::slice::from_ref(&child)).is_ok());
+	assert!(check_package(&[first.clone(), second.clone(), child.clone()]).is_ok());
+	assert!(check_package(&[second.clone(), child.clone()]).is_ok());
+
+	assert!(check_package(&[]).is_err());
+	assert!(check_package(&[child.clone(), first.clone()]).is_err());
+	assert!(check_package(&[first.clone(), first.clone(), child.clone()]).is_err());
+	// unrelated to the child
+	assert!(check_package(&[first.clone(), second.clone()]).is_err());
+	// two spends of the same output
+	let mut double = first.clone();
+	double.outputs[0].value = 2000;
+	let child = spend(&[(first.compute_hash(), 0), (double.compute_hash(), 0)]);
+	assert!(check_package(&[first, double, child]).is_err());
+}
@@ -7,10 +7,14 @@ use crate::{
 	State,
 	network::message::Tx,
 	err::*,
+	common::write_u32,
+	crypto::sha256::{Sha256, compute_double_sha256},
 };
 
 mod op;
+mod template;
 pub use op::*;
+pub use template::*;
 
 #[derive(Clone, Debug)]
 pub struct Script(Vec<u8>);
@@ -50,6 +54,45 @@ impl Script {
 		op.append_to(&mut self.0);
 		self
 	}
+
+	// CHECKSIGs count one each. a CHECKMULTISIG counts its number of keys when
+	// `accurate` and the op before gives it, otherwise the most it could have
+	pub fn sigop_count(&self, accurate: bool) -> usize {
+		let mut count = 0;
+		let mut last_n = None;
+		for op in self.ops() {
+			match op {
+				Op::CHECKSIG | Op::CHECKSIGVERIFY => count += 1,
+				Op::CHECKMULTISIG | Op::CHECKMULTISIGVERIFY => {
+					count += match last_n {
+						Some(n) if accurate => n,
+						_ => MAX_PUBKEYS_PER_MULTISIG,
+					};
+				},
+				_ => {},
+			}
+			last_n = op.small_int();
+		}
+		count
+	}
+
+	// OP_HASH160 <20 bytes> OP_EQUAL
+	pub fn is_p2sh(&self) -> bool {
+		self.0.len() == 23 && self.0[0] == 0xa9 && self.0[1] == 0x14 && self.0[22] == 0x87
+	}
+
+	// BIP 141: a version (OP_0 to OP_16) followed by a 2 to 40 byte push
+	pub fn witness_program(&self) -> Option<(u8, &[u8])> {
+		if self.0.len() < 4 || self.0.len() > 42 || self.0[1] as usize != self.0.len() - 2 {
+			return None;
+		}
+		let version = match self.0[0] {
+			0 => 0,
+			n @ 0x51..=0x60 => n - 0x50,
+			_ => return None,
+		};
+		Some((version, &self.0[2..]))
+	}
 }
 
 impl std::convert::From<Vec<u8>> for Script {
@@ -74,6 +117,64 @@ impl fmt::Display for Script {
 	}
 }
 
+pub const MAX_PUBKEYS_PER_MULTISIG: usize = 20;
+
+pub const SIGHASH_ALL: u8          = 0x01;
+pub const SIGHASH_NONE: u8         = 0x02;
+pub const SIGHASH_SINGLE: u8       = 0x03;
+pub const SIGHASH_ANYONECANPAY: u8 = 0x80;
+
+// legacy (pre-segwit) signature hash of input `index`, with `subscript` standing in for its unlock script
+pub fn compute_sig_hash(tx: &Tx, index: usize, subscript: Script, hash_type: u8) -> Result<Sha256> {
+	let sighash_none = hash_type & 0x1f == SIGHASH_NONE;
+	let sighash_single = hash_type & 0x1f == SIGHASH_SINGLE;
+	let sighash_anyonecanpay = hash_type & SIGHASH_ANYONECANPAY != 0;
+
+	let mut tx_copy = tx.clone();
+	
+	if sighash_none {
+		tx_copy.outputs = Vec::new();
+	} else if sighash_single {
+		if index >= tx_copy.outputs.len() {
+			// the infamous SIGHASH_SINGLE bug: the "hash" is the number one
+			let mut one = [0; 32];
+			one[0] = 1;
+			return Ok(Sha256::from(one));
+		}
+		tx_copy.outputs.truncate(index + 1);
+		for (i, output) in tx_copy.outputs.iter_mut().enumerate() {
+			if i == index {
+				continue;
+			}
+			output.value = -1i64 as u64;
+			output.lock = Script::new();
+		}
+	}
+
+	if sighash_anyonecanpay {
+		let mut input = tx_copy.inputs[index].clone();
+		input.unlock = subscript;
+		tx_copy.inputs = vec![input];
+	} else {
+		for (i, input) in tx_copy.inputs.iter_mut().enumerate() {
+			if i == index {
+				input.unlock = subscript.clone();
+			} else {
+				input.unlock = Script::new();
+				if sighash_none || sighash_single {
+					input.sequence = 0;
+				}
+			}
+		}
+	}
+
+	let mut serialized = Vec::new();
+	tx_copy.serialize_without_witness(&mut serialized)?;
+	write_u32(&mut serialized, hash_type as u32)?;
+
+	Ok(compute_double_sha256(&*serialized))
+}
+
 pub struct ScriptBuilder(Script);
 
 impl ScriptBuilder {
@@ -105,22 +206,22 @@ impl <'a> ScriptIterator<'a> {
 			size
 		}
 	}
+	// reads past the end of the script come back zero-filled
+	fn next_bytes<const N: usize>(&mut self) -> [u8; N] {
+		let mut bytes = [0; N];
+		let size = self.check_size(N);
+		bytes[..size].copy_from_slice(&self.script[self.offset..self.offset + size]);
+		self.offset += size;
+		bytes
+	}
 	fn next_u8(&mut self) -> u8 {
-		let n = self.script[self.offset];
-		self.offset += 1;
-		n
+		u8::from_le_bytes(self.next_bytes())
 	}
 	fn next_u16(&mut self) -> u16 {
-		const SIZE: usize = std::mem::size_of::<u16>();
-		let n = u16::from_le_bytes(self.script[self.offset..self.offset + SIZE].try_into().unwrap());
-		self.offset += SIZE;
-		n
+		u16::from_le_bytes(self.next_bytes())
 	}
 	fn next_u32(&mut self) -> u32 {
-		const SIZE: usize = std::mem::size_of::<u32>();
-		let n = u32::from_le_bytes(self.script[self.offset..self.offset + SIZE].try_into().unwrap());
-		self.offset += SIZE;
-		n
+		u32::from_le_bytes(self.next_bytes())
 	}
 	fn next_slice(&mut self, size: usize) -> &'a [u8] {
 		let size = self.check_size(size);
@@ -191,6 +292,28 @@ impl StackObject {
 		}
 	}
 
+	// numbers as they'd be pushed: minimal little-endian with a sign bit
+	fn to_stack_bytes(&self) -> Vec<u8> {
+		match self {
+			StackObject::Empty => Vec::new(),
+			StackObject::Int(n) => {
+				let mut bytes = Vec::new();
+				let mut abs = n.unsigned_abs();
+				while abs > 0 {
+					bytes.push(abs as u8);
+					abs >>= 8;
+				}
+				match bytes.last_mut() {
+					Some(last) if *last & 0x80 != 0 => bytes.push(if *n < 0 { 0x80 } else { 0 }),
+					Some(last) if *n < 0 => *last |= 0x80,
+					_ => {},
+				}
+				bytes
+			},
+			StackObject::Bytes(bytes) => bytes.clone(),
+		}
+	}
+
 	pub fn is_truthy(&self) -> bool {
 		match self {
 			StackObject::Empty => false,
@@ -210,7 +333,8 @@ impl std::cmp::PartialEq for StackObject {
 			(&StackObject::Empty,        &StackObject::Empty)        => true,
 			(&StackObject::Int(ref i),   &StackObject::Int(ref j))   => i == j,
 			(&StackObject::Bytes(ref i), &StackObject::Bytes(ref j)) => i == j,
-			_ => unimplemented!(),
+			// mixed representations compare by their encoding on the stack
+			_ => self.to_stack_bytes() == other.to_stack_bytes(),
 		}
 	}
 }
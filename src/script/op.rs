@@ -1,11 +1,10 @@
 use std::fmt;
 use super::*;
 use crate::{
-	network::{Serialize, Deserialize},
-	common::write_u32,
 	crypto::sha256,
 	crypto::sha256::Sha256,
 	crypto::ripemd160,
+	crypto::sha1,
 	crypto::ecdsa,
 	network::message::AbsoluteLockTime,
 };
@@ -262,6 +261,41 @@ impl <'a> Op<'a> {
 		Op::DATA(s.as_bytes())
 	}
 
+	// smallest push op able to carry `data`
+	pub fn push(data: &'a [u8]) -> Self {
+		match data.len() {
+			0              => Op::OP_0,
+			1..=75         => Op::DATA(data),
+			76..=0xff      => Op::PUSHDATA1(data),
+			0x100..=0xffff => Op::PUSHDATA2(data),
+			_              => Op::PUSHDATA4(data),
+		}
+	}
+
+	// the bytes pushed by this op, if it is a data push
+	pub fn push_data(&self) -> Option<&[u8]> {
+		match self {
+			Op::DATA(data)       => Some(data),
+			Op::OWNED_DATA(data) => Some(data),
+			Op::PUSHDATA1(data)  => Some(data),
+			Op::PUSHDATA2(data)  => Some(data),
+			Op::PUSHDATA4(data)  => Some(data),
+			_                    => None,
+		}
+	}
+
+	// the value of OP_1 through OP_16
+	pub fn small_int(&self) -> Option<usize> {
+		let n = match self {
+			Op::OP_1  => 1,  Op::OP_2  => 2,  Op::OP_3  => 3,  Op::OP_4  => 4,
+			Op::OP_5  => 5,  Op::OP_6  => 6,  Op::OP_7  => 7,  Op::OP_8  => 8,
+			Op::OP_9  => 9,  Op::OP_10 => 10, Op::OP_11 => 11, Op::OP_12 => 12,
+			Op::OP_13 => 13, Op::OP_14 => 14, Op::OP_15 => 15, Op::OP_16 => 16,
+			_ => return None,
+		};
+		Some(n)
+	}
+
 	pub fn data_hex(s: &str) -> Self {
 		let bytes = crate::common::hex_to_bytes(s).unwrap();
 		assert!(bytes.len() <= 75);
@@ -653,7 +687,7 @@ impl <'a> Op<'a> {
 			Op::MAX                 => Op::do_num_max(runtime),
 			Op::WITHIN              => Op::do_num_within(runtime),
 			Op::RIPEMD160           => Op::do_ripemd160(runtime),
-			Op::SHA1                => unimplemented!(),
+			Op::SHA1                => Op::do_sha1(runtime),
 			Op::SHA256              => Op::do_sha256(runtime),
 			Op::HASH160             => Op::do_hash160(runtime),
 			Op::HASH256             => Op::do_hash256(runtime),
@@ -664,7 +698,7 @@ impl <'a> Op<'a> {
 			Op::CHECKMULTISIGVERIFY => Op::do_check_multisig_verify(runtime),
 			Op::NOP1                => Ok(()),
 			Op::CHECKLOCKTIMEVERIFY => Op::do_check_lock_time_verify(runtime),
-			Op::CHECKSEQUENCEVERIFY => unimplemented!(),
+			Op::CHECKSEQUENCEVERIFY => Op::do_check_sequence_verify(runtime),
 			Op::NOP4                => Ok(()),
 			Op::NOP5                => Ok(()),
 			Op::NOP6                => Ok(()),
@@ -1057,6 +1091,12 @@ impl <'a> Op<'a> {
 		Op::push_stack(runtime, StackObject::Bytes(hash.as_bytes().to_vec()))
 	}
 
+	fn do_sha1(runtime: &mut ScriptRuntime) -> Result<()> {
+		let item = Op::pop_stack(runtime)?;
+		let hash = sha1::compute_sha1(&item.to_vec());
+		Op::push_stack(runtime, StackObject::Bytes(hash.to_vec()))
+	}
+
 	fn do_sha256(runtime: &mut ScriptRuntime) -> Result<()> {
 		let item = Op::pop_stack(runtime)?;
 		let hash = sha256::compute_sha256(&*item.to_vec());
@@ -1081,8 +1121,11 @@ impl <'a> Op<'a> {
 		
 		let pubkey = pubkey_item.to_vec();
 		let sig = sig_item.to_vec();
-		let hash_type = *sig.last().unwrap();
-		let sig = &sig[0..sig.len()-1];
+		let (&hash_type, sig) = match sig.split_last() {
+			Some(split) => split,
+			// an empty signature is just a failed check
+			None => return Op::push_stack(runtime, StackObject::Empty),
+		};
 		
 		let hash = Op::build_sig_hash(runtime, hash_type)?;
 
@@ -1106,8 +1149,11 @@ impl <'a> Op<'a> {
 		let mut sigs = Vec::new();
 		for _ in 0..n_sig.to_i64() {
 			let sig = Op::pop_stack(runtime)?.to_vec();
-			let hash_type = *sig.last().unwrap();
-			let sig = sig[0..sig.len()-1].to_vec();
+			// an empty signature can't match any key; hash type 0 is as good as any
+			let (hash_type, sig) = match sig.split_last() {
+				Some((&hash_type, sig)) => (hash_type, sig.to_vec()),
+				None => (0, Vec::new()),
+			};
 			sigs.push((sig, hash_type));
 		}
 
@@ -1177,59 +1223,47 @@ impl <'a> Op<'a> {
 		Ok(())
 	}
 
-	fn build_sig_hash(runtime: &mut ScriptRuntime, hash_type: u8) -> Result<Sha256> {
-		const SIGHASH_NONE         : u8 = 0x02;
-		const SIGHASH_SINGLE       : u8 = 0x03;
-		const SIGHASH_ANYONECANPAY : u8 = 0x80;
-
-		let sighash_none = hash_type & 0x1f == SIGHASH_NONE;
-		let sighash_single = hash_type & 0x1f == SIGHASH_SINGLE;
-		let sighash_anyonecanpay = hash_type & SIGHASH_ANYONECANPAY != 0;
+	// BIP 112
+	fn do_check_sequence_verify(runtime: &mut ScriptRuntime) -> Result<()> {
+		const DISABLE_FLAG: i64 = 1 << 31;
+		const TYPE_FLAG: i64 = 1 << 22;
+		const MASK: i64 = TYPE_FLAG | 0xffff;
 
-		let mut tx_copy = runtime.tx.clone();
-		
-		if sighash_none {
-			tx_copy.outputs = Vec::new();
-		} else if sighash_single {
-			if runtime.index >= tx_copy.outputs.len() {
-				unimplemented!();
-				// return Ok(sha256::ONE);
-			}
-			tx_copy.outputs.truncate(runtime.index + 1);
-			for (i, output) in tx_copy.outputs.iter_mut().enumerate() {
-				if i == runtime.index {
-					continue;
-				}
-				output.value = -1i64 as u64;
-				output.lock = Script::new();
-			}
+		// before BIP 112 activated, OP_CSV was OP_NOP3
+		if !runtime.state.csv_enabled() {
+			return Ok(());
 		}
 
-		if sighash_anyonecanpay {
-			let mut input = tx_copy.inputs[runtime.index].clone();
-			input.unlock = runtime.get_subscript();
-			tx_copy.inputs = vec![input];
+		let err = || Err(Err::ScriptError("OP_CHECKSEQUENCEVERIFY failed".to_owned()));
+
+		let val = if let Some(val) = runtime.stack.last() {
+			val.to_i64()
 		} else {
-			for (i, input) in tx_copy.inputs.iter_mut().enumerate() {
-				if i == runtime.index {
-					input.unlock = runtime.get_subscript();
-				} else {
-					input.unlock = Script::new();
-					if sighash_none || sighash_single {
-						input.sequence = 0;
-					}
-				}
-			}
+			return err();
+		};
+
+		if val < 0 {
+			return err();
+		} else if val & DISABLE_FLAG != 0 {
+			return Ok(());
 		}
 
-		let serialized: crate::err::Result<_> = try {
-			let mut serialized = Vec::new();
-			tx_copy.serialize(&mut serialized)?;
-			write_u32(&mut serialized, hash_type as u32)?;
-			serialized
-		};
+		let sequence = runtime.tx.inputs[runtime.index].sequence as i64;
+		if runtime.tx.version < 2 || sequence & DISABLE_FLAG != 0 {
+			return err();
+		}
+
+		// both have to be heights or both times, and the script's can't ask for more
+		let (val, sequence) = (val & MASK, sequence & MASK);
+		if (val & TYPE_FLAG) != (sequence & TYPE_FLAG) || val > sequence {
+			return err();
+		}
+
+		Ok(())
+	}
 
-		Ok(sha256::compute_double_sha256(&*serialized?))
+	fn build_sig_hash(runtime: &mut ScriptRuntime, hash_type: u8) -> Result<Sha256> {
+		compute_sig_hash(runtime.tx, runtime.index, runtime.get_subscript(), hash_type)
 	}
 
 	fn push_stack(runtime: &mut ScriptRuntime, item: StackObject) -> Result<()> {
@@ -1258,8 +1292,11 @@ impl <'a> Op<'a> {
 	}
 	
 	fn pop_stack_index(runtime: &mut ScriptRuntime) -> Result<usize> {
-		let n = Op::pop_stack(runtime)?.to_i64() as usize;
-		Op::check_stack(runtime, n + 1).and(Ok(runtime.stack.len() - 1 - n))
+		let n = Op::pop_stack(runtime)?.to_i64();
+		if n < 0 || n >= runtime.stack.len() as i64 {
+			return Err(Err::ScriptError("too few items on the stack".to_owned()))
+		}
+		Ok(runtime.stack.len() - 1 - n as usize)
 	}
 }
 
@@ -1389,6 +1426,7 @@ impl <'a> fmt::Display for Op<'a> {
 // https://bitcoin.stackexchange.com/questions/32305/how-does-the-ecdsa-verification-algorithm-work-during-transaction
 #[test]
 fn pizza() {
+	use crate::network::{Serialize, Deserialize};
 	use crate::crypto::ecdsa::*;
 	use crate::common::*;
 	use crate::crypto::sha256;
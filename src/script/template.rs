@@ -0,0 +1,107 @@
+// The output script forms relay policy knows, matched the way bitcoind's Solver
+// does. Anything else can still be mined, but isn't relayed.
+
+use super::{Script, Op};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ScriptType {
+	PubKey,
+	PubKeyHash,
+	ScriptHash,
+	// m of n keys
+	Multisig(usize, usize),
+	// OP_RETURN followed by pushes only; provably unspendable
+	NullData,
+	WitnessV0KeyHash,
+	WitnessV0ScriptHash,
+	Taproot,
+	// witness versions (or program sizes) not given a meaning yet
+	WitnessUnknown,
+	NonStandard,
+}
+
+impl Script {
+	pub fn classify(&self) -> ScriptType {
+		let bytes = self.as_bytes();
+		if self.is_p2sh() {
+			return ScriptType::ScriptHash;
+		}
+		if let Some((version, program)) = self.witness_program() {
+			return match (version, program.len()) {
+				(0, 20) => ScriptType::WitnessV0KeyHash,
+				(0, 32) => ScriptType::WitnessV0ScriptHash,
+				(0, _)  => ScriptType::NonStandard,
+				(1, 32) => ScriptType::Taproot,
+				_       => ScriptType::WitnessUnknown,
+			};
+		}
+		if bytes.first() == Some(&0x6a) && self.ops_from(1).all(|op| op.is_push()) {
+			return ScriptType::NullData;
+		}
+		// OP_DUP OP_HASH160 <20 bytes> OP_EQUALVERIFY OP_CHECKSIG
+		if bytes.len() == 25 && bytes[0..3] == [0x76, 0xa9, 0x14] && bytes[23..] == [0x88, 0xac] {
+			return ScriptType::PubKeyHash;
+		}
+
+		let ops = self.ops().collect::<Vec<_>>();
+		match &*ops {
+			[key, Op::CHECKSIG] if key.push_data().is_some_and(is_pubkey) => ScriptType::PubKey,
+			[m, keys @ .., n, Op::CHECKMULTISIG] => {
+				let (m, n) = match (m.small_int(), n.small_int()) {
+					(Some(m), Some(n)) => (m, n),
+					_ => return ScriptType::NonStandard,
+				};
+				if keys.len() != n || m > n || !keys.iter().all(|key| key.push_data().is_some_and(is_pubkey)) {
+					return ScriptType::NonStandard;
+				}
+				ScriptType::Multisig(m, n)
+			},
+			_ => ScriptType::NonStandard,
+		}
+	}
+
+	// only pushes data (and small numbers), as unlock scripts have to under policy
+	pub fn is_push_only(&self) -> bool {
+		self.ops().all(|op| op.is_push())
+	}
+}
+
+impl Op<'_> {
+	// OP_0 through OP_16, as bitcoind counts them (OP_RESERVED included)
+	pub fn is_push(&self) -> bool {
+		self.push_data().is_some() || self.small_int().is_some() ||
+			matches!(self, Op::OP_0 | Op::OP_1NEGATE | Op::RESERVED)
+	}
+}
+
+// a compressed or uncompressed key, going by size and prefix
+fn is_pubkey(data: &[u8]) -> bool {
+	match data.len() {
+		33 => data[0] == 0x02 || data[0] == 0x03,
+		65 => data[0] == 0x04,
+		_ => false,
+	}
+}
+
+#[test]
+fn classify() {
+	use crate::common::hex_to_bytes;
+	let script = |hex: &str| Script::from(hex_to_bytes(hex).unwrap());
+	let key = "02".to_owned() + &"11".repeat(32);
+
+	assert!(script("76a914000102030405060708090a0b0c0d0e0f1011121388ac").classify() == ScriptType::PubKeyHash);
+	assert!(script("a914000102030405060708090a0b0c0d0e0f1011121387").classify() == ScriptType::ScriptHash);
+	assert!(script("0014000102030405060708090a0b0c0d0e0f10111213").classify() == ScriptType::WitnessV0KeyHash);
+	assert!(script(&format!("0020{}", "22".repeat(32))).classify() == ScriptType::WitnessV0ScriptHash);
+	assert!(script(&format!("5120{}", "22".repeat(32))).classify() == ScriptType::Taproot);
+	assert!(script("52020001").classify() == ScriptType::WitnessUnknown);
+	assert!(script("0015000102030405060708090a0b0c0d0e0f1011121314").classify() == ScriptType::NonStandard);
+	assert!(script(&format!("21{}ac", key)).classify() == ScriptType::PubKey);
+	assert!(script(&format!("5221{}21{}52ae", key, key)).classify() == ScriptType::Multisig(2, 2));
+	assert!(script(&format!("5321{}21{}52ae", key, key)).classify() == ScriptType::NonStandard);
+	assert!(script("6a").classify() == ScriptType::NullData);
+	assert!(script("6a0401020304").classify() == ScriptType::NullData);
+	assert!(script("6a0401020304ac").classify() == ScriptType::NonStandard);
+	assert!(script("51").classify() == ScriptType::NonStandard);
+	assert!(script("0051").is_push_only() && !script("00ac").is_push_only());
+}
@@ -0,0 +1,354 @@
+// Signet (BIP 325): blocks carry a solution to a challenge script in their
+// coinbase witness commitment. The solution is checked by spending a synthetic
+// "to_spend" tx (which pays to the challenge and commits to the block) with a
+// synthetic "to_sign" tx (whose unlock script and witness are the solution).
+
+use crate::{
+	State,
+	err::*,
+	common::*,
+	crypto::{
+		sha256::*,
+		ecdsa::*,
+		ripemd160::compute_ripemd160,
+	},
+	network::{
+		Serialize,
+		Deserialize,
+		message::{
+			Block,
+			Tx,
+			TxInput,
+			TxOutput,
+			AbsoluteLockTime,
+			compute_merkle_root,
+		},
+	},
+	script::*,
+};
+
+pub const SIGNET_HEADER: [u8; 4] = [0xec, 0xc7, 0xda, 0xa2];
+
+// the challenge of the public signet (a 1-of-2 bare multisig)
+pub const DEFAULT_CHALLENGE: &str = "512103ad5e0edad18cb1f0fc0d28a3d4f1f3e445640337489abb10404f2d1e086be430210359ef5021964fe22d6f8e05b2463c9540ce96883fe3b278760f048f5189f2e6c452ae";
+
+// the message start bytes are the first four bytes of the double-sha256 of the challenge
+pub fn magic(challenge: &Script) -> u32 {
+	let mut buf = Vec::new();
+	write_var_int(&mut buf, challenge.len() as u64).unwrap();
+	buf.extend_from_slice(challenge.as_bytes());
+	let hash = compute_double_sha256(&*buf);
+	u32::from_le_bytes(hash.as_bytes()[0..4].try_into().unwrap())
+}
+
+// solutions are evaluated with the legacy script interpreter, which has no
+// notion of witness programs, so witness challenges are rejected up front
+pub fn check_challenge(challenge: &Script) -> Result<()> {
+	let bytes = challenge.as_bytes();
+	let is_witness_program = bytes.len() >= 4 && bytes.len() <= 42
+		&& (bytes[0] == 0x00 || (0x51..=0x60).contains(&bytes[0]))
+		&& bytes[1] as usize == bytes.len() - 2;
+	if bytes.is_empty() {
+		Err(Err::ValueError("signet challenge is empty".to_owned()))
+	} else if is_witness_program {
+		Err(Err::ValueError("witness program signet challenges (segwit or taproot) are not supported; use a bare script such as a multisig".to_owned()))
+	} else {
+		Ok(())
+	}
+}
+
+pub struct SignetTxs {
+	pub to_spend: Tx,
+	pub to_sign: Tx,
+}
+
+impl SignetTxs {
+	pub fn create(block: &Block, challenge: &Script) -> Option<SignetTxs> {
+		let mut to_spend = Tx {
+			version: 0,
+			segwit: false,
+			inputs: vec![TxInput {
+				tx_hash: Sha256::default(),
+				index: 0xffff_ffff,
+				unlock: Script::builder().append(Op::OP_0).build(),
+				witness: Vec::new(),
+				sequence: 0,
+			}],
+			outputs: vec![TxOutput { value: 0, lock: challenge.clone() }],
+			abs_lock_time: AbsoluteLockTime::None,
+		};
+
+		let mut to_sign = Tx {
+			version: 0,
+			segwit: false,
+			inputs: vec![TxInput {
+				tx_hash: Sha256::default(),
+				index: 0,
+				unlock: Script::new(),
+				witness: Vec::new(),
+				sequence: 0,
+			}],
+			outputs: vec![TxOutput { value: 0, lock: Script::builder().append(Op::RETURN).build() }],
+			abs_lock_time: AbsoluteLockTime::None,
+		};
+
+		let mut modified_cb = block.txs.first()?.clone();
+		let index = block.witness_commitment_index()?;
+
+		// a block without a solution is allowed (e.g. for an OP_TRUE challenge)
+		if let Some((cleared, solution)) = fetch_and_clear_commitment_section(&modified_cb.outputs[index].lock) {
+			modified_cb.outputs[index].lock = cleared;
+			let stream = &mut &*solution;
+			to_sign.inputs[0].unlock = Script::from(read_var_bytes(stream).ok()?);
+			let count = read_var_int(stream).ok()?;
+			for _ in 0..count {
+				to_sign.inputs[0].witness.push(read_var_bytes(stream).ok()?);
+			}
+			if !stream.is_empty() {
+				// extraneous data
+				return None;
+			}
+			to_sign.segwit = !to_sign.inputs[0].witness.is_empty();
+		}
+
+		let mut hashes = block.txs.iter().map(|tx| tx.compute_hash()).collect::<Vec<_>>();
+		hashes[0] = modified_cb.compute_hash();
+		let signet_merkle = compute_merkle_root(hashes);
+
+		let mut block_data = Vec::new();
+		write_i32(&mut block_data, block.header.version).ok()?;
+		write_sha256(&mut block_data, &block.header.prev_block).ok()?;
+		write_sha256(&mut block_data, &signet_merkle).ok()?;
+		write_u32(&mut block_data, block.header.timestamp).ok()?;
+		to_spend.inputs[0].unlock.append(Op::push(&block_data));
+
+		to_sign.inputs[0].tx_hash = to_spend.compute_hash();
+
+		Some(SignetTxs { to_spend, to_sign })
+	}
+}
+
+fn read_var_bytes(stream: &mut dyn std::io::Read) -> Result<Vec<u8>> {
+	let size = read_var_int(stream)? as usize;
+	if size > MAX_SOLUTION_ITEM_SIZE {
+		return Err(Err::ValueError("signet solution item too large".to_owned()));
+	}
+	let mut buf = vec![0; size];
+	read_buf_exact(stream, &mut buf)?;
+	Ok(buf)
+}
+
+const MAX_SOLUTION_ITEM_SIZE: usize = 10_000;
+
+// finds the first push in `commitment` that starts with SIGNET_HEADER (and has
+// some data after it), returning the script with that push truncated to just
+// the header, along with the data that followed the header
+fn fetch_and_clear_commitment_section(commitment: &Script) -> Option<(Script, Vec<u8>)> {
+	let mut cleared = Script::new();
+	let mut section = None;
+	for op in commitment.ops() {
+		match op.push_data() {
+			Some(data) if section.is_none() && data.len() > SIGNET_HEADER.len() && data[0..4] == SIGNET_HEADER => {
+				section = Some(data[4..].to_vec());
+				cleared.append(Op::push(&SIGNET_HEADER));
+			},
+			Some(data) if !data.is_empty() => {
+				cleared.append(Op::push(data));
+			},
+			_ => {
+				cleared.append(op);
+			},
+		}
+	}
+	section.map(|section| (cleared, section))
+}
+
+#[must_use]
+pub fn check_block_solution(block: &Block, challenge: &Script, state: &State) -> bool {
+	let txs = match SignetTxs::create(block, challenge) {
+		Some(txs) => txs,
+		None => {
+			log_debug!("signet block solution could not be parsed");
+			return false;
+		},
+	};
+
+	let unlock = &txs.to_sign.inputs[0].unlock;
+	let lock = &txs.to_spend.outputs[0].lock;
+	let mut runtime = ScriptRuntime::new(&txs.to_sign, 0, state);
+	let result = runtime.execute(unlock)
+		.and_then(|_| runtime.execute(lock))
+		.and_then(|_| runtime.finalize());
+
+	match result {
+		Ok(result) => result.is_truthy(),
+		Err(err) => {
+			log_debug!("signet block solution failed: {}", err);
+			false
+		},
+	}
+}
+
+// adds a solution to `block` (adding a witness commitment first if needed),
+// signing with whichever of `keys` the challenge asks for
+pub fn sign_block(block: &mut Block, challenge: &Script, keys: &[ECDSAPrivKey]) -> Result<()> {
+	block.add_witness_commitment();
+	let index = block.witness_commitment_index().unwrap();
+
+	// drop any previous solution, and leave a bare header in its place, which
+	// is what the commitment will look like once the solution is cleared
+	let mut lock = Script::new();
+	for op in block.txs[0].outputs[index].lock.ops() {
+		match op.push_data() {
+			Some(data) if data.len() >= SIGNET_HEADER.len() && data[0..4] == SIGNET_HEADER => {},
+			_ => { lock.append(op); },
+		}
+	}
+	lock.append(Op::push(&SIGNET_HEADER));
+	block.txs[0].outputs[index].lock = lock;
+
+	let txs = SignetTxs::create(block, challenge)
+		.ok_or_else(|| Err::ValueError("block is not a valid signet block".to_owned()))?;
+	let hash = compute_sig_hash(&txs.to_sign, 0, challenge.clone(), SIGHASH_ALL)?;
+	let unlock = build_unlock_script(challenge, keys, &hash)?;
+
+	let mut section = SIGNET_HEADER.to_vec();
+	write_var_int(&mut section, unlock.len() as u64)?;
+	write_buf_exact(&mut section, unlock.as_bytes())?;
+	write_var_int(&mut section, 0)?;
+
+	let mut lock = Script::new();
+	for op in block.txs[0].outputs[index].lock.ops() {
+		match op.push_data() {
+			Some(data) if data == SIGNET_HEADER => { lock.append(Op::push(&section)); },
+			_ => { lock.append(op); },
+		}
+	}
+	block.txs[0].outputs[index].lock = lock;
+	block.update_merkle_root();
+
+	if !check_block_solution(block, challenge, &State::default()) {
+		return Err(Err::ValueError("the given keys do not satisfy the signet challenge".to_owned()));
+	}
+
+	Ok(())
+}
+
+fn build_unlock_script(challenge: &Script, keys: &[ECDSAPrivKey], hash: &Sha256) -> Result<Script> {
+	let sign = |key: &ECDSAPrivKey| -> Result<Vec<u8>> {
+		let mut sig = Vec::new();
+		key.sign(hash).serialize(&mut sig)?;
+		sig.push(SIGHASH_ALL);
+		Ok(sig)
+	};
+	let find_key = |pubkey: &[u8]| {
+		let pubkey = ECDSAPubKey::deserialize(&mut &*pubkey).ok()?;
+		keys.iter().find(|key| key.pubkey() == pubkey)
+	};
+
+	let ops = challenge.ops().collect::<Vec<_>>();
+	let mut unlock = Script::new();
+	match &*ops {
+		// P2PK
+		[pubkey, Op::CHECKSIG] if pubkey.push_data().is_some() => {
+			let key = find_key(pubkey.push_data().unwrap())
+				.ok_or_else(|| Err::ValueError("no key matches the signet challenge".to_owned()))?;
+			unlock.append(Op::push(&sign(key)?));
+		},
+		// P2PKH
+		[Op::DUP, Op::HASH160, hash160, Op::EQUALVERIFY, Op::CHECKSIG] if hash160.push_data().is_some() => {
+			let expected = hash160.push_data().unwrap();
+			let key = keys.iter()
+				.find(|key| {
+					let pubkey = serialize(&key.pubkey()).unwrap();
+					compute_ripemd160(compute_sha256(&*pubkey).as_bytes()).as_bytes() == expected
+				})
+				.ok_or_else(|| Err::ValueError("no key matches the signet challenge".to_owned()))?;
+			let pubkey = serialize(&key.pubkey())?;
+			unlock.append(Op::push(&sign(key)?));
+			unlock.append(Op::push(&pubkey));
+		},
+		// bare multisig
+		[m, pubkeys @ .., n, Op::CHECKMULTISIG] if m.small_int().is_some() && n.small_int() == Some(pubkeys.len()) => {
+			let m = m.small_int().unwrap();
+			let mut sigs = Vec::new();
+			for pubkey in pubkeys.iter() {
+				if sigs.len() == m {
+					break;
+				}
+				if let Some(key) = pubkey.push_data().and_then(&find_key) {
+					sigs.push(sign(key)?);
+				}
+			}
+			if sigs.len() < m {
+				return Err(Err::ValueError(format!("signet challenge needs {} signatures, only {} key(s) match", m, sigs.len())));
+			}
+			unlock.append(Op::OP_0);
+			for sig in sigs.iter() {
+				unlock.append(Op::push(sig));
+			}
+		},
+		// anything else (e.g. OP_TRUE) gets an empty solution
+		_ => {},
+	}
+
+	Ok(unlock)
+}
+
+// grinds the header nonce until the block meets its own target
+pub fn mine(block: &mut Block) -> Result<()> {
+	let target = block.header.compute_target();
+	for nonce in 0..=u32::MAX {
+		block.header.set_nonce(nonce);
+		if block.header.compute_hash().to_u256() < target {
+			return Ok(());
+		}
+	}
+	Err(Err::ValueError("exhausted nonce space without meeting target".to_owned()))
+}
+
+#[test]
+fn default_challenge_magic() {
+	let challenge = Script::from(hex_to_bytes(DEFAULT_CHALLENGE).unwrap());
+	assert!(magic(&challenge) == 0x40CF030A);
+}
+
+#[test]
+fn sign_and_check() {
+	let key = ECDSAPrivKey::generate();
+	let pubkey = serialize(&key.pubkey()).unwrap();
+	let challenge = Script::builder()
+		.append(Op::push(&pubkey))
+		.append(Op::CHECKSIG)
+		.build();
+
+	let mut block = Block::signet_genesis();
+	block.header.prev_block = block.header.compute_hash();
+	block.txs[0].inputs[0].unlock = Script::builder().append(Op::OP_1).append(Op::OP_0).build();
+	block.update_merkle_root();
+	assert!(!check_block_solution(&block, &challenge, &State::default()));
+
+	sign_block(&mut block, &challenge, &[key]).unwrap();
+	assert!(check_block_solution(&block, &challenge, &State::default()));
+
+	let mut bytes = Vec::new();
+	block.serialize(&mut bytes).unwrap();
+	let block = Block::deserialize(&mut &*bytes).unwrap();
+	assert!(check_block_solution(&block, &challenge, &State::default()));
+
+	let mut tampered = block;
+	tampered.header.timestamp += 1;
+	assert!(!check_block_solution(&tampered, &challenge, &State::default()));
+}
+
+#[test]
+fn witness_program_challenges() {
+	let challenge = |hex: &str| Script::from(hex_to_bytes(hex).unwrap());
+	// p2wpkh, p2wsh and p2tr
+	assert!(check_challenge(&challenge(&format!("0014{}", "11".repeat(20)))).is_err());
+	assert!(check_challenge(&challenge(&format!("0020{}", "11".repeat(32)))).is_err());
+	assert!(check_challenge(&challenge(&format!("5120{}", "11".repeat(32)))).is_err());
+	assert!(check_challenge(&challenge("")).is_err());
+	assert!(check_challenge(&challenge("51")).is_ok());
+	assert!(check_challenge(&challenge(DEFAULT_CHALLENGE)).is_ok());
+}